@@ -0,0 +1,403 @@
+//! Async client for the KStore HTTP API.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), kstore_client::Error> {
+//! use futures_util::StreamExt;
+//!
+//! let client = kstore_client::Client::new("http://127.0.0.1:8080")?;
+//! client.set("user:1", "alice").await?;
+//! assert_eq!(client.get("user:1").await?.as_deref(), Some("alice"));
+//!
+//! let mut events = client.watch(Some("user:*")).await?;
+//! while let Some(event) = events.next().await {
+//!     println!("{:?}", event?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Requests that fail to connect, time out, or get a `502`, `503` or `504`
+//! back are retried with exponential backoff. Connections are pooled and
+//! reused; clone the client to share the pool.
+
+use std::fmt;
+use std::time::Duration;
+
+use futures_util::stream::BoxStream;
+use futures_util::{Stream, StreamExt};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const RETRY_STATUSES: [StatusCode; 3] = [
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+    StatusCode::GATEWAY_TIMEOUT,
+];
+/// Everything but RFC 3986 unreserved characters, so any key is one path
+/// segment.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request could not be sent or its response not read.
+    Http(reqwest::Error),
+    /// The server answered with an error status.
+    Status { status: u16, message: String },
+    /// A response or event did not have the expected format.
+    Decode(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "{}", e),
+            Error::Status { status, message } => write!(f, "{} {}", status, message),
+            Error::Decode(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl Error {
+    async fn from_response(response: Response) -> Self {
+        Error::Status {
+            status: response.status().as_u16(),
+            message: response.text().await.unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Created,
+    Updated,
+    Deleted,
+    Expired,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct KeyEvent {
+    pub event: EventKind,
+    pub key: String,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    Key(KeyEvent),
+    /// The watch fell behind and this many events were dropped.
+    Lagged(u64),
+}
+
+#[derive(Serialize)]
+struct BatchItem<'a> {
+    key: &'a str,
+    value: &'a str,
+}
+
+#[derive(Deserialize)]
+struct BatchResponse {
+    success_count: usize,
+}
+
+#[derive(Deserialize)]
+struct Exists {
+    exists: bool,
+}
+
+#[derive(Deserialize)]
+struct Lagged {
+    missed: u64,
+}
+
+pub struct ClientBuilder {
+    base_url: String,
+    retries: u32,
+    retry_backoff: Duration,
+    timeout: Duration,
+    pool_max_idle_per_host: usize,
+}
+
+impl ClientBuilder {
+    /// How many times a failed request is retried, 3 by default.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Wait before the first retry, doubled for each further one. 100ms by
+    /// default.
+    pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Timeout for each request, 30 seconds by default. Watches are not
+    /// limited.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Idle connections kept open per server.
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.pool_max_idle_per_host = max;
+        self
+    }
+
+    pub fn build(self) -> Result<Client, Error> {
+        Ok(Client {
+            base_url: self.base_url.trim_end_matches('/').to_string(),
+            http: reqwest::Client::builder()
+                .pool_max_idle_per_host(self.pool_max_idle_per_host)
+                .build()?,
+            retries: self.retries,
+            retry_backoff: self.retry_backoff,
+            timeout: self.timeout,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Client {
+    base_url: String,
+    http: reqwest::Client,
+    retries: u32,
+    retry_backoff: Duration,
+    timeout: Duration,
+}
+
+impl Client {
+    /// A client for the server at `base_url`, such as
+    /// `http://127.0.0.1:8080`, with default settings.
+    pub fn new(base_url: impl Into<String>) -> Result<Self, Error> {
+        Self::builder(base_url).build()
+    }
+
+    pub fn builder(base_url: impl Into<String>) -> ClientBuilder {
+        ClientBuilder {
+            base_url: base_url.into(),
+            retries: DEFAULT_RETRIES,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+            timeout: DEFAULT_TIMEOUT,
+            pool_max_idle_per_host: usize::MAX,
+        }
+    }
+
+    fn key_url(&self, key: &str) -> String {
+        format!(
+            "{}/kv/{}",
+            self.base_url,
+            utf8_percent_encode(key, PATH_SEGMENT)
+        )
+    }
+
+    /// Sends the request built by `request`, retrying connection failures
+    /// and gateway errors.
+    async fn send(&self, request: impl Fn() -> RequestBuilder) -> Result<Response, Error> {
+        let mut attempt = 0;
+        loop {
+            let result = request().timeout(self.timeout).send().await;
+            let retry = match &result {
+                Ok(response) => RETRY_STATUSES.contains(&response.status()),
+                Err(e) => e.is_connect() || e.is_timeout(),
+            };
+            if !retry || attempt >= self.retries {
+                return Ok(result?);
+            }
+            tokio::time::sleep(self.retry_backoff * 2u32.pow(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    /// The value of `key`, or `None` if it does not exist.
+    pub async fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        let url = self.key_url(key);
+        let response = self.send(|| self.http.get(&url)).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response.text().await?)),
+            _ => Err(Error::from_response(response).await),
+        }
+    }
+
+    /// Whether `key` exists, without counting as an access.
+    pub async fn exists(&self, key: &str) -> Result<bool, Error> {
+        let url = format!("{}/exists", self.key_url(key));
+        let response = self.send(|| self.http.get(&url)).await?;
+        if !response.status().is_success() {
+            return Err(Error::from_response(response).await);
+        }
+        Ok(response.json::<Exists>().await?.exists)
+    }
+
+    /// Sets `key` to `value`, creating it or overwriting its current value.
+    pub async fn set(&self, key: &str, value: impl Into<String>) -> Result<(), Error> {
+        let url = self.key_url(key);
+        let value = value.into();
+        let mut response = self
+            .send(|| self.http.post(&url).body(value.clone()))
+            .await?;
+        if response.status() == StatusCode::CONFLICT {
+            response = self
+                .send(|| self.http.put(&url).body(value.clone()))
+                .await?;
+        }
+        if !response.status().is_success() {
+            return Err(Error::from_response(response).await);
+        }
+        Ok(())
+    }
+
+    /// Deletes `key`, returning whether it existed.
+    pub async fn delete(&self, key: &str) -> Result<bool, Error> {
+        let url = self.key_url(key);
+        let response = self.send(|| self.http.delete(&url)).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            status if status.is_success() => Ok(true),
+            _ => Err(Error::from_response(response).await),
+        }
+    }
+
+    /// Keys starting with `prefix`, sorted, at most `limit` of them.
+    pub async fn keys(
+        &self,
+        prefix: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<String>, Error> {
+        let url = format!("{}/kv/", self.base_url);
+        let mut query = Vec::new();
+        if let Some(prefix) = prefix {
+            query.push(("prefix", prefix.to_string()));
+        }
+        if let Some(limit) = limit {
+            query.push(("limit", limit.to_string()));
+        }
+        let response = self.send(|| self.http.get(&url).query(&query)).await?;
+        if !response.status().is_success() {
+            return Err(Error::from_response(response).await);
+        }
+        Ok(response.json().await?)
+    }
+
+    /// Sets many keys in one request, returning how many were set.
+    pub async fn batch_set<K, V>(&self, items: &[(K, V)]) -> Result<usize, Error>
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let items: Vec<BatchItem> = items
+            .iter()
+            .map(|(key, value)| BatchItem {
+                key: key.as_ref(),
+                value: value.as_ref(),
+            })
+            .collect();
+        let url = format!("{}/batch", self.base_url);
+        let response = self.send(|| self.http.post(&url).json(&items)).await?;
+        if !response.status().is_success() {
+            return Err(Error::from_response(response).await);
+        }
+        Ok(response.json::<BatchResponse>().await?.success_count)
+    }
+
+    /// Streams changes to keys matching the glob `pattern`, or to every key.
+    /// The stream ends when the server closes the connection.
+    pub async fn watch(
+        &self,
+        pattern: Option<&str>,
+    ) -> Result<BoxStream<'static, Result<WatchEvent, Error>>, Error> {
+        let url = format!("{}/subscribe", self.base_url);
+        let mut attempt = 0;
+        let response = loop {
+            let mut request = self.http.get(&url);
+            if let Some(pattern) = pattern {
+                request = request.query(&[("pattern", pattern)]);
+            }
+            match request.send().await {
+                Err(e) if e.is_connect() && attempt < self.retries => {
+                    tokio::time::sleep(self.retry_backoff * 2u32.pow(attempt)).await;
+                    attempt += 1;
+                }
+                result => break result?,
+            }
+        };
+        if !response.status().is_success() {
+            return Err(Error::from_response(response).await);
+        }
+        Ok(sse_events(response).boxed())
+    }
+}
+
+/// Parses a Server-Sent Events body into watch events.
+fn sse_events(response: Response) -> impl Stream<Item = Result<WatchEvent, Error>> {
+    futures_util::stream::unfold(Some((response, String::new())), |state| async move {
+        let (mut response, mut buffer) = state?;
+        loop {
+            if let Some(end) = buffer.find("\n\n") {
+                let frame: String = buffer.drain(..end + 2).collect();
+                match parse_frame(&frame) {
+                    Some(event) => return Some((event, Some((response, buffer)))),
+                    None => continue,
+                }
+            }
+            match response.chunk().await {
+                Ok(Some(chunk)) => buffer.push_str(&String::from_utf8_lossy(&chunk)),
+                Ok(None) => return None,
+                Err(e) => return Some((Err(Error::Http(e)), None)),
+            }
+        }
+    })
+}
+
+/// Decodes one `event:`/`data:` frame, skipping frames of unknown types.
+fn parse_frame(frame: &str) -> Option<Result<WatchEvent, Error>> {
+    let mut event = None;
+    let mut data = None;
+    for line in frame.lines() {
+        if let Some(value) = line.strip_prefix("event:") {
+            event = Some(value.trim());
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data = Some(value.trim());
+        }
+    }
+    let decode = |e: serde_json::Error| Error::Decode(format!("Invalid event: {}", e));
+    let data = data?;
+    match event? {
+        "lagged" => Some(
+            serde_json::from_str::<Lagged>(data)
+                .map(|lagged| WatchEvent::Lagged(lagged.missed))
+                .map_err(decode),
+        ),
+        "created" | "updated" | "deleted" | "expired" => Some(
+            serde_json::from_str::<KeyEvent>(data)
+                .map(WatchEvent::Key)
+                .map_err(decode),
+        ),
+        _ => None,
+    }
+}
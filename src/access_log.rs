@@ -0,0 +1,174 @@
+//! A request log in a file of its own, for platforms that cut the log on
+//! stderr short. Every request is appended as a line of JSON once its
+//! response is ready.
+//!
+//! The file is rotated once it reaches a size or an age: it becomes
+//! `FILE.1`, earlier ones move up a number and the oldest past the limit
+//! is deleted. With gzip, rotated files are compressed as `FILE.1.gz` and
+//! so on by a thread of their own, so requests never wait for it. A file
+//! still waiting when the server stops is left as `FILE.<time>.pending`.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc::{Sender, channel};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde::Serialize;
+
+/// One request, as written to the file.
+#[derive(Serialize)]
+pub struct Entry<'a> {
+    pub timestamp: u64,
+    pub remote_addr: Option<&'a str>,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub status: u16,
+    /// Size of the response body, when known before it is sent.
+    pub response_bytes: Option<u64>,
+    pub duration_ms: u64,
+    pub user_agent: Option<&'a str>,
+}
+
+pub struct Rotation {
+    pub max_size: u64,
+    pub max_age: Option<Duration>,
+    /// Rotated files to keep.
+    pub keep: usize,
+    pub gzip: bool,
+}
+
+struct Current {
+    file: File,
+    size: u64,
+    opened_at: SystemTime,
+}
+
+pub struct AccessLog {
+    path: PathBuf,
+    rotation: Rotation,
+    current: Mutex<Current>,
+    /// Rotated files waiting to be compressed, oldest first.
+    compressor: Option<Sender<PathBuf>>,
+}
+
+fn open(path: &Path) -> std::io::Result<Current> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    Ok(Current {
+        size: metadata.len(),
+        // A file left by an earlier run keeps its age where the platform
+        // records when files were created.
+        opened_at: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+        file,
+    })
+}
+
+/// `path` with `.{n}` appended, and `.gz` if rotated files are compressed.
+fn rotated(path: &Path, n: usize, gzip: bool) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    if gzip {
+        name.push(".gz");
+    }
+    PathBuf::from(name)
+}
+
+/// Moves each rotated file up a number, deleting the one past `keep`.
+fn shift(path: &Path, keep: usize, gzip: bool) -> std::io::Result<()> {
+    match std::fs::remove_file(rotated(path, keep, gzip)) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    for n in (1..keep).rev() {
+        let from = rotated(path, n, gzip);
+        if from.exists() {
+            std::fs::rename(&from, rotated(path, n + 1, gzip))?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces `path` with a gzip copy at `target`.
+fn compress(path: &Path, target: &Path) -> std::io::Result<()> {
+    let mut temp_path = target.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let mut encoder = GzEncoder::new(File::create(&temp_path)?, Compression::default());
+    std::io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    std::fs::rename(&temp_path, target)?;
+    std::fs::remove_file(path)
+}
+
+impl AccessLog {
+    pub fn open(path: PathBuf, rotation: Rotation) -> Result<Self, String> {
+        let current = open(&path)
+            .map_err(|e| format!("Failed to open access log {}: {}", path.display(), e))?;
+        let compressor = rotation.gzip.then(|| {
+            let (sender, receiver) = channel::<PathBuf>();
+            let (path, keep) = (path.clone(), rotation.keep);
+            std::thread::spawn(move || {
+                for pending in receiver {
+                    let result = shift(&path, keep, true)
+                        .and_then(|()| compress(&pending, &rotated(&path, 1, true)));
+                    if let Err(e) = result {
+                        eprintln!("Failed to compress access log {}: {}", pending.display(), e);
+                    }
+                }
+            });
+            sender
+        });
+        Ok(Self {
+            path,
+            rotation,
+            current: Mutex::new(current),
+            compressor,
+        })
+    }
+
+    pub fn write(&self, entry: &Entry) {
+        let mut line = serde_json::to_vec(entry).unwrap_or_default();
+        line.push(b'\n');
+        let mut current = self.current.lock().unwrap();
+        let too_old = self
+            .rotation
+            .max_age
+            .is_some_and(|max_age| current.opened_at.elapsed().is_ok_and(|age| age >= max_age));
+        let full = current.size + line.len() as u64 > self.rotation.max_size;
+        if current.size > 0
+            && (full || too_old)
+            && let Err(e) = self.rotate(&mut current)
+        {
+            eprintln!("Failed to rotate access log {}: {}", self.path.display(), e);
+        }
+        match current.file.write_all(&line) {
+            Ok(()) => current.size += line.len() as u64,
+            Err(e) => eprintln!("Failed to write access log {}: {}", self.path.display(), e),
+        }
+    }
+
+    /// Moves the current file to `FILE.1`, or with gzip hands it to the
+    /// compressor, and starts a new one.
+    fn rotate(&self, current: &mut Current) -> std::io::Result<()> {
+        let Some(compressor) = &self.compressor else {
+            shift(&self.path, self.rotation.keep, false)?;
+            std::fs::rename(&self.path, rotated(&self.path, 1, false))?;
+            *current = open(&self.path)?;
+            return Ok(());
+        };
+        let mut pending = self.path.as_os_str().to_owned();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        pending.push(format!(".{}.pending", now.as_nanos()));
+        let pending = PathBuf::from(pending);
+        std::fs::rename(&self.path, &pending)?;
+        *current = open(&self.path)?;
+        let _ = compressor.send(pending);
+        Ok(())
+    }
+}
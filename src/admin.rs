@@ -0,0 +1,64 @@
+//! Access control for `/admin/` endpoints, and the state they manage. The
+//! endpoints are disabled unless the server has an admin token, and then
+//! need it as a bearer token.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use actix_web::http::header::AUTHORIZATION;
+
+pub struct Admin {
+    token: Option<String>,
+}
+
+impl Admin {
+    pub fn new(token: Option<String>) -> Self {
+        Self {
+            token: token.filter(|token| !token.is_empty()),
+        }
+    }
+
+    /// Checks that `req` carries the admin token, returning the response to
+    /// send if it does not.
+    pub fn authorize(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        let Some(token) = &self.token else {
+            return Err(HttpResponse::Forbidden()
+                .body("Admin endpoints are disabled, start the server with --admin-token"));
+        };
+        let given = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match given {
+            Some(given) if constant_time_eq(given.as_bytes(), token.as_bytes()) => Ok(()),
+            _ => Err(HttpResponse::Unauthorized()
+                .insert_header(("WWW-Authenticate", "Bearer"))
+                .body("Missing or invalid admin token")),
+        }
+    }
+}
+
+/// Compares without returning early, so response times do not reveal how
+/// much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Operator-controlled switches that change how the node serves requests.
+#[derive(Default)]
+pub struct Maintenance {
+    read_only: AtomicBool,
+}
+
+impl Maintenance {
+    /// Whether writes are frozen while reads keep working.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
+}
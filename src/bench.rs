@@ -0,0 +1,242 @@
+//! `kstore bench`: a load generator that sends a mix of reads and writes to
+//! a running server from a number of concurrent connections and reports
+//! throughput and latency percentiles per operation.
+//!
+//! Reads are `GET /kv/{key}`; writes go through `POST /batch` with a single
+//! entry, which creates or overwrites a key in one request. Requests are not
+//! retried, so failures show up as errors instead of inflated latencies.
+
+use std::cell::Cell;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::rt::Runtime;
+use futures_util::future::join_all;
+use kstore_client::Client;
+
+use crate::config::{BenchArgs, KeyDistribution};
+
+const PRELOAD_BATCH_SIZE: usize = 1000;
+const PERCENTILES: [(f64, &str); 4] = [(0.5, "p50"), (0.9, "p90"), (0.99, "p99"), (0.999, "p99.9")];
+
+/// xorshift64*: fast and good enough to pick keys and operations.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+enum KeyPicker {
+    Uniform(usize),
+    /// Cumulative probability of each key, rising to 1.
+    Zipf(Vec<f64>),
+    Sequential(usize),
+}
+
+impl KeyPicker {
+    fn new(args: &BenchArgs) -> Self {
+        match args.distribution {
+            KeyDistribution::Uniform => KeyPicker::Uniform(args.keys),
+            KeyDistribution::Sequential => KeyPicker::Sequential(args.keys),
+            KeyDistribution::Zipf => {
+                let weights: Vec<f64> = (1..=args.keys)
+                    .map(|rank| 1.0 / (rank as f64).powf(args.zipf_exponent))
+                    .collect();
+                let total: f64 = weights.iter().sum();
+                let mut cumulative = 0.0;
+                KeyPicker::Zipf(
+                    weights
+                        .into_iter()
+                        .map(|weight| {
+                            cumulative += weight / total;
+                            cumulative
+                        })
+                        .collect(),
+                )
+            }
+        }
+    }
+
+    /// The key for request number `request`.
+    fn pick(&self, request: usize, rng: &mut Rng) -> usize {
+        match self {
+            KeyPicker::Uniform(keys) => (rng.next_u64() % *keys as u64) as usize,
+            KeyPicker::Sequential(keys) => request % keys,
+            KeyPicker::Zipf(cdf) => {
+                let target = rng.next_f64();
+                cdf.partition_point(|&p| p < target).min(cdf.len() - 1)
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Latencies {
+    /// Microseconds per request.
+    samples: Vec<u64>,
+    errors: usize,
+}
+
+impl Latencies {
+    fn merge(&mut self, other: Latencies) {
+        self.samples.extend(other.samples);
+        self.errors += other.errors;
+    }
+
+    fn report(&mut self, name: &str) {
+        self.samples.sort_unstable();
+        let mut line = format!("{:<5} {:>9} {:>7}", name, self.samples.len(), self.errors);
+        for (percentile, _) in PERCENTILES {
+            line.push_str(&format!(
+                " {:>9}",
+                format_latency(self.percentile(percentile))
+            ));
+        }
+        line.push_str(&format!(
+            " {:>9}",
+            format_latency(self.samples.last().copied())
+        ));
+        println!("{}", line);
+    }
+
+    fn percentile(&self, percentile: f64) -> Option<u64> {
+        let rank = (percentile * self.samples.len() as f64).ceil() as usize;
+        self.samples.get(rank.max(1) - 1).copied()
+    }
+}
+
+fn format_latency(micros: Option<u64>) -> String {
+    match micros {
+        None => "-".to_string(),
+        Some(micros) if micros < 1000 => format!("{}us", micros),
+        Some(micros) => format!("{:.2}ms", micros as f64 / 1000.0),
+    }
+}
+
+struct Run<'a> {
+    args: &'a BenchArgs,
+    client: Client,
+    picker: KeyPicker,
+    value: String,
+    next_request: Cell<usize>,
+}
+
+impl Run<'_> {
+    async fn worker(&self, mut rng: Rng) -> (Latencies, Latencies) {
+        let mut reads = Latencies::default();
+        let mut writes = Latencies::default();
+        loop {
+            let request = self.next_request.get();
+            if request >= self.args.requests {
+                return (reads, writes);
+            }
+            self.next_request.set(request + 1);
+
+            let key = format!(
+                "{}{}",
+                self.args.key_prefix,
+                self.picker.pick(request, &mut rng)
+            );
+            let read = rng.next_f64() < self.args.read_ratio;
+            let start = Instant::now();
+            let ok = if read {
+                self.client.get(&key).await.is_ok()
+            } else {
+                self.client
+                    .batch_set(&[(key.as_str(), self.value.as_str())])
+                    .await
+                    .is_ok()
+            };
+            let latencies = if read { &mut reads } else { &mut writes };
+            latencies.samples.push(start.elapsed().as_micros() as u64);
+            if !ok {
+                latencies.errors += 1;
+            }
+        }
+    }
+}
+
+async fn preload(run: &Run<'_>) -> Result<(), kstore_client::Error> {
+    let keys: Vec<(String, &str)> = (0..run.args.keys)
+        .map(|i| (format!("{}{}", run.args.key_prefix, i), run.value.as_str()))
+        .collect();
+    for chunk in keys.chunks(PRELOAD_BATCH_SIZE) {
+        run.client.batch_set(chunk).await?;
+    }
+    Ok(())
+}
+
+pub fn run(args: BenchArgs) -> std::io::Result<()> {
+    let runtime = Runtime::new()?;
+    let client = Client::builder(&args.url)
+        .retries(0)
+        .pool_max_idle_per_host(args.concurrency)
+        .build()
+        .map_err(std::io::Error::other)?;
+    let run = Run {
+        args: &args,
+        client,
+        picker: KeyPicker::new(&args),
+        value: "x".repeat(args.value_size),
+        next_request: Cell::new(0),
+    };
+
+    if !args.no_preload {
+        println!("Preloading {} keys...", args.keys);
+        runtime
+            .block_on(preload(&run))
+            .map_err(std::io::Error::other)?;
+    }
+
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+    let start = Instant::now();
+    let results = runtime.block_on(join_all(
+        (0..args.concurrency as u64).map(|worker| run.worker(Rng::new(seed ^ (worker << 48)))),
+    ));
+    let elapsed = start.elapsed().max(Duration::from_micros(1));
+
+    let mut reads = Latencies::default();
+    let mut writes = Latencies::default();
+    for (worker_reads, worker_writes) in results {
+        reads.merge(worker_reads);
+        writes.merge(worker_writes);
+    }
+    let mut all = Latencies::default();
+    all.samples.extend(&reads.samples);
+    all.samples.extend(&writes.samples);
+    all.errors = reads.errors + writes.errors;
+
+    println!(
+        "Sent {} requests in {:.2}s over {} connections: {:.0} requests/s",
+        args.requests,
+        elapsed.as_secs_f64(),
+        args.concurrency,
+        args.requests as f64 / elapsed.as_secs_f64()
+    );
+    let mut header = format!("{:<5} {:>9} {:>7}", "op", "count", "errors");
+    for (_, name) in PERCENTILES {
+        header.push_str(&format!(" {:>9}", name));
+    }
+    header.push_str(&format!(" {:>9}", "max"));
+    println!("{}", header);
+    reads.report("get");
+    writes.report("set");
+    all.report("all");
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! A Bloom filter over the store's keys. Most lookups of a key that does
+//! not exist are answered by the filter alone, without waiting for the
+//! data lock that every read and write shares.
+//!
+//! Deleting a key leaves its bits set, so the filter only ever errs
+//! towards a real lookup. It is rebuilt from the live keys once it has
+//! taken as many keys as it was sized for, which also clears the bits of
+//! deleted ones.
+
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// About 1% false positives at capacity.
+const BITS_PER_KEY: usize = 10;
+const HASHES: u64 = 7;
+const MIN_CAPACITY: usize = 1024;
+
+pub(crate) struct BloomFilter {
+    bits: Vec<AtomicU64>,
+    hasher: RandomState,
+    capacity: usize,
+    inserted: AtomicUsize,
+}
+
+impl BloomFilter {
+    /// A filter sized for twice as many keys as `keys` holds, so it is not
+    /// rebuilt again straight away.
+    pub(crate) fn build<'a>(keys: impl ExactSizeIterator<Item = &'a str>) -> Self {
+        let capacity = (keys.len() * 2).max(MIN_CAPACITY);
+        let words = (capacity * BITS_PER_KEY).div_ceil(64);
+        let filter = Self {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            hasher: RandomState::new(),
+            capacity,
+            inserted: AtomicUsize::new(0),
+        };
+        for key in keys {
+            filter.insert(key);
+        }
+        filter
+    }
+
+    /// Bit positions of `key`, by double hashing one 64-bit hash.
+    fn positions(&self, key: &str) -> impl Iterator<Item = usize> {
+        let hash = self.hasher.hash_one(key);
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let len = self.bits.len() as u64 * 64;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    pub(crate) fn insert(&self, key: &str) {
+        for position in self.positions(key) {
+            self.bits[position / 64].fetch_or(1 << (position % 64), Ordering::Release);
+        }
+        self.inserted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `false` only if `key` was never inserted.
+    pub(crate) fn may_contain(&self, key: &str) -> bool {
+        self.positions(key).all(|position| {
+            self.bits[position / 64].load(Ordering::Acquire) & (1 << (position % 64)) != 0
+        })
+    }
+
+    /// Whether the filter has taken more keys than it was sized for.
+    pub(crate) fn is_full(&self) -> bool {
+        self.inserted.load(Ordering::Relaxed) > self.capacity
+    }
+}
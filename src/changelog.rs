@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::events::EventKind;
+use crate::{Attributes, Value};
+
+pub const CHANGE_LOG_CAPACITY: usize = 100_000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Change {
+    pub seq: u64,
+    pub event: EventKind,
+    pub key: Arc<str>,
+    pub timestamp: u64,
+}
+
+/// A change as sent to replicas.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeRecord {
+    pub seq: u64,
+    pub event: EventKind,
+    pub key: Arc<str>,
+    /// Current value of the key, or `None` if it no longer exists.
+    pub value: Option<Value>,
+    /// Current attributes of the key.
+    #[serde(default, skip_serializing_if = "Attributes::is_empty")]
+    pub attributes: Attributes,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangeBatch {
+    /// Newest sequence on the primary when the batch was read.
+    pub last_seq: u64,
+    pub changes: Vec<ChangeRecord>,
+}
+
+/// Bounded, in-memory log of key changes, numbered by a sequence that only
+/// grows. Consumers remember the last sequence they saw and ask for
+/// everything after it; once the entries they need have been evicted they
+/// must start over from a full snapshot.
+pub struct ChangeLog {
+    /// Identifies this log across restarts, since sequences start over
+    /// from zero whenever the process does.
+    id: u64,
+    entries: VecDeque<Change>,
+    last_seq: u64,
+    /// Sequence of the newest change that is no longer in `entries`.
+    truncated_seq: u64,
+}
+
+impl Default for ChangeLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChangeLog {
+    pub fn new() -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        Self {
+            id: now.as_nanos() as u64 ^ ((std::process::id() as u64) << 32),
+            entries: VecDeque::new(),
+            last_seq: 0,
+            truncated_seq: 0,
+        }
+    }
+
+    pub fn record(&mut self, event: EventKind, key: Arc<str>, timestamp: u64) -> u64 {
+        self.last_seq += 1;
+        if self.entries.len() == CHANGE_LOG_CAPACITY
+            && let Some(evicted) = self.entries.pop_front()
+        {
+            self.truncated_seq = evicted.seq;
+        }
+        self.entries.push_back(Change {
+            seq: self.last_seq,
+            event,
+            key,
+            timestamp,
+        });
+        self.last_seq
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Forgets every entry, so anyone behind the current sequence has to
+    /// resync. Used when the dataset is replaced wholesale.
+    pub fn reset(&mut self) {
+        self.entries.clear();
+        self.truncated_seq = self.last_seq;
+    }
+
+    /// Returns up to `limit` changes with a sequence greater than `seq`, or
+    /// `None` if some of them have already been evicted.
+    pub fn since(&self, seq: u64, limit: usize) -> Option<Vec<Change>> {
+        if seq < self.truncated_seq {
+            return None;
+        }
+        let start = self.entries.partition_point(|change| change.seq <= seq);
+        Some(
+            self.entries
+                .iter()
+                .skip(start)
+                .take(limit)
+                .cloned()
+                .collect(),
+        )
+    }
+}
@@ -0,0 +1,150 @@
+//! End-to-end integrity checks. A write may send the digest of its value
+//! in a `Content-MD5` (base64, as in RFC 1864) or `X-Checksum-SHA256`
+//! (hex) header, and is refused if the body it arrived with does not
+//! match. The digests are then stored in the attributes of the key, written
+//! with the value in one record, and sent back with every read of the key,
+//! so clients can check what they get.
+//!
+//! Every write gives a key its attributes anew, so a key rewritten without
+//! a checksum, for example by `/batch` or an import, never comes with a
+//! stale one.
+
+use actix_web::HttpRequest;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use kstore::Checksum;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+pub const MD5_HEADER: &str = "Content-MD5";
+pub const SHA256_HEADER: &str = "X-Checksum-SHA256";
+
+/// The digests of `value`, in the encoding of their headers.
+fn digest(value: &[u8]) -> Checksum {
+    Checksum {
+        md5: STANDARD.encode(Md5::digest(value)),
+        sha256: Sha256::digest(value)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+    }
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Result<Option<&'a str>, String> {
+    req.headers()
+        .get(name)
+        .map(|value| {
+            value
+                .to_str()
+                .map_err(|_| format!("Invalid {} header", name))
+        })
+        .transpose()
+}
+
+/// Checks `body` against the digests `req` sends, returning them if it
+/// sends any.
+pub fn verify(req: &HttpRequest, body: &[u8]) -> Result<Option<Checksum>, String> {
+    let md5 = header(req, MD5_HEADER)?;
+    let sha256 = header(req, SHA256_HEADER)?;
+    if md5.is_none() && sha256.is_none() {
+        return Ok(None);
+    }
+    if let Some(md5) = md5
+        && STANDARD
+            .decode(md5)
+            .map_or(true, |digest| digest.len() != 16)
+    {
+        return Err(format!("{} must be a base64 MD5 digest", MD5_HEADER));
+    }
+    if let Some(sha256) = sha256
+        && (sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()))
+    {
+        return Err(format!("{} must be a hex SHA-256 digest", SHA256_HEADER));
+    }
+    let checksum = digest(body);
+    if md5.is_some_and(|md5| md5 != checksum.md5) {
+        return Err(format!("The value does not match its {}", MD5_HEADER));
+    }
+    if sha256.is_some_and(|sha256| !sha256.eq_ignore_ascii_case(&checksum.sha256)) {
+        return Err(format!("The value does not match its {}", SHA256_HEADER));
+    }
+    Ok(Some(checksum))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    const HELLO_MD5: &str = "XUFAKrxLKna5cZ2REBfFkg==";
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn request(headers: &[(&'static str, &str)]) -> HttpRequest {
+        headers
+            .iter()
+            .fold(TestRequest::default(), |request, &(name, value)| {
+                request.insert_header((name, value.to_string()))
+            })
+            .to_http_request()
+    }
+
+    #[test]
+    fn writes_without_digests_are_not_checked() {
+        assert_eq!(verify(&request(&[]), b"hello"), Ok(None));
+    }
+
+    #[test]
+    fn matching_digests_are_returned_whole() {
+        let expected = Some(Checksum {
+            md5: HELLO_MD5.to_string(),
+            sha256: HELLO_SHA256.to_string(),
+        });
+        assert_eq!(
+            verify(&request(&[(MD5_HEADER, HELLO_MD5)]), b"hello"),
+            Ok(expected.clone())
+        );
+        let uppercase = HELLO_SHA256.to_uppercase();
+        assert_eq!(
+            verify(&request(&[(SHA256_HEADER, &uppercase)]), b"hello"),
+            Ok(expected)
+        );
+    }
+
+    #[test]
+    fn mismatched_digests_are_refused() {
+        assert_eq!(
+            verify(&request(&[(MD5_HEADER, HELLO_MD5)]), b"hellx"),
+            Err(format!("The value does not match its {}", MD5_HEADER))
+        );
+        assert_eq!(
+            verify(&request(&[(SHA256_HEADER, HELLO_SHA256)]), b"hellx"),
+            Err(format!("The value does not match its {}", SHA256_HEADER))
+        );
+        // Both are checked when both are sent.
+        let other_md5 = digest(b"hellx").md5;
+        let headers = [
+            (MD5_HEADER, other_md5.as_str()),
+            (SHA256_HEADER, HELLO_SHA256),
+        ];
+        assert!(verify(&request(&headers), b"hello").is_err());
+    }
+
+    #[test]
+    fn malformed_digests_are_refused() {
+        let md5 = verify(&request(&[(MD5_HEADER, "not base64!")]), b"hello");
+        assert_eq!(
+            md5,
+            Err(format!("{} must be a base64 MD5 digest", MD5_HEADER))
+        );
+        let md5 = verify(&request(&[(MD5_HEADER, "aGVsbG8=")]), b"hello");
+        assert_eq!(
+            md5,
+            Err(format!("{} must be a base64 MD5 digest", MD5_HEADER))
+        );
+        let sha256 = verify(&request(&[(SHA256_HEADER, &HELLO_SHA256[1..])]), b"hello");
+        assert_eq!(
+            sha256,
+            Err(format!("{} must be a hex SHA-256 digest", SHA256_HEADER))
+        );
+    }
+}
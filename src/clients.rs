@@ -0,0 +1,106 @@
+//! Usage per client for `GET /stats/clients`, to find out which service is
+//! putting the load on the store. A client is the bearer token it sends,
+//! identified by a short hash so the token itself is never shown, or
+//! without one its address.
+//!
+//! Counts cover this node since it started. Past a fixed number of
+//! clients, new ones are counted together as `other`, so a scan from many
+//! addresses cannot use up memory.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::AUTHORIZATION;
+use kstore::current_timestamp;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+const MAX_CLIENTS: usize = 10_000;
+const OTHER: &str = "other";
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Usage {
+    pub requests: u64,
+    /// Response body bytes sent to the client.
+    pub bytes_read: u64,
+    /// Request body bytes received from the client.
+    pub bytes_written: u64,
+    /// Requests answered with a 4xx or 5xx status.
+    pub errors: u64,
+    pub last_seen: u64,
+}
+
+#[derive(Serialize)]
+pub struct ClientUsage {
+    pub client: String,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+#[derive(Default)]
+pub struct Clients {
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+/// Who sent `req`: `token:` and a hash of its bearer token, or `ip:` and
+/// its address.
+pub fn identify(req: &ServiceRequest) -> String {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if let Some(token) = token {
+        let hash: String = Sha256::digest(token.as_bytes())[..6]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        return format!("token:{}", hash);
+    }
+    format!(
+        "ip:{}",
+        req.connection_info()
+            .realip_remote_addr()
+            .unwrap_or("unknown")
+    )
+}
+
+impl Clients {
+    pub fn record(&self, client: String, bytes_read: u64, bytes_written: u64, error: bool) {
+        let mut usage = self.usage.lock().unwrap();
+        let client = if usage.len() >= MAX_CLIENTS && !usage.contains_key(&client) {
+            OTHER.to_string()
+        } else {
+            client
+        };
+        let usage = usage.entry(client).or_default();
+        usage.requests += 1;
+        usage.bytes_read += bytes_read;
+        usage.bytes_written += bytes_written;
+        usage.errors += u64::from(error);
+        usage.last_seen = current_timestamp();
+    }
+
+    /// The `n` clients with the most requests, most first.
+    pub fn top(&self, n: usize) -> Vec<ClientUsage> {
+        let mut clients: Vec<ClientUsage> = self
+            .usage
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(client, usage)| ClientUsage {
+                client: client.clone(),
+                usage: usage.clone(),
+            })
+            .collect();
+        clients.sort_by(|a, b| {
+            b.usage
+                .requests
+                .cmp(&a.usage.requests)
+                .then_with(|| a.client.cmp(&b.client))
+        });
+        clients.truncate(n);
+        clients
+    }
+}
@@ -0,0 +1,799 @@
+//! Cluster mode: a fixed set of nodes elects a single leader with Raft's
+//! election rules (terms, one vote per term, randomized timeouts, leader
+//! heartbeats). The leader accepts writes; every other node is a read-only
+//! replica tailing the leader's change stream, which serves as the
+//! replicated log. When the leader stops sending heartbeats, the most
+//! up-to-date reachable node takes over.
+//!
+//! Heartbeats double as acknowledgements: each follower answers with how
+//! much of the leader's change log it has applied and synced to disk, and
+//! the leader only acknowledges a write once a majority, counting itself,
+//! holds it. Any majority that elects the next leader therefore includes a
+//! node with every acknowledged write, and that node only votes for a
+//! candidate at least as current as itself.
+//!
+//! The term, the vote and how far this node's data goes are saved to
+//! [`STATE_FILE`] before a vote is granted or an acknowledgement sent, so
+//! a restarted node can neither vote twice in a term nor claim less data
+//! than it acknowledged. A leader steps down as soon as a round of
+//! heartbeats fails to reach a majority.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::rt::time::{sleep, timeout};
+use actix_web::web;
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Notify, watch};
+
+use kstore::KvStore;
+
+use crate::proxy::NodeSecret;
+use crate::replication::Replication;
+
+/// In the working directory like the data file.
+pub const STATE_FILE: &str = "kvstore.cluster";
+
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(300);
+/// How soon the leader sends the next round of heartbeats while writes
+/// wait for a majority.
+const REPLICATION_INTERVAL: Duration = Duration::from_millis(10);
+const ELECTION_TIMEOUT_MIN: Duration = Duration::from_millis(1500);
+const ELECTION_TIMEOUT_JITTER_MS: u64 = 1500;
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long a write waits for a majority before it is reported as
+/// unconfirmed.
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VoteRequest {
+    pub term: u64,
+    pub candidate: String,
+    /// Term of the leader whose data the candidate holds.
+    pub data_term: u64,
+    /// Sequence of that leader's change log the candidate has applied.
+    pub applied: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VoteResponse {
+    pub term: u64,
+    pub granted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HeartbeatRequest {
+    pub term: u64,
+    pub leader: String,
+    /// The leader's change log, which followers replicate.
+    pub log: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HeartbeatResponse {
+    pub term: u64,
+    pub success: bool,
+    /// Sequence of the leader's change log the follower has applied and
+    /// synced to disk, once it has bootstrapped from the leader.
+    #[serde(default)]
+    pub applied: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClusterStatus {
+    pub id: String,
+    pub role: NodeRole,
+    pub term: u64,
+    pub leader: Option<String>,
+    pub peers: Vec<String>,
+    pub data_term: u64,
+}
+
+/// What a node must remember across restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedState {
+    term: u64,
+    voted_for: Option<String>,
+    data_term: u64,
+    data_applied: u64,
+}
+
+/// How much of the leader's change log a majority holds.
+#[derive(Debug, Clone, Copy)]
+struct Commit {
+    term: u64,
+    leader: bool,
+    seq: u64,
+}
+
+struct ClusterState {
+    term: u64,
+    voted_for: Option<String>,
+    role: NodeRole,
+    leader: Option<String>,
+    /// The leader this node last heard from and its term, whose change
+    /// log it replicates.
+    following: Option<(String, u64)>,
+    /// How far this node's data goes on disk: the term of the leader it
+    /// came from and the sequence of that leader's change log synced.
+    data_term: u64,
+    data_applied: u64,
+    election_deadline: Instant,
+    /// Every other node that votes, as configured or learned via gossip.
+    peers: Vec<String>,
+    /// While leader, the sequence of its change log each peer has synced.
+    progress: HashMap<String, u64>,
+}
+
+impl ClusterState {
+    fn majority(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    /// While leader, how far a majority has synced its change log: the
+    /// highest sequence held by the leader and enough peers. The leader
+    /// counts only data of its own term.
+    fn committed(&self) -> u64 {
+        let own = if self.data_term == self.term {
+            self.data_applied
+        } else {
+            0
+        };
+        let mut positions: Vec<u64> = self
+            .peers
+            .iter()
+            .map(|peer| self.progress.get(peer).copied().unwrap_or(0))
+            .collect();
+        positions.push(own);
+        positions.sort_unstable_by(|a, b| b.cmp(a));
+        positions[self.majority() - 1]
+    }
+}
+
+pub struct Cluster {
+    id: String,
+    state: Mutex<ClusterState>,
+    client: reqwest::Client,
+    /// Signs votes and heartbeats, which peers refuse unless signed.
+    secret: NodeSecret,
+    path: PathBuf,
+    commit: watch::Sender<Commit>,
+    /// Woken by writes waiting for a majority, so the leader replicates
+    /// them straight away.
+    pending: Notify,
+}
+
+fn election_timeout() -> Duration {
+    // Jitter keeps nodes from timing out together and splitting the vote.
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .subsec_nanos() as u64;
+    ELECTION_TIMEOUT_MIN + Duration::from_millis(nanos % ELECTION_TIMEOUT_JITTER_MS)
+}
+
+/// Reads the state saved at `path`. A missing file means this is the
+/// node's first run.
+fn load(path: &Path) -> Result<SavedState, String> {
+    match std::fs::read(path) {
+        Ok(saved) => serde_json::from_slice(&saved).map_err(|e| e.to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(SavedState::default()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+impl Cluster {
+    /// Joins the cluster as `id`, picking up the term, vote and data
+    /// position saved at `path`. Without `durable_data` the data starts
+    /// empty on every run, and so does its position.
+    pub fn new(
+        id: String,
+        peers: Vec<String>,
+        secret: NodeSecret,
+        path: PathBuf,
+        durable_data: bool,
+    ) -> Result<Self, String> {
+        let saved = load(&path).map_err(|e| {
+            format!(
+                "Failed to load the cluster state from {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+        let (data_term, data_applied) = if durable_data {
+            (saved.data_term, saved.data_applied)
+        } else {
+            (0, 0)
+        };
+        let id = id.trim_end_matches('/').to_string();
+        let peers: Vec<String> = peers
+            .into_iter()
+            .map(|peer| peer.trim_end_matches('/').to_string())
+            .filter(|peer| *peer != id)
+            .collect();
+        let (commit, _) = watch::channel(Commit {
+            term: saved.term,
+            leader: false,
+            seq: 0,
+        });
+        Ok(Self {
+            state: Mutex::new(ClusterState {
+                term: saved.term,
+                voted_for: saved.voted_for,
+                role: NodeRole::Follower,
+                leader: None,
+                following: None,
+                data_term,
+                data_applied,
+                election_deadline: Instant::now() + election_timeout(),
+                peers,
+                progress: HashMap::new(),
+            }),
+            id,
+            client: reqwest::Client::builder()
+                .timeout(RPC_TIMEOUT)
+                .build()
+                .expect("HTTP client"),
+            secret,
+            path,
+            commit,
+            pending: Notify::new(),
+        })
+    }
+
+    pub fn status(&self) -> ClusterStatus {
+        let state = self.state.lock().unwrap();
+        ClusterStatus {
+            id: self.id.clone(),
+            role: state.role,
+            term: state.term,
+            leader: state.leader.clone(),
+            peers: state.peers.clone(),
+            data_term: state.data_term,
+        }
+    }
+
+    /// Writes `state` to disk, replacing the saved copy only once the new
+    /// one is complete and synced.
+    fn save(&self, state: &ClusterState) -> Result<(), String> {
+        let saved = SavedState {
+            term: state.term,
+            voted_for: state.voted_for.clone(),
+            data_term: state.data_term,
+            data_applied: state.data_applied,
+        };
+        let mut temp_path = self.path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let write = || -> std::io::Result<()> {
+            let mut file = std::fs::File::create(&temp_path)?;
+            std::io::Write::write_all(&mut file, &serde_json::to_vec(&saved)?)?;
+            file.sync_all()?;
+            std::fs::rename(&temp_path, &self.path)?;
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            std::fs::File::open(dir)?.sync_all()
+        };
+        write().map_err(|e| {
+            format!(
+                "Failed to save the cluster state to {}: {}",
+                self.path.display(),
+                e
+            )
+        })
+    }
+
+    /// Makes this node a follower, failing the writes waiting for a
+    /// majority if it was the leader.
+    fn step_down(&self, state: &mut ClusterState) {
+        if state.role == NodeRole::Leader {
+            self.commit.send_replace(Commit {
+                term: state.term,
+                leader: false,
+                seq: 0,
+            });
+        }
+        state.role = NodeRole::Follower;
+        state.leader = None;
+        state.progress.clear();
+    }
+
+    /// Moves on to `term`, seen from a peer, as a follower that has not
+    /// voted in it yet.
+    fn enter_term(&self, state: &mut ClusterState, term: u64) {
+        self.step_down(state);
+        state.term = term;
+        state.voted_for = None;
+    }
+
+    /// How far this node's data goes: the term of the leader it came from
+    /// and how much of that leader's change log it holds. A node that is
+    /// still bootstrapping from a new leader counts with the data it had.
+    fn position(
+        &self,
+        state: &ClusterState,
+        store: &KvStore,
+        replication: &Replication,
+    ) -> (u64, u64) {
+        if state.role == NodeRole::Leader {
+            return (state.term, store.change_position().1);
+        }
+        let status = replication.status();
+        match &state.following {
+            Some((leader, term))
+                if replication.primary().as_ref() == Some(leader) && status.log.is_some() =>
+            {
+                (*term, status.applied_seq)
+            }
+            _ => (state.data_term, state.data_applied),
+        }
+    }
+
+    pub fn handle_vote(
+        &self,
+        request: VoteRequest,
+        store: &KvStore,
+        replication: &Replication,
+    ) -> VoteResponse {
+        let mut state = self.state.lock().unwrap();
+        let mut changed = false;
+        if request.term > state.term {
+            self.enter_term(&mut state, request.term);
+            changed = true;
+        }
+        let up_to_date =
+            (request.data_term, request.applied) >= self.position(&state, store, replication);
+        let mut granted = request.term == state.term
+            && state
+                .voted_for
+                .as_ref()
+                .is_none_or(|voted| *voted == request.candidate)
+            && up_to_date;
+        if granted && state.voted_for.is_none() {
+            state.voted_for = Some(request.candidate);
+            changed = true;
+        }
+        // The vote must outlive a crash before the candidate can count it.
+        if changed && let Err(e) = self.save(&state) {
+            eprintln!("{}", e);
+            granted = false;
+        }
+        if granted {
+            state.election_deadline = Instant::now() + election_timeout();
+        }
+        VoteResponse {
+            term: state.term,
+            granted,
+        }
+    }
+
+    pub async fn handle_heartbeat(
+        &self,
+        request: HeartbeatRequest,
+        store: web::Data<KvStore>,
+        replication: web::Data<Replication>,
+    ) -> HeartbeatResponse {
+        let term = {
+            let mut state = self.state.lock().unwrap();
+            if request.term < state.term {
+                return HeartbeatResponse {
+                    term: state.term,
+                    success: false,
+                    applied: None,
+                };
+            }
+            if request.term > state.term {
+                self.enter_term(&mut state, request.term);
+                if let Err(e) = self.save(&state) {
+                    eprintln!("{}", e);
+                    return HeartbeatResponse {
+                        term: state.term,
+                        success: false,
+                        applied: None,
+                    };
+                }
+            }
+            self.step_down(&mut state);
+            state.election_deadline = Instant::now() + election_timeout();
+            state.leader = Some(request.leader.clone());
+            state.following = Some((request.leader.clone(), request.term));
+            state.term
+        };
+        if replication.primary().as_ref() != Some(&request.leader) {
+            Replication::start(replication.clone(), store.clone(), request.leader.clone());
+        }
+        HeartbeatResponse {
+            term,
+            success: true,
+            applied: self.sync_applied(&request, store, &replication).await,
+        }
+    }
+
+    /// Syncs what this follower has applied of the leader's change log to
+    /// disk and saves it as this node's data position. Returns the synced
+    /// sequence, or None until the follower has bootstrapped from the
+    /// leader.
+    async fn sync_applied(
+        &self,
+        request: &HeartbeatRequest,
+        store: web::Data<KvStore>,
+        replication: &Replication,
+    ) -> Option<u64> {
+        let status = replication.status();
+        if replication.primary().as_ref() != Some(&request.leader)
+            || status.log != Some(request.log)
+        {
+            return None;
+        }
+        let applied = status.applied_seq;
+        {
+            let state = self.state.lock().unwrap();
+            if (state.data_term, state.data_applied) == (request.term, applied) {
+                return Some(applied);
+            }
+        }
+        match web::block(move || store.sync()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                eprintln!("Failed to sync replicated changes: {}", e);
+                return None;
+            }
+            Err(e) => {
+                eprintln!("Failed to sync replicated changes: {}", e);
+                return None;
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.term != request.term {
+            return None;
+        }
+        state.data_term = request.term;
+        state.data_applied = applied;
+        if let Err(e) = self.save(&state) {
+            eprintln!("{}", e);
+            return None;
+        }
+        Some(applied)
+    }
+
+    /// Moves on to a higher term seen from a peer.
+    fn observe_term(&self, term: u64) {
+        let mut state = self.state.lock().unwrap();
+        if term > state.term {
+            self.enter_term(&mut state, term);
+            state.election_deadline = Instant::now() + election_timeout();
+            if let Err(e) = self.save(&state) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    async fn run_election(&self, store: &KvStore, replication: &Replication) {
+        let (request, peers) = {
+            let mut state = self.state.lock().unwrap();
+            state.term += 1;
+            state.role = NodeRole::Candidate;
+            state.voted_for = Some(self.id.clone());
+            state.leader = None;
+            state.election_deadline = Instant::now() + election_timeout();
+            if let Err(e) = self.save(&state) {
+                eprintln!("{}", e);
+                return;
+            }
+            let (data_term, applied) = self.position(&state, store, replication);
+            let request = VoteRequest {
+                term: state.term,
+                candidate: self.id.clone(),
+                data_term,
+                applied,
+            };
+            (request, state.peers.clone())
+        };
+
+        let responses = join_all(peers.iter().map(|peer| {
+            self.secret
+                .request(
+                    &self.client,
+                    reqwest::Method::POST,
+                    &format!("{}/cluster/vote", peer),
+                    &self.id,
+                )
+                .json(&request)
+                .send()
+        }))
+        .await;
+
+        let mut votes = 1;
+        for response in responses.into_iter().flatten() {
+            let Ok(vote) = response.json::<VoteResponse>().await else {
+                continue;
+            };
+            self.observe_term(vote.term);
+            if vote.granted && vote.term == request.term {
+                votes += 1;
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.role == NodeRole::Candidate
+            && state.term == request.term
+            && votes >= state.majority()
+        {
+            state.role = NodeRole::Leader;
+            state.leader = Some(self.id.clone());
+            state.following = None;
+            state.progress.clear();
+            self.commit.send_replace(Commit {
+                term: state.term,
+                leader: true,
+                seq: 0,
+            });
+            replication.promote();
+        }
+    }
+
+    /// One round of heartbeats, which also collects how far each follower
+    /// has synced and advances the commit point.
+    async fn send_heartbeats(&self, store: &web::Data<KvStore>) {
+        let (log, seq) = store.change_position();
+        // The leader counts towards a majority once its own copy is on disk.
+        let synced = {
+            let store = store.clone();
+            match web::block(move || store.sync()).await {
+                Ok(Ok(())) => true,
+                Ok(Err(e)) => {
+                    eprintln!("Failed to sync the change log: {}", e);
+                    false
+                }
+                Err(e) => {
+                    eprintln!("Failed to sync the change log: {}", e);
+                    false
+                }
+            }
+        };
+        let (request, peers) = {
+            let state = self.state.lock().unwrap();
+            let request = HeartbeatRequest {
+                term: state.term,
+                leader: self.id.clone(),
+                log,
+            };
+            (request, state.peers.clone())
+        };
+
+        let (client, secret, id, body) = (&self.client, &self.secret, &self.id, &request);
+        let responses = join_all(peers.iter().map(|peer| async move {
+            let response = secret
+                .request(
+                    client,
+                    reqwest::Method::POST,
+                    &format!("{}/cluster/heartbeat", peer),
+                    id,
+                )
+                .json(body)
+                .send()
+                .await
+                .ok()?;
+            response.json::<HeartbeatResponse>().await.ok()
+        }))
+        .await;
+
+        let mut state = self.state.lock().unwrap();
+        if state.role != NodeRole::Leader || state.term != request.term {
+            return;
+        }
+        if let Some(term) = responses
+            .iter()
+            .flatten()
+            .map(|heartbeat| heartbeat.term)
+            .max()
+            .filter(|term| *term > state.term)
+        {
+            self.enter_term(&mut state, term);
+            state.election_deadline = Instant::now() + election_timeout();
+            if let Err(e) = self.save(&state) {
+                eprintln!("{}", e);
+            }
+            return;
+        }
+
+        let mut reachable = 1;
+        for (peer, heartbeat) in peers.into_iter().zip(responses) {
+            let Some(heartbeat) = heartbeat.filter(|heartbeat| heartbeat.success) else {
+                continue;
+            };
+            reachable += 1;
+            if let Some(applied) = heartbeat.applied {
+                state.progress.insert(peer, applied);
+            }
+        }
+        // The leader may be on the minority side of a partition: step down
+        // so it stops taking writes the rest of the cluster will never see.
+        if reachable < state.majority() {
+            self.step_down(&mut state);
+            state.election_deadline = Instant::now() + election_timeout();
+            return;
+        }
+
+        if synced && (state.data_term, state.data_applied) != (state.term, seq) {
+            let previous = (state.data_term, state.data_applied);
+            (state.data_term, state.data_applied) = (state.term, seq);
+            if let Err(e) = self.save(&state) {
+                eprintln!("{}", e);
+                (state.data_term, state.data_applied) = previous;
+            }
+        }
+        let committed = state.committed();
+        self.commit.send_if_modified(|commit| {
+            let advanced = commit.term == state.term && committed > commit.seq;
+            if advanced {
+                commit.seq = committed;
+            }
+            advanced
+        });
+    }
+
+    /// Whether the leader has writes a majority does not hold yet.
+    fn has_pending(&self, store: &KvStore) -> bool {
+        self.commit.borrow().seq < store.change_position().1
+    }
+
+    /// Waits until a majority holds the leader's change log up to `seq`.
+    /// False if this node is not the leader, stops being the leader first,
+    /// or no majority confirms it within `COMMIT_TIMEOUT`.
+    pub async fn wait_for_commit(&self, seq: u64) -> bool {
+        let term = {
+            let state = self.state.lock().unwrap();
+            if state.role != NodeRole::Leader {
+                return false;
+            }
+            state.term
+        };
+        let mut receiver = self.commit.subscribe();
+        self.pending.notify_one();
+        let reached = timeout(
+            COMMIT_TIMEOUT,
+            receiver.wait_for(|commit| commit.term != term || !commit.leader || commit.seq >= seq),
+        )
+        .await;
+        matches!(reached, Ok(Ok(commit)) if commit.term == term && commit.leader)
+    }
+
+    /// Whether this node accepts writes. Nodes that have not yet heard from
+    /// a leader do not.
+    pub fn is_leader(&self) -> bool {
+        self.state.lock().unwrap().role == NodeRole::Leader
+    }
+
+    pub fn leader(&self) -> Option<String> {
+        self.state.lock().unwrap().leader.clone()
+    }
+
+    /// Replaces the voting nodes with `nodes`, which lists every node
+    /// including this one.
+    pub fn set_nodes(&self, nodes: &[String]) {
+        let mut state = self.state.lock().unwrap();
+        state.peers = nodes
+            .iter()
+            .filter(|node| **node != self.id)
+            .cloned()
+            .collect();
+    }
+}
+
+/// Drives elections and heartbeats until the server shuts down.
+pub async fn run(
+    cluster: web::Data<Option<Cluster>>,
+    store: web::Data<KvStore>,
+    replication: web::Data<Replication>,
+) {
+    let Some(node) = cluster.as_ref() else {
+        return;
+    };
+    let mut next_heartbeat = Instant::now();
+    loop {
+        let pending = node.is_leader() && node.has_pending(&store);
+        if pending {
+            sleep(REPLICATION_INTERVAL).await;
+        } else {
+            let _ = timeout(TICK_INTERVAL, node.pending.notified()).await;
+        }
+        let (role, deadline) = {
+            let state = node.state.lock().unwrap();
+            (state.role, state.election_deadline)
+        };
+        let now = Instant::now();
+        match role {
+            NodeRole::Leader if now >= next_heartbeat || node.has_pending(&store) => {
+                next_heartbeat = now + HEARTBEAT_INTERVAL;
+                node.send_heartbeats(&store).await;
+            }
+            NodeRole::Leader => {}
+            NodeRole::Follower | NodeRole::Candidate if now >= deadline => {
+                node.run_election(&store, &replication).await;
+                next_heartbeat = Instant::now();
+            }
+            NodeRole::Follower | NodeRole::Candidate => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The state of the leader of term 2, with `peers` synced as far as
+    /// given and its own change log synced up to `own`.
+    fn leader(own: u64, peers: &[(&str, Option<u64>)]) -> ClusterState {
+        ClusterState {
+            term: 2,
+            voted_for: None,
+            role: NodeRole::Leader,
+            leader: None,
+            following: None,
+            data_term: 2,
+            data_applied: own,
+            election_deadline: Instant::now(),
+            peers: peers.iter().map(|(peer, _)| peer.to_string()).collect(),
+            progress: peers
+                .iter()
+                .filter_map(|(peer, applied)| Some((peer.to_string(), (*applied)?)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn a_lone_leader_commits_what_it_has_synced() {
+        assert_eq!(leader(7, &[]).committed(), 7);
+    }
+
+    #[test]
+    fn commits_what_a_majority_holds() {
+        let state = leader(10, &[("b", Some(8)), ("c", Some(3))]);
+        assert_eq!(state.majority(), 2);
+        assert_eq!(state.committed(), 8);
+
+        let state = leader(
+            10,
+            &[("b", Some(9)), ("c", Some(4)), ("d", Some(6)), ("e", None)],
+        );
+        assert_eq!(state.majority(), 3);
+        assert_eq!(state.committed(), 6);
+    }
+
+    #[test]
+    fn peers_ahead_of_the_leader_do_not_commit_past_a_majority() {
+        let state = leader(2, &[("b", Some(9)), ("c", Some(1))]);
+        assert_eq!(state.committed(), 2);
+        let state = leader(2, &[("b", Some(9)), ("c", Some(9))]);
+        assert_eq!(state.committed(), 9);
+    }
+
+    #[test]
+    fn even_clusters_need_more_than_half() {
+        let state = leader(10, &[("b", Some(10)), ("c", None), ("d", None)]);
+        assert_eq!(state.majority(), 3);
+        assert_eq!(state.committed(), 0);
+    }
+
+    #[test]
+    fn data_from_an_earlier_term_does_not_count() {
+        let mut state = leader(10, &[("b", Some(4)), ("c", None)]);
+        state.data_term = 1;
+        assert_eq!(state.committed(), 0);
+    }
+}
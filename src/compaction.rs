@@ -0,0 +1,147 @@
+//! Compaction on a schedule, from the `[compaction]` table of the config
+//! file. Once enough of the data file is overwritten values, it is
+//! rewritten, but only inside the configured windows and never sooner than
+//! the minimum interval after the last compaction, so the rewrite can be
+//! kept away from peak traffic.
+//!
+//! Checkpoints, the compactions that keep the write-ahead log short so
+//! startup does not replay too much of it, compactions to free space while
+//! the disk is full and ones requested with `POST /compact` are not held
+//! back.
+
+use std::time::Duration;
+
+use actix_web::rt::time::sleep;
+use actix_web::web;
+use kstore::{KvStore, current_timestamp};
+
+use crate::config::CompactionConfig;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const CHECKPOINT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A time of day range, in minutes since midnight UTC.
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    start: u64,
+    end: u64,
+}
+
+fn parse_time(time: &str) -> Option<u64> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let (hours, minutes): (u64, u64) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+impl Window {
+    fn parse(window: &str) -> Result<Self, String> {
+        window
+            .split_once('-')
+            .and_then(|(start, end)| {
+                Some(Self {
+                    start: parse_time(start)?,
+                    end: parse_time(end)?,
+                })
+            })
+            .filter(|window| window.start != window.end)
+            .ok_or_else(|| {
+                format!(
+                    "Invalid compaction window '{}', expected HH:MM-HH:MM",
+                    window
+                )
+            })
+    }
+
+    fn contains(&self, minute: u64) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+pub struct Schedule {
+    /// Empty for any time of day.
+    windows: Vec<Window>,
+    min_interval: u64,
+    min_reclaimable_percent: u64,
+}
+
+impl Schedule {
+    pub fn new(config: &CompactionConfig) -> Result<Self, String> {
+        if config.min_reclaimable_percent > 100 {
+            return Err("min_reclaimable_percent must be at most 100".to_string());
+        }
+        Ok(Self {
+            windows: config
+                .windows
+                .iter()
+                .map(|window| Window::parse(window))
+                .collect::<Result<_, _>>()?,
+            min_interval: config.min_interval_secs,
+            min_reclaimable_percent: config.min_reclaimable_percent,
+        })
+    }
+
+    /// Whether a compaction may start at `now`.
+    fn allows(&self, store: &KvStore, now: u64) -> bool {
+        let minute = now % 86_400 / 60;
+        let in_window =
+            self.windows.is_empty() || self.windows.iter().any(|window| window.contains(minute));
+        let rested = store
+            .compaction_stats()
+            .last_compacted_at
+            .is_none_or(|last| now.saturating_sub(last) >= self.min_interval);
+        in_window && rested
+    }
+
+    /// Whether enough of the data file is reclaimable to rewrite it.
+    fn worthwhile(&self, store: &KvStore) -> bool {
+        store.reclaimable().is_some_and(|(size, reclaimable)| {
+            size > 0 && reclaimable * 100 >= size * self.min_reclaimable_percent
+        })
+    }
+}
+
+/// Compacts the store whenever the schedule allows it and it is worth it.
+pub async fn run(schedule: Schedule, store: web::Data<KvStore>) {
+    let schedule = web::Data::new(schedule);
+    loop {
+        sleep(CHECK_INTERVAL).await;
+        // Compacting then is up to the disk full recovery.
+        if store.disk_full().is_some() || !schedule.allows(&store, current_timestamp()) {
+            continue;
+        }
+        let (schedule, store) = (schedule.clone(), store.clone());
+        let result = web::block(move || {
+            if !schedule.worthwhile(&store) {
+                return Ok(None);
+            }
+            store.compact().map(Some)
+        })
+        .await;
+        match result {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => eprintln!("Scheduled compaction failed: {}", e),
+            Err(e) => eprintln!("Scheduled compaction failed: {}", e),
+        }
+    }
+}
+
+/// Compacts the store, writing a new checkpoint, whenever the backend's
+/// log of changes reaches `max_log_size` bytes.
+pub async fn checkpoint(store: web::Data<KvStore>, max_log_size: u64) {
+    loop {
+        sleep(CHECKPOINT_CHECK_INTERVAL).await;
+        if store.disk_full().is_some() || store.log_size().is_none_or(|size| size < max_log_size) {
+            continue;
+        }
+        let store = store.clone();
+        match web::block(move || store.compact()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => eprintln!("Checkpoint failed: {}", e),
+            Err(e) => eprintln!("Checkpoint failed: {}", e),
+        }
+    }
+}
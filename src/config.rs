@@ -0,0 +1,491 @@
+use std::path::{Path, PathBuf};
+
+use clap::builder::RangedU64ValueParser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use kstore::{
+    Error, FileBackend, Limits, MAX_KEY_SIZE, MAX_VALUE_SIZE, MemoryBackend, StorageBackend,
+};
+use serde::Deserialize;
+
+/// Startup options, from command-line flags or `KSTORE_*` environment
+/// variables.
+#[derive(Parser, Debug, Clone)]
+#[command(version, about = "A simple key-value store with an HTTP API")]
+pub struct Config {
+    /// Address to listen on.
+    #[arg(long, env = "KSTORE_BIND", default_value = "127.0.0.1:8080")]
+    pub bind: String,
+
+    /// Run as a read-only replica of the primary at this URL.
+    #[arg(
+        long,
+        env = "KSTORE_REPLICA_OF",
+        value_name = "URL",
+        requires = "node_secret"
+    )]
+    pub replica_of: Option<String>,
+
+    /// Run as part of a cluster, joining through these nodes, comma
+    /// separated. One running member is enough; the others are discovered
+    /// through gossip.
+    #[arg(
+        long,
+        env = "KSTORE_CLUSTER_PEERS",
+        value_name = "URL,...",
+        value_delimiter = ',',
+        conflicts_with = "replica_of",
+        requires = "node_secret"
+    )]
+    pub cluster_peers: Vec<String>,
+
+    /// Spread keys over a set of nodes, joining through these nodes, comma
+    /// separated, and forward requests for keys owned by another node. One
+    /// running member is enough; the others are discovered through gossip.
+    #[arg(
+        long,
+        env = "KSTORE_SHARD_PEERS",
+        value_name = "URL,...",
+        value_delimiter = ',',
+        conflicts_with_all = ["replica_of", "cluster_peers"],
+        requires = "node_secret"
+    )]
+    pub shard_peers: Vec<String>,
+
+    /// URL other nodes reach this node at, as it appears in
+    /// `--cluster-peers` or `--shard-peers`. Defaults to `http://<bind>`.
+    #[arg(long, env = "KSTORE_ADVERTISE_URL", value_name = "URL")]
+    pub advertise_url: Option<String>,
+
+    /// Secret shared by every node of a cluster, shard set or replica set,
+    /// used to sign the requests they make to each other. Required with
+    /// `--cluster-peers`, `--shard-peers` and `--replica-of`.
+    #[arg(long, env = "KSTORE_NODE_SECRET", hide_env_values = true)]
+    pub node_secret: Option<String>,
+
+    /// Where to keep the data.
+    #[arg(long, env = "KSTORE_STORAGE", value_enum, default_value_t = Storage::File)]
+    pub storage: Storage,
+
+    /// Keep everything in memory and never open `kvstore.db`, for tests
+    /// and cache-only use. Same as `--storage memory`.
+    #[arg(long, env = "KSTORE_EPHEMERAL", conflicts_with = "storage")]
+    pub ephemeral: bool,
+
+    /// Largest key accepted, in bytes.
+    #[arg(long, env = "KSTORE_MAX_KEY_SIZE", default_value_t = MAX_KEY_SIZE, value_parser = at_least_one())]
+    pub max_key_size: usize,
+
+    /// Largest value accepted, in bytes.
+    #[arg(long, env = "KSTORE_MAX_VALUE_SIZE", default_value_t = MAX_VALUE_SIZE, value_parser = at_least_one())]
+    pub max_value_size: usize,
+
+    /// Most keys the store may hold. Creating more fails with 507 Insufficient
+    /// Storage.
+    #[arg(long, env = "KSTORE_MAX_KEYS", value_parser = at_least_one())]
+    pub max_keys: Option<usize>,
+
+    /// Largest request body accepted, in bytes. Streaming imports are not
+    /// limited by it.
+    #[arg(long, env = "KSTORE_MAX_PAYLOAD_SIZE", default_value_t = 2_097_152, value_parser = at_least_one())]
+    pub max_payload_size: usize,
+
+    /// Largest Redis dump `POST /import/rdb` accepts, in bytes. Larger
+    /// uploads fail with 413 Payload Too Large.
+    #[arg(long, env = "KSTORE_MAX_RDB_SIZE", value_name = "BYTES", default_value_t = 1_073_741_824, value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    pub max_rdb_size: u64,
+
+    /// Milliseconds a write may wait for other operations, such as a
+    /// compaction, before failing with 503 Service Unavailable. Unset waits
+    /// as long as it takes.
+    #[arg(long, env = "KSTORE_OPERATION_DEADLINE", value_name = "MS", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    pub operation_deadline: Option<u64>,
+
+    /// Key prefix kept for the server's own bookkeeping. Clients may read
+    /// keys under it but not write them. Empty reserves nothing.
+    #[arg(long, env = "KSTORE_RESERVED_PREFIX", default_value = "__kstore/")]
+    pub reserved_prefix: String,
+
+    /// Seconds to remember writes sent with an `Idempotency-Key` header, so
+    /// retries get the original response. 0 turns it off.
+    #[arg(
+        long,
+        env = "KSTORE_IDEMPOTENCY_WINDOW",
+        value_name = "SECS",
+        default_value_t = 300
+    )]
+    pub idempotency_window: u64,
+
+    /// Read-through cache mode: a GET for a missing key fetches it from
+    /// this URL, with `{key}` replaced by the key, and stores it.
+    #[arg(long, env = "KSTORE_ORIGIN", value_name = "URL")]
+    pub origin: Option<String>,
+
+    /// Seconds a value fetched from the origin stays fresh.
+    #[arg(
+        long,
+        env = "KSTORE_ORIGIN_TTL",
+        value_name = "SECS",
+        default_value_t = 300
+    )]
+    pub origin_ttl: u64,
+
+    /// Seconds past the TTL a value is still served while it is refreshed
+    /// in the background.
+    #[arg(
+        long,
+        env = "KSTORE_ORIGIN_STALE",
+        value_name = "SECS",
+        default_value_t = 60
+    )]
+    pub origin_stale: u64,
+
+    /// Send every change to this endpoint in the background, retrying
+    /// until it accepts them.
+    #[arg(long, env = "KSTORE_MIRROR", value_name = "URL")]
+    pub mirror: Option<String>,
+
+    /// How changes are sent to `--mirror`.
+    #[arg(long, env = "KSTORE_MIRROR_FORMAT", value_enum, default_value_t = MirrorFormat::Kstore)]
+    pub mirror_format: MirrorFormat,
+
+    /// Values larger than this many bytes are kept in S3 (configured with
+    /// `KSTORE_S3_*`), with only a pointer in the store.
+    #[arg(long, env = "KSTORE_TIER_THRESHOLD", value_name = "BYTES", value_parser = at_least_one())]
+    pub tier_threshold: Option<usize>,
+
+    /// Also log every request to this file as a line of JSON, apart from
+    /// the log on stderr.
+    #[arg(long, env = "KSTORE_ACCESS_LOG", value_name = "FILE")]
+    pub access_log: Option<PathBuf>,
+
+    /// Rotate the access log once it would grow past this many bytes.
+    #[arg(
+        long,
+        env = "KSTORE_ACCESS_LOG_MAX_SIZE",
+        value_name = "BYTES",
+        default_value_t = 104_857_600,
+        value_parser = RangedU64ValueParser::<u64>::new().range(1..)
+    )]
+    pub access_log_max_size: u64,
+
+    /// Also rotate the access log once it is this many seconds old.
+    #[arg(long, env = "KSTORE_ACCESS_LOG_MAX_AGE", value_name = "SECS", value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    pub access_log_max_age: Option<u64>,
+
+    /// Rotated access logs to keep.
+    #[arg(long, env = "KSTORE_ACCESS_LOG_KEEP", default_value_t = 10, value_parser = at_least_one())]
+    pub access_log_keep: usize,
+
+    /// Compress rotated access logs with gzip.
+    #[arg(long, env = "KSTORE_ACCESS_LOG_GZIP")]
+    pub access_log_gzip: bool,
+
+    /// Number of HTTP worker threads. Defaults to the number of CPUs.
+    #[arg(long, env = "KSTORE_WORKERS", value_parser = at_least_one())]
+    pub workers: Option<usize>,
+
+    /// Most threads for blocking work such as backups and large scans,
+    /// spread evenly over the workers. Defaults to 512.
+    #[arg(long, env = "KSTORE_BLOCKING_THREADS", value_parser = at_least_one())]
+    pub blocking_threads: Option<usize>,
+
+    /// Seconds to let in-flight requests finish after SIGTERM or SIGINT
+    /// before closing their connections.
+    #[arg(
+        long,
+        env = "KSTORE_SHUTDOWN_TIMEOUT",
+        value_name = "SECS",
+        default_value_t = 30
+    )]
+    pub shutdown_timeout: u64,
+
+    /// Compact the data file once requests have drained on shutdown.
+    #[arg(long, env = "KSTORE_COMPACT_ON_SHUTDOWN")]
+    pub compact_on_shutdown: bool,
+
+    /// Write a new checkpoint of the data file once the write-ahead log of
+    /// changes since the last one reaches this many bytes.
+    #[arg(long, env = "KSTORE_CHECKPOINT_WAL_SIZE", value_name = "BYTES", default_value_t = 67_108_864, value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    pub checkpoint_wal_size: u64,
+
+    /// TOML file with settings that have no flag, such as HTTP tuning.
+    #[arg(long, env = "KSTORE_CONFIG", value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Bearer token for the `/admin/` endpoints, which are disabled
+    /// without one.
+    #[arg(long, env = "KSTORE_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Tools run instead of the server.
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Interactive shell for a running server.
+    Shell(ShellArgs),
+    /// Load generator: runs a read/write mix against a server and reports
+    /// throughput and latency percentiles.
+    Bench(BenchArgs),
+    /// Print the records of a data file with their offsets, sizes and
+    /// checksums.
+    Dump(DumpArgs),
+    /// Check a data file for corrupt or truncated records, and optionally
+    /// rewrite it without them.
+    Fsck(FsckArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ShellArgs {
+    /// Server to connect to.
+    #[arg(long, env = "KSTORE_URL", default_value = "http://127.0.0.1:8080")]
+    pub url: String,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct BenchArgs {
+    /// Server to benchmark.
+    #[arg(long, env = "KSTORE_URL", default_value = "http://127.0.0.1:8080")]
+    pub url: String,
+
+    /// Total number of requests to send.
+    #[arg(long, default_value_t = 10_000, value_parser = at_least_one())]
+    pub requests: usize,
+
+    /// Requests kept in flight at once.
+    #[arg(long, default_value_t = 16, value_parser = at_least_one())]
+    pub concurrency: usize,
+
+    /// Fraction of requests that are reads, from 0 to 1.
+    #[arg(long, default_value_t = 0.8, value_parser = parse_ratio)]
+    pub read_ratio: f64,
+
+    /// Number of distinct keys.
+    #[arg(long, default_value_t = 10_000, value_parser = at_least_one())]
+    pub keys: usize,
+
+    /// How keys are picked for each request.
+    #[arg(long, value_enum, default_value_t = KeyDistribution::Uniform)]
+    pub distribution: KeyDistribution,
+
+    /// Skew of the zipf distribution; higher values concentrate requests
+    /// on fewer keys.
+    #[arg(long, default_value_t = 0.99)]
+    pub zipf_exponent: f64,
+
+    /// Size of written values in bytes.
+    #[arg(long, default_value_t = 100, value_parser = at_least_one())]
+    pub value_size: usize,
+
+    /// Prefix of the benchmark's keys.
+    #[arg(long, default_value = "bench:")]
+    pub key_prefix: String,
+
+    /// Do not write every key before the run, so early reads may miss.
+    #[arg(long)]
+    pub no_preload: bool,
+}
+
+fn at_least_one() -> RangedU64ValueParser<usize> {
+    RangedU64ValueParser::new().range(1..)
+}
+
+fn parse_ratio(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err("expected a number from 0 to 1".to_string()),
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyDistribution {
+    /// Every key equally likely.
+    Uniform,
+    /// A few hot keys get most requests.
+    Zipf,
+    /// Keys in order, wrapping around.
+    Sequential,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct DumpArgs {
+    /// Data file to read, such as `kvstore.db`.
+    pub file: PathBuf,
+
+    /// Print only the totals, not every record.
+    #[arg(long)]
+    pub summary: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct FsckArgs {
+    /// Data file to check. The server using it must not be running.
+    pub file: PathBuf,
+
+    /// Rewrite the file with only its valid records, keeping the original
+    /// as `<file>.bak`.
+    #[arg(long)]
+    pub repair: bool,
+
+    /// Largest key the server was allowed to write.
+    #[arg(long, default_value_t = MAX_KEY_SIZE)]
+    pub max_key_size: usize,
+
+    /// Largest value the server was allowed to write.
+    #[arg(long, default_value_t = MAX_VALUE_SIZE)]
+    pub max_value_size: usize,
+}
+
+impl Config {
+    /// Reads the `--config` file, or returns the defaults without one.
+    pub fn file(&self) -> Result<FileConfig, String> {
+        match &self.config {
+            Some(path) => FileConfig::load(path),
+            None => Ok(FileConfig::default()),
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_key_size: self.max_key_size,
+            max_value_size: self.max_value_size,
+            max_keys: self.max_keys,
+        }
+    }
+
+    pub fn storage(&self) -> Storage {
+        if self.ephemeral {
+            Storage::Memory
+        } else {
+            self.storage
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MirrorFormat {
+    /// Another kstore server: sets and deletes through its key API.
+    Kstore,
+    /// Any HTTP endpoint: a POST of `{"key", "value", "timestamp"}` per
+    /// change, with a null value for deleted keys.
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    /// The `kvstore.db` data file.
+    File,
+    /// Nowhere: all data is lost when the process exits.
+    Memory,
+    /// A sled database in the `kvstore.sled` directory.
+    #[cfg(feature = "sled")]
+    Sled,
+}
+
+impl Storage {
+    pub fn open(self) -> Result<Box<dyn StorageBackend>, Error> {
+        Ok(match self {
+            Storage::File => Box::new(FileBackend::open("kvstore.db")?),
+            Storage::Memory => Box::new(MemoryBackend),
+            #[cfg(feature = "sled")]
+            Storage::Sled => Box::new(kstore::storage::SledBackend::open("kvstore.sled")?),
+        })
+    }
+
+    /// Directory the data is kept in, if it is kept on disk at all.
+    pub fn data_dir(self) -> Option<PathBuf> {
+        match self {
+            Storage::File => Some(PathBuf::from(".")),
+            Storage::Memory => None,
+            #[cfg(feature = "sled")]
+            Storage::Sled => Some(PathBuf::from("kvstore.sled")),
+        }
+    }
+}
+
+/// Settings from the `--config` file. Every field is optional and unknown
+/// fields are rejected, so typos do not go unnoticed.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub http: HttpConfig,
+    /// Expiry policies, one `[[ttl]]` table each.
+    pub ttl: Vec<TtlPolicy>,
+    pub metrics: MetricsConfig,
+    /// Compacts the data file on its own, within the `[compaction]` table's
+    /// limits.
+    pub compaction: Option<CompactionConfig>,
+}
+
+/// When the data file may be compacted without being asked to.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompactionConfig {
+    /// Times of day compactions may start in, as `HH:MM-HH:MM` in UTC. A
+    /// window may wrap past midnight. Empty for any time.
+    pub windows: Vec<String>,
+    /// Seconds to leave between compactions.
+    pub min_interval_secs: u64,
+    /// Share of the data file, in percent, that must be reclaimable.
+    pub min_reclaimable_percent: u64,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            windows: Vec::new(),
+            min_interval_secs: 3600,
+            min_reclaimable_percent: 50,
+        }
+    }
+}
+
+/// What `/stats` and `/metrics` break usage down by, in the `[metrics]`
+/// table.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Prefixes to report key counts, sizes and operations for.
+    pub prefixes: Vec<String>,
+}
+
+/// Times to live, in seconds, for keys written under `prefix`: the default
+/// for writes that do not ask for one and the most a write may ask for.
+/// Where prefixes overlap, the longest one applies.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TtlPolicy {
+    pub prefix: String,
+    pub default_ttl: Option<u64>,
+    pub max_ttl: Option<u64>,
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+    }
+}
+
+/// HTTP server tunables, in the `[http]` table. Unset fields keep actix's
+/// defaults.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Seconds an idle connection is kept open for another request, or 0
+    /// to close connections after each response.
+    pub keep_alive_secs: Option<u64>,
+    /// Milliseconds a client has to send a request's headers.
+    pub client_request_timeout_ms: Option<u64>,
+    /// Milliseconds a client has to acknowledge a closing connection.
+    pub client_disconnect_timeout_ms: Option<u64>,
+    /// Most connections each worker handles at once.
+    pub max_connections: Option<usize>,
+    /// Most connections waiting to be accepted.
+    pub backlog: Option<u32>,
+    /// Also accept cleartext HTTP/2 with prior knowledge on the same port.
+    pub h2c: bool,
+}
@@ -0,0 +1,108 @@
+//! `kstore dump`: walks a data file record by record without loading it
+//! into a store, for looking into files that are corrupt or larger than
+//! expected.
+//!
+//! The data file format has no checksums of its own. The ones printed here
+//! are the first 8 bytes of each record's SHA-256, so the same record can
+//! be recognised across copies of a file.
+
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+
+use kstore::storage::{Record, Records};
+use sha2::{Digest, Sha256};
+
+use crate::config::DumpArgs;
+
+fn checksum(buffer: &[u8], record: &Record) -> String {
+    Sha256::digest(&buffer[record.offset..record.offset + record.size()])[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn run(args: DumpArgs) -> std::io::Result<()> {
+    let buffer = std::fs::read(&args.file)?;
+    let mut out = BufWriter::new(std::io::stdout().lock());
+
+    if !args.summary {
+        writeln!(
+            out,
+            "{:>12} {:>10} {:>10} {:<6} {:<16} key",
+            "offset", "key_size", "value_size", "op", "checksum"
+        )?;
+    }
+    let mut records = Records::new(&buffer);
+    let (mut sets, mut deltas, mut deletes) = (0usize, 0usize, 0usize);
+    // Size of the records each key's value is read from, or 0 once it is
+    // deleted.
+    let mut live: HashMap<&[u8], usize> = HashMap::new();
+    for record in records.by_ref() {
+        if record.delta {
+            deltas += 1;
+            *live.entry(record.key).or_default() += record.size();
+        } else if record.is_delete() {
+            deletes += 1;
+            live.insert(record.key, 0);
+        } else {
+            sets += 1;
+            live.insert(record.key, record.size());
+        }
+        if !args.summary {
+            writeln!(
+                out,
+                "{:>12} {:>10} {:>10} {:<6} {:<16} {:?}",
+                record.offset,
+                record.key.len(),
+                record.value.len(),
+                if record.delta {
+                    "delta"
+                } else if record.is_delete() {
+                    "delete"
+                } else {
+                    "set"
+                },
+                checksum(&buffer, &record),
+                String::from_utf8_lossy(record.key)
+            )?;
+        }
+    }
+    let complete = records.position();
+    let live_keys = live.values().filter(|&&size| size > 0).count();
+    let live_bytes: usize = live.values().sum();
+    let reclaimable = complete - live_bytes;
+
+    if !args.summary {
+        writeln!(out)?;
+    }
+    writeln!(out, "File:        {}", args.file.display())?;
+    writeln!(out, "Size:        {} bytes", buffer.len())?;
+    writeln!(
+        out,
+        "Records:     {} ({} sets, {} deltas, {} deletes)",
+        sets + deltas + deletes,
+        sets,
+        deltas,
+        deletes
+    )?;
+    writeln!(out, "Live keys:   {} ({} bytes)", live_keys, live_bytes)?;
+    writeln!(
+        out,
+        "Reclaimable: {} bytes ({:.1}%) in overwritten and deleted records",
+        reclaimable,
+        if complete == 0 {
+            0.0
+        } else {
+            reclaimable as f64 * 100.0 / complete as f64
+        }
+    )?;
+    if complete < buffer.len() {
+        writeln!(
+            out,
+            "Truncated:   {} bytes at offset {} do not form a complete record",
+            buffer.len() - complete,
+            complete
+        )?;
+    }
+    out.flush()
+}
@@ -0,0 +1,84 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Errors returned by [`KvStore`](crate::KvStore) operations. The `Display`
+/// output is the message the HTTP API sends back to clients.
+#[derive(Debug)]
+pub enum Error {
+    EmptyKey,
+    /// Carries the store's key size limit.
+    KeyTooLarge(usize),
+    /// Carries the store's value size limit.
+    ValueTooLarge(usize),
+    KeyNotFound,
+    /// The store already holds its maximum number of keys, carried here.
+    TooManyKeys(usize),
+    /// The key holds a value that is not a geo set.
+    NotAGeoSet,
+    /// A cursor, coordinate or backup that could not be used as given.
+    InvalidInput(String),
+    /// The operation needs state the store does not have yet, such as an
+    /// incremental backup without a full backup to base it on.
+    Precondition(&'static str),
+    /// A write failed because the disk is full, or one did earlier and the
+    /// store is read-only until there is room again.
+    DiskFull,
+    /// The operation waited for other operations longer than the store's
+    /// deadline, carried here, and gave up without changing anything.
+    DeadlineExceeded(Duration),
+    /// A read as of a time before the history the store retains, which
+    /// starts at the timestamp carried here.
+    HistoryUnavailable(u64),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::EmptyKey => write!(f, "Key cannot be empty"),
+            Error::KeyTooLarge(max) => write!(f, "Key exceeds maximum size of {} bytes", max),
+            Error::ValueTooLarge(max) => write!(f, "Value exceeds maximum size of {} bytes", max),
+            Error::KeyNotFound => write!(f, "Key does not exist"),
+            Error::TooManyKeys(max) => {
+                write!(
+                    f,
+                    "Store is full: it already holds the maximum of {} keys",
+                    max
+                )
+            }
+            Error::NotAGeoSet => write!(f, "Key does not hold a geo set"),
+            Error::InvalidInput(message) => write!(f, "{}", message),
+            Error::Precondition(message) => write!(f, "{}", message),
+            Error::DiskFull => write!(
+                f,
+                "The disk is full, writes are disabled until space is freed"
+            ),
+            Error::DeadlineExceeded(deadline) => write!(
+                f,
+                "The store is busy: the operation could not start within its {} ms deadline, try again",
+                deadline.as_millis()
+            ),
+            Error::HistoryUnavailable(since) => write!(
+                f,
+                "History is only retained from {} on, so the value at that time is unknown",
+                since
+            ),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
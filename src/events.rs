@@ -0,0 +1,64 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::glob::glob_match;
+
+pub const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Created,
+    Updated,
+    Deleted,
+    /// Deleted by the expiry sweep because its time to live ran out.
+    Expired,
+}
+
+impl EventKind {
+    pub fn parse(kind: &str) -> Result<Self, String> {
+        match kind {
+            "created" => Ok(EventKind::Created),
+            "updated" => Ok(EventKind::Updated),
+            "deleted" => Ok(EventKind::Deleted),
+            "expired" => Ok(EventKind::Expired),
+            _ => Err(format!("Unknown event type '{}'", kind)),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Created => "created",
+            EventKind::Updated => "updated",
+            EventKind::Deleted => "deleted",
+            EventKind::Expired => "expired",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyEvent {
+    pub event: EventKind,
+    pub key: Arc<str>,
+    pub timestamp: u64,
+}
+
+pub struct EventFilter {
+    pub pattern: Option<String>,
+    pub kinds: Option<Vec<EventKind>>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &KeyEvent) -> bool {
+        if let Some(kinds) = &self.kinds
+            && !kinds.contains(&event.event)
+        {
+            return false;
+        }
+        match &self.pattern {
+            Some(pattern) => glob_match(pattern, &event.key),
+            None => true,
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! Key expiry. A write can give its key a time to live with the `X-TTL`
+//! header, and the `[[ttl]]` tables of the config file set a default for
+//! the writes under a prefix that do not, a maximum the ones that do are
+//! capped at, or both. The expiry is worked out when the key is written
+//! and stored with it, so it survives restarts, and keys are deleted by a
+//! sweep every second once it passes.
+//!
+//! Only a node that accepts writes sweeps; replicas and cluster followers
+//! get the deletes from their primary. Nothing expires while the node is
+//! in read-only maintenance mode, and keys under the reserved prefix never
+//! expire.
+
+use std::time::Duration;
+
+use actix_web::HttpRequest;
+use actix_web::rt::time::sleep;
+use actix_web::web;
+use kstore::{KvStore, current_timestamp};
+
+use crate::admin::Maintenance;
+use crate::cluster::Cluster;
+use crate::config::TtlPolicy;
+use crate::replication::Replication;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Seconds a key written with it has to live.
+pub const TTL_HEADER: &str = "X-TTL";
+
+/// The time to live `req` asks for its key, if any.
+pub fn requested(req: &HttpRequest) -> Result<Option<u64>, String> {
+    let Some(ttl) = req.headers().get(TTL_HEADER) else {
+        return Ok(None);
+    };
+    let invalid = || format!("{} must be a number of seconds above 0", TTL_HEADER);
+    let ttl: u64 = ttl
+        .to_str()
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .ok_or_else(invalid)?;
+    if ttl == 0 {
+        return Err(invalid());
+    }
+    Ok(Some(ttl))
+}
+
+/// When a key asking for `ttl` seconds expires if written now.
+pub fn expires_at(ttl: Option<u64>) -> Option<u64> {
+    ttl.map(|ttl| current_timestamp().saturating_add(ttl))
+}
+
+pub struct Expiry {
+    /// Longest prefix first, so the first match is the one that applies.
+    policies: Vec<TtlPolicy>,
+}
+
+impl Expiry {
+    pub fn new(mut policies: Vec<TtlPolicy>) -> Result<Self, String> {
+        for policy in &policies {
+            match (policy.default_ttl, policy.max_ttl) {
+                (None, None) => {
+                    return Err(format!(
+                        "The TTL for prefix '{}' needs a default_ttl, a max_ttl or both",
+                        policy.prefix
+                    ));
+                }
+                (Some(0), _) | (_, Some(0)) => {
+                    return Err(format!(
+                        "The TTL for prefix '{}' must be at least 1 second",
+                        policy.prefix
+                    ));
+                }
+                (Some(default), Some(max)) if default > max => {
+                    return Err(format!(
+                        "The default_ttl for prefix '{}' is over its max_ttl",
+                        policy.prefix
+                    ));
+                }
+                _ => {}
+            }
+        }
+        policies.sort_by(|a, b| {
+            b.prefix
+                .len()
+                .cmp(&a.prefix.len())
+                .then_with(|| a.prefix.cmp(&b.prefix))
+        });
+        if let Some(pair) = policies
+            .windows(2)
+            .find(|pair| pair[0].prefix == pair[1].prefix)
+        {
+            return Err(format!(
+                "The TTL for prefix '{}' is configured twice",
+                pair[0].prefix
+            ));
+        }
+        Ok(Self { policies })
+    }
+
+    /// When `key`, written at `now` and asking to expire at `requested`,
+    /// expires: at the default of the policy covering it if it asked for
+    /// nothing, and no later than the policy's maximum.
+    pub fn expires_at(&self, key: &str, requested: Option<u64>, now: u64) -> Option<u64> {
+        let Some(policy) = self
+            .policies
+            .iter()
+            .find(|policy| key.starts_with(&policy.prefix))
+        else {
+            return requested;
+        };
+        let latest = policy.max_ttl.map(|ttl| now.saturating_add(ttl));
+        match requested {
+            Some(at) => Some(latest.map_or(at, |latest| at.min(latest))),
+            None => policy
+                .default_ttl
+                .map(|ttl| now.saturating_add(ttl))
+                .or(latest),
+        }
+    }
+}
+
+/// Deletes expired keys every second while this node takes writes and is
+/// not in maintenance mode.
+pub async fn run(
+    store: web::Data<KvStore>,
+    replication: web::Data<Replication>,
+    cluster: web::Data<Option<Cluster>>,
+    maintenance: web::Data<Maintenance>,
+) {
+    loop {
+        sleep(SWEEP_INTERVAL).await;
+        let follower = replication.primary().is_some()
+            || cluster
+                .as_ref()
+                .as_ref()
+                .is_some_and(|cluster| !cluster.is_leader());
+        if follower || maintenance.is_read_only() || store.disk_full().is_some() {
+            continue;
+        }
+        let store = store.clone();
+        match web::block(move || store.expire(current_timestamp())).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => eprintln!("Failed to delete expired keys: {}", e),
+            Err(e) => eprintln!("Failed to delete expired keys: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use kstore::events::EventKind;
+    use kstore::{Attributes, MemoryBackend};
+
+    const NOW: u64 = 1_000;
+
+    fn policy(prefix: &str, default_ttl: Option<u64>, max_ttl: Option<u64>) -> TtlPolicy {
+        TtlPolicy {
+            prefix: prefix.to_string(),
+            default_ttl,
+            max_ttl,
+        }
+    }
+
+    fn expiring(expires_at: Option<u64>) -> Attributes {
+        Attributes {
+            expires_at,
+            ..Attributes::NONE
+        }
+    }
+
+    #[test]
+    fn policies_set_defaults_and_cap_requests() {
+        let expiry = Expiry::new(vec![
+            policy("session:", Some(60), Some(600)),
+            policy("cache:", None, Some(300)),
+            policy("temp:", Some(30), None),
+        ])
+        .unwrap();
+        assert_eq!(expiry.expires_at("session:a", None, NOW), Some(NOW + 60));
+        assert_eq!(
+            expiry.expires_at("session:a", Some(NOW + 120), NOW),
+            Some(NOW + 120)
+        );
+        assert_eq!(
+            expiry.expires_at("session:a", Some(NOW + 6000), NOW),
+            Some(NOW + 600)
+        );
+        // Without a default, the maximum applies to every write.
+        assert_eq!(expiry.expires_at("cache:a", None, NOW), Some(NOW + 300));
+        assert_eq!(
+            expiry.expires_at("temp:a", Some(NOW + 6000), NOW),
+            Some(NOW + 6000)
+        );
+        assert_eq!(expiry.expires_at("other", None, NOW), None);
+        assert_eq!(
+            expiry.expires_at("other", Some(NOW + 5), NOW),
+            Some(NOW + 5)
+        );
+    }
+
+    #[test]
+    fn the_longest_prefix_applies() {
+        let expiry = Expiry::new(vec![
+            policy("a", Some(10), None),
+            policy("abc", Some(30), None),
+            policy("ab", Some(20), None),
+        ])
+        .unwrap();
+        assert_eq!(expiry.expires_at("abcd", None, NOW), Some(NOW + 30));
+        assert_eq!(expiry.expires_at("abx", None, NOW), Some(NOW + 20));
+        assert_eq!(expiry.expires_at("ax", None, NOW), Some(NOW + 10));
+    }
+
+    #[test]
+    fn invalid_policies_are_refused() {
+        for policies in [
+            vec![policy("a", None, None)],
+            vec![policy("a", Some(0), None)],
+            vec![policy("a", None, Some(0))],
+            vec![policy("a", Some(60), Some(30))],
+            vec![policy("a", Some(1), None), policy("a", Some(2), None)],
+        ] {
+            assert!(Expiry::new(policies).is_err());
+        }
+    }
+
+    #[test]
+    fn the_sweep_deletes_keys_whose_time_has_come() {
+        let store = KvStore::with_backend(Box::new(MemoryBackend)).unwrap();
+        let mut events = store.subscribe();
+        store
+            .set_with("past".to_string(), "v".to_string(), expiring(Some(NOW - 1)))
+            .unwrap();
+        store
+            .set_with("now".to_string(), "v".to_string(), expiring(Some(NOW)))
+            .unwrap();
+        store
+            .set_with(
+                "later".to_string(),
+                "v".to_string(),
+                expiring(Some(NOW + 1)),
+            )
+            .unwrap();
+        store.set("forever".to_string(), "v".to_string()).unwrap();
+        // Rewriting a key without a TTL keeps it.
+        store
+            .set_with(
+                "rewritten".to_string(),
+                "v".to_string(),
+                expiring(Some(NOW)),
+            )
+            .unwrap();
+        store.update("rewritten", "w".to_string()).unwrap();
+        while events.try_recv().is_ok() {}
+
+        assert_eq!(store.expire(NOW).unwrap(), 2);
+        let keys = store.keys_where(|_| true);
+        assert_eq!(
+            keys,
+            ["forever", "later", "rewritten"].map(Arc::<str>::from)
+        );
+        let mut expired = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            assert_eq!(event.event, EventKind::Expired);
+            expired.push(event.key);
+        }
+        expired.sort();
+        assert_eq!(expired, ["now", "past"].map(Arc::<str>::from));
+
+        assert_eq!(store.expire(NOW).unwrap(), 0);
+        assert_eq!(store.expire(NOW + 1).unwrap(), 1);
+        assert!(!store.exists("later"));
+    }
+
+    #[test]
+    fn ttl_headers_must_be_positive_numbers() {
+        use actix_web::test::TestRequest;
+        let ttl = |value: &str| {
+            requested(
+                &TestRequest::default()
+                    .insert_header((TTL_HEADER, value.to_string()))
+                    .to_http_request(),
+            )
+        };
+        assert_eq!(ttl("60"), Ok(Some(60)));
+        assert!(ttl("0").is_err());
+        assert!(ttl("-5").is_err());
+        assert!(ttl("soon").is_err());
+        assert_eq!(
+            requested(&TestRequest::default().to_http_request()),
+            Ok(None)
+        );
+    }
+}
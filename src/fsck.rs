@@ -0,0 +1,110 @@
+//! `kstore fsck`: checks a data file offline and can rewrite it without the
+//! records a store would not have written.
+//!
+//! A record is invalid when its key is empty, either part is not UTF-8 or
+//! its attributes or delta cannot be read.
+//! A header claiming a key or value over the size limits means the framing
+//! itself is lost, so nothing from that offset on can be read, the same as
+//! a record cut short at the end of the file.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use kstore::storage::{Record, Records, parse_delta};
+
+use crate::config::FsckArgs;
+
+/// Why a store could not have written `record`, if it could not.
+fn problem(record: &Record) -> Option<&'static str> {
+    if record.key.is_empty() {
+        Some("empty key")
+    } else if std::str::from_utf8(record.key).is_err() {
+        Some("key is not valid UTF-8")
+    } else if std::str::from_utf8(record.value).is_err() {
+        Some("value is not valid UTF-8")
+    } else {
+        match record.split_attributes() {
+            None => Some("malformed attributes"),
+            Some((_, delta)) if record.delta && parse_delta(delta).is_none() => {
+                Some("malformed delta")
+            }
+            Some(_) => None,
+        }
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+pub fn run(args: FsckArgs) -> std::io::Result<()> {
+    let buffer = std::fs::read(&args.file)?;
+    let mut records = Records::new(&buffer);
+    let mut clean = Vec::with_capacity(buffer.len());
+    let (mut checked, mut invalid) = (0usize, 0usize);
+    let mut readable = None;
+    for record in records.by_ref() {
+        if record.key.len() > args.max_key_size || record.value.len() > args.max_value_size {
+            println!(
+                "offset {}: header claims a {}-byte key and a {}-byte value, over the size limits",
+                record.offset,
+                record.key.len(),
+                record.value.len()
+            );
+            readable = Some(record.offset);
+            break;
+        }
+        checked += 1;
+        match problem(&record) {
+            Some(problem) => {
+                println!("offset {}: {}", record.offset, problem);
+                invalid += 1;
+            }
+            None => clean.extend_from_slice(&buffer[record.offset..record.offset + record.size()]),
+        }
+    }
+    let readable = readable.unwrap_or(records.position());
+    let unreadable = buffer.len() - readable;
+    if unreadable > 0 && readable == records.position() {
+        println!(
+            "offset {}: record cut short by the end of the file",
+            readable
+        );
+    }
+
+    println!(
+        "{}: {} records, {} invalid, {} unreadable bytes at the end",
+        args.file.display(),
+        checked,
+        invalid,
+        unreadable
+    );
+    if invalid == 0 && unreadable == 0 {
+        println!("No problems found");
+        return Ok(());
+    }
+    if !args.repair {
+        println!("Run with --repair to rewrite the file without them");
+        // Like fsck(8), report problems through the exit status too.
+        std::process::exit(1);
+    }
+
+    let temp = with_suffix(&args.file, ".fsck");
+    let backup = with_suffix(&args.file, ".bak");
+    let mut file = File::create(&temp)?;
+    file.write_all(&clean)?;
+    file.sync_all()?;
+    std::fs::rename(&args.file, &backup)?;
+    std::fs::rename(&temp, &args.file)?;
+    println!(
+        "Rewrote {} with {} valid records ({} bytes dropped); the original is at {}",
+        args.file.display(),
+        checked - invalid,
+        buffer.len() - clean.len(),
+        backup.display()
+    );
+    Ok(())
+}
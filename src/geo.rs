@@ -0,0 +1,260 @@
+use serde::{Deserialize, Serialize};
+
+const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+const GEOHASH_PRECISION: usize = 12;
+// Same earth radius Redis uses for GEODIST/GEOSEARCH, so results line up.
+const EARTH_RADIUS_METERS: f64 = 6_372_797.560856;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GeoMember {
+    pub member: String,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GeoMatch {
+    pub member: String,
+    pub lat: f64,
+    pub lon: f64,
+    pub geohash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum DistanceUnit {
+    Meters,
+    Kilometers,
+    Miles,
+    Feet,
+}
+
+impl DistanceUnit {
+    pub fn parse(unit: &str) -> Result<Self, String> {
+        match unit {
+            "m" => Ok(DistanceUnit::Meters),
+            "km" => Ok(DistanceUnit::Kilometers),
+            "mi" => Ok(DistanceUnit::Miles),
+            "ft" => Ok(DistanceUnit::Feet),
+            _ => Err(format!(
+                "Unsupported unit '{}', expected m, km, mi or ft",
+                unit
+            )),
+        }
+    }
+
+    fn meters_per_unit(self) -> f64 {
+        match self {
+            DistanceUnit::Meters => 1.0,
+            DistanceUnit::Kilometers => 1000.0,
+            DistanceUnit::Miles => 1609.34,
+            DistanceUnit::Feet => 0.3048,
+        }
+    }
+
+    pub fn to_meters(self, distance: f64) -> f64 {
+        distance * self.meters_per_unit()
+    }
+
+    pub fn convert_meters(self, meters: f64) -> f64 {
+        meters / self.meters_per_unit()
+    }
+}
+
+pub fn validate_coordinates(lat: f64, lon: f64) -> Result<(), String> {
+    if !(-90.0..=90.0).contains(&lat) {
+        return Err(format!("Latitude {} is out of range [-90, 90]", lat));
+    }
+    if !(-180.0..=180.0).contains(&lon) {
+        return Err(format!("Longitude {} is out of range [-180, 180]", lon));
+    }
+    Ok(())
+}
+
+pub fn encode(lat: f64, lon: f64) -> String {
+    let mut lat_range = (-90.0, 90.0);
+    let mut lon_range = (-180.0, 180.0);
+    let mut hash = String::with_capacity(GEOHASH_PRECISION);
+    let mut bits = 0u8;
+    let mut bit_count = 0;
+    let mut even = true;
+
+    while hash.len() < GEOHASH_PRECISION {
+        let (range, value) = if even {
+            (&mut lon_range, lon)
+        } else {
+            (&mut lat_range, lat)
+        };
+        let mid = (range.0 + range.1) / 2.0;
+        bits <<= 1;
+        if value >= mid {
+            bits |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        even = !even;
+        bit_count += 1;
+
+        if bit_count == 5 {
+            hash.push(BASE32[bits as usize] as char);
+            bits = 0;
+            bit_count = 0;
+        }
+    }
+    hash
+}
+
+/// Decodes a geohash to the center of the cell it describes.
+pub fn decode(hash: &str) -> Result<(f64, f64), String> {
+    let mut lat_range = (-90.0, 90.0);
+    let mut lon_range = (-180.0, 180.0);
+    let mut even = true;
+
+    for c in hash.bytes() {
+        let index = BASE32
+            .iter()
+            .position(|&b| b == c)
+            .ok_or_else(|| format!("Invalid geohash '{}'", hash))?;
+        for shift in (0..5).rev() {
+            let range = if even { &mut lon_range } else { &mut lat_range };
+            let mid = (range.0 + range.1) / 2.0;
+            if (index >> shift) & 1 == 1 {
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            even = !even;
+        }
+    }
+
+    Ok((
+        (lat_range.0 + lat_range.1) / 2.0,
+        (lon_range.0 + lon_range.1) / 2.0,
+    ))
+}
+
+/// Great-circle distance between two points in meters.
+pub fn haversine(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let d_lat = (lat2 - lat1).to_radians();
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * a.sqrt().asin()
+}
+
+/// Checks whether a point lies inside a bounding box. Boxes whose min_lon is
+/// greater than max_lon are treated as crossing the antimeridian.
+pub fn in_box(lat: f64, lon: f64, min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64) -> bool {
+    if lat < min_lat || lat > max_lat {
+        return false;
+    }
+    if min_lon <= max_lon {
+        lon >= min_lon && lon <= max_lon
+    } else {
+        lon >= min_lon || lon <= max_lon
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KvStore, MemoryBackend};
+
+    const PALERMO: (f64, f64) = (38.115556, 13.361389);
+    const CATANIA: (f64, f64) = (37.502669, 15.087269);
+
+    fn sicily() -> KvStore {
+        let store = KvStore::with_backend(Box::new(MemoryBackend)).unwrap();
+        let members = [("Palermo", PALERMO), ("Catania", CATANIA)]
+            .into_iter()
+            .map(|(member, (lat, lon))| GeoMember {
+                member: member.to_string(),
+                lat,
+                lon,
+            })
+            .collect();
+        store.geo_add("Sicily", members).unwrap();
+        store
+    }
+
+    fn names(matches: &[GeoMatch]) -> Vec<&str> {
+        matches.iter().map(|m| m.member.as_str()).collect()
+    }
+
+    #[test]
+    fn geohashes_decode_to_the_encoded_point() {
+        let hash = encode(PALERMO.0, PALERMO.1);
+        assert_eq!(hash.len(), GEOHASH_PRECISION);
+        assert!(hash.starts_with("sqc8b49rny"));
+        let (lat, lon) = decode(&hash).unwrap();
+        assert!((lat - PALERMO.0).abs() < 1e-6);
+        assert!((lon - PALERMO.1).abs() < 1e-6);
+        assert!(decode("sqc8a").is_err());
+    }
+
+    #[test]
+    fn haversine_matches_redis_distances() {
+        // GEODIST Sicily Palermo Catania in the Redis documentation.
+        let meters = haversine(PALERMO.0, PALERMO.1, CATANIA.0, CATANIA.1);
+        assert!((meters - 166274.1516).abs() < 1.0, "{}", meters);
+        assert_eq!(haversine(CATANIA.0, CATANIA.1, CATANIA.0, CATANIA.1), 0.0);
+    }
+
+    #[test]
+    fn radius_finds_members_within_it_nearest_first() {
+        let store = sicily();
+        // GEORADIUS Sicily 15 37 200 km WITHDIST in the Redis documentation.
+        let matches = store
+            .geo_radius("Sicily", 37.0, 15.0, 200.0, DistanceUnit::Kilometers)
+            .unwrap()
+            .unwrap();
+        assert_eq!(names(&matches), ["Catania", "Palermo"]);
+        assert!((matches[0].distance.unwrap() - 56.4413).abs() < 0.01);
+        assert!((matches[1].distance.unwrap() - 190.4424).abs() < 0.01);
+
+        let matches = store
+            .geo_radius("Sicily", 37.0, 15.0, 100.0, DistanceUnit::Kilometers)
+            .unwrap()
+            .unwrap();
+        assert_eq!(names(&matches), ["Catania"]);
+    }
+
+    #[test]
+    fn radius_reports_distances_in_its_unit() {
+        let store = sicily();
+        let matches = store
+            .geo_radius("Sicily", 37.0, 15.0, 40.0, DistanceUnit::Miles)
+            .unwrap()
+            .unwrap();
+        assert_eq!(names(&matches), ["Catania"]);
+        let miles = matches[0].distance.unwrap();
+        assert!((DistanceUnit::Miles.to_meters(miles) - 56_441.3).abs() < 10.0);
+    }
+
+    #[test]
+    fn radius_needs_a_geo_set_and_a_valid_center() {
+        let store = sicily();
+        let radius = |key, lat| store.geo_radius(key, lat, 15.0, 1.0, DistanceUnit::Meters);
+        assert!(radius("missing", 37.0).unwrap().is_none());
+        assert!(matches!(
+            radius("Sicily", 91.0),
+            Err(crate::Error::InvalidInput(_))
+        ));
+        store.set("plain".to_string(), "value".to_string()).unwrap();
+        assert!(matches!(
+            radius("plain", 37.0),
+            Err(crate::Error::NotAGeoSet)
+        ));
+    }
+
+    #[test]
+    fn boxes_may_cross_the_antimeridian() {
+        assert!(in_box(10.0, 20.0, 0.0, 10.0, 20.0, 30.0));
+        assert!(!in_box(10.0, 40.0, 0.0, 10.0, 20.0, 30.0));
+        assert!(in_box(10.0, 179.0, 0.0, 170.0, 20.0, -170.0));
+        assert!(in_box(10.0, -179.0, 0.0, 170.0, 20.0, -170.0));
+        assert!(!in_box(10.0, 0.0, 0.0, 170.0, 20.0, -170.0));
+    }
+}
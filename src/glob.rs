@@ -0,0 +1,167 @@
+/// Redis-style glob matching: `*` matches any sequence, `?` matches a single
+/// character, `[abc]`/`[a-z]` match a class (negated with `^` or `!`), and
+/// `\` escapes the next character.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let mut p = 0;
+    let mut t = 0;
+    // Position of the last `*` seen and the text position it is currently
+    // assumed to cover up to, used to backtrack on mismatch.
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                '*' => {
+                    star = Some((p, t));
+                    p += 1;
+                    continue;
+                }
+                '?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                '[' => {
+                    if let Some((matched, next)) = match_class(&pattern, p, text[t]) {
+                        if matched {
+                            p = next;
+                            t += 1;
+                            continue;
+                        }
+                    } else if text[t] == '[' {
+                        // Unterminated class, treat `[` literally.
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                }
+                '\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == text[t] {
+                        p += 2;
+                        t += 1;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == text[t] {
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p + 1;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches `c` against the class starting at `pattern[start] == '['`.
+/// Returns whether it matched and the index just past the closing `]`, or
+/// `None` if the class is not terminated.
+fn match_class(pattern: &[char], start: usize, c: char) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negated = matches!(pattern.get(i), Some('^') | Some('!'));
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        match pattern[i] {
+            ']' if !first => return Some((matched != negated, i + 1)),
+            '\\' if i + 1 < pattern.len() => {
+                if pattern[i + 1] == c {
+                    matched = true;
+                }
+                i += 2;
+            }
+            lo if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' => {
+                let hi = pattern[i + 2];
+                let (lo, hi) = if lo <= hi { (lo, hi) } else { (hi, lo) };
+                if c >= lo && c <= hi {
+                    matched = true;
+                }
+                i += 3;
+            }
+            other => {
+                if other == c {
+                    matched = true;
+                }
+                i += 1;
+            }
+        }
+        first = false;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_match_any_run_or_one_character() {
+        assert!(glob_match("user:*", "user:"));
+        assert!(glob_match("user:*", "user:42:name"));
+        assert!(glob_match("*:name", "user:42:name"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+        assert!(glob_match("user:?", "user:7"));
+        assert!(!glob_match("user:?", "user:"));
+        assert!(!glob_match("user:?", "user:42"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn wildcards_backtrack_after_a_mismatch() {
+        assert!(glob_match("*ab", "aab"));
+        assert!(glob_match("*a*b", "xaxxab"));
+        assert!(!glob_match("*ab", "aba"));
+    }
+
+    #[test]
+    fn classes_match_sets_ranges_and_negations() {
+        assert!(glob_match("h[ae]llo", "hello"));
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("key[0-9]", "key5"));
+        assert!(glob_match("key[9-0]", "key5"));
+        assert!(!glob_match("key[0-9]", "keyx"));
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(glob_match("h[!e]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        // A `]` first in the class is one of its characters.
+        assert!(glob_match("[]]", "]"));
+    }
+
+    #[test]
+    fn escapes_and_unterminated_classes_are_literal() {
+        assert!(glob_match(r"what\?", "what?"));
+        assert!(!glob_match(r"what\?", "whats"));
+        assert!(glob_match(r"a\*b", "a*b"));
+        assert!(!glob_match(r"a\*b", "axb"));
+        assert!(glob_match(r"[\]]", "]"));
+        assert!(glob_match("[abc", "[abc"));
+    }
+
+    #[test]
+    fn matches_characters_not_bytes() {
+        assert!(glob_match("caf?", "café"));
+        assert!(glob_match("[é]", "é"));
+    }
+}
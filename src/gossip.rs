@@ -0,0 +1,331 @@
+//! Gossip-based membership for cluster and shard mode. Every second each
+//! node bumps its own heartbeat counter and swaps its member list with a
+//! couple of other members; both sides keep the newer entry for every
+//! node. New nodes only need to know one existing member to join, and
+//! everyone learns about them within a few rounds.
+//!
+//! A member whose heartbeat stops advancing is reported as suspect and then
+//! dead, but stays a member: its keys keep their owner and its vote keeps
+//! counting until an operator removes it.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::web;
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::Cluster;
+use crate::proxy::NodeSecret;
+use crate::shard::Sharding;
+
+const GOSSIP_INTERVAL: Duration = Duration::from_secs(1);
+const FANOUT: usize = 2;
+const SUSPECT_AFTER: Duration = Duration::from_secs(5);
+const DEAD_AFTER: Duration = Duration::from_secs(15);
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+    Removed,
+}
+
+/// One node's entry as exchanged between members.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemberDigest {
+    pub url: String,
+    /// Start time of the node's process, so a restarted node's heartbeat
+    /// counter starting over from zero still counts as newer.
+    pub incarnation: u64,
+    pub heartbeat: u64,
+    pub removed: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GossipMessage {
+    /// `cluster` or `shard`; nodes only gossip with nodes in the same mode.
+    pub mode: String,
+    pub members: Vec<MemberDigest>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MemberStatus {
+    pub url: String,
+    pub state: MemberState,
+    pub incarnation: u64,
+    pub heartbeat: u64,
+    /// Seconds since the member's heartbeat last advanced.
+    pub last_seen_secs: u64,
+}
+
+struct Member {
+    incarnation: u64,
+    heartbeat: u64,
+    removed: bool,
+    updated_at: Instant,
+}
+
+impl Member {
+    fn version(&self) -> (u64, u64) {
+        (self.incarnation, self.heartbeat)
+    }
+
+    fn state(&self, now: Instant) -> MemberState {
+        let age = now - self.updated_at;
+        if self.removed {
+            MemberState::Removed
+        } else if age >= DEAD_AFTER {
+            MemberState::Dead
+        } else if age >= SUSPECT_AFTER {
+            MemberState::Suspect
+        } else {
+            MemberState::Alive
+        }
+    }
+}
+
+pub struct Membership {
+    id: String,
+    mode: &'static str,
+    members: Mutex<BTreeMap<String, Member>>,
+    client: reqwest::Client,
+    /// Signs gossip messages, which members refuse unless signed.
+    secret: NodeSecret,
+}
+
+impl Membership {
+    /// `seeds` are the members known at startup; any one reachable member
+    /// is enough to learn about the rest.
+    pub fn new(id: String, mode: &'static str, seeds: &[String], secret: NodeSecret) -> Self {
+        let id = id.trim_end_matches('/').to_string();
+        let now = Instant::now();
+        let incarnation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let mut members = BTreeMap::new();
+        for seed in seeds {
+            members.insert(
+                seed.trim_end_matches('/').to_string(),
+                Member {
+                    incarnation: 0,
+                    heartbeat: 0,
+                    removed: false,
+                    updated_at: now,
+                },
+            );
+        }
+        members.insert(
+            id.clone(),
+            Member {
+                incarnation,
+                heartbeat: 0,
+                removed: false,
+                updated_at: now,
+            },
+        );
+        Self {
+            id,
+            mode,
+            members: Mutex::new(members),
+            client: reqwest::Client::builder()
+                .timeout(RPC_TIMEOUT)
+                .build()
+                .expect("HTTP client"),
+            secret,
+        }
+    }
+
+    /// Every member that has not been removed, including this node, sorted.
+    pub fn nodes(&self) -> Vec<String> {
+        let members = self.members.lock().unwrap();
+        members
+            .iter()
+            .filter(|(_, member)| !member.removed)
+            .map(|(url, _)| url.clone())
+            .collect()
+    }
+
+    pub fn status(&self) -> Vec<MemberStatus> {
+        let now = Instant::now();
+        let members = self.members.lock().unwrap();
+        members
+            .iter()
+            .map(|(url, member)| MemberStatus {
+                url: url.clone(),
+                state: if *url == self.id {
+                    MemberState::Alive
+                } else {
+                    member.state(now)
+                },
+                incarnation: member.incarnation,
+                heartbeat: member.heartbeat,
+                last_seen_secs: (now - member.updated_at).as_secs(),
+            })
+            .collect()
+    }
+
+    fn message(&self) -> GossipMessage {
+        let members = self.members.lock().unwrap();
+        GossipMessage {
+            mode: self.mode.to_string(),
+            members: members
+                .iter()
+                .map(|(url, member)| MemberDigest {
+                    url: url.clone(),
+                    incarnation: member.incarnation,
+                    heartbeat: member.heartbeat,
+                    removed: member.removed,
+                })
+                .collect(),
+        }
+    }
+
+    /// Keeps the newer entry for every member in `digests`.
+    fn merge(&self, digests: Vec<MemberDigest>) {
+        let now = Instant::now();
+        let mut members = self.members.lock().unwrap();
+        for digest in digests {
+            if digest.url == self.id {
+                continue;
+            }
+            let version = (digest.incarnation, digest.heartbeat);
+            match members.get_mut(&digest.url) {
+                Some(member)
+                    if version > member.version()
+                        || (version == member.version() && digest.removed && !member.removed) =>
+                {
+                    member.incarnation = digest.incarnation;
+                    member.heartbeat = digest.heartbeat;
+                    member.removed = digest.removed;
+                    member.updated_at = now;
+                }
+                Some(_) => {}
+                None => {
+                    members.insert(
+                        digest.url,
+                        Member {
+                            incarnation: digest.incarnation,
+                            heartbeat: digest.heartbeat,
+                            removed: digest.removed,
+                            updated_at: now,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Handles a gossip message from another member and returns this
+    /// node's view in reply.
+    pub fn receive(&self, message: GossipMessage) -> Result<GossipMessage, String> {
+        if message.mode != self.mode {
+            return Err(format!(
+                "This node is in {} mode, not {} mode",
+                self.mode, message.mode
+            ));
+        }
+        self.merge(message.members);
+        Ok(self.message())
+    }
+
+    /// Removes a dead member, so it no longer owns keys or votes. The
+    /// removal spreads to other members with the next gossip rounds. A
+    /// removed node that comes back rejoins on its own.
+    pub fn remove(&self, url: &str) -> Result<(), String> {
+        let url = url.trim_end_matches('/');
+        if url == self.id {
+            return Err("A node cannot remove itself".to_string());
+        }
+        let now = Instant::now();
+        let mut members = self.members.lock().unwrap();
+        let member = members.get_mut(url).ok_or("Unknown member")?;
+        match member.state(now) {
+            MemberState::Dead => {
+                // Bump the version so the removal wins over other copies of
+                // the entry.
+                member.heartbeat += 1;
+                member.removed = true;
+                member.updated_at = now;
+                Ok(())
+            }
+            MemberState::Removed => Ok(()),
+            _ => Err("Only dead members can be removed".to_string()),
+        }
+    }
+
+    async fn round(&self) {
+        let targets = {
+            let mut members = self.members.lock().unwrap();
+            if let Some(me) = members.get_mut(&self.id) {
+                me.heartbeat += 1;
+                me.updated_at = Instant::now();
+            }
+            let others: Vec<&String> = members
+                .iter()
+                .filter(|(url, member)| **url != self.id && !member.removed)
+                .map(|(url, _)| url)
+                .collect();
+            // Start at a pseudo-random member so rounds spread out.
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .subsec_nanos() as usize;
+            let offset = nanos % others.len().max(1);
+            others
+                .iter()
+                .cycle()
+                .skip(offset)
+                .take(FANOUT.min(others.len()))
+                .map(|url| url.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let message = self.message();
+        let responses = join_all(targets.iter().map(|target| {
+            self.secret
+                .request(
+                    &self.client,
+                    reqwest::Method::POST,
+                    &format!("{}/gossip", target),
+                    &self.id,
+                )
+                .json(&message)
+                .send()
+        }))
+        .await;
+        for response in responses.into_iter().flatten() {
+            if let Ok(reply) = response.json::<GossipMessage>().await {
+                self.merge(reply.members);
+            }
+        }
+    }
+}
+
+/// Gossips until the server shuts down, passing membership changes on
+/// to cluster and shard mode.
+pub async fn run(
+    membership: web::Data<Option<Membership>>,
+    cluster: web::Data<Option<Cluster>>,
+    sharding: web::Data<Option<Sharding>>,
+) {
+    let Some(membership) = membership.as_ref() else {
+        return;
+    };
+    loop {
+        actix_web::rt::time::sleep(GOSSIP_INTERVAL).await;
+        membership.round().await;
+        let nodes = membership.nodes();
+        if let Some(cluster) = cluster.as_ref() {
+            cluster.set_nodes(&nodes);
+        }
+        if let Some(sharding) = sharding.as_ref() {
+            sharding.set_nodes(&nodes);
+        }
+    }
+}
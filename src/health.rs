@@ -0,0 +1,95 @@
+//! The deep health check: a small write, read and delete on the disk the
+//! data lives on, timed, so a full or failing disk shows up in monitoring
+//! before writes start failing for clients. Once the disk has filled up,
+//! the store is compacted every few seconds until that succeeds, making it
+//! writable again.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use actix_web::rt::time::sleep;
+use actix_web::web;
+use kstore::KvStore;
+use serde::Serialize;
+
+const PROBE_SIZE: usize = 4096;
+const DISK_FULL_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct DiskCheck {
+    /// None when nothing is kept on disk.
+    dir: Option<PathBuf>,
+}
+
+/// How long each step of the probe took, in milliseconds.
+#[derive(Serialize)]
+pub struct DiskLatency {
+    pub write_ms: f64,
+    pub read_ms: f64,
+    pub delete_ms: f64,
+}
+
+/// Milliseconds since `start`, to the microsecond.
+fn elapsed_ms(start: Instant) -> f64 {
+    start.elapsed().as_micros() as f64 / 1000.0
+}
+
+impl DiskCheck {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self { dir }
+    }
+
+    /// Writes and syncs a probe file next to the data, reads it back and
+    /// deletes it. Returns None when there is no disk to check.
+    pub fn run(&self) -> Option<Result<DiskLatency, String>> {
+        let dir = self.dir.as_ref()?;
+        let path = dir.join(format!(".kstore-health-{}", std::process::id()));
+        let payload: Vec<u8> = (0..PROBE_SIZE).map(|i| i as u8).collect();
+        let result = (|| {
+            let start = Instant::now();
+            let mut file = File::create(&path).map_err(|e| format!("write failed: {}", e))?;
+            file.write_all(&payload)
+                .and_then(|()| file.sync_all())
+                .map_err(|e| format!("write failed: {}", e))?;
+            let write_ms = elapsed_ms(start);
+
+            let start = Instant::now();
+            let mut read = Vec::with_capacity(PROBE_SIZE);
+            File::open(&path)
+                .and_then(|mut file| file.read_to_end(&mut read))
+                .map_err(|e| format!("read failed: {}", e))?;
+            if read != payload {
+                return Err("read back different bytes than were written".to_string());
+            }
+            let read_ms = elapsed_ms(start);
+
+            let start = Instant::now();
+            std::fs::remove_file(&path).map_err(|e| format!("delete failed: {}", e))?;
+            Ok(DiskLatency {
+                write_ms,
+                read_ms,
+                delete_ms: elapsed_ms(start),
+            })
+        })();
+        if result.is_err() {
+            let _ = std::fs::remove_file(&path);
+        }
+        Some(result)
+    }
+}
+
+/// Compacts the store while the disk is full, until there is room for the
+/// whole data file again.
+pub async fn recover_from_disk_full(store: web::Data<KvStore>) {
+    loop {
+        sleep(DISK_FULL_RETRY_INTERVAL).await;
+        if store.disk_full().is_none() {
+            continue;
+        }
+        let store = store.clone();
+        if let Ok(Ok(_)) = web::block(move || store.compact()).await {
+            println!("Disk space is available again, writes are enabled");
+        }
+    }
+}
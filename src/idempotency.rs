@@ -0,0 +1,172 @@
+//! Remembers the responses to writes sent with an `Idempotency-Key`
+//! header, so a client or proxy retrying one gets the original response
+//! back instead of a 409 or a second application of a batch.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::http::StatusCode;
+use actix_web::http::header::HeaderValue;
+use actix_web::web::Bytes;
+
+pub const HEADER: &str = "Idempotency-Key";
+/// Set on responses that were replayed rather than produced again.
+pub const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+pub const MAX_KEY_LENGTH: usize = 255;
+
+#[derive(Clone)]
+pub struct StoredResponse {
+    pub status: StatusCode,
+    pub content_type: Option<HeaderValue>,
+    pub body: Bytes,
+}
+
+enum Entry {
+    Pending,
+    Done(StoredResponse),
+}
+
+struct Slot {
+    /// Hash of the request, so a key reused for a different request is
+    /// caught instead of answered with the wrong response.
+    fingerprint: [u8; 32],
+    created: Instant,
+    entry: Entry,
+}
+
+pub enum Lookup {
+    /// First time the key is seen; the caller must call
+    /// [`Idempotency::finish`] once it has a response.
+    New,
+    Replay(StoredResponse),
+    InProgress,
+    Mismatch,
+}
+
+pub struct Idempotency {
+    window: Duration,
+    slots: Mutex<HashMap<String, Slot>>,
+}
+
+impl Idempotency {
+    /// Keys are remembered for `window`; a zero window turns the feature
+    /// off.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+
+    pub fn begin(&self, key: &str, fingerprint: [u8; 32]) -> Lookup {
+        let mut slots = self.slots.lock().unwrap();
+        let now = Instant::now();
+        slots.retain(|_, slot| now.duration_since(slot.created) < self.window);
+        match slots.get(key) {
+            Some(slot) if slot.fingerprint != fingerprint => Lookup::Mismatch,
+            Some(Slot {
+                entry: Entry::Done(response),
+                ..
+            }) => Lookup::Replay(response.clone()),
+            Some(_) => Lookup::InProgress,
+            None => {
+                slots.insert(
+                    key.to_string(),
+                    Slot {
+                        fingerprint,
+                        created: now,
+                        entry: Entry::Pending,
+                    },
+                );
+                Lookup::New
+            }
+        }
+    }
+
+    /// Records the response for a key [`begin`](Self::begin) returned
+    /// [`Lookup::New`] for. `None` forgets the key, so a retry runs again.
+    pub fn finish(&self, key: &str, response: Option<StoredResponse>) {
+        let mut slots = self.slots.lock().unwrap();
+        match response {
+            Some(response) => {
+                if let Some(slot) = slots.get_mut(key) {
+                    slot.entry = Entry::Done(response);
+                }
+            }
+            None => {
+                slots.remove(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIRST: [u8; 32] = [1; 32];
+    const SECOND: [u8; 32] = [2; 32];
+
+    fn created() -> StoredResponse {
+        StoredResponse {
+            status: StatusCode::CREATED,
+            content_type: Some(HeaderValue::from_static("text/plain")),
+            body: Bytes::from_static(b"OK"),
+        }
+    }
+
+    #[test]
+    fn finished_requests_are_replayed() {
+        let idempotency = Idempotency::new(Duration::from_secs(60));
+        assert!(matches!(idempotency.begin("k", FIRST), Lookup::New));
+        idempotency.finish("k", Some(created()));
+        let Lookup::Replay(response) = idempotency.begin("k", FIRST) else {
+            panic!("the response was not replayed");
+        };
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert_eq!(response.content_type.unwrap(), "text/plain");
+        assert_eq!(response.body, "OK");
+        // Other keys are independent.
+        assert!(matches!(idempotency.begin("other", FIRST), Lookup::New));
+    }
+
+    #[test]
+    fn retries_wait_for_the_first_request() {
+        let idempotency = Idempotency::new(Duration::from_secs(60));
+        assert!(matches!(idempotency.begin("k", FIRST), Lookup::New));
+        assert!(matches!(idempotency.begin("k", FIRST), Lookup::InProgress));
+    }
+
+    #[test]
+    fn a_reused_key_for_another_request_is_refused() {
+        let idempotency = Idempotency::new(Duration::from_secs(60));
+        assert!(matches!(idempotency.begin("k", FIRST), Lookup::New));
+        assert!(matches!(idempotency.begin("k", SECOND), Lookup::Mismatch));
+        idempotency.finish("k", Some(created()));
+        assert!(matches!(idempotency.begin("k", SECOND), Lookup::Mismatch));
+    }
+
+    #[test]
+    fn unfinished_requests_run_again() {
+        let idempotency = Idempotency::new(Duration::from_secs(60));
+        assert!(matches!(idempotency.begin("k", FIRST), Lookup::New));
+        idempotency.finish("k", None);
+        assert!(matches!(idempotency.begin("k", SECOND), Lookup::New));
+    }
+
+    #[test]
+    fn keys_are_forgotten_after_the_window() {
+        let idempotency = Idempotency::new(Duration::from_millis(20));
+        assert!(idempotency.is_enabled());
+        assert!(matches!(idempotency.begin("k", FIRST), Lookup::New));
+        idempotency.finish("k", Some(created()));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(matches!(idempotency.begin("k", SECOND), Lookup::New));
+        assert!(!Idempotency::new(Duration::ZERO).is_enabled());
+    }
+}
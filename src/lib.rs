@@ -0,0 +1,2077 @@
+//! The kstore storage engine: an in-memory map of keys to values backed by
+//! an append-only data file, with per-key metadata and history, a change
+//! log for replicas and keyspace notifications, backups and geo sets.
+//! The `kstore` server is an HTTP frontend to [`KvStore`]; other programs
+//! can embed the engine directly:
+//!
+//! ```no_run
+//! let store = kstore::KvStore::open("kvstore.db")?;
+//! store.set("greeting".to_string(), "hello".to_string())?;
+//! assert_eq!(store.get("greeting").as_deref(), Some("hello"));
+//! # Ok::<(), kstore::Error>(())
+//! ```
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::BufWriter;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, TryLockError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+mod bloom;
+pub mod changelog;
+mod error;
+pub mod events;
+pub mod geo;
+pub mod glob;
+pub mod merkle;
+pub mod storage;
+mod value;
+
+use bloom::BloomFilter;
+use changelog::{ChangeBatch, ChangeLog, ChangeRecord};
+pub use error::Error;
+use events::{EventKind, KeyEvent};
+use geo::{DistanceUnit, GeoMatch, GeoMember};
+use merkle::{KeyHash, MerkleTree};
+pub use storage::{FileBackend, MemoryBackend, StorageBackend};
+use storage::{load_records, record_size, write_record};
+pub use value::Value;
+
+/// Default [`Limits::max_key_size`].
+pub const MAX_KEY_SIZE: usize = 256;
+/// Default [`Limits::max_value_size`].
+pub const MAX_VALUE_SIZE: usize = 10_485_760;
+pub const MAX_VALUE_VERSIONS: usize = 10;
+/// Longest a write sleeps between attempts to take a lock before its
+/// deadline.
+const MAX_LOCK_BACKOFF: Duration = Duration::from_millis(5);
+// Upper bounds (exclusive) of the value size histogram buckets; the last
+// bucket is open-ended.
+const SIZE_BUCKETS: [(usize, &str); 4] = [
+    (1024, "<1KB"),
+    (10 * 1024, "1KB-10KB"),
+    (100 * 1024, "10KB-100KB"),
+    (1024 * 1024, "100KB-1MB"),
+];
+
+pub fn current_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Cursors are the hex-encoded last key of a page, so clients treat them as
+/// opaque tokens and they survive URL encoding untouched.
+pub fn encode_cursor(key: &str) -> String {
+    key.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_cursor(cursor: &str) -> Result<String, String> {
+    let invalid = || "Invalid cursor".to_string();
+    if !cursor.is_ascii() || !cursor.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&cursor[i..i + 2], 16).map_err(|_| invalid()))
+        .collect::<Result<Vec<u8>, String>>()?;
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ValueVersion {
+    value: Value,
+    version: u64,
+    valid_from: u64,
+}
+
+/// Digests of a value: MD5 in base64 and SHA-256 in hex.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checksum {
+    pub md5: String,
+    pub sha256: String,
+}
+
+/// What is kept about a key besides its value. Backends store the
+/// attributes with the value, so a write changes both or neither, and each
+/// write gives the key all of its attributes anew.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attributes {
+    /// When the key expires, in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// The type the key's values must be of, named by the server.
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub value_type: Option<String>,
+    /// The digests the value was written with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<Checksum>,
+}
+
+impl Attributes {
+    /// No attributes, what a key written without any has.
+    pub const NONE: Attributes = Attributes {
+        expires_at: None,
+        value_type: None,
+        checksum: None,
+    };
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::NONE
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeyMetadata {
+    value: Value,
+    created_at: u64,
+    updated_at: u64,
+    access_count: u64,
+    version: u64,
+    /// Previous values, oldest first, capped at `MAX_VALUE_VERSIONS`.
+    history: Vec<ValueVersion>,
+    attributes: Attributes,
+}
+
+impl KeyMetadata {
+    fn new(value: impl Into<Value>, attributes: Attributes) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Self {
+            value: value.into(),
+            created_at: now,
+            updated_at: now,
+            access_count: 0,
+            version: 1,
+            history: Vec::new(),
+            attributes,
+        }
+    }
+
+    fn replace_value(&mut self, value: impl Into<Value>) {
+        let previous = std::mem::replace(&mut self.value, value.into());
+        self.history.push(ValueVersion {
+            value: previous,
+            version: self.version,
+            valid_from: self.updated_at,
+        });
+        if self.history.len() > MAX_VALUE_VERSIONS {
+            self.history.remove(0);
+        }
+        self.version += 1;
+        self.updated_at = current_timestamp();
+    }
+
+    /// Returns the value that was current at `timestamp`, or `None` if the
+    /// key did not exist yet. Fails with the start of the retained history
+    /// if older values have been dropped.
+    fn value_as_of(&self, timestamp: u64) -> Result<Option<&Value>, u64> {
+        if timestamp < self.created_at {
+            return Ok(None);
+        }
+        if timestamp >= self.updated_at {
+            return Ok(Some(&self.value));
+        }
+        // The oldest version is valid from creation until some have been
+        // dropped, so anything before it is lost.
+        self.history
+            .iter()
+            .rev()
+            .find(|v| v.valid_from <= timestamp)
+            .map(|v| Some(&v.value))
+            .ok_or_else(|| {
+                self.history
+                    .first()
+                    .map_or(self.updated_at, |v| v.valid_from)
+            })
+    }
+}
+
+#[derive(Serialize)]
+pub struct KeyInfo {
+    pub key: Arc<str>,
+    pub size: usize,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub access_count: u64,
+    pub version: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub value_type: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ScanEntry {
+    pub key: Arc<str>,
+    pub value: Value,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub access_count: u64,
+    pub version: u64,
+}
+
+#[derive(Serialize)]
+pub struct KeyValue {
+    pub key: Arc<str>,
+    pub value: Value,
+}
+
+/// One write of [`KvStore::compare_and_swap`], made only if the key is
+/// still as the caller last saw it.
+#[derive(Debug, Clone)]
+pub struct CasWrite {
+    pub key: String,
+    pub value: String,
+    /// The version the key must be at, or 0 if it must not exist.
+    pub expected_version: Option<u64>,
+    /// The value the key must hold.
+    pub expected_value: Option<String>,
+    pub attributes: Attributes,
+}
+
+/// A write of [`KvStore::compare_and_swap`] whose condition did not hold.
+#[derive(Debug, Serialize)]
+pub struct CasFailure {
+    /// Position of the write in the batch.
+    pub index: usize,
+    pub key: String,
+    /// The key's current version, or 0 if it does not exist.
+    pub current_version: u64,
+}
+
+impl KeyInfo {
+    fn new(key: &Arc<str>, metadata: &KeyMetadata) -> Self {
+        Self {
+            key: key.clone(),
+            size: metadata.value.len(),
+            created_at: metadata.created_at,
+            updated_at: metadata.updated_at,
+            access_count: metadata.access_count,
+            version: metadata.version,
+            expires_at: metadata.attributes.expires_at,
+            value_type: metadata.attributes.value_type.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortField {
+    Key,
+    UpdatedAt,
+    CreatedAt,
+    Size,
+    AccessCount,
+}
+
+impl SortField {
+    pub fn parse(field: &str) -> Result<Self, String> {
+        match field {
+            "key" => Ok(SortField::Key),
+            "updated_at" => Ok(SortField::UpdatedAt),
+            "created_at" => Ok(SortField::CreatedAt),
+            "size" => Ok(SortField::Size),
+            "access_count" => Ok(SortField::AccessCount),
+            _ => Err(format!("Unknown sort field '{}'", field)),
+        }
+    }
+
+    fn value(self, metadata: &KeyMetadata) -> u64 {
+        match self {
+            SortField::Key => 0,
+            SortField::UpdatedAt => metadata.updated_at,
+            SortField::CreatedAt => metadata.created_at,
+            SortField::Size => metadata.value.len() as u64,
+            SortField::AccessCount => metadata.access_count,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn parse(order: &str) -> Result<Self, String> {
+        match order {
+            "asc" => Ok(SortOrder::Asc),
+            "desc" => Ok(SortOrder::Desc),
+            _ => Err(format!(
+                "Unknown sort order '{}', expected asc or desc",
+                order
+            )),
+        }
+    }
+}
+
+pub struct ListOptions<'a> {
+    pub prefix: Option<&'a str>,
+    pub pattern: Option<&'a str>,
+    pub limit: Option<usize>,
+    pub cursor: Option<&'a str>,
+    pub sort: SortField,
+    pub order: SortOrder,
+    pub min_size: Option<usize>,
+    pub max_size: Option<usize>,
+    pub created_after: Option<u64>,
+    pub created_before: Option<u64>,
+    pub updated_after: Option<u64>,
+    pub updated_before: Option<u64>,
+    /// Like `updated_after`, but also matching keys updated at the
+    /// timestamp itself.
+    pub updated_since: Option<u64>,
+}
+
+impl ListOptions<'_> {
+    fn matches(&self, key: &str, metadata: &KeyMetadata) -> bool {
+        let size = metadata.value.len();
+        self.prefix.is_none_or(|p| key.starts_with(p))
+            && self.pattern.is_none_or(|p| glob::glob_match(p, key))
+            && self.min_size.is_none_or(|min| size >= min)
+            && self.max_size.is_none_or(|max| size <= max)
+            && self.created_after.is_none_or(|t| metadata.created_at > t)
+            && self.created_before.is_none_or(|t| metadata.created_at < t)
+            && self.updated_after.is_none_or(|t| metadata.updated_at > t)
+            && self.updated_before.is_none_or(|t| metadata.updated_at < t)
+            && self.updated_since.is_none_or(|t| metadata.updated_at >= t)
+    }
+
+    /// The earliest update a matching key can have, if any is required.
+    fn updated_from(&self) -> Option<u64> {
+        let after = self.updated_after.map(|t| t.saturating_add(1));
+        after.max(self.updated_since)
+    }
+}
+
+#[derive(Serialize)]
+pub struct SizeBucket {
+    pub range: &'static str,
+    pub min_bytes: usize,
+    pub max_bytes: Option<usize>,
+    pub count: usize,
+    pub total_bytes: usize,
+}
+
+#[derive(Serialize)]
+pub struct StoreStats {
+    pub total_keys: usize,
+    pub total_size_bytes: usize,
+    pub operations_count: u64,
+    pub uptime_seconds: u64,
+    /// Uptime of this run and every earlier one restored from
+    /// [`SavedStats`].
+    pub total_uptime_seconds: u64,
+    pub restarts: u64,
+    /// Set while writes are refused because the disk is full.
+    pub disk_full: Option<DiskFull>,
+    pub limits: Limits,
+    /// One entry per prefix set with
+    /// [`with_metric_prefixes`](KvStore::with_metric_prefixes).
+    pub prefixes: Vec<PrefixStats>,
+}
+
+/// Usage of the keys under one metric prefix.
+#[derive(Debug, Clone, Serialize)]
+pub struct PrefixStats {
+    pub prefix: String,
+    pub keys: usize,
+    pub size_bytes: usize,
+    /// Operations since the store was opened that touched at least one key
+    /// under the prefix.
+    pub operations_count: u64,
+}
+
+/// Counters to carry over to the next run, from [`KvStore::saved_stats`].
+/// Access counts are kept for keys that have been read.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedStats {
+    pub operations_count: u64,
+    /// Uptime of every run so far, together.
+    pub uptime_seconds: u64,
+    pub restarts: u64,
+    pub access_counts: HashMap<String, u64>,
+}
+
+/// Compactions of the storage backend since the store was opened, counting
+/// the ones after deletes and restores as well as requested ones.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct CompactionStats {
+    pub compactions: u64,
+    pub last_compacted_at: Option<u64>,
+    pub last_duration_ms: Option<f64>,
+    /// How much smaller the last compaction left the data on disk, if the
+    /// backend keeps it on disk.
+    pub last_bytes_reclaimed: Option<u64>,
+    pub total_bytes_reclaimed: u64,
+}
+
+/// Why the store stopped taking writes: the disk filled up. Cleared once
+/// a compaction succeeds again.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskFull {
+    pub since: u64,
+    pub error: String,
+}
+
+/// What earlier runs of the store contribute to its statistics.
+#[derive(Debug, Clone, Copy, Default)]
+struct EarlierRuns {
+    uptime_seconds: u64,
+    count: u64,
+}
+
+/// Largest keys and values a store accepts, in bytes, and how many keys
+/// it may hold.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Limits {
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+    /// None for no limit.
+    pub max_keys: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_key_size: MAX_KEY_SIZE,
+            max_value_size: MAX_VALUE_SIZE,
+            max_keys: None,
+        }
+    }
+}
+
+/// Every key and its metadata, indexed by when each key was last updated
+/// and when it expires. Reads go through to the map, while changes go
+/// through the methods here so the indexes stay in step.
+struct Keys {
+    map: HashMap<Arc<str>, KeyMetadata>,
+    by_updated: BTreeSet<(u64, Arc<str>)>,
+    /// Keys that expire, soonest first.
+    by_expiry: BTreeSet<(u64, Arc<str>)>,
+}
+
+impl Deref for Keys {
+    type Target = HashMap<Arc<str>, KeyMetadata>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.map
+    }
+}
+
+impl FromIterator<(Arc<str>, KeyMetadata)> for Keys {
+    fn from_iter<I: IntoIterator<Item = (Arc<str>, KeyMetadata)>>(entries: I) -> Self {
+        let mut keys = Keys {
+            map: HashMap::new(),
+            by_updated: BTreeSet::new(),
+            by_expiry: BTreeSet::new(),
+        };
+        keys.extend(entries);
+        keys
+    }
+}
+
+impl Extend<(Arc<str>, KeyMetadata)> for Keys {
+    fn extend<I: IntoIterator<Item = (Arc<str>, KeyMetadata)>>(&mut self, entries: I) {
+        for (key, metadata) in entries {
+            self.insert(key, metadata);
+        }
+    }
+}
+
+impl Keys {
+    fn insert(&mut self, key: Arc<str>, metadata: KeyMetadata) {
+        if let Some(previous) = self.map.get(&key) {
+            self.by_updated.remove(&(previous.updated_at, key.clone()));
+            if let Some(expires_at) = previous.attributes.expires_at {
+                self.by_expiry.remove(&(expires_at, key.clone()));
+            }
+        }
+        self.by_updated.insert((metadata.updated_at, key.clone()));
+        if let Some(expires_at) = metadata.attributes.expires_at {
+            self.by_expiry.insert((expires_at, key.clone()));
+        }
+        self.map.insert(key, metadata);
+    }
+
+    fn remove_entry(&mut self, key: &str) -> Option<(Arc<str>, KeyMetadata)> {
+        let (key, metadata) = self.map.remove_entry(key)?;
+        self.by_updated.remove(&(metadata.updated_at, key.clone()));
+        if let Some(expires_at) = metadata.attributes.expires_at {
+            self.by_expiry.remove(&(expires_at, key.clone()));
+        }
+        Some((key, metadata))
+    }
+
+    /// Gives `key`, which must exist, a new value and `attributes`.
+    fn replace_value(&mut self, key: &Arc<str>, value: impl Into<Value>, attributes: Attributes) {
+        let metadata = self.map.get_mut(key).unwrap();
+        self.by_updated.remove(&(metadata.updated_at, key.clone()));
+        metadata.replace_value(value);
+        self.by_updated.insert((metadata.updated_at, key.clone()));
+        self.set_attributes(key, attributes);
+    }
+
+    /// Gives `key`, which must exist, `attributes` in place of its own.
+    fn set_attributes(&mut self, key: &Arc<str>, attributes: Attributes) {
+        let metadata = self.map.get_mut(key).unwrap();
+        if let Some(expires_at) = metadata.attributes.expires_at {
+            self.by_expiry.remove(&(expires_at, key.clone()));
+        }
+        if let Some(expires_at) = attributes.expires_at {
+            self.by_expiry.insert((expires_at, key.clone()));
+        }
+        metadata.attributes = attributes;
+    }
+
+    /// The metadata of `key`, for changes that leave `updated_at` alone,
+    /// such as counting accesses.
+    fn access_mut(&mut self, key: &str) -> Option<&mut KeyMetadata> {
+        self.map.get_mut(key)
+    }
+
+    /// Keys last updated at or after `timestamp`, oldest update first.
+    fn updated_since(&self, timestamp: u64) -> impl Iterator<Item = (&Arc<str>, &KeyMetadata)> {
+        self.by_updated
+            .range((timestamp, Arc::from(""))..)
+            .map(|(_, key)| (key, &self.map[key]))
+    }
+
+    /// Keys that expire at or before `timestamp`, soonest first.
+    fn expiring_by(&self, timestamp: u64) -> impl Iterator<Item = &Arc<str>> {
+        self.by_expiry
+            .range(..(timestamp.saturating_add(1), Arc::from("")))
+            .map(|(_, key)| key)
+    }
+}
+
+/// The map's own copy of `key`, to share instead of allocating another.
+fn interned(data: &Keys, key: &str) -> Option<Arc<str>> {
+    data.get_key_value(key).map(|(key, _)| key.clone())
+}
+
+pub struct KvStore {
+    /// Each key is allocated once, here, and shared with the change log,
+    /// events and listings that refer to it.
+    data: Mutex<Keys>,
+    /// Lets lookups of missing keys skip the data lock.
+    filter: RwLock<BloomFilter>,
+    backend: Mutex<Box<dyn StorageBackend>>,
+    operations_count: Mutex<u64>,
+    /// Prefixes to report usage for, each with its operation count.
+    metric_prefixes: Mutex<Vec<(String, u64)>>,
+    start_time: u64,
+    /// When the keys were loaded or last restored. History is kept in
+    /// memory only, so what the keys held before then is unknown.
+    history_since: Mutex<u64>,
+    earlier_runs: Mutex<EarlierRuns>,
+    compactions: Mutex<CompactionStats>,
+    disk_full: Mutex<Option<DiskFull>>,
+    events: broadcast::Sender<KeyEvent>,
+    changes: Mutex<ChangeLog>,
+    /// Timestamp and change sequence of the last full backup per target,
+    /// which incremental backups are taken against.
+    full_backups: Mutex<HashMap<&'static str, (u64, u64)>>,
+    limits: Limits,
+    /// How long a write may wait for the locks it needs, None to wait for
+    /// as long as it takes.
+    deadline: Option<Duration>,
+    /// Names the keys holding state about a key, which are deleted with it.
+    companions: Option<Box<Companions>>,
+    /// Decides when written keys expire.
+    expiry: Option<Box<ExpiryPolicy>>,
+}
+
+type Companions = dyn Fn(&str) -> Vec<String> + Send + Sync;
+type ExpiryPolicy = dyn Fn(&str, Option<u64>) -> Option<u64> + Send + Sync;
+
+impl KvStore {
+    /// Opens the data file at `path`, creating it if needed, and loads
+    /// every key from it.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::with_backend(Box::new(FileBackend::open(path)?))
+    }
+
+    /// Loads every key from `backend` and persists all changes to it.
+    pub fn with_backend(mut backend: Box<dyn StorageBackend>) -> Result<Self, Error> {
+        let data: Keys = backend
+            .load()?
+            .into_iter()
+            .map(|(key, value, attributes)| (Arc::from(key), KeyMetadata::new(value, attributes)))
+            .collect();
+        let filter = BloomFilter::build(data.keys().map(|key| &**key));
+
+        let start_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        Ok(Self {
+            data: Mutex::new(data),
+            filter: RwLock::new(filter),
+            backend: Mutex::new(backend),
+            operations_count: Mutex::new(0),
+            metric_prefixes: Mutex::new(Vec::new()),
+            start_time,
+            history_since: Mutex::new(start_time),
+            earlier_runs: Mutex::new(EarlierRuns::default()),
+            compactions: Mutex::new(CompactionStats::default()),
+            disk_full: Mutex::new(None),
+            events: broadcast::channel(events::EVENT_CHANNEL_CAPACITY).0,
+            changes: Mutex::new(ChangeLog::new()),
+            full_backups: Mutex::new(HashMap::new()),
+            limits: Limits::default(),
+            deadline: None,
+            companions: None,
+            expiry: None,
+        })
+    }
+
+    /// Replaces the default size limits. Keys already loaded are kept even
+    /// if they exceed the new limits.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Makes writes give up with [`Error::DeadlineExceeded`] when they
+    /// cannot get to the data and the backend within `deadline`, such as
+    /// while a compaction holds them. A write that has started on the
+    /// backend always finishes, so a slow disk can still take it longer.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Reports key counts, sizes and operation counts for the keys under
+    /// each of `prefixes` in [`get_stats`](Self::get_stats). Prefixes may
+    /// overlap, a key then counts towards each of them.
+    pub fn with_metric_prefixes(self, prefixes: Vec<String>) -> Self {
+        *self.metric_prefixes.lock().unwrap() =
+            prefixes.into_iter().map(|prefix| (prefix, 0)).collect();
+        self
+    }
+
+    /// Deletes the keys `companions` names for a key along with the key,
+    /// in the same write, so state kept about a key under other names, such
+    /// as its type, never outlives it. Names that do not exist are skipped.
+    pub fn with_companions(
+        mut self,
+        companions: impl Fn(&str) -> Vec<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.companions = Some(Box::new(companions));
+        self
+    }
+
+    /// Has `policy` decide when each key written from now on expires,
+    /// given the key and the expiry the write asked for, if any, such as
+    /// to give keys a default time to live or cap the one they ask for.
+    /// Without a policy keys expire when their writes ask them to.
+    pub fn with_expiry(
+        mut self,
+        policy: impl Fn(&str, Option<u64>) -> Option<u64> + Send + Sync + 'static,
+    ) -> Self {
+        self.expiry = Some(Box::new(policy));
+        self
+    }
+
+    /// The attributes a write of `key` asking for `attributes` gives it.
+    fn written_attributes(&self, key: &str, mut attributes: Attributes) -> Attributes {
+        if let Some(policy) = &self.expiry {
+            attributes.expires_at = policy(key, attributes.expires_at);
+        }
+        attributes
+    }
+
+    /// Locks `mutex` for the write that started at `start`, failing once it
+    /// has waited past the deadline.
+    fn lock_within<'a, T>(
+        &self,
+        mutex: &'a Mutex<T>,
+        start: Instant,
+    ) -> Result<MutexGuard<'a, T>, Error> {
+        let Some(deadline) = self.deadline else {
+            return Ok(mutex.lock().unwrap());
+        };
+        let mut backoff = Duration::from_micros(50);
+        loop {
+            match mutex.try_lock() {
+                Ok(guard) => return Ok(guard),
+                Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+                Err(TryLockError::WouldBlock) => {}
+            }
+            let waited = start.elapsed();
+            if waited >= deadline {
+                return Err(Error::DeadlineExceeded(deadline));
+            }
+            std::thread::sleep(backoff.min(deadline - waited));
+            backoff = (backoff * 2).min(MAX_LOCK_BACKOFF);
+        }
+    }
+
+    fn validate_key(&self, key: &str) -> Result<(), Error> {
+        if key.is_empty() {
+            return Err(Error::EmptyKey);
+        }
+        if key.len() > self.limits.max_key_size {
+            return Err(Error::KeyTooLarge(self.limits.max_key_size));
+        }
+        Ok(())
+    }
+
+    fn validate_value(&self, value: &str) -> Result<(), Error> {
+        if value.len() > self.limits.max_value_size {
+            return Err(Error::ValueTooLarge(self.limits.max_value_size));
+        }
+        Ok(())
+    }
+
+    /// Fails if one more key would take the store past its key limit.
+    fn check_capacity(&self, data: &Keys) -> Result<(), Error> {
+        match self.limits.max_keys {
+            Some(max) if data.len() >= max => Err(Error::TooManyKeys(max)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds `key`, just inserted into `data`, to the Bloom filter.
+    fn add_to_filter(&self, data: &Keys, key: &str) {
+        let filter = self.filter.read().unwrap();
+        filter.insert(key);
+        if filter.is_full() {
+            drop(filter);
+            *self.filter.write().unwrap() = BloomFilter::build(data.keys().map(|key| &**key));
+        }
+    }
+
+    /// Whether `key` may exist, without taking the data lock.
+    fn may_exist(&self, key: &str) -> bool {
+        self.filter.read().unwrap().may_contain(key)
+    }
+
+    /// Passes on the result of a backend write, switching the store to
+    /// read-only if it failed because the disk is full.
+    fn storage_result<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        match result {
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::StorageFull => {
+                let mut disk_full = self.disk_full.lock().unwrap();
+                if disk_full.is_none() {
+                    *disk_full = Some(DiskFull {
+                        since: current_timestamp(),
+                        error: e.to_string(),
+                    });
+                }
+                Err(Error::DiskFull)
+            }
+            result => result,
+        }
+    }
+
+    /// Fails while the store is read-only because the disk is full.
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.disk_full.lock().unwrap().is_some() {
+            return Err(Error::DiskFull);
+        }
+        Ok(())
+    }
+
+    /// Set while writes are refused because the disk is full. A successful
+    /// [`compact`](Self::compact) makes the store writable again.
+    pub fn disk_full(&self) -> Option<DiskFull> {
+        self.disk_full.lock().unwrap().clone()
+    }
+
+    /// Counts an operation on `keys`, once in total and once for each
+    /// metric prefix any of them is under.
+    fn increment_operations<K: AsRef<str>>(&self, keys: impl IntoIterator<Item = K> + Clone) {
+        let mut count = self.operations_count.lock().unwrap();
+        *count += 1;
+        drop(count);
+        let mut prefixes = self.metric_prefixes.lock().unwrap();
+        for (prefix, count) in prefixes.iter_mut() {
+            if keys
+                .clone()
+                .into_iter()
+                .any(|key| key.as_ref().starts_with(prefix.as_str()))
+            {
+                *count += 1;
+            }
+        }
+    }
+
+    fn publish(&self, event: EventKind, key: &Arc<str>) {
+        let timestamp = current_timestamp();
+        self.changes
+            .lock()
+            .unwrap()
+            .record(event, key.clone(), timestamp);
+        // Sending only fails when nobody is subscribed, which is fine.
+        let _ = self.events.send(KeyEvent {
+            event,
+            key: key.clone(),
+            timestamp,
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<KeyEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn set(&self, key: String, value: impl Into<Value>) -> Result<(), Error> {
+        self.set_with(key, value, Attributes::NONE)
+    }
+
+    /// Sets `key` to `value` with `attributes`, in place of any it had.
+    pub fn set_with(
+        &self,
+        key: String,
+        value: impl Into<Value>,
+        attributes: Attributes,
+    ) -> Result<(), Error> {
+        let value = value.into();
+        self.validate_key(&key)?;
+        self.validate_value(&value)?;
+
+        self.check_writable()?;
+
+        let start = Instant::now();
+        let mut data = self.lock_within(&self.data, start)?;
+        let existing = interned(&data, &key);
+        if existing.is_none() {
+            self.check_capacity(&data)?;
+        }
+        let attributes = self.written_attributes(&key, attributes);
+        // Written before the map changes, so a failed write changes nothing.
+        let mut backend = self.lock_within(&self.backend, start)?;
+        let written = match &existing {
+            Some(existing) => {
+                backend.append_update(&key, &data[existing].value, &value, &attributes)
+            }
+            None => backend.append(&key, &value, &attributes),
+        };
+        self.storage_result(written)?;
+        drop(backend);
+
+        let (key, existed) = match existing {
+            Some(key) => {
+                data.replace_value(&key, value, attributes);
+                (key, true)
+            }
+            None => {
+                let key = Arc::<str>::from(key);
+                data.insert(key.clone(), KeyMetadata::new(value, attributes));
+                self.add_to_filter(&data, &key);
+                (key, false)
+            }
+        };
+        drop(data);
+
+        self.increment_operations([&key]);
+        self.publish(
+            if existed {
+                EventKind::Updated
+            } else {
+                EventKind::Created
+            },
+            &key,
+        );
+        Ok(())
+    }
+
+    pub fn update(&self, key: &str, value: impl Into<Value>) -> Result<(), Error> {
+        self.update_with(key, value, Attributes::NONE)
+    }
+
+    /// Sets `key`, which must exist, to `value` with `attributes`, in
+    /// place of the ones it had.
+    pub fn update_with(
+        &self,
+        key: &str,
+        value: impl Into<Value>,
+        attributes: Attributes,
+    ) -> Result<(), Error> {
+        let value = value.into();
+        self.validate_key(key)?;
+        self.validate_value(&value)?;
+
+        self.check_writable()?;
+
+        let start = Instant::now();
+        let mut data = self.lock_within(&self.data, start)?;
+
+        let key = interned(&data, key).ok_or(Error::KeyNotFound)?;
+        let attributes = self.written_attributes(&key, attributes);
+        let mut backend = self.lock_within(&self.backend, start)?;
+        self.storage_result(backend.append_update(&key, &data[&key].value, &value, &attributes))?;
+        drop(backend);
+        data.replace_value(&key, value, attributes);
+        drop(data);
+        self.increment_operations([&key]);
+        self.publish(EventKind::Updated, &key);
+        Ok(())
+    }
+
+    /// The value of `key`, shared with the store rather than copied.
+    pub fn get(&self, key: &str) -> Option<Value> {
+        if !self.may_exist(key) {
+            return None;
+        }
+        let mut data = self.data.lock().unwrap();
+        if let Some(metadata) = data.access_mut(key) {
+            metadata.access_count += 1;
+            self.increment_operations([key]);
+            Some(metadata.value.clone())
+        } else {
+            None
+        }
+    }
+
+    /// The value of `key` with its attributes, read together.
+    pub fn get_with_attributes(&self, key: &str) -> Option<(Value, Attributes)> {
+        if !self.may_exist(key) {
+            return None;
+        }
+        let mut data = self.data.lock().unwrap();
+        if let Some(metadata) = data.access_mut(key) {
+            metadata.access_count += 1;
+            self.increment_operations([key]);
+            Some((metadata.value.clone(), metadata.attributes.clone()))
+        } else {
+            None
+        }
+    }
+
+    /// The attributes of `key`, without counting as an access.
+    pub fn attributes(&self, key: &str) -> Option<Attributes> {
+        if !self.may_exist(key) {
+            return None;
+        }
+        let data = self.data.lock().unwrap();
+        data.get(key).map(|metadata| metadata.attributes.clone())
+    }
+
+    /// The value `key` had at `timestamp`, or `None` if it did not exist
+    /// then. Fails with [`Error::HistoryUnavailable`] when the value is no
+    /// longer retained, for times before the keys were loaded or restored
+    /// or before the oldest value kept for the key.
+    pub fn get_as_of(&self, key: &str, timestamp: u64) -> Result<Option<Value>, Error> {
+        let data = self.data.lock().unwrap();
+        let Some(metadata) = data.get(key) else {
+            return Ok(None);
+        };
+        let history_since = *self.history_since.lock().unwrap();
+        if timestamp < history_since {
+            return Err(Error::HistoryUnavailable(history_since));
+        }
+        let value = metadata
+            .value_as_of(timestamp)
+            .map_err(Error::HistoryUnavailable)?
+            .cloned();
+        drop(data);
+        if value.is_some() {
+            self.increment_operations([key]);
+        }
+        Ok(value)
+    }
+
+    pub fn get_info(&self, key: &str) -> Option<KeyInfo> {
+        if !self.may_exist(key) {
+            return None;
+        }
+        let data = self.data.lock().unwrap();
+        data.get_key_value(key)
+            .map(|(key, metadata)| KeyInfo::new(key, metadata))
+    }
+
+    /// Looks up info for many keys under a single lock, returning the keys
+    /// that do not exist separately.
+    pub fn get_info_many(&self, keys: &[Arc<str>]) -> (Vec<KeyInfo>, Vec<Arc<str>>) {
+        let data = self.data.lock().unwrap();
+        let mut found = Vec::with_capacity(keys.len());
+        let mut missing = Vec::new();
+        for key in keys {
+            match data.get_key_value(key) {
+                Some((key, metadata)) => found.push(KeyInfo::new(key, metadata)),
+                None => missing.push(key.clone()),
+            }
+        }
+        (found, missing)
+    }
+
+    /// Lists keys in the requested order, ties broken by key. When a limit is
+    /// given and more keys remain, the cursor for the next page is returned
+    /// as well.
+    pub fn list_keys(
+        &self,
+        options: &ListOptions,
+    ) -> Result<(Vec<Arc<str>>, Option<String>), Error> {
+        // Cursors for non-key orderings carry the sort value of the last
+        // entry so the position stays stable when that key changes.
+        let after = match options.cursor {
+            Some(cursor) => {
+                let invalid = || Error::InvalidInput("Invalid cursor".to_string());
+                let payload = decode_cursor(cursor).map_err(Error::InvalidInput)?;
+                Some(match options.sort {
+                    SortField::Key => (0, Arc::from(payload)),
+                    _ => {
+                        let (value, key) = payload.split_once(':').ok_or_else(invalid)?;
+                        let value = value.parse::<u64>().map_err(|_| invalid())?;
+                        (value, Arc::from(key))
+                    }
+                })
+            }
+            None => None,
+        };
+
+        let compare = |a: &(u64, Arc<str>), b: &(u64, Arc<str>)| -> Ordering {
+            let ordering = a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1));
+            match options.order {
+                SortOrder::Asc => ordering,
+                SortOrder::Desc => ordering.reverse(),
+            }
+        };
+
+        let data = self.data.lock().unwrap();
+        // Only keys updated recently enough are visited when the listing
+        // asks for them, so a sync job does not pay for the whole store.
+        let candidates: Box<dyn Iterator<Item = (&Arc<str>, &KeyMetadata)>> =
+            match options.updated_from() {
+                Some(from) => Box::new(data.updated_since(from)),
+                None => Box::new(data.iter()),
+            };
+        let mut entries: Vec<(u64, Arc<str>)> = candidates
+            .filter(|(k, metadata)| options.matches(k, metadata))
+            .map(|(k, metadata)| (options.sort.value(metadata), k.clone()))
+            .filter(|entry| {
+                after
+                    .as_ref()
+                    .is_none_or(|a| compare(entry, a) == Ordering::Greater)
+            })
+            .collect();
+        drop(data);
+
+        entries.sort_by(compare);
+
+        let mut next_cursor = None;
+        if let Some(l) = options.limit {
+            if entries.len() > l && l > 0 {
+                let (value, key) = &entries[l - 1];
+                next_cursor = Some(match options.sort {
+                    SortField::Key => encode_cursor(key),
+                    _ => encode_cursor(&format!("{}:{}", value, key)),
+                });
+            }
+            entries.truncate(l);
+        }
+
+        Ok((entries.into_iter().map(|(_, k)| k).collect(), next_cursor))
+    }
+
+    pub fn keys_with_prefix(&self, prefix: Option<&str>) -> Vec<Arc<str>> {
+        self.keys_where(|k| prefix.is_none_or(|p| k.starts_with(p)))
+    }
+
+    /// Fetches full entries for `keys`, skipping any deleted since the key
+    /// list was taken.
+    pub fn scan_entries(&self, keys: &[Arc<str>]) -> Vec<ScanEntry> {
+        let data = self.data.lock().unwrap();
+        keys.iter()
+            .filter_map(|key| {
+                data.get(key).map(|metadata| ScanEntry {
+                    key: key.clone(),
+                    value: metadata.value.clone(),
+                    created_at: metadata.created_at,
+                    updated_at: metadata.updated_at,
+                    access_count: metadata.access_count,
+                    version: metadata.version,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns info for the `n` keys with the highest value of `by`, highest
+    /// first, ties broken by key.
+    pub fn top_keys(&self, by: SortField, n: usize) -> Vec<KeyInfo> {
+        let data = self.data.lock().unwrap();
+        let mut entries: Vec<(u64, &Arc<str>, &KeyMetadata)> = data
+            .iter()
+            .map(|(key, metadata)| (by.value(metadata), key, metadata))
+            .collect();
+        let compare = |a: &(u64, &Arc<str>, &KeyMetadata), b: &(u64, &Arc<str>, &KeyMetadata)| {
+            b.0.cmp(&a.0).then_with(|| a.1.cmp(b.1))
+        };
+        if n < entries.len() {
+            entries.select_nth_unstable_by(n, compare);
+            entries.truncate(n);
+        }
+        entries.sort_unstable_by(compare);
+        entries
+            .into_iter()
+            .map(|(_, key, metadata)| KeyInfo::new(key, metadata))
+            .collect()
+    }
+
+    pub fn size_histogram(&self) -> Vec<SizeBucket> {
+        let mut buckets: Vec<SizeBucket> = Vec::with_capacity(SIZE_BUCKETS.len() + 1);
+        let mut min_bytes = 0;
+        for (upper, range) in SIZE_BUCKETS {
+            buckets.push(SizeBucket {
+                range,
+                min_bytes,
+                max_bytes: Some(upper - 1),
+                count: 0,
+                total_bytes: 0,
+            });
+            min_bytes = upper;
+        }
+        buckets.push(SizeBucket {
+            range: ">1MB",
+            min_bytes,
+            max_bytes: None,
+            count: 0,
+            total_bytes: 0,
+        });
+
+        let data = self.data.lock().unwrap();
+        for metadata in data.values() {
+            let size = metadata.value.len();
+            let index = SIZE_BUCKETS
+                .iter()
+                .position(|(upper, _)| size < *upper)
+                .unwrap_or(SIZE_BUCKETS.len());
+            buckets[index].count += 1;
+            buckets[index].total_bytes += size;
+        }
+        buckets
+    }
+
+    pub fn count_by_prefix(&self, prefix: &str) -> (usize, usize) {
+        let data = self.data.lock().unwrap();
+        data.iter()
+            .filter(|(k, _)| k.starts_with(prefix))
+            .fold((0, 0), |(count, size), (_, metadata)| {
+                (count + 1, size + metadata.value.len())
+            })
+    }
+
+    fn uptime(&self) -> u64 {
+        current_timestamp().saturating_sub(self.start_time)
+    }
+
+    pub fn get_stats(&self) -> StoreStats {
+        let data = self.data.lock().unwrap();
+        let operations = *self.operations_count.lock().unwrap();
+        let total_size: usize = data.values().map(|m| m.value.len()).sum();
+        let prefixes = self
+            .metric_prefixes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(prefix, operations_count)| {
+                let (keys, size_bytes) = data
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(prefix.as_str()))
+                    .fold((0, 0), |(keys, size), (_, metadata)| {
+                        (keys + 1, size + metadata.value.len())
+                    });
+                PrefixStats {
+                    prefix: prefix.clone(),
+                    keys,
+                    size_bytes,
+                    operations_count: *operations_count,
+                }
+            })
+            .collect();
+        let uptime = self.uptime();
+        let earlier = *self.earlier_runs.lock().unwrap();
+
+        StoreStats {
+            total_keys: data.len(),
+            total_size_bytes: total_size,
+            operations_count: operations,
+            uptime_seconds: uptime,
+            total_uptime_seconds: earlier.uptime_seconds + uptime,
+            restarts: earlier.count,
+            disk_full: self.disk_full(),
+            limits: self.limits,
+            prefixes,
+        }
+    }
+
+    /// Operations since the store was opened, in total and under each
+    /// metric prefix, without the cost of [`get_stats`](Self::get_stats).
+    pub fn operation_counts(&self) -> (u64, Vec<u64>) {
+        let total = *self.operations_count.lock().unwrap();
+        let prefixes = self
+            .metric_prefixes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, count)| *count)
+            .collect();
+        (total, prefixes)
+    }
+
+    /// The counters to hand to [`restore_stats`](Self::restore_stats) when
+    /// the store is next opened.
+    pub fn saved_stats(&self) -> SavedStats {
+        let access_counts = self
+            .data
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, metadata)| metadata.access_count > 0)
+            .map(|(key, metadata)| (key.to_string(), metadata.access_count))
+            .collect();
+        let earlier = *self.earlier_runs.lock().unwrap();
+        SavedStats {
+            operations_count: *self.operations_count.lock().unwrap(),
+            uptime_seconds: earlier.uptime_seconds + self.uptime(),
+            restarts: earlier.count,
+            access_counts,
+        }
+    }
+
+    /// Continues the counters of the run `saved` was taken in, counting
+    /// this run as a restart. Access counts of keys that no longer exist
+    /// are dropped.
+    pub fn restore_stats(&self, saved: SavedStats) {
+        let mut data = self.data.lock().unwrap();
+        for (key, count) in saved.access_counts {
+            if let Some(metadata) = data.access_mut(key.as_str()) {
+                metadata.access_count += count;
+            }
+        }
+        drop(data);
+        *self.operations_count.lock().unwrap() += saved.operations_count;
+        *self.earlier_runs.lock().unwrap() = EarlierRuns {
+            uptime_seconds: saved.uptime_seconds,
+            count: saved.restarts + 1,
+        };
+    }
+
+    /// Makes every acknowledged write durable, for example before the
+    /// process exits.
+    pub fn sync(&self) -> Result<(), Error> {
+        self.storage_result(self.backend.lock().unwrap().sync())
+    }
+
+    /// Rewrites the backend with only the live keys, returning the
+    /// compaction statistics including this one. Succeeding makes the
+    /// store writable again if the disk had filled up.
+    pub fn compact(&self) -> Result<CompactionStats, Error> {
+        let data = self.data.lock().unwrap();
+        self.compact_data(&data, &mut self.backend.lock().unwrap())
+    }
+
+    /// Compacts `backend` to hold `data`, both of which the caller has
+    /// locked.
+    fn compact_data(
+        &self,
+        data: &Keys,
+        backend: &mut Box<dyn StorageBackend>,
+    ) -> Result<CompactionStats, Error> {
+        let start = Instant::now();
+        let size_before = backend.size().ok().flatten();
+        self.storage_result(
+            backend.compact(
+                &mut data
+                    .iter()
+                    .map(|(key, metadata)| (&**key, metadata.value.as_str(), &metadata.attributes)),
+            ),
+        )?;
+        *self.disk_full.lock().unwrap() = None;
+        let reclaimed = size_before
+            .zip(backend.size().ok().flatten())
+            .map(|(before, after)| before.saturating_sub(after));
+
+        let mut stats = self.compactions.lock().unwrap();
+        stats.compactions += 1;
+        stats.last_compacted_at = Some(current_timestamp());
+        stats.last_duration_ms = Some(start.elapsed().as_micros() as f64 / 1000.0);
+        stats.last_bytes_reclaimed = reclaimed;
+        stats.total_bytes_reclaimed += reclaimed.unwrap_or(0);
+        Ok(*stats)
+    }
+
+    /// Size of the data on disk and roughly how much of it a compaction
+    /// would free, going by the size of the live keys, or `None` if the
+    /// backend does not keep the data on disk.
+    pub fn reclaimable(&self) -> Option<(u64, u64)> {
+        let live: u64 = self
+            .data
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, metadata)| (16 + key.len() + metadata.value.len()) as u64)
+            .sum();
+        let size = self.backend.lock().unwrap().size().ok().flatten()?;
+        Some((size, size.saturating_sub(live)))
+    }
+
+    /// Bytes of changes the backend has logged since the last compaction,
+    /// or `None` if it does not keep them apart.
+    pub fn log_size(&self) -> Option<u64> {
+        self.backend.lock().unwrap().log_size().ok().flatten()
+    }
+
+    pub fn compaction_stats(&self) -> CompactionStats {
+        *self.compactions.lock().unwrap()
+    }
+
+    /// Deletes `key`, returning whether it existed.
+    pub fn delete(&self, key: &str) -> Result<bool, Error> {
+        self.check_writable()?;
+        let start = Instant::now();
+        let data = self.lock_within(&self.data, start)?;
+        let Some(key) = interned(&data, key) else {
+            return Ok(false);
+        };
+        self.remove_keys(data, vec![key], start, EventKind::Deleted)?;
+        Ok(true)
+    }
+
+    pub fn delete_by_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        self.delete_where(|k| k.starts_with(prefix))
+    }
+
+    pub fn delete_by_regex(&self, pattern: &str) -> Result<usize, Error> {
+        let re = Regex::new(pattern).map_err(|e| Error::InvalidInput(e.to_string()))?;
+        self.delete_where(|k| re.is_match(k))
+    }
+
+    /// Returns the sorted keys a prefix or regex delete would remove,
+    /// without removing them.
+    pub fn keys_where<F: Fn(&str) -> bool>(&self, predicate: F) -> Vec<Arc<str>> {
+        let data = self.data.lock().unwrap();
+        let mut keys: Vec<Arc<str>> = data.keys().filter(|k| predicate(k)).cloned().collect();
+        drop(data);
+        keys.sort();
+        keys
+    }
+
+    /// Deletes every key `predicate` accepts, returning how many there
+    /// were.
+    pub fn delete_where<F: Fn(&str) -> bool>(&self, predicate: F) -> Result<usize, Error> {
+        self.check_writable()?;
+        let start = Instant::now();
+        let data = self.lock_within(&self.data, start)?;
+        let keys: Vec<Arc<str>> = data.keys().filter(|k| predicate(k)).cloned().collect();
+        self.remove_keys(data, keys, start, EventKind::Deleted)
+    }
+
+    /// Deletes the keys that expire at or before `now`, publishing
+    /// [`EventKind::Expired`] for them. Returns how many there were. Only
+    /// the expired keys are visited.
+    pub fn expire(&self, now: u64) -> Result<usize, Error> {
+        self.check_writable()?;
+        let start = Instant::now();
+        let data = self.lock_within(&self.data, start)?;
+        let keys: Vec<Arc<str>> = data.expiring_by(now).cloned().collect();
+        self.remove_keys(data, keys, start, EventKind::Expired)
+    }
+
+    /// Deletes `keys` and their companions from `data`, which the caller
+    /// locked for the write that started at `start`, records the deletes in
+    /// the backend and publishes `event` for each key.
+    fn remove_keys(
+        &self,
+        mut data: MutexGuard<'_, Keys>,
+        keys: Vec<Arc<str>>,
+        start: Instant,
+        event: EventKind,
+    ) -> Result<usize, Error> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let companions: Vec<Arc<str>> = match &self.companions {
+            Some(companions) => {
+                let deleted: HashSet<&str> = keys.iter().map(|key| &**key).collect();
+                let mut found: Vec<Arc<str>> = keys
+                    .iter()
+                    .flat_map(|key| companions(key))
+                    .filter(|name| !deleted.contains(name.as_str()))
+                    .filter_map(|name| interned(&data, &name))
+                    .collect();
+                found.sort();
+                found.dedup();
+                found
+            }
+            None => Vec::new(),
+        };
+        let mut backend = self.lock_within(&self.backend, start)?;
+        let names: Vec<&str> = keys.iter().chain(&companions).map(|key| &**key).collect();
+        self.storage_result(backend.delete(&names))?;
+        drop(backend);
+        for key in keys.iter().chain(&companions) {
+            data.remove_entry(key);
+        }
+        drop(data);
+        self.increment_operations(&keys);
+        for key in &keys {
+            self.publish(event, key);
+        }
+        // Replicas copy companions like any key, so they need the change.
+        for key in &companions {
+            self.publish(EventKind::Deleted, key);
+        }
+        Ok(keys.len())
+    }
+
+    /// Updates every key `predicate` accepts with what `transform`, given
+    /// the key, its value and its attributes to change, makes of it: a new
+    /// value, or `None` to keep the value and only change the attributes.
+    /// All or nothing: if `transform` fails for any key or the backend
+    /// cannot record the changes, no key changes. Returns how many keys
+    /// were updated.
+    pub fn update_where<P, T>(&self, predicate: P, transform: T) -> Result<usize, Error>
+    where
+        P: Fn(&str) -> bool,
+        T: Fn(&str, &str, &mut Attributes) -> Result<Option<String>, String>,
+    {
+        self.check_writable()?;
+        let start = Instant::now();
+        let mut data = self.lock_within(&self.data, start)?;
+        let mut updates = Vec::new();
+        for (key, metadata) in data.iter().filter(|(key, _)| predicate(key)) {
+            let mut attributes = metadata.attributes.clone();
+            let value = transform(key, &metadata.value, &mut attributes)
+                .map_err(|e| Error::InvalidInput(format!("{}: {}", key, e)))?;
+            if let Some(value) = &value {
+                self.validate_value(value)?;
+            }
+            let attributes = self.written_attributes(key, attributes);
+            updates.push((key.clone(), value, attributes));
+        }
+        if updates.is_empty() {
+            return Ok(0);
+        }
+
+        // Written before the map changes, so a failed write changes nothing.
+        let entries: Vec<_> = updates
+            .iter()
+            .map(|(key, value, attributes)| {
+                let value = value.as_deref().unwrap_or(&data[key].value);
+                (&**key, value, attributes)
+            })
+            .collect();
+        let mut backend = self.lock_within(&self.backend, start)?;
+        self.storage_result(backend.append_batch(&entries))?;
+        drop(backend);
+        let count = updates.len();
+        let mut keys = Vec::with_capacity(count);
+        for (key, value, attributes) in updates {
+            match value {
+                Some(value) => data.replace_value(&key, value, attributes),
+                None => data.set_attributes(&key, attributes),
+            }
+            keys.push(key);
+        }
+        drop(data);
+        self.increment_operations(&keys);
+        for key in &keys {
+            self.publish(EventKind::Updated, key);
+        }
+        Ok(count)
+    }
+
+    /// Finds entries whose key matches `pattern`, in key order, starting
+    /// after the key `after`. Returns the cursor for the next page when
+    /// `limit` cut the results short.
+    pub fn find_by_regex(
+        &self,
+        pattern: &str,
+        limit: Option<usize>,
+        after: Option<&str>,
+    ) -> Result<(Vec<KeyValue>, Option<String>), regex::Error> {
+        let re = Regex::new(pattern)?;
+        let data = self.data.lock().unwrap();
+        let mut keys: Vec<&Arc<str>> = data
+            .keys()
+            .filter(|key| after.is_none_or(|a| &***key > a))
+            .filter(|key| re.is_match(key))
+            .collect();
+        keys.sort();
+
+        let mut next_cursor = None;
+        if let Some(l) = limit {
+            if keys.len() > l && l > 0 {
+                next_cursor = Some(encode_cursor(keys[l - 1]));
+            }
+            keys.truncate(l);
+        }
+
+        let entries = keys
+            .into_iter()
+            .map(|key| KeyValue {
+                key: key.clone(),
+                value: data[key].value.clone(),
+            })
+            .collect();
+        Ok((entries, next_cursor))
+    }
+
+    /// Finds keys whose value matches `pattern`, in key order, paginated
+    /// like `find_by_regex`.
+    pub fn find_keys_by_value_regex(
+        &self,
+        pattern: &str,
+        limit: Option<usize>,
+        after: Option<&str>,
+    ) -> Result<(Vec<Arc<str>>, Option<String>), regex::Error> {
+        let re = Regex::new(pattern)?;
+        let data = self.data.lock().unwrap();
+        let mut keys: Vec<Arc<str>> = data
+            .iter()
+            .filter(|(key, _)| after.is_none_or(|a| &***key > a))
+            .filter(|(_, metadata)| re.is_match(&metadata.value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        drop(data);
+        keys.sort();
+
+        let mut next_cursor = None;
+        if let Some(l) = limit {
+            if keys.len() > l && l > 0 {
+                next_cursor = Some(encode_cursor(&keys[l - 1]));
+            }
+            keys.truncate(l);
+        }
+        Ok((keys, next_cursor))
+    }
+
+    pub fn exists(&self, key: &str) -> bool {
+        if !self.may_exist(key) {
+            return false;
+        }
+        let data = self.data.lock().unwrap();
+        data.contains_key(key)
+    }
+
+    /// Makes every write in `writes` if all of their conditions hold, and
+    /// none of them otherwise. Returns the writes whose condition failed,
+    /// so nothing was written unless it is empty. A key may only appear
+    /// once in a batch.
+    pub fn compare_and_swap(&self, writes: Vec<CasWrite>) -> Result<Vec<CasFailure>, Error> {
+        let mut seen = BTreeSet::new();
+        for write in &writes {
+            self.validate_key(&write.key)?;
+            self.validate_value(&write.value)?;
+            if !seen.insert(write.key.as_str()) {
+                return Err(Error::InvalidInput(format!(
+                    "{} appears more than once in the batch",
+                    write.key
+                )));
+            }
+        }
+        self.check_writable()?;
+
+        let start = Instant::now();
+        let mut data = self.lock_within(&self.data, start)?;
+        let failures: Vec<CasFailure> = writes
+            .iter()
+            .enumerate()
+            .filter_map(|(index, write)| {
+                let current = data.get(write.key.as_str());
+                let version = current.map_or(0, |metadata| metadata.version);
+                let holds = write.expected_version.is_none_or(|v| v == version)
+                    && write.expected_value.as_ref().is_none_or(|value| {
+                        current.is_some_and(|metadata| *metadata.value == **value)
+                    });
+                (!holds).then(|| CasFailure {
+                    index,
+                    key: write.key.clone(),
+                    current_version: version,
+                })
+            })
+            .collect();
+        if !failures.is_empty() {
+            return Ok(failures);
+        }
+        let created = writes
+            .iter()
+            .filter(|write| !data.contains_key(write.key.as_str()))
+            .count();
+        if let Some(max) = self.limits.max_keys
+            && data.len() + created > max
+        {
+            return Err(Error::TooManyKeys(max));
+        }
+
+        let writes: Vec<(CasWrite, Attributes)> = writes
+            .into_iter()
+            .map(|mut write| {
+                let attributes =
+                    self.written_attributes(&write.key, std::mem::take(&mut write.attributes));
+                (write, attributes)
+            })
+            .collect();
+        // One append for the whole batch, before the map changes, so a
+        // failed write changes nothing.
+        let entries: Vec<_> = writes
+            .iter()
+            .map(|(write, attributes)| (write.key.as_str(), write.value.as_str(), attributes))
+            .collect();
+        let mut backend = self.lock_within(&self.backend, start)?;
+        self.storage_result(backend.append_batch(&entries))?;
+        drop(backend);
+        drop(entries);
+
+        let mut written = Vec::with_capacity(writes.len());
+        for (write, attributes) in writes {
+            match interned(&data, &write.key) {
+                Some(key) => {
+                    data.replace_value(&key, write.value, attributes);
+                    written.push((key, EventKind::Updated));
+                }
+                None => {
+                    let key = Arc::<str>::from(write.key);
+                    data.insert(key.clone(), KeyMetadata::new(write.value, attributes));
+                    self.add_to_filter(&data, &key);
+                    written.push((key, EventKind::Created));
+                }
+            }
+        }
+        drop(data);
+        self.increment_operations(written.iter().map(|(key, _)| key));
+        for (key, event) in &written {
+            self.publish(*event, key);
+        }
+        Ok(Vec::new())
+    }
+
+    pub fn batch_set(&self, items: Vec<(String, String, Attributes)>) -> Result<usize, Error> {
+        let mut success_count = 0;
+        for (key, value, attributes) in items {
+            if self.set_with(key, value, attributes).is_ok() {
+                success_count += 1;
+            }
+        }
+        Ok(success_count)
+    }
+
+    /// Copies the records a backup of `kind` needs. The data lock is only
+    /// held for the copy, so the snapshot can be written out without pausing
+    /// traffic. Incremental snapshots contain the keys changed since the last
+    /// full backup to `target`, with deleted keys as empty tombstone values.
+    pub fn backup_snapshot(
+        &self,
+        kind: BackupKind,
+        target: &'static str,
+    ) -> Result<BackupSnapshot, Error> {
+        let timestamp = current_timestamp();
+        let base = self.full_backups.lock().unwrap().get(target).copied();
+
+        let data = self.data.lock().unwrap();
+        let changes = self.changes.lock().unwrap();
+        let seq = changes.last_seq();
+        let (name, records) = match kind {
+            BackupKind::Full => {
+                let records = data
+                    .iter()
+                    .map(|(key, metadata)| {
+                        (
+                            key.clone(),
+                            metadata.value.clone(),
+                            metadata.attributes.clone(),
+                        )
+                    })
+                    .collect();
+                (format!("kvstore_backup_{}.db", timestamp), records)
+            }
+            BackupKind::Incremental => {
+                let (base_timestamp, base_seq) = base.ok_or(Error::Precondition(
+                    "No full backup has been taken since startup, create one first",
+                ))?;
+                let changed = changes
+                    .since(base_seq, usize::MAX)
+                    .ok_or(Error::Precondition(
+                        "Too many changes since the last full backup, create a new full backup",
+                    ))?;
+                let keys: BTreeSet<Arc<str>> = changed.into_iter().map(|c| c.key).collect();
+                let records = keys
+                    .into_iter()
+                    .map(|key| match data.get(&key) {
+                        Some(metadata) => {
+                            (key, metadata.value.clone(), metadata.attributes.clone())
+                        }
+                        None => (key, Value::default(), Attributes::NONE),
+                    })
+                    .collect();
+                (
+                    format!("kvstore_incr_{}_{}.db", base_timestamp, timestamp),
+                    records,
+                )
+            }
+        };
+
+        Ok(BackupSnapshot {
+            name,
+            kind,
+            timestamp,
+            seq,
+            records,
+        })
+    }
+
+    /// Makes `snapshot` the base for later incremental backups to `target`,
+    /// once it has been written successfully.
+    pub fn backup_completed(&self, target: &'static str, snapshot: &BackupSnapshot) {
+        if snapshot.kind == BackupKind::Full {
+            self.full_backups
+                .lock()
+                .unwrap()
+                .insert(target, (snapshot.timestamp, snapshot.seq));
+        }
+    }
+
+    pub fn write_backup(&self, snapshot: BackupSnapshot) -> Result<String, Error> {
+        // Written under a temporary name so a partial backup is never listed.
+        let temp_name = format!("{}.tmp", snapshot.name);
+        let mut backup_file = BufWriter::new(File::create(&temp_name)?);
+
+        for (key, value, attributes) in &snapshot.records {
+            write_record(&mut backup_file, key, value, attributes)?;
+        }
+        let backup_file = backup_file.into_inner().map_err(|e| e.into_error())?;
+        backup_file.sync_all()?;
+        std::fs::rename(&temp_name, &snapshot.name)?;
+
+        self.backup_completed("local", &snapshot);
+        Ok(snapshot.name)
+    }
+
+    /// Replaces the whole dataset with the contents of a backup and rewrites
+    /// the data file. Returns the number of keys restored.
+    pub fn restore(&self, buffer: &[u8]) -> Result<usize, Error> {
+        let (restored, consumed) = load_records(buffer);
+        if consumed != buffer.len() {
+            return Err(Error::InvalidInput(
+                "Backup is truncated or corrupted".to_string(),
+            ));
+        }
+        let count = restored.len();
+        self.check_writable()?;
+        let mut data = self.data.lock().unwrap();
+        let previous = std::mem::replace(
+            &mut *data,
+            restored
+                .into_iter()
+                .map(|(key, (value, attributes))| {
+                    (Arc::from(key), KeyMetadata::new(value, attributes))
+                })
+                .collect(),
+        );
+        if let Err(e) = self.compact_data(&data, &mut self.backend.lock().unwrap()) {
+            *data = previous;
+            return Err(e);
+        }
+        *self.filter.write().unwrap() = BloomFilter::build(data.keys().map(|key| &**key));
+        *self.history_since.lock().unwrap() = current_timestamp();
+        drop(data);
+        // Earlier changes no longer describe the dataset.
+        self.changes.lock().unwrap().reset();
+        self.full_backups.lock().unwrap().clear();
+        // A restore replaces every key, so it counts under no prefix.
+        self.increment_operations(std::iter::empty::<&str>());
+        Ok(count)
+    }
+
+    /// Reads up to `limit` changes after `since` for replicas, with each
+    /// key's current value. Returns `None` if the changes are no longer in
+    /// the log, or `log` names a different change log (the process has
+    /// restarted since), in which case the replica has to bootstrap again.
+    pub fn changes_since(&self, log: u64, since: u64, limit: usize) -> Option<ChangeBatch> {
+        let data = self.data.lock().unwrap();
+        let changes = self.changes.lock().unwrap();
+        if changes.id() != log || since > changes.last_seq() {
+            return None;
+        }
+        let records = changes
+            .since(since, limit)?
+            .into_iter()
+            .map(|change| ChangeRecord {
+                value: data.get(&change.key).map(|m| m.value.clone()),
+                attributes: data
+                    .get(&change.key)
+                    .map(|m| m.attributes.clone())
+                    .unwrap_or_default(),
+                seq: change.seq,
+                event: change.event,
+                key: change.key,
+            })
+            .collect();
+        Some(ChangeBatch {
+            last_seq: changes.last_seq(),
+            changes: records,
+        })
+    }
+
+    pub fn merkle_tree(&self) -> MerkleTree {
+        let data = self.data.lock().unwrap();
+        MerkleTree::build(
+            data.iter()
+                .map(|(key, metadata)| (&**key, metadata.value.as_str())),
+        )
+    }
+
+    /// Keys in one leaf of the Merkle tree with their value hashes, sorted.
+    pub fn merkle_leaf(&self, leaf: usize) -> Vec<KeyHash> {
+        let data = self.data.lock().unwrap();
+        let mut keys: Vec<KeyHash> = data
+            .iter()
+            .filter(|(key, _)| merkle::leaf_of(key) == leaf)
+            .map(|(key, metadata)| KeyHash::new(key, &metadata.value))
+            .collect();
+        drop(data);
+        keys.sort_by(|a, b| a.key.cmp(&b.key));
+        keys
+    }
+
+    /// Current values of `keys`, without counting as accesses.
+    pub fn values(&self, keys: &[String]) -> HashMap<String, Option<Value>> {
+        let data = self.data.lock().unwrap();
+        keys.iter()
+            .map(|key| (key.clone(), data.get(key.as_str()).map(|m| m.value.clone())))
+            .collect()
+    }
+
+    /// Sets `key` to the primary's `value`, or deletes it, provided its
+    /// value still hashes to `expected`, the hash it was compared by.
+    /// Returns whether the key was repaired.
+    pub fn repair(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        value: Option<String>,
+    ) -> Result<bool, Error> {
+        let current = self
+            .data
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|metadata| KeyHash::new(key, &metadata.value).hash);
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+        match value {
+            Some(value) => self.set(key.to_string(), value).map(|()| true),
+            None => self.delete(key),
+        }
+    }
+
+    /// Id of the change log and its newest sequence.
+    pub fn change_position(&self) -> (u64, u64) {
+        let changes = self.changes.lock().unwrap();
+        (changes.id(), changes.last_seq())
+    }
+
+    pub fn geo_add(&self, key: &str, members: Vec<GeoMember>) -> Result<usize, Error> {
+        self.validate_key(key)?;
+        for member in &members {
+            geo::validate_coordinates(member.lat, member.lon).map_err(Error::InvalidInput)?;
+        }
+        self.check_writable()?;
+
+        let start = Instant::now();
+        let mut data = self.lock_within(&self.data, start)?;
+        let mut set: BTreeMap<String, String> = match data.get(key) {
+            Some(metadata) => {
+                serde_json::from_str(&metadata.value).map_err(|_| Error::NotAGeoSet)?
+            }
+            None => BTreeMap::new(),
+        };
+
+        let mut added = 0;
+        for member in members {
+            if set
+                .insert(member.member, geo::encode(member.lat, member.lon))
+                .is_none()
+            {
+                added += 1;
+            }
+        }
+
+        let value = serde_json::to_string(&set).map_err(|e| Error::InvalidInput(e.to_string()))?;
+        self.validate_value(&value)?;
+        if !data.contains_key(key) {
+            self.check_capacity(&data)?;
+        }
+
+        let attributes = self.written_attributes(key, Attributes::NONE);
+        let mut backend = self.lock_within(&self.backend, start)?;
+        self.storage_result(backend.append(key, &value, &attributes))?;
+        drop(backend);
+
+        let (key, event) = match interned(&data, key) {
+            Some(key) => {
+                data.replace_value(&key, value, attributes);
+                (key, EventKind::Updated)
+            }
+            None => {
+                let key = Arc::<str>::from(key);
+                data.insert(key.clone(), KeyMetadata::new(value, attributes));
+                self.add_to_filter(&data, &key);
+                (key, EventKind::Created)
+            }
+        };
+
+        self.increment_operations([&key]);
+        self.publish(event, &key);
+        Ok(added)
+    }
+
+    pub fn geo_members(&self, key: &str) -> Result<Option<Vec<GeoMatch>>, Error> {
+        let data = self.data.lock().unwrap();
+        let metadata = match data.get(key) {
+            Some(metadata) => metadata,
+            None => return Ok(None),
+        };
+        let set: BTreeMap<String, String> =
+            serde_json::from_str(&metadata.value).map_err(|_| Error::NotAGeoSet)?;
+
+        let mut members = Vec::with_capacity(set.len());
+        for (member, hash) in set {
+            let (lat, lon) = geo::decode(&hash).map_err(|_| Error::NotAGeoSet)?;
+            members.push(GeoMatch {
+                member,
+                lat,
+                lon,
+                geohash: hash,
+                distance: None,
+            });
+        }
+        Ok(Some(members))
+    }
+
+    pub fn geo_radius(
+        &self,
+        key: &str,
+        lat: f64,
+        lon: f64,
+        radius: f64,
+        unit: DistanceUnit,
+    ) -> Result<Option<Vec<GeoMatch>>, Error> {
+        geo::validate_coordinates(lat, lon).map_err(Error::InvalidInput)?;
+        let radius_meters = unit.to_meters(radius);
+
+        let members = match self.geo_members(key)? {
+            Some(members) => members,
+            None => return Ok(None),
+        };
+        let mut matches: Vec<GeoMatch> = members
+            .into_iter()
+            .filter_map(|mut m| {
+                let meters = geo::haversine(lat, lon, m.lat, m.lon);
+                if meters <= radius_meters {
+                    m.distance = Some(unit.convert_meters(meters));
+                    Some(m)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        matches.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap());
+        Ok(Some(matches))
+    }
+
+    pub fn geo_box(
+        &self,
+        key: &str,
+        min_lat: f64,
+        min_lon: f64,
+        max_lat: f64,
+        max_lon: f64,
+    ) -> Result<Option<Vec<GeoMatch>>, Error> {
+        geo::validate_coordinates(min_lat, min_lon).map_err(Error::InvalidInput)?;
+        geo::validate_coordinates(max_lat, max_lon).map_err(Error::InvalidInput)?;
+        if min_lat > max_lat {
+            return Err(Error::InvalidInput(
+                "min_lat must not be greater than max_lat".to_string(),
+            ));
+        }
+
+        Ok(self.geo_members(key)?.map(|members| {
+            members
+                .into_iter()
+                .filter(|m| geo::in_box(m.lat, m.lon, min_lat, min_lon, max_lat, max_lon))
+                .collect()
+        }))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupKind {
+    Full,
+    Incremental,
+}
+
+impl BackupKind {
+    pub fn parse(kind: &str) -> Result<Self, String> {
+        match kind {
+            "full" => Ok(BackupKind::Full),
+            "incremental" => Ok(BackupKind::Incremental),
+            _ => Err(format!("Unknown backup type '{}'", kind)),
+        }
+    }
+}
+
+pub struct BackupSnapshot {
+    pub name: String,
+    pub kind: BackupKind,
+    pub timestamp: u64,
+    pub seq: u64,
+    records: Vec<(Arc<str>, Value, Attributes)>,
+}
+
+impl BackupSnapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for (key, value, attributes) in &self.records {
+            write_record(&mut buffer, key, value, attributes).unwrap();
+        }
+        buffer
+    }
+
+    /// Length of [`to_bytes`](Self::to_bytes), without building it.
+    pub fn size(&self) -> u64 {
+        self.records
+            .iter()
+            .map(|(key, value, attributes)| record_size(key, value, attributes) as u64)
+            .sum()
+    }
+
+    /// The bytes of [`to_bytes`](Self::to_bytes) in pieces of at least
+    /// `chunk_size` bytes, or whole records, so a large snapshot can be
+    /// sent without holding all of it in memory twice.
+    pub fn into_chunks(self, chunk_size: usize) -> impl Iterator<Item = Vec<u8>> {
+        let mut records = self.records.into_iter();
+        std::iter::from_fn(move || {
+            let mut chunk = Vec::new();
+            while chunk.len() < chunk_size {
+                let Some((key, value, attributes)) = records.next() else {
+                    break;
+                };
+                write_record(&mut chunk, &key, &value, &attributes).unwrap();
+            }
+            (!chunk.is_empty()).then_some(chunk)
+        })
+    }
+}
@@ -1,396 +1,632 @@
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, Read, Seek, SeekFrom, Write};
-use std::sync::Mutex;
-use std::time::{SystemTime, UNIX_EPOCH};
-
-use actix_web::middleware::{Compress, Logger};
-use actix_web::{App, HttpResponse, HttpServer, Responder, web};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::body::{BodySize, MessageBody, SizedStream};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{CONTENT_LENGTH, CONTENT_TYPE, USER_AGENT};
+use actix_web::http::{KeepAlive, Method};
+use actix_web::middleware::{Compress, Logger, Next, from_fn};
+use actix_web::web::Bytes;
+use actix_web::{App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Responder, web};
+use clap::Parser;
 use env_logger::Env;
+use futures_util::stream::LocalBoxStream;
+use futures_util::{Stream, StreamExt};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
 
-const MAX_KEY_SIZE: usize = 256;
-const MAX_VALUE_SIZE: usize = 10_485_760;
-const BACKUP_THRESHOLD: usize = 1000;
+mod access_log;
+mod admin;
+mod bench;
+mod checksums;
+mod clients;
+mod cluster;
+mod compaction;
+mod config;
+mod dump;
+mod expiry;
+mod fsck;
+mod gossip;
+mod health;
+mod idempotency;
+mod metrics;
+mod migrate;
+mod mirror;
+mod origin;
+mod profiling;
+mod proxy;
+mod pubsub;
+mod rdb;
+mod replication;
+mod reserved;
+mod s3;
+mod shard;
+mod shell;
+mod shutdown;
+mod stats;
+mod systemd;
+mod tiering;
+mod types;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct KeyMetadata {
-    value: String,
+use access_log::{AccessLog, Entry, Rotation};
+use admin::{Admin, Maintenance};
+use clients::Clients;
+use cluster::{Cluster, HeartbeatRequest, VoteRequest};
+use config::{Command, Config};
+use expiry::Expiry;
+use gossip::{GossipMessage, Membership};
+use health::DiskCheck;
+use idempotency::{Idempotency, Lookup, StoredResponse};
+use kstore::events::{EventFilter, EventKind, KeyEvent};
+use kstore::geo::{DistanceUnit, GeoMember};
+use kstore::{
+    Attributes, BackupKind, CasWrite, KvStore, ListOptions, ScanEntry, SortField, SortOrder, Value,
+    current_timestamp, decode_cursor, merkle,
+};
+use metrics::Rates;
+use migrate::{MigrationRequest, Migrations};
+use mirror::Mirror;
+use origin::Origin;
+use profiling::{CountingAllocator, ProfileQuery};
+use proxy::NodeSecret;
+use pubsub::PubSub;
+use replication::{Consistency, Replication};
+use reserved::Reserved;
+use s3::{S3Client, S3Config};
+use shard::Sharding;
+use shutdown::Shutdown;
+use tiering::Tiering;
+use types::ValueType;
+
+const SCAN_BATCH_SIZE: usize = 256;
+const MAX_IMPORT_ERRORS: usize = 100;
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Serialize)]
+struct BackupInfo {
+    name: String,
+    kind: BackupKind,
+    size: u64,
     created_at: u64,
-    updated_at: u64,
-    access_count: u64,
+    /// For incremental backups, the name of the full backup they apply to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    base: Option<String>,
 }
 
-impl KeyMetadata {
-    fn new(value: String) -> Self {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        Self {
-            value,
-            created_at: now,
-            updated_at: now,
-            access_count: 0,
+/// Parses `kvstore_backup_{timestamp}.db` (full) and
+/// `kvstore_incr_{base_timestamp}_{timestamp}.db` (incremental) names into
+/// the kind, creation time and base backup. Anything else is rejected,
+/// which also keeps path traversal out of `/backups/{name}`.
+fn parse_backup_name(name: &str) -> Option<(BackupKind, u64, Option<String>)> {
+    let number = |digits: &str| -> Option<u64> {
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
         }
+        digits.parse().ok()
+    };
+
+    let stem = name.strip_suffix(".db")?;
+    if let Some(timestamp) = stem.strip_prefix("kvstore_backup_") {
+        return Some((BackupKind::Full, number(timestamp)?, None));
     }
+    let (base, timestamp) = stem.strip_prefix("kvstore_incr_")?.split_once('_')?;
+    let base = format!("kvstore_backup_{}.db", number(base)?);
+    Some((BackupKind::Incremental, number(timestamp)?, Some(base)))
 }
 
-#[derive(Serialize)]
-struct KeyInfo {
-    key: String,
-    size: usize,
-    created_at: u64,
-    updated_at: u64,
-    access_count: u64,
+fn list_backups() -> std::io::Result<Vec<BackupInfo>> {
+    let mut backups = Vec::new();
+    for entry in std::fs::read_dir(".")? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some((kind, created_at, base)) = parse_backup_name(&name) else {
+            continue;
+        };
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            backups.push(BackupInfo {
+                name,
+                kind,
+                size: metadata.len(),
+                created_at,
+                base,
+            });
+        }
+    }
+    backups.sort_by_key(|b| std::cmp::Reverse(b.created_at));
+    Ok(backups)
 }
 
-#[derive(Serialize)]
-struct StoreStats {
-    total_keys: usize,
-    total_size_bytes: usize,
-    operations_count: u64,
-    uptime_seconds: u64,
-}
-
-struct KvStore {
-    data: Mutex<HashMap<String, KeyMetadata>>,
-    file: Mutex<File>,
-    operations_count: Mutex<u64>,
-    start_time: u64,
-}
-
-impl KvStore {
-    fn new() -> Self {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("kvstore.db")
-            .unwrap();
-
-        let mut data = HashMap::new();
-        let mut reader = BufReader::new(&file);
-        let mut buffer = Vec::new();
-
-        if reader.read_to_end(&mut buffer).is_ok() {
-            let mut pos = 0;
-            while pos < buffer.len() {
-                if buffer.len() - pos < 16 {
-                    break;
-                }
-
-                let key_size =
-                    u64::from_le_bytes(buffer[pos..pos + 8].try_into().unwrap()) as usize;
-                pos += 8;
-                let value_size =
-                    u64::from_le_bytes(buffer[pos..pos + 8].try_into().unwrap()) as usize;
-                pos += 8;
-
-                if pos + key_size + value_size > buffer.len() {
-                    break;
+/// Turns a broadcast subscription into a Server-Sent Events body. Slow
+/// subscribers that fall behind the channel capacity receive a `lagged`
+/// event with the number of dropped events instead of being disconnected.
+pub fn sse_stream(
+    receiver: broadcast::Receiver<KeyEvent>,
+    filter: EventFilter,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    futures_util::stream::unfold((receiver, filter), |(mut receiver, filter)| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    if !filter.matches(&event) {
+                        continue;
+                    }
+                    let data = serde_json::to_string(&event).unwrap_or_default();
+                    let frame = format!("event: {}\ndata: {}\n\n", event.event.as_str(), data);
+                    return Some((Ok(Bytes::from(frame)), (receiver, filter)));
                 }
-
-                let key = String::from_utf8_lossy(&buffer[pos..pos + key_size]).to_string();
-                pos += key_size;
-                let value = String::from_utf8_lossy(&buffer[pos..pos + value_size]).to_string();
-                pos += value_size;
-
-                if !value.is_empty() {
-                    data.insert(key, KeyMetadata::new(value));
-                } else {
-                    data.remove(&key);
+                Err(RecvError::Lagged(missed)) => {
+                    let frame = format!("event: lagged\ndata: {{\"missed\":{}}}\n\n", missed);
+                    return Some((Ok(Bytes::from(frame)), (receiver, filter)));
                 }
+                Err(RecvError::Closed) => return None,
             }
         }
+    })
+}
 
-        file.seek(SeekFrom::End(0)).unwrap();
-        
-        let start_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-
-        Self {
-            data: Mutex::new(data),
-            file: Mutex::new(file),
-            operations_count: Mutex::new(0),
-            start_time,
+fn file_stream(file: File) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    futures_util::stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buffer = vec![0u8; FILE_CHUNK_SIZE];
+        match file.read(&mut buffer) {
+            Ok(0) => None,
+            Ok(n) => {
+                buffer.truncate(n);
+                Some((Ok(Bytes::from(buffer)), Some(file)))
+            }
+            Err(e) => Some((Err(actix_web::error::ErrorInternalServerError(e)), None)),
         }
-    }
+    })
+}
 
-    fn validate_key(&self, key: &str) -> Result<(), String> {
-        if key.is_empty() {
-            return Err("Key cannot be empty".to_string());
-        }
-        if key.len() > MAX_KEY_SIZE {
-            return Err(format!("Key exceeds maximum size of {} bytes", MAX_KEY_SIZE));
-        }
-        Ok(())
-    }
+/// Streams `keys` in batches, taking the data lock once per batch so large
+/// scans never hold it for long or build the whole body in memory. Keys
+/// deleted mid-scan are skipped.
+fn entry_stream<F>(
+    store: web::Data<KvStore>,
+    keys: Vec<Arc<str>>,
+    render: F,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>>
+where
+    F: FnMut(&ScanEntry, &mut String) + 'static,
+{
+    futures_util::stream::unfold(
+        (store, keys, 0, render),
+        |(store, keys, position, mut render)| async move {
+            if position >= keys.len() {
+                return None;
+            }
+            let end = (position + SCAN_BATCH_SIZE).min(keys.len());
+            let mut chunk = String::new();
+            for entry in store.scan_entries(&keys[position..end]) {
+                render(&entry, &mut chunk);
+            }
+            Some((Ok(Bytes::from(chunk)), (store, keys, end, render)))
+        },
+    )
+}
 
-    fn validate_value(&self, value: &str) -> Result<(), String> {
-        if value.len() > MAX_VALUE_SIZE {
-            return Err(format!("Value exceeds maximum size of {} bytes", MAX_VALUE_SIZE));
-        }
-        Ok(())
-    }
+#[derive(Deserialize)]
+struct HealthQuery {
+    deep: Option<bool>,
+}
 
-    fn increment_operations(&self) {
-        let mut count = self.operations_count.lock().unwrap();
-        *count += 1;
+/// With `?deep=true`, also probes the disk the data lives on and reports
+/// its latency, answering 503 if the probe fails.
+async fn health_check(
+    disk: web::Data<DiskCheck>,
+    query: web::Query<HealthQuery>,
+) -> impl Responder {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    if !query.deep.unwrap_or(false) {
+        return HttpResponse::Ok().json(serde_json::json!({
+            "status": "healthy",
+            "timestamp": timestamp
+        }));
     }
-
-    fn set(&self, key: String, value: String) -> Result<(), String> {
-        self.validate_key(&key)?;
-        self.validate_value(&value)?;
-
-        let mut data = self.data.lock().unwrap();
-        let mut file = self.file.lock().unwrap();
-
-        let metadata = KeyMetadata::new(value.clone());
-        data.insert(key.clone(), metadata);
-
-        let key_bytes = key.as_bytes();
-        let value_bytes = value.as_bytes();
-        file.write_all(&(key_bytes.len() as u64).to_le_bytes())
-            .map_err(|e| e.to_string())?;
-        file.write_all(&(value_bytes.len() as u64).to_le_bytes())
-            .map_err(|e| e.to_string())?;
-        file.write_all(key_bytes).map_err(|e| e.to_string())?;
-        file.write_all(value_bytes).map_err(|e| e.to_string())?;
-        file.flush().map_err(|e| e.to_string())?;
-
-        self.increment_operations();
-        Ok(())
+    match web::block(move || disk.run()).await {
+        Ok(None) => HttpResponse::Ok().json(serde_json::json!({
+            "status": "healthy",
+            "timestamp": timestamp,
+            "disk": null
+        })),
+        Ok(Some(Ok(latency))) => HttpResponse::Ok().json(serde_json::json!({
+            "status": "healthy",
+            "timestamp": timestamp,
+            "disk": latency
+        })),
+        Ok(Some(Err(e))) => HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "status": "unhealthy",
+            "timestamp": timestamp,
+            "disk": { "error": e }
+        })),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Disk check failed: {}", e)),
     }
+}
 
-    fn update(&self, key: &str, value: String) -> Result<(), String> {
-        self.validate_key(key)?;
-        self.validate_value(&value)?;
+/// Liveness: the process is up and serving HTTP, whatever state its data
+/// is in.
+async fn health_live() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "alive" }))
+}
 
-        let mut data = self.data.lock().unwrap();
-        
-        if let Some(metadata) = data.get_mut(key) {
-            metadata.value = value.clone();
-            metadata.updated_at = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            drop(data);
-            self.compact();
-            self.increment_operations();
-            Ok(())
-        } else {
-            Err("Key does not exist".to_string())
-        }
+/// Readiness: whether this node should get traffic. It should not while a
+/// replica is still loading its first copy of the data, while writes are
+/// frozen for maintenance or by a full disk, while shutting down, or once
+/// the data file can no longer be synced to disk.
+async fn health_ready(
+    store: web::Data<KvStore>,
+    replication: web::Data<Replication>,
+    maintenance: web::Data<Maintenance>,
+    shutdown: web::Data<Shutdown>,
+) -> impl Responder {
+    let mut reasons = Vec::new();
+    if !replication.has_synced() {
+        reasons.push("replica has not finished loading data from its primary".to_string());
     }
-
-    fn get(&self, key: &str) -> Option<String> {
-        let mut data = self.data.lock().unwrap();
-        if let Some(metadata) = data.get_mut(key) {
-            metadata.access_count += 1;
-            self.increment_operations();
-            Some(metadata.value.clone())
-        } else {
-            None
-        }
+    if maintenance.is_read_only() {
+        reasons.push("read-only maintenance mode".to_string());
     }
-
-    fn get_info(&self, key: &str) -> Option<KeyInfo> {
-        let data = self.data.lock().unwrap();
-        data.get(key).map(|metadata| KeyInfo {
-            key: key.to_string(),
-            size: metadata.value.len(),
-            created_at: metadata.created_at,
-            updated_at: metadata.updated_at,
-            access_count: metadata.access_count,
-        })
+    if let Some(disk_full) = store.disk_full() {
+        reasons.push(format!(
+            "disk is full, writes are disabled: {}",
+            disk_full.error
+        ));
     }
-
-    fn list_keys(&self, prefix: Option<&str>, limit: Option<usize>) -> Vec<String> {
-        let data = self.data.lock().unwrap();
-        let mut keys: Vec<String> = data
-            .keys()
-            .filter(|k| {
-                if let Some(p) = prefix {
-                    k.starts_with(p)
-                } else {
-                    true
-                }
-            })
-            .cloned()
-            .collect();
-        
-        keys.sort();
-        
-        if let Some(l) = limit {
-            keys.truncate(l);
-        }
-        
-        keys
+    if shutdown.is_started() {
+        reasons.push("shutting down".to_string());
     }
-
-    fn get_stats(&self) -> StoreStats {
-        let data = self.data.lock().unwrap();
-        let operations = *self.operations_count.lock().unwrap();
-        let total_size: usize = data.values().map(|m| m.value.len()).sum();
-        let uptime = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() - self.start_time;
-
-        StoreStats {
-            total_keys: data.len(),
-            total_size_bytes: total_size,
-            operations_count: operations,
-            uptime_seconds: uptime,
-        }
+    match web::block(move || store.sync()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => reasons.push(format!("data file is not writable: {}", e)),
+        Err(e) => reasons.push(format!("disk check failed: {}", e)),
     }
+    if reasons.is_empty() {
+        HttpResponse::Ok().json(serde_json::json!({ "status": "ready" }))
+    } else {
+        HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "status": "not ready",
+            "reasons": reasons
+        }))
+    }
+}
 
-    fn compact(&self) {
-        let data = self.data.lock().unwrap();
-        let mut file = self.file.lock().unwrap();
-
-        file.set_len(0).unwrap();
-        file.seek(SeekFrom::Start(0)).unwrap();
+/// Largest request body the server accepts, shown in `/stats`.
+struct PayloadLimit(usize);
 
-        for (key, metadata) in data.iter() {
-            let key_bytes = key.as_bytes();
-            let value_bytes = metadata.value.as_bytes();
-            file.write_all(&(key_bytes.len() as u64).to_le_bytes())
-                .unwrap();
-            file.write_all(&(value_bytes.len() as u64).to_le_bytes())
-                .unwrap();
-            file.write_all(key_bytes).unwrap();
-            file.write_all(value_bytes).unwrap();
-        }
-        file.flush().unwrap();
-    }
+/// Largest Redis dump `POST /import/rdb` accepts, shown in `/stats`.
+struct RdbLimit(u64);
 
-    fn delete(&self, key: &str) -> bool {
-        let mut data = self.data.lock().unwrap();
-        if data.remove(key).is_some() {
-            drop(data);
-            self.compact();
-            self.increment_operations();
-            true
-        } else {
-            false
+async fn get_stats(
+    store: web::Data<KvStore>,
+    payload: web::Data<PayloadLimit>,
+    rdb: web::Data<RdbLimit>,
+    rates: web::Data<Rates>,
+) -> impl Responder {
+    let mut stats = serde_json::json!(store.get_stats());
+    stats["limits"]["max_payload_size"] = payload.0.into();
+    stats["limits"]["max_rdb_size"] = rdb.0.into();
+    let (total_rate, prefix_rates) = rates.get();
+    stats["operations_per_second"] = total_rate.into();
+    if let Some(prefixes) = stats["prefixes"].as_array_mut() {
+        for (prefix, rate) in prefixes.iter_mut().zip(prefix_rates) {
+            prefix["operations_per_second"] = rate.into();
         }
     }
+    HttpResponse::Ok().json(stats)
+}
 
-    fn delete_by_prefix(&self, prefix: &str) -> usize {
-        let mut data = self.data.lock().unwrap();
-        let keys_to_remove: Vec<String> = data
-            .keys()
-            .filter(|k| k.starts_with(prefix))
-            .cloned()
-            .collect();
-        
-        let count = keys_to_remove.len();
-        for key in keys_to_remove {
-            data.remove(&key);
-        }
-        
-        drop(data);
-        if count > 0 {
-            self.compact();
-            self.increment_operations();
-        }
-        count
-    }
+async fn get_metrics(store: web::Data<KvStore>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(metrics::render(&store.get_stats()))
+}
 
-    fn find_values_by_regex(&self, pattern: &str) -> Result<Vec<String>, regex::Error> {
-        let re = Regex::new(pattern)?;
-        let data = self.data.lock().unwrap();
-        let values: Vec<String> = data
-            .iter()
-            .filter(|(key, _)| re.is_match(key))
-            .map(|(_, metadata)| metadata.value.clone())
-            .collect();
-        Ok(values)
-    }
+fn parse_query_param<T: std::str::FromStr>(
+    query: &HashMap<String, String>,
+    name: &str,
+) -> Result<Option<T>, String> {
+    query
+        .get(name)
+        .map(|v| {
+            v.parse::<T>()
+                .map_err(|_| format!("Invalid value '{}' for {}", v, name))
+        })
+        .transpose()
+}
 
-    fn exists(&self, key: &str) -> bool {
-        let data = self.data.lock().unwrap();
-        data.contains_key(key)
-    }
+fn list_options_from_query(query: &HashMap<String, String>) -> Result<ListOptions<'_>, String> {
+    Ok(ListOptions {
+        prefix: query.get("prefix").map(|s| s.as_str()),
+        pattern: query.get("match").map(|s| s.as_str()),
+        limit: query.get("limit").and_then(|s| s.parse::<usize>().ok()),
+        cursor: query.get("cursor").map(|s| s.as_str()),
+        sort: query
+            .get("sort")
+            .map(|s| SortField::parse(s))
+            .transpose()?
+            .unwrap_or(SortField::Key),
+        order: query
+            .get("order")
+            .map(|o| SortOrder::parse(o))
+            .transpose()?
+            .unwrap_or(SortOrder::Asc),
+        min_size: parse_query_param(query, "min_size")?,
+        max_size: parse_query_param(query, "max_size")?,
+        created_after: parse_query_param(query, "created_after")?,
+        created_before: parse_query_param(query, "created_before")?,
+        updated_after: parse_query_param(query, "updated_after")?,
+        updated_before: parse_query_param(query, "updated_before")?,
+        updated_since: parse_query_param(query, "updated_since")?,
+    })
+}
 
-    fn batch_set(&self, items: Vec<(String, String)>) -> Result<usize, String> {
-        let mut success_count = 0;
-        for (key, value) in items {
-            if self.set(key, value).is_ok() {
-                success_count += 1;
-            }
-        }
-        Ok(success_count)
-    }
+#[derive(Deserialize)]
+struct ClientStatsQuery {
+    n: Option<usize>,
+}
 
-    fn backup(&self) -> Result<(), String> {
-        let data = self.data.lock().unwrap();
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        
-        let backup_name = format!("kvstore_backup_{}.db", timestamp);
-        let mut backup_file = File::create(&backup_name)
-            .map_err(|e| e.to_string())?;
+async fn get_client_stats(
+    clients: web::Data<Clients>,
+    query: web::Query<ClientStatsQuery>,
+) -> impl Responder {
+    HttpResponse::Ok().json(clients.top(query.n.unwrap_or(100)))
+}
 
-        for (key, metadata) in data.iter() {
-            let key_bytes = key.as_bytes();
-            let value_bytes = metadata.value.as_bytes();
-            backup_file.write_all(&(key_bytes.len() as u64).to_le_bytes())
-                .map_err(|e| e.to_string())?;
-            backup_file.write_all(&(value_bytes.len() as u64).to_le_bytes())
-                .map_err(|e| e.to_string())?;
-            backup_file.write_all(key_bytes).map_err(|e| e.to_string())?;
-            backup_file.write_all(value_bytes).map_err(|e| e.to_string())?;
-        }
-        backup_file.flush().map_err(|e| e.to_string())?;
-        
-        Ok(())
-    }
+async fn get_size_histogram(store: web::Data<KvStore>) -> impl Responder {
+    HttpResponse::Ok().json(store.size_histogram())
 }
 
-async fn health_check() -> impl Responder {
-    HttpResponse::Ok().json(serde_json::json!({
-        "status": "healthy",
-        "timestamp": SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-    }))
+#[derive(Deserialize)]
+struct TopKeysQuery {
+    by: Option<String>,
+    n: Option<usize>,
 }
 
-async fn get_stats(store: web::Data<KvStore>) -> impl Responder {
-    let stats = store.get_stats();
-    HttpResponse::Ok().json(stats)
+async fn get_top_keys(
+    store: web::Data<KvStore>,
+    query: web::Query<TopKeysQuery>,
+) -> impl Responder {
+    let by = match SortField::parse(query.by.as_deref().unwrap_or("access_count")) {
+        Ok(SortField::Key) => {
+            return HttpResponse::BadRequest().body("Cannot rank keys by 'key'");
+        }
+        Ok(by) => by,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    HttpResponse::Ok().json(store.top_keys(by, query.n.unwrap_or(10)))
 }
 
 async fn get_all_keys(
     store: web::Data<KvStore>,
     query: web::Query<HashMap<String, String>>,
 ) -> impl Responder {
-    let prefix = query.get("prefix").map(|s| s.as_str());
-    let limit = query.get("limit").and_then(|s| s.parse::<usize>().ok());
+    let options = match list_options_from_query(&query) {
+        Ok(options) => options,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
     
-    let keys = store.list_keys(prefix, limit);
+    let (keys, next_cursor) = match store.list_keys(&options) {
+        Ok(page) => page,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
     if keys.is_empty() {
         HttpResponse::NotFound().json(vec![] as Vec<String>)
     } else {
-        HttpResponse::Ok().json(keys)
+        let mut response = HttpResponse::Ok();
+        if let Some(cursor) = next_cursor {
+            response.insert_header(("X-Next-Cursor", cursor));
+        }
+        response.json(keys)
+    }
+}
+
+#[derive(Deserialize)]
+struct GetKeyQuery {
+    as_of: Option<u64>,
+    /// Type to read the value as, instead of the key's own.
+    #[serde(rename = "as")]
+    as_type: Option<String>,
+}
+
+/// Sends `value` as the body of `response`, first fetching it from S3 if
+/// it is a pointer to a tiered value, along with the type and digests in
+/// its `attributes`. A value read as a type, the `requested` one or else
+/// the key's own, is checked against it and sent with its content type.
+async fn value_response(
+    mut response: HttpResponseBuilder,
+    tiering: &Option<Tiering>,
+    store: &KvStore,
+    key: &str,
+    value: Value,
+    attributes: Attributes,
+    requested: Option<ValueType>,
+) -> HttpResponse {
+    let tagged = types::of(&attributes);
+    if let Some(tagged) = tagged {
+        response.insert_header((types::TYPE_HEADER, tagged.as_str()));
     }
+    let body = match tiering {
+        Some(tiering) => match tiering.resolve(store, key, value).await {
+            Ok(body) => body,
+            Err(e) => return HttpResponse::BadGateway().body(e),
+        },
+        None => value.into_bytes(),
+    };
+    if let Some(checksum) = attributes.checksum {
+        response.insert_header((checksums::MD5_HEADER, checksum.md5));
+        response.insert_header((checksums::SHA256_HEADER, checksum.sha256));
+    }
+    let Some(value_type) = requested.or(tagged) else {
+        return response.body(body);
+    };
+    if !std::str::from_utf8(&body).is_ok_and(|value| value_type.accepts(value)) {
+        return HttpResponse::UnprocessableEntity().body(types::mismatch(key, value_type));
+    }
+    response.content_type(value_type.content_type()).body(body)
 }
 
-async fn get_key(store: web::Data<KvStore>, path: web::Path<String>) -> impl Responder {
+#[allow(clippy::too_many_arguments)]
+async fn get_key(
+    store: web::Data<KvStore>,
+    origin: web::Data<Option<Origin>>,
+    tiering: web::Data<Option<Tiering>>,
+    replication: web::Data<Replication>,
+    path: web::Path<String>,
+    query: web::Query<GetKeyQuery>,
+) -> impl Responder {
     let key = path.into_inner();
-    match store.get(&key) {
-        Some(value) => HttpResponse::Ok().body(value),
-        None => HttpResponse::NotFound().body("Key not found"),
+    let requested = match query.as_type.as_deref().map(ValueType::parse).transpose() {
+        Ok(requested) => requested,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    // Replicas get fetched values from their primary instead.
+    if origin.is_some() && query.as_of.is_none() && replication.primary().is_none() {
+        return match origin::get(origin, store.clone(), key.clone()).await {
+            Ok(Some((value, attributes, status))) => {
+                let mut response = HttpResponse::Ok();
+                if let Some(status) = status {
+                    response.insert_header(("X-Cache", status.as_str()));
+                }
+                value_response(
+                    response, &tiering, &store, &key, value, attributes, requested,
+                )
+                .await
+            }
+            Ok(None) => HttpResponse::NotFound().body("Key not found"),
+            Err(e) => HttpResponse::BadGateway().body(e),
+        };
     }
+    // Past values are read as the type the key has now, but without the
+    // digests, which are of its current value.
+    let read = match query.as_of {
+        Some(timestamp) => match store.get_as_of(&key, timestamp) {
+            Ok(value) => value.map(|value| {
+                let attributes = store.attributes(&key).unwrap_or_default();
+                let attributes = Attributes {
+                    checksum: None,
+                    ..attributes
+                };
+                (value, attributes)
+            }),
+            Err(e) => return HttpResponse::Gone().body(e.to_string()),
+        },
+        None => store.get_with_attributes(&key),
+    };
+    let Some((value, attributes)) = read else {
+        return HttpResponse::NotFound().body("Key not found");
+    };
+    value_response(
+        HttpResponse::Ok(),
+        &tiering,
+        &store,
+        &key,
+        value,
+        attributes,
+        requested,
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct ScanQuery {
+    prefix: Option<String>,
+}
+
+async fn scan(store: web::Data<KvStore>, query: web::Query<ScanQuery>) -> impl Responder {
+    let keys = store.keys_with_prefix(query.prefix.as_deref());
+    let stream = entry_stream(store, keys, |entry, chunk| {
+        if let Ok(line) = serde_json::to_string(entry) {
+            chunk.push_str(&line);
+            chunk.push('\n');
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(stream)
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    format: Option<String>,
+    prefix: Option<String>,
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+async fn export(store: web::Data<KvStore>, query: web::Query<ExportQuery>) -> impl Responder {
+    let format = query.format.as_deref().unwrap_or("json");
+    let keys = store.keys_with_prefix(query.prefix.as_deref());
+
+    let (content_type, body): (
+        &str,
+        LocalBoxStream<'static, Result<Bytes, actix_web::Error>>,
+    ) = match format {
+        "json" => {
+            let mut first = true;
+            let entries = entry_stream(store, keys, move |entry, chunk| {
+                if let Ok(json) = serde_json::to_string(entry) {
+                    chunk.push_str(if first { "\n  " } else { ",\n  " });
+                    chunk.push_str(&json);
+                    first = false;
+                }
+            });
+            let body = futures_util::stream::once(async { Ok(Bytes::from_static(b"[")) })
+                .chain(entries)
+                .chain(futures_util::stream::once(async {
+                    Ok(Bytes::from_static(b"\n]\n"))
+                }));
+            ("application/json", body.boxed_local())
+        }
+        "csv" => {
+            let rows = entry_stream(store, keys, |entry, chunk| {
+                chunk.push_str(&format!(
+                    "{},{},{},{}\r\n",
+                    csv_field(&entry.key),
+                    csv_field(&entry.value),
+                    entry.created_at,
+                    entry.updated_at
+                ));
+            });
+            let body = futures_util::stream::once(async {
+                Ok(Bytes::from_static(b"key,value,created_at,updated_at\r\n"))
+            })
+            .chain(rows);
+            ("text/csv", body.boxed_local())
+        }
+        _ => {
+            return HttpResponse::BadRequest()
+                .body(format!("Unsupported export format '{}'", format));
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"kvstore_export_{}.{}\"",
+                current_timestamp(),
+                format
+            ),
+        ))
+        .streaming(body)
 }
 
 async fn get_key_info(store: web::Data<KvStore>, path: web::Path<String>) -> impl Responder {
@@ -401,6 +637,36 @@ async fn get_key_info(store: web::Data<KvStore>, path: web::Path<String>) -> imp
     }
 }
 
+#[derive(Deserialize)]
+struct BulkInfoRequest {
+    keys: Option<Vec<Arc<str>>>,
+    prefix: Option<String>,
+}
+
+async fn get_bulk_info(
+    store: web::Data<KvStore>,
+    request: web::Json<BulkInfoRequest>,
+) -> impl Responder {
+    let keys = match request.into_inner() {
+        BulkInfoRequest {
+            keys: Some(keys),
+            prefix: None,
+        } => keys,
+        BulkInfoRequest {
+            keys: None,
+            prefix: Some(prefix),
+        } => store.keys_with_prefix(Some(&prefix)),
+        _ => {
+            return HttpResponse::BadRequest().body("Provide exactly one of 'keys' or 'prefix'");
+        }
+    };
+    let (info, missing) = store.get_info_many(&keys);
+    HttpResponse::Ok().json(serde_json::json!({
+        "info": info,
+        "missing": missing
+    }))
+}
+
 async fn check_key_exists(store: web::Data<KvStore>, path: web::Path<String>) -> impl Responder {
     let key = path.into_inner();
     if store.exists(&key) {
@@ -410,129 +676,2386 @@ async fn check_key_exists(store: web::Data<KvStore>, path: web::Path<String>) ->
     }
 }
 
+/// Response for a write the store refused: 507 once it or the disk is
+/// full, so clients can tell running out of room from a bad request.
+fn write_error(e: kstore::Error) -> HttpResponse {
+    match e {
+        kstore::Error::TooManyKeys(_) | kstore::Error::DiskFull => {
+            HttpResponse::InsufficientStorage().body(e.to_string())
+        }
+        kstore::Error::DeadlineExceeded(_) => HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", "1"))
+            .body(e.to_string()),
+        kstore::Error::Io(_) => HttpResponse::InternalServerError().body(e.to_string()),
+        e => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn put_key(
+    req: HttpRequest,
     store: web::Data<KvStore>,
+    reserved: web::Data<Reserved>,
+    tiering: web::Data<Option<Tiering>>,
     path: web::Path<String>,
     body: String,
 ) -> impl Responder {
     let key = path.into_inner();
+    if let Err(e) = reserved.check(&key) {
+        return HttpResponse::Forbidden().body(e);
+    }
     if store.exists(&key) {
         return HttpResponse::Conflict().body("Key already exists");
     }
-    
-    match store.set(key, body) {
-        Ok(_) => HttpResponse::Created().body("OK"),
-        Err(e) => HttpResponse::BadRequest().body(e),
+    let ttl = match expiry::requested(&req) {
+        Ok(ttl) => ttl,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let checksum = match checksums::verify(&req, body.as_bytes()) {
+        Ok(checksum) => checksum,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let requested = match types::requested(&req) {
+        Ok(requested) => requested,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let value_type = match types::check_write(&store, &key, &body, requested, true) {
+        Ok(value_type) => value_type,
+        Err(e) => return HttpResponse::UnprocessableEntity().body(e),
+    };
+    let attributes = Attributes {
+        expires_at: expiry::expires_at(ttl),
+        value_type: types::tag(value_type),
+        checksum,
+    };
+    let Some(tiering) = tiering.as_ref() else {
+        return match store.set_with(key, body, attributes) {
+            Ok(_) => HttpResponse::Created().body("OK"),
+            Err(e) => write_error(e),
+        };
+    };
+
+    let (body, offloaded) = match tiering.offload(&store, body).await {
+        Ok(offloaded) => offloaded,
+        Err(response) => return response,
+    };
+    match store.set_with(key.clone(), body.clone(), attributes) {
+        Ok(_) => {
+            tiering.record(&store, &key, &body, offloaded);
+            HttpResponse::Created().body("OK")
+        }
+        Err(e) => write_error(e),
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn update_key(
+    req: HttpRequest,
     store: web::Data<KvStore>,
+    reserved: web::Data<Reserved>,
+    tiering: web::Data<Option<Tiering>>,
     path: web::Path<String>,
     body: String,
 ) -> impl Responder {
     let key = path.into_inner();
-    match store.update(&key, body) {
-        Ok(_) => HttpResponse::Ok().body("OK"),
-        Err(e) => HttpResponse::BadRequest().body(e),
+    if let Err(e) = reserved.check(&key) {
+        return HttpResponse::Forbidden().body(e);
+    }
+    let ttl = match expiry::requested(&req) {
+        Ok(ttl) => ttl,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let checksum = match checksums::verify(&req, body.as_bytes()) {
+        Ok(checksum) => checksum,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let requested = match types::requested(&req) {
+        Ok(requested) => requested,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    // Only a write that names a type changes the tag.
+    let value_type = match types::check_write(&store, &key, &body, requested, false) {
+        Ok(value_type) => value_type,
+        Err(e) => return HttpResponse::UnprocessableEntity().body(e),
+    };
+    let attributes = Attributes {
+        expires_at: expiry::expires_at(ttl),
+        value_type: types::tag(value_type),
+        checksum,
+    };
+    let Some(tiering) = tiering.as_ref() else {
+        return match store.update_with(&key, body, attributes) {
+            Ok(_) => HttpResponse::Ok().body("OK"),
+            Err(e) => write_error(e),
+        };
+    };
+
+    if !store.exists(&key) {
+        return HttpResponse::BadRequest().body(kstore::Error::KeyNotFound.to_string());
+    }
+    let (body, offloaded) = match tiering.offload(&store, body).await {
+        Ok(offloaded) => offloaded,
+        Err(response) => return response,
+    };
+    match store.update_with(&key, body.clone(), attributes) {
+        Ok(_) => {
+            tiering.record(&store, &key, &body, offloaded);
+            HttpResponse::Ok().body("OK")
+        }
+        Err(e) => write_error(e),
     }
 }
 
-async fn delete_key(store: web::Data<KvStore>, path: web::Path<String>) -> impl Responder {
+async fn delete_key(
+    store: web::Data<KvStore>,
+    reserved: web::Data<Reserved>,
+    path: web::Path<String>,
+) -> impl Responder {
     let key = path.into_inner();
-    if store.delete(&key) {
-        HttpResponse::Ok().body("OK")
-    } else {
-        HttpResponse::NotFound().body("Key not found")
+    if let Err(e) = reserved.check(&key) {
+        return HttpResponse::Forbidden().body(e);
+    }
+    match store.delete(&key) {
+        Ok(true) => HttpResponse::Ok().body("OK"),
+        Ok(false) => HttpResponse::NotFound().body("Key not found"),
+        Err(e) => write_error(e),
     }
 }
 
-async fn delete_by_prefix(store: web::Data<KvStore>, path: web::Path<String>) -> impl Responder {
-    let prefix = path.into_inner();
-    let count = store.delete_by_prefix(&prefix);
+#[derive(Deserialize)]
+struct BulkDeleteQuery {
+    dry_run: Option<bool>,
+}
+
+fn dry_run_response(keys: Vec<Arc<str>>) -> HttpResponse {
     HttpResponse::Ok().json(serde_json::json!({
-        "deleted_count": count
+        "dry_run": true,
+        "matched_count": keys.len(),
+        "keys": keys
     }))
 }
 
-async fn get_values_by_regex(store: web::Data<KvStore>, path: web::Path<String>) -> impl Responder {
-    let pattern = path.into_inner();
-    match store.find_values_by_regex(&pattern) {
-        Ok(values) => {
-            if values.is_empty() {
-                HttpResponse::NotFound().body("No values matched the pattern")
-            } else {
-                HttpResponse::Ok().json(values)
-            }
-        }
-        Err(e) => HttpResponse::BadRequest().body(format!("Invalid regex pattern: {}", e)),
+/// Bulk deletes pass over reserved keys instead of failing, since a broad
+/// prefix or pattern can match them without meaning to.
+async fn delete_by_prefix(
+    store: web::Data<KvStore>,
+    reserved: web::Data<Reserved>,
+    path: web::Path<String>,
+    query: web::Query<BulkDeleteQuery>,
+) -> impl Responder {
+    let prefix = path.into_inner();
+    let matches = |k: &str| k.starts_with(&prefix) && !reserved.contains(k);
+    if query.dry_run.unwrap_or(false) {
+        return dry_run_response(store.keys_where(matches));
+    }
+    match store.delete_where(matches) {
+        Ok(count) => HttpResponse::Ok().json(serde_json::json!({
+            "deleted_count": count
+        })),
+        Err(e) => write_error(e),
     }
 }
 
+/// What `PUT /kv/prefix/{prefix}` does to each value.
 #[derive(Deserialize)]
-struct BatchItem {
-    key: String,
-    value: String,
+#[serde(rename_all = "snake_case")]
+enum PrefixUpdate {
+    /// Sets every value to this one.
+    Replace(String),
+    /// Applies a JSON merge patch (RFC 7396) to every value, which must be
+    /// JSON.
+    MergePatch(serde_json::Value),
+    /// Gives every key this many seconds to live, leaving its value as it
+    /// is.
+    Ttl(u64),
 }
 
-async fn batch_set(
+/// Applies `patch` to `target` as RFC 7396 describes.
+fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let serde_json::Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(serde_json::Map::new());
+    }
+    let target = target.as_object_mut().unwrap();
+    for (name, value) in patch {
+        if value.is_null() {
+            target.remove(name);
+        } else {
+            merge_patch(
+                target
+                    .entry(name.clone())
+                    .or_insert(serde_json::Value::Null),
+                value,
+            );
+        }
+    }
+}
+
+/// Updates every key under a prefix at once, or none of them if any value
+/// cannot be updated. Reserved keys are passed over, as in bulk deletes.
+async fn update_by_prefix(
     store: web::Data<KvStore>,
-    items: web::Json<Vec<BatchItem>>,
+    reserved: web::Data<Reserved>,
+    path: web::Path<String>,
+    update: web::Json<PrefixUpdate>,
 ) -> impl Responder {
-    let items: Vec<(String, String)> = items
-        .into_inner()
-        .into_iter()
-        .map(|item| (item.key, item.value))
-        .collect();
-    
-    match store.batch_set(items) {
+    let prefix = path.into_inner();
+    let matches = |k: &str| k.starts_with(&prefix) && !reserved.contains(k);
+    // A new value is a write like any other, so the key's expiry is set
+    // anew from its policy and its digests are dropped, while its type
+    // stays.
+    let check_type = |value: String, attributes: &mut Attributes| {
+        if let Some(value_type) = types::of(attributes)
+            && !value_type.accepts(&value)
+        {
+            return Err(format!("the value must be a valid {}", value_type.as_str()));
+        }
+        attributes.expires_at = None;
+        attributes.checksum = None;
+        Ok(Some(value))
+    };
+    let result = match update.into_inner() {
+        PrefixUpdate::Replace(value) => store.update_where(matches, |_, _, attributes| {
+            check_type(value.clone(), attributes)
+        }),
+        PrefixUpdate::MergePatch(patch) => store.update_where(matches, |_, value, attributes| {
+            let mut value: serde_json::Value = serde_json::from_str(value)
+                .map_err(|_| "the value is not JSON, so it cannot be merge patched")?;
+            merge_patch(&mut value, &patch);
+            check_type(value.to_string(), attributes)
+        }),
+        PrefixUpdate::Ttl(0) => {
+            return HttpResponse::BadRequest().body("ttl must be a number of seconds above 0");
+        }
+        PrefixUpdate::Ttl(ttl) => store.update_where(matches, |_, _, attributes| {
+            attributes.expires_at = expiry::expires_at(Some(ttl));
+            Ok(None)
+        }),
+    };
+    match result {
         Ok(count) => HttpResponse::Ok().json(serde_json::json!({
-            "success_count": count
+            "updated_count": count
         })),
-        Err(e) => HttpResponse::BadRequest().body(e),
+        Err(e) => write_error(e),
     }
 }
 
-async fn create_backup(store: web::Data<KvStore>) -> impl Responder {
-    match store.backup() {
-        Ok(_) => HttpResponse::Ok().body("Backup created successfully"),
-        Err(e) => HttpResponse::InternalServerError().body(format!("Backup failed: {}", e)),
+/// Header and values `DELETE /kv` and `POST /restore/s3/{name}` need on
+/// top of the admin token, so the whole store is never wiped by a stray
+/// request.
+const CONFIRM_HEADER: &str = "X-Confirm";
+const FLUSH_CONFIRM_VALUE: &str = "delete-all-keys";
+const RESTORE_CONFIRM_VALUE: &str = "replace-all-keys";
+
+/// Checks that `req` carries the confirmation `value` for `action`,
+/// returning the response to send if it does not.
+fn confirm(req: &HttpRequest, value: &str, action: &str) -> Result<(), HttpResponse> {
+    let confirmed = req
+        .headers()
+        .get(CONFIRM_HEADER)
+        .is_some_and(|given| given == value);
+    if !confirmed {
+        return Err(HttpResponse::BadRequest().body(format!(
+            "{} needs the header {}: {}",
+            action, CONFIRM_HEADER, value
+        )));
     }
+    Ok(())
 }
 
-async fn manual_compact(store: web::Data<KvStore>) -> impl Responder {
-    store.compact();
-    HttpResponse::Ok().body("Database compacted successfully")
+async fn flush_all(
+    req: HttpRequest,
+    store: web::Data<KvStore>,
+    admin: web::Data<Admin>,
+    reserved: web::Data<Reserved>,
+) -> impl Responder {
+    if let Err(response) = admin.authorize(&req) {
+        return response;
+    }
+    if let Err(response) = confirm(&req, FLUSH_CONFIRM_VALUE, "Deleting every key") {
+        return response;
+    }
+    match store.delete_where(|k| !reserved.contains(k)) {
+        Ok(count) => HttpResponse::Ok().json(serde_json::json!({
+            "deleted_count": count
+        })),
+        Err(e) => write_error(e),
+    }
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let store = web::Data::new(KvStore::new());
-    println!("Server running at http://127.0.0.1:8080");
-    env_logger::init_from_env(Env::default().default_filter_or("info"));
-    
-    HttpServer::new(move || {
-        App::new()
-            .app_data(store.clone())
-            .wrap(Compress::default())
-            .wrap(Logger::default())
-            .wrap(Logger::new("%a %{User-Agent}i"))
-            .route("/health", web::get().to(health_check))
-            .route("/stats", web::get().to(get_stats))
-            .route("/kv/", web::get().to(get_all_keys))
-            .route("/kv/{key}", web::get().to(get_key))
-            .route("/kv/{key}/info", web::get().to(get_key_info))
-            .route("/kv/{key}/exists", web::get().to(check_key_exists))
-            .route("/kv/{key}", web::post().to(put_key))
-            .route("/kv/{key}", web::put().to(update_key))
-            .route("/kv/{key}", web::delete().to(delete_key))
-            .route("/kv/prefix/{prefix}", web::delete().to(delete_by_prefix))
-            .route("/kv/r/{regex}", web::get().to(get_values_by_regex))
-            .route("/batch", web::post().to(batch_set))
-            .route("/backup", web::post().to(create_backup))
-            .route("/compact", web::post().to(manual_compact))
-    })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+async fn count_by_prefix(store: web::Data<KvStore>, path: web::Path<String>) -> impl Responder {
+    let prefix = path.into_inner();
+    let (count, total_size) = store.count_by_prefix(&prefix);
+    HttpResponse::Ok().json(serde_json::json!({
+        "prefix": prefix,
+        "count": count,
+        "total_size_bytes": total_size
+    }))
+}
+
+#[derive(Deserialize)]
+struct RegexSearchQuery {
+    include_keys: Option<bool>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+async fn get_values_by_regex(
+    store: web::Data<KvStore>,
+    path: web::Path<String>,
+    query: web::Query<RegexSearchQuery>,
+) -> impl Responder {
+    let pattern = path.into_inner();
+    let after = match query.cursor.as_deref().map(decode_cursor).transpose() {
+        Ok(after) => after,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    match store.find_by_regex(&pattern, query.limit, after.as_deref()) {
+        Ok((entries, next_cursor)) => {
+            if entries.is_empty() {
+                return HttpResponse::NotFound().body("No values matched the pattern");
+            }
+            let mut response = HttpResponse::Ok();
+            if let Some(cursor) = next_cursor {
+                response.insert_header(("X-Next-Cursor", cursor));
+            }
+            if query.include_keys.unwrap_or(false) {
+                response.json(entries)
+            } else {
+                let values: Vec<Value> = entries.into_iter().map(|e| e.value).collect();
+                response.json(values)
+            }
+        }
+        Err(e) => HttpResponse::BadRequest().body(format!("Invalid regex pattern: {}", e)),
+    }
+}
+
+async fn get_keys_by_value_regex(
+    store: web::Data<KvStore>,
+    path: web::Path<String>,
+    query: web::Query<RegexSearchQuery>,
+) -> impl Responder {
+    let pattern = path.into_inner();
+    let after = match query.cursor.as_deref().map(decode_cursor).transpose() {
+        Ok(after) => after,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    match store.find_keys_by_value_regex(&pattern, query.limit, after.as_deref()) {
+        Ok((keys, next_cursor)) => {
+            if keys.is_empty() {
+                return HttpResponse::NotFound().body("No values matched the pattern");
+            }
+            let mut response = HttpResponse::Ok();
+            if let Some(cursor) = next_cursor {
+                response.insert_header(("X-Next-Cursor", cursor));
+            }
+            response.json(keys)
+        }
+        Err(e) => HttpResponse::BadRequest().body(format!("Invalid regex pattern: {}", e)),
+    }
+}
+
+async fn delete_by_regex(
+    store: web::Data<KvStore>,
+    reserved: web::Data<Reserved>,
+    path: web::Path<String>,
+    query: web::Query<BulkDeleteQuery>,
+) -> impl Responder {
+    let pattern = path.into_inner();
+    let result = Regex::new(&pattern).map(|re| {
+        let matches = |k: &str| re.is_match(k) && !reserved.contains(k);
+        if query.dry_run.unwrap_or(false) {
+            dry_run_response(store.keys_where(matches))
+        } else {
+            match store.delete_where(matches) {
+                Ok(count) => HttpResponse::Ok().json(serde_json::json!({
+                    "deleted_count": count
+                })),
+                Err(e) => write_error(e),
+            }
+        }
+    });
+    result.unwrap_or_else(|e| {
+        HttpResponse::BadRequest().body(format!("Invalid regex pattern: {}", e))
+    })
+}
+
+#[derive(Deserialize)]
+struct BatchItem {
+    key: String,
+    value: String,
+    /// Write only if the key is at this version, or 0 if it must not exist.
+    expected_version: Option<u64>,
+    /// Write only if the key holds this value.
+    expected_value: Option<String>,
+    /// Seconds the key has to live.
+    ttl: Option<u64>,
+}
+
+impl BatchItem {
+    fn is_conditional(&self) -> bool {
+        self.expected_version.is_some() || self.expected_value.is_some()
+    }
+}
+
+/// Sets each item on its own, or when any item has a condition, all of
+/// them at once if every condition holds and none of them otherwise.
+async fn batch_set(
+    store: web::Data<KvStore>,
+    reserved: web::Data<Reserved>,
+    items: web::Json<Vec<BatchItem>>,
+) -> impl Responder {
+    let items = items.into_inner();
+    // Refuse the whole batch, as skipping an entry would look like success.
+    if let Some(e) = items
+        .iter()
+        .find_map(|item| reserved.check(&item.key).err())
+    {
+        return HttpResponse::Forbidden().body(e);
+    }
+    if items.iter().any(|item| item.ttl == Some(0)) {
+        return HttpResponse::BadRequest().body("ttl must be a number of seconds above 0");
+    }
+    // Keys the batch creates have no type, and the others keep theirs.
+    let mut value_types = Vec::with_capacity(items.len());
+    for item in &items {
+        let created = !store.exists(&item.key);
+        match types::check_write(&store, &item.key, &item.value, None, created) {
+            Ok(value_type) => value_types.push(types::tag(value_type)),
+            Err(e) => return HttpResponse::UnprocessableEntity().body(e),
+        }
+    }
+
+    if items.iter().any(BatchItem::is_conditional) {
+        let writes = items
+            .into_iter()
+            .zip(value_types)
+            .map(|(item, value_type)| CasWrite {
+                key: item.key,
+                value: item.value,
+                expected_version: item.expected_version,
+                expected_value: item.expected_value,
+                attributes: Attributes {
+                    expires_at: expiry::expires_at(item.ttl),
+                    value_type,
+                    ..Attributes::NONE
+                },
+            })
+            .collect::<Vec<_>>();
+        let count = writes.len();
+        return match store.compare_and_swap(writes) {
+            Ok(failed) if failed.is_empty() => HttpResponse::Ok().json(serde_json::json!({
+                "success_count": count
+            })),
+            Ok(failed) => HttpResponse::Conflict().json(serde_json::json!({
+                "success_count": 0,
+                "failed": failed
+            })),
+            Err(e) => write_error(e),
+        };
+    }
+
+    let items: Vec<(String, String, Attributes)> = items
+        .into_iter()
+        .zip(value_types)
+        .map(|(item, value_type)| {
+            let attributes = Attributes {
+                expires_at: expiry::expires_at(item.ttl),
+                value_type,
+                ..Attributes::NONE
+            };
+            (item.key, item.value, attributes)
+        })
+        .collect();
+    match store.batch_set(items) {
+        Ok(count) => HttpResponse::Ok().json(serde_json::json!({
+            "success_count": count
+        })),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct ImportRecord {
+    key: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+struct ImportError {
+    line: usize,
+    error: String,
+}
+
+#[derive(Serialize, Default)]
+struct ImportSummary {
+    imported: usize,
+    failed: usize,
+    /// The first `MAX_IMPORT_ERRORS` failures; `failed` has the full count.
+    errors: Vec<ImportError>,
+}
+
+impl ImportSummary {
+    fn apply_line(
+        &mut self,
+        store: &KvStore,
+        reserved: &Reserved,
+        line_number: usize,
+        line: &[u8],
+    ) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return;
+        }
+        let result = serde_json::from_slice::<ImportRecord>(line)
+            .map_err(|e| format!("Invalid record: {}", e))
+            .and_then(|record| {
+                reserved.check(&record.key)?;
+                types::write_untyped(store, record.key, record.value)
+            });
+        match result {
+            Ok(()) => self.imported += 1,
+            Err(error) => {
+                self.failed += 1;
+                if self.errors.len() < MAX_IMPORT_ERRORS {
+                    self.errors.push(ImportError {
+                        line: line_number,
+                        error,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Applies newline-delimited `{"key": ..., "value": ...}` records as they
+/// arrive, so the request body never has to fit in memory. Extra fields,
+/// such as the metadata written by `/scan`, are ignored.
+async fn import_ndjson(
+    store: web::Data<KvStore>,
+    reserved: web::Data<Reserved>,
+    mut payload: web::Payload,
+) -> impl Responder {
+    // A record holds a key and a value, plus JSON escaping overhead.
+    let limits = store.limits();
+    let max_line = 2 * (limits.max_key_size + limits.max_value_size) + 1024;
+    let mut summary = ImportSummary::default();
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut line_number = 0;
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                return HttpResponse::BadRequest().body(format!("Failed to read body: {}", e));
+            }
+        };
+        buffer.extend_from_slice(&chunk);
+
+        let mut start = 0;
+        while let Some(offset) = buffer[start..].iter().position(|&b| b == b'\n') {
+            line_number += 1;
+            summary.apply_line(
+                &store,
+                &reserved,
+                line_number,
+                &buffer[start..start + offset],
+            );
+            start += offset + 1;
+        }
+        buffer.drain(..start);
+
+        if buffer.len() > max_line {
+            return HttpResponse::BadRequest().body(format!(
+                "Line {} exceeds the maximum record size",
+                line_number + 1
+            ));
+        }
+    }
+    if !buffer.is_empty() {
+        summary.apply_line(&store, &reserved, line_number + 1, &buffer);
+    }
+
+    HttpResponse::Ok().json(summary)
+}
+
+#[derive(Deserialize)]
+struct RdbImportQuery {
+    db: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct RdbImportError {
+    key: String,
+    error: String,
+}
+
+#[derive(Serialize, Default)]
+struct RdbImportSummary {
+    imported: usize,
+    failed: usize,
+    /// Keys whose Redis TTL had already passed; Redis drops these on load too.
+    expired: usize,
+    /// Keys imported without the TTL they had in Redis.
+    ttl_dropped: usize,
+    /// Non-string keys that were skipped, by Redis type.
+    skipped: BTreeMap<&'static str, usize>,
+    errors: Vec<RdbImportError>,
+}
+
+impl RdbImportSummary {
+    fn apply_entry(
+        &mut self,
+        store: &KvStore,
+        reserved: &Reserved,
+        entry: rdb::RdbEntry,
+        now_ms: u64,
+    ) {
+        if entry.expires_at_ms.is_some_and(|at| at <= now_ms) {
+            self.expired += 1;
+            return;
+        }
+        let key = String::from_utf8(entry.key);
+        let result = match (&key, String::from_utf8(entry.value)) {
+            (Ok(key), Ok(value)) => reserved
+                .check(key)
+                .and_then(|()| types::write_untyped(store, key.clone(), value)),
+            (Err(_), _) => Err("Key is not valid UTF-8".to_string()),
+            (_, Err(_)) => Err("Value is not valid UTF-8".to_string()),
+        };
+        match result {
+            Ok(()) => {
+                self.imported += 1;
+                if entry.expires_at_ms.is_some() {
+                    self.ttl_dropped += 1;
+                }
+            }
+            Err(error) => {
+                self.failed += 1;
+                if self.errors.len() < MAX_IMPORT_ERRORS {
+                    let key = match key {
+                        Ok(key) => key,
+                        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+                    };
+                    self.errors.push(RdbImportError { key, error });
+                }
+            }
+        }
+    }
+}
+
+fn import_rdb_file(
+    store: &KvStore,
+    reserved: &Reserved,
+    path: &std::path::Path,
+    db: Option<u64>,
+) -> Result<RdbImportSummary, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mut reader = rdb::RdbReader::new(BufReader::new(file))?;
+    let now_ms = current_timestamp() * 1000;
+    let mut summary = RdbImportSummary::default();
+    let mut skipped: BTreeMap<&'static str, usize> = BTreeMap::new();
+
+    while let Some(entry) = reader.next_entry(&mut |entry_db, kind| {
+        if db.is_none_or(|db| db == entry_db) {
+            *skipped.entry(kind).or_insert(0) += 1;
+        }
+    })? {
+        if db.is_some_and(|db| db != entry.db) {
+            continue;
+        }
+        summary.apply_entry(store, reserved, entry, now_ms);
+    }
+    summary.skipped = skipped;
+    Ok(summary)
+}
+
+/// Loads the string keys of a Redis RDB dump. The upload is spooled to a
+/// temporary file first so large dumps are never held in memory, then parsed
+/// on the blocking pool.
+async fn import_rdb(
+    store: web::Data<KvStore>,
+    reserved: web::Data<Reserved>,
+    limit: web::Data<RdbLimit>,
+    query: web::Query<RdbImportQuery>,
+    mut payload: web::Payload,
+) -> impl Responder {
+    let path = std::env::temp_dir().join(format!(
+        "kstore_import_{}_{}.rdb",
+        std::process::id(),
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    ));
+    let mut file = match File::create(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to create temporary file: {}", e));
+        }
+    };
+
+    let mut size = 0;
+    while let Some(chunk) = payload.next().await {
+        let written = match chunk {
+            Ok(chunk) => {
+                size += chunk.len() as u64;
+                if size > limit.0 {
+                    let _ = std::fs::remove_file(&path);
+                    return HttpResponse::PayloadTooLarge()
+                        .body(format!("The dump is larger than {} bytes", limit.0));
+                }
+                file.write_all(&chunk).map_err(|e| e.to_string())
+            }
+            Err(e) => Err(format!("Failed to read body: {}", e)),
+        };
+        if let Err(e) = written {
+            let _ = std::fs::remove_file(&path);
+            return HttpResponse::BadRequest().body(e);
+        }
+    }
+    drop(file);
+
+    let db = query.db;
+    let spooled = path.clone();
+    let result = web::block(move || import_rdb_file(&store, &reserved, &spooled, db)).await;
+    let _ = std::fs::remove_file(&path);
+
+    match result {
+        Ok(Ok(summary)) => HttpResponse::Ok().json(summary),
+        Ok(Err(e)) => HttpResponse::BadRequest().body(e),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Import failed: {}", e)),
+    }
+}
+
+/// Starts copying string keys from a running Redis instance in the
+/// background. Poll `GET /migrate/redis` for progress.
+async fn start_redis_migration(
+    store: web::Data<KvStore>,
+    reserved: web::Data<Reserved>,
+    migrations: web::Data<Migrations>,
+    request: web::Json<MigrationRequest>,
+) -> impl Responder {
+    match migrations.start(
+        store.into_inner(),
+        reserved.into_inner(),
+        request.into_inner(),
+    ) {
+        Ok(progress) => HttpResponse::Accepted().json(progress),
+        Err(e) if e.starts_with("A migration") => HttpResponse::Conflict().body(e),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+async fn get_redis_migration(migrations: web::Data<Migrations>) -> impl Responder {
+    match migrations.progress() {
+        Some(progress) => HttpResponse::Ok().json(progress),
+        None => HttpResponse::NotFound().body("No migration has been started"),
+    }
+}
+
+async fn cancel_redis_migration(migrations: web::Data<Migrations>) -> impl Responder {
+    if migrations.cancel() {
+        HttpResponse::Ok().body("Migration cancellation requested")
+    } else {
+        HttpResponse::NotFound().body("No migration is running")
+    }
+}
+
+#[derive(Deserialize)]
+struct BackupQuery {
+    target: Option<String>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+async fn create_backup(
+    store: web::Data<KvStore>,
+    s3: web::Data<Option<S3Client>>,
+    query: web::Query<BackupQuery>,
+) -> impl Responder {
+    let kind = match BackupKind::parse(query.kind.as_deref().unwrap_or("full")) {
+        Ok(kind) => kind,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    match query.target.as_deref().unwrap_or("local") {
+        "local" => {
+            let result = web::block(move || {
+                let snapshot = store.backup_snapshot(kind, "local")?;
+                Ok::<_, kstore::Error>(store.write_backup(snapshot))
+            })
+            .await;
+            match result {
+                Ok(Ok(Ok(_))) if kind == BackupKind::Full => {
+                    HttpResponse::Ok().body("Backup created successfully")
+                }
+                Ok(Ok(Ok(name))) => HttpResponse::Ok().json(serde_json::json!({
+                    "target": "local",
+                    "type": kind,
+                    "name": name,
+                })),
+                Ok(Ok(Err(e))) => {
+                    HttpResponse::InternalServerError().body(format!("Backup failed: {}", e))
+                }
+                Ok(Err(e)) => HttpResponse::Conflict().body(e.to_string()),
+                Err(e) => HttpResponse::InternalServerError().body(format!("Backup failed: {}", e)),
+            }
+        }
+        "s3" => {
+            let Some(s3) = s3.as_ref() else {
+                return HttpResponse::BadRequest().body("S3 backups are not configured");
+            };
+            let snapshot_store = store.clone();
+            let result = web::block(move || {
+                let snapshot = snapshot_store.backup_snapshot(kind, "s3")?;
+                let body = snapshot.to_bytes();
+                Ok::<_, kstore::Error>((snapshot, body))
+            })
+            .await;
+            let (snapshot, body) = match result {
+                Ok(Ok(snapshot)) => snapshot,
+                Ok(Err(e)) => return HttpResponse::Conflict().body(e.to_string()),
+                Err(e) => {
+                    return HttpResponse::InternalServerError()
+                        .body(format!("Backup failed: {}", e));
+                }
+            };
+            match s3.put_object(&snapshot.name, body).await {
+                Ok(()) => {
+                    store.backup_completed("s3", &snapshot);
+                    HttpResponse::Ok().json(serde_json::json!({
+                        "target": "s3",
+                        "type": kind,
+                        "name": snapshot.name,
+                        "key": s3.object_key(&snapshot.name),
+                    }))
+                }
+                Err(e) => HttpResponse::InternalServerError().body(format!("Backup failed: {}", e)),
+            }
+        }
+        other => HttpResponse::BadRequest().body(format!("Unknown backup target '{}'", other)),
+    }
+}
+
+/// Restores a backup from S3. An incremental backup is applied on top of
+/// the full backup it was taken against, which is fetched first.
+async fn restore_from_s3(
+    req: HttpRequest,
+    store: web::Data<KvStore>,
+    s3: web::Data<Option<S3Client>>,
+    admin: web::Data<Admin>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(response) = admin.authorize(&req) {
+        return response;
+    }
+    if let Err(response) = confirm(&req, RESTORE_CONFIRM_VALUE, "Replacing every key") {
+        return response;
+    }
+    let Some(s3) = s3.as_ref() else {
+        return HttpResponse::BadRequest().body("S3 backups are not configured");
+    };
+    let name = path.into_inner();
+    let Some((_, _, base)) = parse_backup_name(&name) else {
+        return HttpResponse::BadRequest().body("Invalid backup name");
+    };
+
+    let mut body = Vec::new();
+    for object in base.iter().chain(std::iter::once(&name)) {
+        match s3.get_object(object).await {
+            Ok(Some(bytes)) => body.extend_from_slice(&bytes),
+            Ok(None) => {
+                return HttpResponse::NotFound().body(format!("Backup {} not found", object));
+            }
+            Err(e) => {
+                return HttpResponse::InternalServerError().body(format!("Restore failed: {}", e));
+            }
+        }
+    }
+    match store.restore(&body) {
+        Ok(count) => HttpResponse::Ok().json(serde_json::json!({
+            "name": name,
+            "restored_keys": count,
+        })),
+        Err(e) => write_error(e),
+    }
+}
+
+/// Bytes of records sent at a time by `GET /export/snapshot`.
+const SNAPSHOT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The whole store in the data file format, for seeding a new node, which
+/// loads it like any data file once it is saved as `kvstore.db`. The keys
+/// are copied while the data lock is held, which is brief since values are
+/// shared rather than copied, and then streamed while writes carry on.
+async fn export_snapshot(store: web::Data<KvStore>) -> impl Responder {
+    let snapshot = match web::block(move || store.backup_snapshot(BackupKind::Full, "export")).await
+    {
+        Ok(Ok(snapshot)) => snapshot,
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Snapshot failed: {}", e));
+        }
+    };
+    let size = snapshot.size();
+    let chunks = snapshot
+        .into_chunks(SNAPSHOT_CHUNK_SIZE)
+        .map(|chunk| Ok::<_, actix_web::Error>(Bytes::from(chunk)));
+    HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header(("Content-Disposition", "attachment; filename=\"kvstore.db\""))
+        .body(SizedStream::new(size, futures_util::stream::iter(chunks)))
+}
+
+/// Full snapshot for bootstrapping a replica, in the data file format. The
+/// change log id and sequence the snapshot was taken at are returned in
+/// headers so the replica knows where to start tailing changes.
+async fn replication_snapshot(
+    req: HttpRequest,
+    store: web::Data<KvStore>,
+    node_secret: web::Data<NodeSecret>,
+) -> impl Responder {
+    if let Err(response) = node_secret.authorize(&req) {
+        return response;
+    }
+    let (log, _) = store.change_position();
+    let result = web::block(move || {
+        let snapshot = store.backup_snapshot(BackupKind::Full, "replication")?;
+        let body = snapshot.to_bytes();
+        Ok::<_, kstore::Error>((snapshot.seq, body))
+    })
+    .await;
+    match result {
+        Ok(Ok((seq, body))) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .insert_header((replication::LOG_HEADER, log.to_string()))
+            .insert_header((replication::SEQ_HEADER, seq.to_string()))
+            .body(body),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Snapshot failed: {}", e)),
+    }
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    log: u64,
+    since: u64,
+    limit: Option<usize>,
+}
+
+async fn replication_changes(
+    req: HttpRequest,
+    store: web::Data<KvStore>,
+    node_secret: web::Data<NodeSecret>,
+    query: web::Query<ChangesQuery>,
+) -> impl Responder {
+    if let Err(response) = node_secret.authorize(&req) {
+        return response;
+    }
+    let limit = query
+        .limit
+        .unwrap_or(replication::MAX_CHANGES_PER_POLL)
+        .min(replication::MAX_CHANGES_PER_POLL);
+    match store.changes_since(query.log, query.since, limit) {
+        Some(batch) => HttpResponse::Ok().json(batch),
+        None => {
+            HttpResponse::Gone().body("Changes are no longer available, bootstrap from a snapshot")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    log: u64,
+    since: u64,
+}
+
+async fn replication_stream(
+    req: HttpRequest,
+    store: web::Data<KvStore>,
+    shutdown: web::Data<Shutdown>,
+    node_secret: web::Data<NodeSecret>,
+    query: web::Query<StreamQuery>,
+) -> impl Responder {
+    if let Err(response) = node_secret.authorize(&req) {
+        return response;
+    }
+    if store.changes_since(query.log, query.since, 0).is_none() {
+        return HttpResponse::Gone()
+            .body("Changes are no longer available, bootstrap from a snapshot");
+    }
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        // Keep the compression middleware from buffering records.
+        .insert_header(("Content-Encoding", "identity"))
+        .streaming(
+            replication::change_stream(store, query.log, query.since)
+                .take_until(shutdown.started()),
+        )
+}
+
+#[derive(Deserialize)]
+struct MerkleQuery {
+    depth: Option<usize>,
+}
+
+/// This node's Merkle tree, down to `depth` levels below the root.
+async fn replication_merkle(
+    req: HttpRequest,
+    store: web::Data<KvStore>,
+    node_secret: web::Data<NodeSecret>,
+    query: web::Query<MerkleQuery>,
+) -> impl Responder {
+    if let Err(response) = node_secret.authorize(&req) {
+        return response;
+    }
+    let depth = query.depth.unwrap_or(merkle::DEPTH);
+    match web::block(move || store.merkle_tree().truncate(depth)).await {
+        Ok(tree) => HttpResponse::Ok().json(tree),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Merkle tree failed: {}", e)),
+    }
+}
+
+async fn replication_merkle_leaf(
+    req: HttpRequest,
+    store: web::Data<KvStore>,
+    node_secret: web::Data<NodeSecret>,
+    path: web::Path<usize>,
+) -> impl Responder {
+    if let Err(response) = node_secret.authorize(&req) {
+        return response;
+    }
+    let leaf = path.into_inner();
+    if leaf >= merkle::LEAVES {
+        return HttpResponse::BadRequest().body(format!("Leaf must be below {}", merkle::LEAVES));
+    }
+    match web::block(move || store.merkle_leaf(leaf)).await {
+        Ok(keys) => HttpResponse::Ok().json(keys),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Merkle leaf failed: {}", e)),
+    }
+}
+
+async fn replication_values(
+    req: HttpRequest,
+    store: web::Data<KvStore>,
+    node_secret: web::Data<NodeSecret>,
+    keys: web::Json<Vec<String>>,
+) -> impl Responder {
+    if let Err(response) = node_secret.authorize(&req) {
+        return response;
+    }
+    if keys.len() > replication::MAX_VALUES_PER_REQUEST {
+        return HttpResponse::BadRequest().body(format!(
+            "At most {} keys can be fetched at once",
+            replication::MAX_VALUES_PER_REQUEST
+        ));
+    }
+    HttpResponse::Ok().json(store.values(&keys))
+}
+
+fn replication_status_body(store: &KvStore, replication: &Replication) -> serde_json::Value {
+    let (log, seq) = store.change_position();
+    let status = replication.status();
+    let mut body = serde_json::json!({
+        "role": status.role,
+        "change_log": log,
+        "seq": seq,
+    });
+    if status.role == replication::Role::Replica {
+        body["replication"] = serde_json::to_value(status).unwrap();
+    }
+    body
+}
+
+async fn replication_status(
+    store: web::Data<KvStore>,
+    replication: web::Data<Replication>,
+) -> impl Responder {
+    HttpResponse::Ok().json(replication_status_body(&store, &replication))
+}
+
+#[derive(Deserialize)]
+struct FollowRequest {
+    primary: String,
+}
+
+/// Turns this node into a replica of `primary`. The local dataset is
+/// replaced by the primary's once the snapshot has been downloaded.
+async fn replication_follow(
+    req: HttpRequest,
+    store: web::Data<KvStore>,
+    replication: web::Data<Replication>,
+    cluster: web::Data<Option<Cluster>>,
+    admin: web::Data<Admin>,
+    request: web::Json<FollowRequest>,
+) -> impl Responder {
+    if let Err(response) = admin.authorize(&req) {
+        return response;
+    }
+    if cluster.is_some() {
+        return HttpResponse::Conflict().body("Replication is managed by the cluster");
+    }
+    let primary = request.into_inner().primary;
+    if reqwest::Url::parse(&primary).is_err() {
+        return HttpResponse::BadRequest().body("Invalid primary URL");
+    }
+    Replication::start(replication.clone(), store.clone(), primary);
+    HttpResponse::Accepted().json(replication_status_body(&store, &replication))
+}
+
+async fn replication_promote(
+    req: HttpRequest,
+    store: web::Data<KvStore>,
+    replication: web::Data<Replication>,
+    cluster: web::Data<Option<Cluster>>,
+    admin: web::Data<Admin>,
+) -> impl Responder {
+    if let Err(response) = admin.authorize(&req) {
+        return response;
+    }
+    if cluster.is_some() {
+        return HttpResponse::Conflict().body("Replication is managed by the cluster");
+    }
+    if replication.promote() {
+        HttpResponse::Ok().json(replication_status_body(&store, &replication))
+    } else {
+        HttpResponse::Conflict().body("This node is not a replica")
+    }
+}
+
+async fn cluster_status(cluster: web::Data<Option<Cluster>>) -> impl Responder {
+    match cluster.as_ref() {
+        Some(cluster) => HttpResponse::Ok().json(cluster.status()),
+        None => HttpResponse::NotFound().body("Cluster mode is not enabled"),
+    }
+}
+
+async fn cluster_vote(
+    req: HttpRequest,
+    store: web::Data<KvStore>,
+    replication: web::Data<Replication>,
+    cluster: web::Data<Option<Cluster>>,
+    node_secret: web::Data<NodeSecret>,
+    request: web::Json<VoteRequest>,
+) -> impl Responder {
+    if let Err(response) = node_secret.authorize(&req) {
+        return response;
+    }
+    match cluster.as_ref() {
+        Some(cluster) => {
+            HttpResponse::Ok().json(cluster.handle_vote(request.into_inner(), &store, &replication))
+        }
+        None => HttpResponse::NotFound().body("Cluster mode is not enabled"),
+    }
+}
+
+async fn cluster_heartbeat(
+    req: HttpRequest,
+    store: web::Data<KvStore>,
+    replication: web::Data<Replication>,
+    cluster: web::Data<Option<Cluster>>,
+    node_secret: web::Data<NodeSecret>,
+    request: web::Json<HeartbeatRequest>,
+) -> impl Responder {
+    if let Err(response) = node_secret.authorize(&req) {
+        return response;
+    }
+    match cluster.as_ref() {
+        Some(cluster) => HttpResponse::Ok().json(
+            cluster
+                .handle_heartbeat(request.into_inner(), store, replication)
+                .await,
+        ),
+        None => HttpResponse::NotFound().body("Cluster mode is not enabled"),
+    }
+}
+
+async fn gossip(
+    req: HttpRequest,
+    membership: web::Data<Option<Membership>>,
+    node_secret: web::Data<NodeSecret>,
+    message: web::Json<GossipMessage>,
+) -> impl Responder {
+    if let Err(response) = node_secret.authorize(&req) {
+        return response;
+    }
+    let Some(membership) = membership.as_ref() else {
+        return HttpResponse::NotFound().body("Membership is only used in cluster and shard mode");
+    };
+    match membership.receive(message.into_inner()) {
+        Ok(reply) => HttpResponse::Ok().json(reply),
+        Err(e) => HttpResponse::Conflict().body(e),
+    }
+}
+
+async fn get_members(membership: web::Data<Option<Membership>>) -> impl Responder {
+    match membership.as_ref() {
+        Some(membership) => HttpResponse::Ok().json(membership.status()),
+        None => HttpResponse::NotFound().body("Membership is only used in cluster and shard mode"),
+    }
+}
+
+#[derive(Deserialize)]
+struct RemoveMemberQuery {
+    url: String,
+}
+
+async fn remove_member(
+    req: HttpRequest,
+    membership: web::Data<Option<Membership>>,
+    cluster: web::Data<Option<Cluster>>,
+    sharding: web::Data<Option<Sharding>>,
+    admin: web::Data<Admin>,
+    query: web::Query<RemoveMemberQuery>,
+) -> impl Responder {
+    if let Err(response) = admin.authorize(&req) {
+        return response;
+    }
+    let Some(membership) = membership.as_ref() else {
+        return HttpResponse::NotFound().body("Membership is only used in cluster and shard mode");
+    };
+    if let Err(e) = membership.remove(&query.url) {
+        return HttpResponse::Conflict().body(e);
+    }
+    let nodes = membership.nodes();
+    if let Some(cluster) = cluster.as_ref() {
+        cluster.set_nodes(&nodes);
+    }
+    if let Some(sharding) = sharding.as_ref() {
+        sharding.set_nodes(&nodes);
+    }
+    HttpResponse::Ok().json(membership.status())
+}
+
+async fn shard_status(sharding: web::Data<Option<Sharding>>) -> impl Responder {
+    match sharding.as_ref() {
+        Some(sharding) => HttpResponse::Ok().json(sharding.status()),
+        None => HttpResponse::NotFound().body("Sharding is not enabled"),
+    }
+}
+
+async fn shard_owner(
+    sharding: web::Data<Option<Sharding>>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let key = path.into_inner();
+    match sharding.as_ref() {
+        Some(sharding) => HttpResponse::Ok().json(serde_json::json!({
+            "key": key,
+            "owner": sharding.owner(&key),
+        })),
+        None => HttpResponse::NotFound().body("Sharding is not enabled"),
+    }
+}
+
+async fn get_backups() -> impl Responder {
+    match list_backups() {
+        Ok(backups) => HttpResponse::Ok().json(backups),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list backups: {}", e))
+        }
+    }
+}
+
+async fn download_backup(path: web::Path<String>) -> impl Responder {
+    let name = path.into_inner();
+    if parse_backup_name(&name).is_none() {
+        return HttpResponse::BadRequest().body("Invalid backup name");
+    }
+    let file = match File::open(&name) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return HttpResponse::NotFound().body("Backup not found");
+        }
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to open backup: {}", e));
+        }
+    };
+    let size = match file.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to open backup: {}", e));
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", name),
+        ))
+        .no_chunking(size)
+        .streaming(file_stream(file))
+}
+
+#[derive(Deserialize)]
+struct SubscribeQuery {
+    pattern: Option<String>,
+    events: Option<String>,
+}
+
+async fn subscribe(
+    store: web::Data<KvStore>,
+    shutdown: web::Data<Shutdown>,
+    query: web::Query<SubscribeQuery>,
+) -> impl Responder {
+    let query = query.into_inner();
+    let kinds = match query.events {
+        Some(events) => {
+            match events
+                .split(',')
+                .map(|e| EventKind::parse(e.trim()))
+                .collect()
+            {
+                Ok(kinds) => Some(kinds),
+                Err(e) => return HttpResponse::BadRequest().body(e),
+            }
+        }
+        None => None,
+    };
+    let filter = EventFilter {
+        pattern: query.pattern,
+        kinds,
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        // Keep the compression middleware from buffering events.
+        .insert_header(("Content-Encoding", "identity"))
+        .streaming(sse_stream(store.subscribe(), filter).take_until(shutdown.started()))
+}
+
+async fn pubsub_publish(
+    pubsub: web::Data<PubSub>,
+    path: web::Path<String>,
+    body: String,
+) -> impl Responder {
+    let receivers = pubsub.publish(&path.into_inner(), body);
+    HttpResponse::Ok().json(serde_json::json!({ "receivers": receivers }))
+}
+
+async fn pubsub_subscribe(
+    pubsub: web::Data<PubSub>,
+    shutdown: web::Data<Shutdown>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let receiver = pubsub.subscribe(&path.into_inner());
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        // Keep the compression middleware from buffering messages.
+        .insert_header(("Content-Encoding", "identity"))
+        .streaming(pubsub::sse_stream(receiver).take_until(shutdown.started()))
+}
+
+async fn pubsub_channels(pubsub: web::Data<PubSub>) -> impl Responder {
+    let channels: Vec<_> = pubsub
+        .channels()
+        .into_iter()
+        .map(|(channel, subscribers)| {
+            serde_json::json!({ "channel": channel, "subscribers": subscribers })
+        })
+        .collect();
+    HttpResponse::Ok().json(channels)
+}
+
+async fn geo_add(
+    store: web::Data<KvStore>,
+    reserved: web::Data<Reserved>,
+    path: web::Path<String>,
+    members: web::Json<Vec<GeoMember>>,
+) -> impl Responder {
+    let key = path.into_inner();
+    if let Err(e) = reserved.check(&key) {
+        return HttpResponse::Forbidden().body(e);
+    }
+    match store.geo_add(&key, members.into_inner()) {
+        Ok(added) => HttpResponse::Ok().json(serde_json::json!({
+            "added": added
+        })),
+        Err(e) => write_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct GeoRadiusQuery {
+    lat: f64,
+    lon: f64,
+    radius: f64,
+    unit: Option<String>,
+    count: Option<usize>,
+}
+
+async fn geo_radius(
+    store: web::Data<KvStore>,
+    path: web::Path<String>,
+    query: web::Query<GeoRadiusQuery>,
+) -> impl Responder {
+    let key = path.into_inner();
+    let unit = match DistanceUnit::parse(query.unit.as_deref().unwrap_or("m")) {
+        Ok(unit) => unit,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    match store.geo_radius(&key, query.lat, query.lon, query.radius, unit) {
+        Ok(Some(mut matches)) => {
+            if let Some(count) = query.count {
+                matches.truncate(count);
+            }
+            HttpResponse::Ok().json(matches)
+        }
+        Ok(None) => HttpResponse::NotFound().body("Key not found"),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct GeoBoxQuery {
+    min_lat: f64,
+    min_lon: f64,
+    max_lat: f64,
+    max_lon: f64,
+    count: Option<usize>,
+}
+
+async fn geo_box(
+    store: web::Data<KvStore>,
+    path: web::Path<String>,
+    query: web::Query<GeoBoxQuery>,
+) -> impl Responder {
+    let key = path.into_inner();
+    match store.geo_box(
+        &key,
+        query.min_lat,
+        query.min_lon,
+        query.max_lat,
+        query.max_lon,
+    ) {
+        Ok(Some(mut matches)) => {
+            if let Some(count) = query.count {
+                matches.truncate(count);
+            }
+            HttpResponse::Ok().json(matches)
+        }
+        Ok(None) => HttpResponse::NotFound().body("Key not found"),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+async fn mirror_status(mirror: web::Data<Option<Mirror>>) -> impl Responder {
+    match mirror.as_ref() {
+        Some(mirror) => HttpResponse::Ok().json(mirror.status()),
+        None => HttpResponse::NotFound().body("Mirroring is not enabled"),
+    }
+}
+
+async fn manual_compact(store: web::Data<KvStore>) -> impl Responder {
+    match store.compact() {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(e) => write_error(e),
+    }
+}
+
+async fn compaction_stats(store: web::Data<KvStore>) -> impl Responder {
+    HttpResponse::Ok().json(store.compaction_stats())
+}
+
+async fn debug_cpu_profile(
+    req: HttpRequest,
+    admin: web::Data<Admin>,
+    query: web::Query<ProfileQuery>,
+) -> impl Responder {
+    if let Err(response) = admin.authorize(&req) {
+        return response;
+    }
+    profiling::cpu_profile(&query).await
+}
+
+async fn debug_heap(req: HttpRequest, admin: web::Data<Admin>) -> impl Responder {
+    if let Err(response) = admin.authorize(&req) {
+        return response;
+    }
+    HttpResponse::Ok().json(profiling::heap_stats())
+}
+
+/// Shuts down the same way as on SIGTERM, once this response is sent.
+async fn admin_shutdown(
+    req: HttpRequest,
+    admin: web::Data<Admin>,
+    shutdown: web::Data<Shutdown>,
+) -> impl Responder {
+    if let Err(response) = admin.authorize(&req) {
+        return response;
+    }
+    shutdown.start();
+    HttpResponse::Accepted().body("Shutting down")
+}
+
+#[derive(Deserialize)]
+struct ReadOnlyQuery {
+    enabled: Option<bool>,
+}
+
+async fn get_read_only(
+    req: HttpRequest,
+    admin: web::Data<Admin>,
+    maintenance: web::Data<Maintenance>,
+) -> impl Responder {
+    if let Err(response) = admin.authorize(&req) {
+        return response;
+    }
+    HttpResponse::Ok().json(serde_json::json!({ "readonly": maintenance.is_read_only() }))
+}
+
+/// Turns read-only mode on, or off with `?enabled=false`.
+async fn set_read_only(
+    req: HttpRequest,
+    admin: web::Data<Admin>,
+    maintenance: web::Data<Maintenance>,
+    query: web::Query<ReadOnlyQuery>,
+) -> impl Responder {
+    if let Err(response) = admin.authorize(&req) {
+        return response;
+    }
+    let enabled = query.enabled.unwrap_or(true);
+    maintenance.set_read_only(enabled);
+    HttpResponse::Ok().json(serde_json::json!({ "readonly": enabled }))
+}
+
+/// POST endpoints that do not change any data, so replicas still serve
+/// them.
+const READ_ONLY_POSTS: [&str; 3] = ["/info", "/backup", "/compact"];
+
+/// Endpoints that manage the node or carry messages rather than its data,
+/// which every node serves.
+const CONTROL_PREFIXES: [&str; 6] = [
+    "/replication/",
+    "/cluster/",
+    "/gossip",
+    "/members",
+    "/admin/",
+    "/pubsub/",
+];
+
+/// Whether `req` leaves the data alone, either by reading it or by
+/// managing the node.
+fn is_read_only(req: &ServiceRequest) -> bool {
+    matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS)
+        || (req.method() == Method::POST && READ_ONLY_POSTS.contains(&req.path()))
+        || CONTROL_PREFIXES
+            .iter()
+            .any(|prefix| req.path().starts_with(prefix))
+}
+
+/// Replicas serve reads from their replicated copy but must not diverge
+/// from the primary, so every mutating request is refused. In cluster mode
+/// the same goes for every node but the leader.
+async fn reject_writes_on_replica(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    if !is_read_only(&req) {
+        let cluster = req
+            .app_data::<web::Data<Option<Cluster>>>()
+            .and_then(|cluster| cluster.as_ref().as_ref());
+        let response = match cluster {
+            Some(cluster) if !cluster.is_leader() => Some(match cluster.leader() {
+                Some(leader) => HttpResponse::Forbidden().body(format!(
+                    "This node is not the cluster leader, send writes to the leader at {}",
+                    leader
+                )),
+                None => HttpResponse::ServiceUnavailable()
+                    .body("No cluster leader is available, try again shortly"),
+            }),
+            Some(_) => None,
+            None => req
+                .app_data::<web::Data<Replication>>()
+                .and_then(|replication| replication.primary())
+                .map(|primary| {
+                    HttpResponse::Forbidden().body(format!(
+                        "This node is a read-only replica, send writes to the primary at {}",
+                        primary
+                    ))
+                }),
+        };
+        if let Some(response) = response {
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+#[derive(Deserialize)]
+struct ConsistencyQuery {
+    consistency: Option<Consistency>,
+    max_staleness: Option<u64>,
+}
+
+/// Reads on a replica choose how fresh their data must be. `strong` reads
+/// are proxied to the primary (in cluster mode, the leader), `bounded`
+/// reads only if this replica has not heard from the primary recently, and
+/// `eventual` reads, the default, are served from the local copy.
+async fn route_reads_by_consistency(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let forwarded = req
+        .app_data::<web::Data<NodeSecret>>()
+        .is_some_and(|secret| secret.is_forwarded(&req));
+    if !matches!(*req.method(), Method::GET | Method::HEAD) || forwarded {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    }
+    let query = match web::Query::<ConsistencyQuery>::from_query(req.query_string()) {
+        Ok(query) => query.into_inner(),
+        Err(e) => {
+            let response = HttpResponse::BadRequest().body(e.to_string());
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    };
+    let replication = req.app_data::<web::Data<Replication>>().cloned();
+    let cluster = req.app_data::<web::Data<Option<Cluster>>>().cloned();
+    let cluster = cluster
+        .as_ref()
+        .and_then(|cluster| cluster.as_ref().as_ref());
+    let max_staleness = query
+        .max_staleness
+        .unwrap_or(replication::DEFAULT_MAX_STALENESS);
+
+    let local = match query.consistency.unwrap_or(Consistency::Eventual) {
+        Consistency::Eventual => true,
+        Consistency::Bounded => replication.as_ref().is_none_or(|replication| {
+            replication.primary().is_none() || replication.is_fresh(max_staleness)
+        }),
+        Consistency::Strong => false,
+    };
+    if !local {
+        // A cluster node that is not the leader defers to the leader, even
+        // before it has started replicating from it.
+        let primary = match cluster {
+            Some(cluster) if cluster.is_leader() => None,
+            Some(cluster) => match cluster.leader() {
+                Some(leader) => Some(leader),
+                None => {
+                    let response = HttpResponse::ServiceUnavailable()
+                        .body("No cluster leader is available, try again shortly");
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            },
+            None => replication
+                .as_ref()
+                .and_then(|replication| replication.primary()),
+        };
+        if let (Some(primary), Some(replication)) = (primary, replication.as_ref()) {
+            let via = req.connection_info().host().to_string();
+            let secret = req
+                .app_data::<web::Data<NodeSecret>>()
+                .map(|secret| secret.get_ref().clone())
+                .unwrap_or_default();
+            let response = proxy::forward(
+                replication.client(),
+                &secret,
+                &primary,
+                &via,
+                &req,
+                Bytes::new(),
+            )
+            .await;
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// Refuses mutating requests while an operator has put the node in
+/// read-only mode through `POST /admin/readonly`, or while the disk is
+/// full.
+async fn reject_writes_in_maintenance(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let maintenance = req
+        .app_data::<web::Data<Maintenance>>()
+        .is_some_and(|maintenance| maintenance.is_read_only());
+    if maintenance && !is_read_only(&req) {
+        let response = HttpResponse::ServiceUnavailable()
+            .body("The server is in read-only mode, writes are disabled");
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    let disk_full = req
+        .app_data::<web::Data<KvStore>>()
+        .is_some_and(|store| store.disk_full().is_some());
+    if disk_full && !is_read_only(&req) {
+        let response =
+            HttpResponse::InsufficientStorage().body(kstore::Error::DiskFull.to_string());
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// Appends every request to the `--access-log` file once its response is
+/// ready.
+async fn log_access(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let access_log = req.app_data::<web::Data<Option<AccessLog>>>().cloned();
+    let Some(access_log) = access_log.filter(|access_log| access_log.is_some()) else {
+        return next.call(req).await;
+    };
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let path = req.uri().to_string();
+    let remote_addr = req
+        .connection_info()
+        .realip_remote_addr()
+        .map(str::to_string);
+    let user_agent = req
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|agent| agent.to_str().ok())
+        .map(str::to_string);
+    let response = next.call(req).await;
+    let (status, response_bytes) = match &response {
+        Ok(response) => (
+            response.status(),
+            match response.response().body().size() {
+                BodySize::Sized(size) => Some(size),
+                _ => None,
+            },
+        ),
+        Err(e) => (e.as_response_error().status_code(), None),
+    };
+    access_log.as_ref().as_ref().unwrap().write(&Entry {
+        timestamp: current_timestamp(),
+        remote_addr: remote_addr.as_deref(),
+        method: &method,
+        path: &path,
+        status: status.as_u16(),
+        response_bytes,
+        duration_ms: start.elapsed().as_millis() as u64,
+        user_agent: user_agent.as_deref(),
+    });
+    response
+}
+
+/// Counts every request towards the client that sent it, for
+/// `GET /stats/clients`.
+async fn count_client_usage(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let Some(clients) = req.app_data::<web::Data<Clients>>().cloned() else {
+        return next.call(req).await;
+    };
+    let client = clients::identify(&req);
+    // Bodies sent without a length are not counted.
+    let bytes_written = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let response = next.call(req).await;
+    let (status, bytes_read) = match &response {
+        Ok(response) => (
+            response.status(),
+            match response.response().body().size() {
+                BodySize::Sized(size) => size,
+                _ => 0,
+            },
+        ),
+        Err(e) => (e.as_response_error().status_code(), 0),
+    };
+    let error = status.is_client_error() || status.is_server_error();
+    clients.record(client, bytes_read, bytes_written, error);
+    response
+}
+
+/// Replays the stored response when a `POST /kv/{key}` or `POST /batch`
+/// is retried with the same `Idempotency-Key`. Only responses below 500
+/// are kept, so a retry after a server error runs again.
+async fn remember_idempotent_writes(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let idempotency = req.app_data::<web::Data<Idempotency>>().cloned();
+    let key = req
+        .headers()
+        .get(idempotency::HEADER)
+        .map(|value| value.to_str().map(str::to_string));
+    let applies =
+        req.method() == Method::POST && (req.path() == "/batch" || req.path().starts_with("/kv/"));
+    let (Some(idempotency), Some(key), true) = (idempotency, key, applies) else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    };
+    if !idempotency.is_enabled() {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    }
+    let key = match key {
+        Ok(key) if !key.is_empty() && key.len() <= idempotency::MAX_KEY_LENGTH => key,
+        _ => {
+            let response = HttpResponse::BadRequest().body(format!(
+                "{} must be 1 to {} visible ASCII characters",
+                idempotency::HEADER,
+                idempotency::MAX_KEY_LENGTH
+            ));
+            return Ok(req.into_response(response).map_into_boxed_body());
+        }
+    };
+
+    let body = req.extract::<Bytes>().await?;
+    let mut hasher = Sha256::new();
+    hasher.update(req.path().as_bytes());
+    hasher.update(b"\n");
+    hasher.update(&body);
+    let fingerprint = hasher.finalize().into();
+    req.set_payload(body.into());
+
+    let response = match idempotency.begin(&key, fingerprint) {
+        Lookup::New => None,
+        Lookup::Replay(stored) => {
+            let mut response = HttpResponse::build(stored.status);
+            if let Some(content_type) = stored.content_type {
+                response.insert_header((CONTENT_TYPE, content_type));
+            }
+            Some(
+                response
+                    .insert_header((idempotency::REPLAYED_HEADER, "true"))
+                    .body(stored.body),
+            )
+        }
+        Lookup::InProgress => Some(HttpResponse::Conflict().body(format!(
+            "A request with this {} is still being processed",
+            idempotency::HEADER
+        ))),
+        Lookup::Mismatch => Some(HttpResponse::UnprocessableEntity().body(format!(
+            "This {} was already used for a different request",
+            idempotency::HEADER
+        ))),
+    };
+    if let Some(response) = response {
+        return Ok(req.into_response(response).map_into_boxed_body());
+    }
+
+    let response = match next.call(req).await {
+        Ok(response) => response,
+        Err(e) => {
+            idempotency.finish(&key, None);
+            return Err(e);
+        }
+    };
+    let (req, response) = response.into_parts();
+    let (response, body) = response.into_parts();
+    let body = match actix_web::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            idempotency.finish(&key, None);
+            return Err(actix_web::error::ErrorInternalServerError(e.into()));
+        }
+    };
+    let status = response.status();
+    idempotency.finish(
+        &key,
+        (!status.is_server_error()).then(|| StoredResponse {
+            status,
+            content_type: response.headers().get(CONTENT_TYPE).cloned(),
+            body: body.clone(),
+        }),
+    );
+    Ok(ServiceResponse::new(req, response.set_body(body)).map_into_boxed_body())
+}
+
+/// In cluster mode the leader only acknowledges a write once a majority of
+/// the nodes hold it, so it survives the leader failing. A write no
+/// majority confirms in time has still been applied by the leader and may
+/// or may not survive a failover, which the client is told.
+async fn wait_for_quorum(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let cluster = req.app_data::<web::Data<Option<Cluster>>>().cloned();
+    let store = req.app_data::<web::Data<KvStore>>().cloned();
+    let read_only = is_read_only(&req);
+    let response = next.call(req).await?;
+    let (Some(cluster), Some(store)) = (cluster, store) else {
+        return Ok(response.map_into_left_body());
+    };
+    let Some(cluster) = cluster.as_ref().as_ref() else {
+        return Ok(response.map_into_left_body());
+    };
+    if read_only || !response.status().is_success() {
+        return Ok(response.map_into_left_body());
+    }
+    let (_, seq) = store.change_position();
+    if cluster.wait_for_commit(seq).await {
+        return Ok(response.map_into_left_body());
+    }
+    let (req, _) = response.into_parts();
+    let response = HttpResponse::ServiceUnavailable()
+        .body("The write was not confirmed by a majority of the cluster and may be lost");
+    Ok(ServiceResponse::new(req, response).map_into_right_body())
+}
+
+/// With sharding enabled, requests for a single key that another node owns
+/// are proxied to that node. Everything else is served locally.
+async fn forward_to_shard_owner(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let sharding = req.app_data::<web::Data<Option<Sharding>>>().cloned();
+    if let Some(sharding) = sharding
+        .as_ref()
+        .and_then(|sharding| sharding.as_ref().as_ref())
+        && let Some(owner) = sharding.remote_owner(&req)
+    {
+        let body = req.extract::<Bytes>().await?;
+        let response = sharding.forward(&owner, &req, body).await;
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn main() -> std::io::Result<()> {
+    let config = Config::parse();
+    match config.command.clone() {
+        Some(Command::Shell(args)) => shell::run(args),
+        Some(Command::Bench(args)) => bench::run(args),
+        Some(Command::Dump(args)) => dump::run(args),
+        Some(Command::Fsck(args)) => fsck::run(args),
+        None => actix_web::rt::System::new().block_on(serve(config)),
+    }
+}
+
+async fn serve(config: Config) -> std::io::Result<()> {
+    let file_config = config.file().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let backend = config.storage().open().expect("Failed to open storage");
+    let mut store = KvStore::with_backend(backend)
+        .expect("Failed to load data")
+        .with_limits(config.limits());
+    if let Some(deadline) = config.operation_deadline {
+        store = store.with_deadline(Duration::from_millis(deadline));
+    }
+    let metric_prefixes = file_config.metrics.prefixes.clone();
+    if let Some(prefix) = metric_prefixes
+        .iter()
+        .enumerate()
+        .find_map(|(i, prefix)| metric_prefixes[..i].contains(prefix).then_some(prefix))
+    {
+        eprintln!("The metric prefix '{}' is configured twice", prefix);
+        std::process::exit(1);
+    }
+    store = store.with_metric_prefixes(metric_prefixes);
+    let expiry = Expiry::new(file_config.ttl.clone()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let unexpiring = Reserved::new(config.reserved_prefix.clone());
+    store = store.with_expiry(move |key, requested| {
+        if unexpiring.contains(key) {
+            return None;
+        }
+        expiry.expires_at(key, requested, current_timestamp())
+    });
+    if !config.reserved_prefix.is_empty() {
+        // Records kept about a key under the reserved prefix go with it.
+        let namespaces: Vec<String> = [origin::RECORD_NAMESPACE, tiering::RECORD_NAMESPACE]
+            .iter()
+            .map(|namespace| format!("{}{}", config.reserved_prefix, namespace))
+            .collect();
+        store = store.with_companions(move |key| {
+            namespaces
+                .iter()
+                .map(|namespace| reserved::record_key(namespace, key))
+                .collect()
+        });
+    }
+    let store = web::Data::new(store);
+    let payload_limit = web::Data::new(PayloadLimit(config.max_payload_size));
+    let rdb_limit = web::Data::new(RdbLimit(config.max_rdb_size));
+    let migrations = web::Data::new(Migrations::default());
+    let s3 = web::Data::new(S3Config::from_env().map(S3Client::new));
+    let advertise_url = config
+        .advertise_url
+        .clone()
+        .unwrap_or_else(|| format!("http://{}", config.bind));
+    let node_secret = NodeSecret::new(config.node_secret.clone());
+    let replication = web::Data::new(Replication::new(advertise_url.clone(), node_secret.clone()));
+    if let Some(primary) = config.replica_of.clone() {
+        Replication::start(replication.clone(), store.clone(), primary);
+    }
+    let cluster = web::Data::new((!config.cluster_peers.is_empty()).then(|| {
+        Cluster::new(
+            advertise_url.clone(),
+            config.cluster_peers.clone(),
+            node_secret.clone(),
+            std::path::PathBuf::from(cluster::STATE_FILE),
+            config.storage().data_dir().is_some(),
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        })
+    }));
+    let sharding = web::Data::new((!config.shard_peers.is_empty()).then(|| {
+        Sharding::new(
+            advertise_url.clone(),
+            config.shard_peers.clone(),
+            node_secret.clone(),
+        )
+    }));
+    let node_secret = web::Data::new(node_secret);
+    let membership = web::Data::new(if !config.cluster_peers.is_empty() {
+        Some(Membership::new(
+            advertise_url.clone(),
+            "cluster",
+            &config.cluster_peers,
+            node_secret.get_ref().clone(),
+        ))
+    } else if !config.shard_peers.is_empty() {
+        Some(Membership::new(
+            advertise_url.clone(),
+            "shard",
+            &config.shard_peers,
+            node_secret.get_ref().clone(),
+        ))
+    } else {
+        None
+    });
+    let mut tasks = Vec::new();
+    // Without a data file the statistics would not outlive the data.
+    let stats_file = config
+        .storage()
+        .data_dir()
+        .map(|_| std::path::PathBuf::from(stats::STATS_FILE));
+    if let Some(path) = &stats_file {
+        if let Err(e) = stats::load(&store, path) {
+            eprintln!(
+                "Failed to load statistics from {}, starting them over: {}",
+                path.display(),
+                e
+            );
+        }
+        tasks.push(actix_web::rt::spawn(stats::run(
+            store.clone(),
+            path.clone(),
+        )));
+    }
+    if membership.is_some() {
+        tasks.push(actix_web::rt::spawn(gossip::run(
+            membership.clone(),
+            cluster.clone(),
+            sharding.clone(),
+        )));
+    }
+    if cluster.is_some() {
+        tasks.push(actix_web::rt::spawn(cluster::run(
+            cluster.clone(),
+            store.clone(),
+            replication.clone(),
+        )));
+    }
+    let shutdown = web::Data::new(Shutdown::default());
+    let pubsub = web::Data::new(PubSub::default());
+    let admin = web::Data::new(Admin::new(config.admin_token.clone()));
+    let maintenance = web::Data::new(Maintenance::default());
+    let disk_check = web::Data::new(DiskCheck::new(config.storage().data_dir()));
+    let reserved = web::Data::new(Reserved::new(config.reserved_prefix.clone()));
+    let origin = config.origin.clone().map(|template| {
+        Origin::new(
+            template,
+            config.origin_ttl,
+            config.origin_stale,
+            &config.reserved_prefix,
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        })
+    });
+    let origin = web::Data::new(origin);
+    let mirror = config.mirror.clone().map(|url| {
+        // Without a data file there is nothing to resume after a restart.
+        let journal = config
+            .storage()
+            .data_dir()
+            .map(|_| std::path::PathBuf::from(mirror::JOURNAL_FILE));
+        Mirror::new(url, config.mirror_format, journal).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        })
+    });
+    let mirror = web::Data::new(mirror);
+    let tiering = config.tier_threshold.map(|threshold| {
+        Tiering::new(
+            S3Config::from_env().map(S3Client::new),
+            threshold,
+            &config.reserved_prefix,
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        })
+    });
+    let tiering = web::Data::new(tiering);
+    tasks.push(actix_web::rt::spawn(health::recover_from_disk_full(
+        store.clone(),
+    )));
+    if mirror.is_some() {
+        tasks.push(actix_web::rt::spawn(mirror::run(
+            mirror.clone(),
+            store.clone(),
+            reserved.clone(),
+        )));
+    }
+    tasks.push(actix_web::rt::spawn(expiry::run(
+        store.clone(),
+        replication.clone(),
+        cluster.clone(),
+        maintenance.clone(),
+    )));
+    let access_log = config.access_log.clone().map(|path| {
+        let rotation = Rotation {
+            max_size: config.access_log_max_size,
+            max_age: config.access_log_max_age.map(Duration::from_secs),
+            keep: config.access_log_keep,
+            gzip: config.access_log_gzip,
+        };
+        AccessLog::open(path, rotation).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        })
+    });
+    let access_log = web::Data::new(access_log);
+    tasks.push(actix_web::rt::spawn(compaction::checkpoint(
+        store.clone(),
+        config.checkpoint_wal_size,
+    )));
+    if let Some(compaction) = &file_config.compaction {
+        let schedule = compaction::Schedule::new(compaction).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        tasks.push(actix_web::rt::spawn(compaction::run(
+            schedule,
+            store.clone(),
+        )));
+    }
+    let rates = web::Data::new(Rates::default());
+    let clients = web::Data::new(Clients::default());
+    tasks.push(actix_web::rt::spawn(metrics::run(
+        rates.clone(),
+        store.clone(),
+    )));
+    let idempotency = web::Data::new(Idempotency::new(Duration::from_secs(
+        config.idempotency_window,
+    )));
+    let listeners = systemd::listeners()?;
+    let status = if listeners.is_empty() {
+        format!("Server running at http://{}", config.bind)
+    } else {
+        let addrs: Vec<String> = listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .map(|addr| addr.to_string())
+            .collect();
+        format!("Server running on systemd sockets {}", addrs.join(", "))
+    };
+    println!("{}", status);
+    env_logger::init_from_env(Env::default().default_filter_or("info"));
+
+    let (exit_store, exit_shutdown) = (store.clone(), shutdown.clone());
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(store.clone())
+            .app_data(migrations.clone())
+            .app_data(s3.clone())
+            .app_data(replication.clone())
+            .app_data(cluster.clone())
+            .app_data(sharding.clone())
+            .app_data(node_secret.clone())
+            .app_data(membership.clone())
+            .app_data(shutdown.clone())
+            .app_data(pubsub.clone())
+            .app_data(admin.clone())
+            .app_data(maintenance.clone())
+            .app_data(disk_check.clone())
+            .app_data(reserved.clone())
+            .app_data(idempotency.clone())
+            .app_data(rates.clone())
+            .app_data(access_log.clone())
+            .app_data(clients.clone())
+            .app_data(origin.clone())
+            .app_data(mirror.clone())
+            .app_data(tiering.clone())
+            .app_data(payload_limit.clone())
+            .app_data(rdb_limit.clone())
+            .app_data(web::PayloadConfig::new(config.max_payload_size))
+            .app_data(web::JsonConfig::default().limit(config.max_payload_size))
+            .wrap(from_fn(wait_for_quorum))
+            .wrap(from_fn(remember_idempotent_writes))
+            .wrap(from_fn(reject_writes_on_replica))
+            .wrap(from_fn(reject_writes_in_maintenance))
+            .wrap(from_fn(forward_to_shard_owner))
+            .wrap(from_fn(route_reads_by_consistency))
+            .wrap(Compress::default())
+            .wrap(Logger::default())
+            .wrap(Logger::new("%a %{User-Agent}i"))
+            .wrap(from_fn(log_access))
+            .wrap(from_fn(count_client_usage))
+            .route("/health", web::get().to(health_check))
+            .route("/health/live", web::get().to(health_live))
+            .route("/health/ready", web::get().to(health_ready))
+            .route("/stats", web::get().to(get_stats))
+            .route("/metrics", web::get().to(get_metrics))
+            .route("/stats/top", web::get().to(get_top_keys))
+            .route("/stats/sizes", web::get().to(get_size_histogram))
+            .route("/stats/clients", web::get().to(get_client_stats))
+            .route("/kv/", web::get().to(get_all_keys))
+            .route("/scan", web::get().to(scan))
+            .route("/info", web::post().to(get_bulk_info))
+            .route("/export", web::get().to(export))
+            .route("/export/snapshot", web::get().to(export_snapshot))
+            .route("/kv/{key}", web::get().to(get_key))
+            .route("/kv/{key}", web::head().to(get_key))
+            .route("/kv/{key}/info", web::get().to(get_key_info))
+            .route("/kv/{key}/exists", web::get().to(check_key_exists))
+            .route("/kv/{key}", web::post().to(put_key))
+            .route("/kv/{key}", web::put().to(update_key))
+            .route("/kv/{key}", web::delete().to(delete_key))
+            .route("/kv", web::delete().to(flush_all))
+            .route("/kv/prefix/{prefix}", web::put().to(update_by_prefix))
+            .route("/kv/prefix/{prefix}", web::delete().to(delete_by_prefix))
+            .route("/kv/prefix/{prefix}/count", web::get().to(count_by_prefix))
+            .route("/kv/r/{regex}", web::get().to(get_values_by_regex))
+            .route("/kv/r/{regex}", web::delete().to(delete_by_regex))
+            .route("/kv/rv/{regex}", web::get().to(get_keys_by_value_regex))
+            .route("/batch", web::post().to(batch_set))
+            .route("/import/ndjson", web::post().to(import_ndjson))
+            .route("/import/rdb", web::post().to(import_rdb))
+            .route("/migrate/redis", web::post().to(start_redis_migration))
+            .route("/migrate/redis", web::get().to(get_redis_migration))
+            .route("/migrate/redis", web::delete().to(cancel_redis_migration))
+            .route("/backup", web::post().to(create_backup))
+            .route("/backups", web::get().to(get_backups))
+            .route("/backups/{name}", web::get().to(download_backup))
+            .route("/restore/s3/{name}", web::post().to(restore_from_s3))
+            .route("/replication/snapshot", web::get().to(replication_snapshot))
+            .route("/replication/changes", web::get().to(replication_changes))
+            .route("/replication/stream", web::get().to(replication_stream))
+            .route("/replication/merkle", web::get().to(replication_merkle))
+            .route(
+                "/replication/merkle/{leaf}",
+                web::get().to(replication_merkle_leaf),
+            )
+            .route("/replication/values", web::post().to(replication_values))
+            .route("/replication/status", web::get().to(replication_status))
+            .route("/replication/follow", web::post().to(replication_follow))
+            .route("/replication/promote", web::post().to(replication_promote))
+            .route("/cluster/status", web::get().to(cluster_status))
+            .route("/cluster/vote", web::post().to(cluster_vote))
+            .route("/cluster/heartbeat", web::post().to(cluster_heartbeat))
+            .route("/gossip", web::post().to(gossip))
+            .route("/members", web::get().to(get_members))
+            .route("/members", web::delete().to(remove_member))
+            .route("/shard/status", web::get().to(shard_status))
+            .route("/mirror/status", web::get().to(mirror_status))
+            .route("/shard/owner/{key}", web::get().to(shard_owner))
+            .route("/compact", web::post().to(manual_compact))
+            .route("/compact/stats", web::get().to(compaction_stats))
+            .route("/admin/shutdown", web::post().to(admin_shutdown))
+            .route("/admin/readonly", web::get().to(get_read_only))
+            .route("/admin/readonly", web::post().to(set_read_only))
+            .route("/debug/pprof/profile", web::get().to(debug_cpu_profile))
+            .route("/debug/pprof/heap", web::get().to(debug_heap))
+            .route("/subscribe", web::get().to(subscribe))
+            .route("/pubsub", web::get().to(pubsub_channels))
+            .route("/pubsub/{channel}", web::get().to(pubsub_subscribe))
+            .route("/pubsub/{channel}", web::post().to(pubsub_publish))
+            .route("/geo/{key}", web::post().to(geo_add))
+            .route("/geo/{key}/radius", web::get().to(geo_radius))
+            .route("/geo/{key}/box", web::get().to(geo_box))
+    });
+    let mut server = server;
+    if let Some(workers) = config.workers {
+        server = server.workers(workers);
+    }
+    if let Some(threads) = config.blocking_threads {
+        let workers = config
+            .workers
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(2, |n| n.get()));
+        server = server.worker_max_blocking_threads((threads / workers).max(1));
+    }
+    let http = &file_config.http;
+    if let Some(secs) = http.keep_alive_secs {
+        server = server.keep_alive(match secs {
+            0 => KeepAlive::Disabled,
+            secs => KeepAlive::Timeout(Duration::from_secs(secs)),
+        });
+    }
+    if let Some(ms) = http.client_request_timeout_ms {
+        server = server.client_request_timeout(Duration::from_millis(ms));
+    }
+    if let Some(ms) = http.client_disconnect_timeout_ms {
+        server = server.client_disconnect_timeout(Duration::from_millis(ms));
+    }
+    if let Some(max) = http.max_connections {
+        server = server.max_connections(max);
+    }
+    if let Some(backlog) = http.backlog {
+        server = server.backlog(backlog);
+    }
+    let server = match (listeners.is_empty(), http.h2c) {
+        (true, false) => server.bind(&config.bind)?,
+        (true, true) => server.bind_auto_h2c(&config.bind)?,
+        (false, h2c) => listeners.into_iter().try_fold(server, |server, listener| {
+            if h2c {
+                server.listen_auto_h2c(listener)
+            } else {
+                server.listen(listener)
+            }
+        })?,
+    };
+    let server = server
+        .shutdown_timeout(config.shutdown_timeout)
+        .disable_signals()
+        .run();
+    systemd::notify_ready(&status);
+    actix_web::rt::spawn(shutdown::run(exit_shutdown, server.handle(), tasks));
+    server.await?;
+    if let Some(path) = &stats_file
+        && let Err(e) = stats::save(&exit_store, path)
+    {
+        eprintln!("Failed to save statistics: {}", e);
+    }
+    shutdown::finish(&exit_store, config.compact_on_shutdown)
 }
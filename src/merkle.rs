@@ -0,0 +1,128 @@
+//! Merkle trees over the keyspace, used by replicas to find keys that
+//! differ from the primary without comparing every key. Keys are split into
+//! ranges by the leading bits of their hash; each leaf hashes the keys and
+//! values in one range, and each inner node hashes its two children. Two
+//! nodes hold the same data exactly when their roots match, and walking
+//! down only the mismatching branches leads to the ranges that differ.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// The tree has `2^DEPTH` leaves.
+pub const DEPTH: usize = 8;
+pub const LEAVES: usize = 1 << DEPTH;
+
+type Hash = [u8; 32];
+
+fn value_hash(value: &str) -> Hash {
+    Sha256::digest(value.as_bytes()).into()
+}
+
+/// The leaf, i.e. key range, a key belongs to.
+pub fn leaf_of(key: &str) -> usize {
+    let digest = Sha256::digest(key.as_bytes());
+    u16::from_be_bytes([digest[0], digest[1]]) as usize >> (16 - DEPTH)
+}
+
+/// A key and the hash of its value.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyHash {
+    pub key: String,
+    pub hash: String,
+}
+
+impl KeyHash {
+    pub fn new(key: &str, value: &str) -> Self {
+        Self {
+            key: key.to_string(),
+            hash: hex(&value_hash(value)),
+        }
+    }
+}
+
+/// Tree levels from the root down, as hex strings: `levels[0]` holds the
+/// root, `levels[DEPTH]` the leaves.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleTree {
+    pub levels: Vec<Vec<String>>,
+}
+
+impl MerkleTree {
+    /// Builds the tree from every key and value, in any order.
+    pub fn build<'a>(entries: impl Iterator<Item = (&'a str, &'a str)>) -> Self {
+        let mut ranges: Vec<Vec<(&str, Hash)>> = vec![Vec::new(); LEAVES];
+        for (key, value) in entries {
+            ranges[leaf_of(key)].push((key, value_hash(value)));
+        }
+
+        let mut level: Vec<Hash> = ranges
+            .into_iter()
+            .map(|mut range| {
+                range.sort_unstable_by_key(|&(key, _)| key);
+                let mut hasher = Sha256::new();
+                for (key, hash) in range {
+                    hasher.update((key.len() as u64).to_le_bytes());
+                    hasher.update(key.as_bytes());
+                    hasher.update(hash);
+                }
+                hasher.finalize().into()
+            })
+            .collect();
+
+        let mut levels = vec![level.clone()];
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| {
+                    let mut hasher = Sha256::new();
+                    hasher.update(pair[0]);
+                    hasher.update(pair[1]);
+                    hasher.finalize().into()
+                })
+                .collect();
+            levels.push(level.clone());
+        }
+        levels.reverse();
+
+        Self {
+            levels: levels
+                .into_iter()
+                .map(|level| level.iter().map(|hash| hex(hash)).collect())
+                .collect(),
+        }
+    }
+
+    pub fn root(&self) -> &str {
+        &self.levels[0][0]
+    }
+
+    /// Truncates the tree to its top `depth` levels below the root.
+    pub fn truncate(mut self, depth: usize) -> Self {
+        self.levels.truncate(depth + 1);
+        self
+    }
+
+    /// Leaves whose hash differs from `other`'s, found by descending only
+    /// into subtrees whose hashes differ. Both trees must be complete.
+    pub fn diff(&self, other: &MerkleTree) -> Vec<usize> {
+        if self.levels.len() != DEPTH + 1 || other.levels.len() != DEPTH + 1 {
+            return (0..LEAVES).collect();
+        }
+        if self.root() == other.root() {
+            return Vec::new();
+        }
+        let mut differing = vec![0];
+        for level in 1..=DEPTH {
+            differing = differing
+                .into_iter()
+                .flat_map(|index| [index * 2, index * 2 + 1])
+                .filter(|&index| self.levels[level].get(index) != other.levels[level].get(index))
+                .collect();
+        }
+        differing
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,149 @@
+//! Operation rates for `/stats`, and `GET /metrics` in the Prometheus text
+//! format. Usage can be broken down by the prefixes in the `[metrics]`
+//! table of the config file, so the teams sharing a store each see their
+//! own key counts, sizes and operations.
+//!
+//! Rates are the operations of the last sampling interval. Prometheus works
+//! them out from the counters instead, so `/metrics` only has those.
+
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::rt::time::sleep;
+use actix_web::web;
+use kstore::{KvStore, PrefixStats, StoreStats};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Sample {
+    counts: (u64, Vec<u64>),
+    /// Operations per second in total and under each prefix.
+    rates: (f64, Vec<f64>),
+}
+
+#[derive(Default)]
+pub struct Rates {
+    sample: Mutex<Sample>,
+}
+
+impl Rates {
+    /// Operations per second over the last interval, in total and under
+    /// each metric prefix.
+    pub fn get(&self) -> (f64, Vec<f64>) {
+        self.sample.lock().unwrap().rates.clone()
+    }
+
+    fn update(&self, counts: (u64, Vec<u64>)) {
+        let mut sample = self.sample.lock().unwrap();
+        let seconds = SAMPLE_INTERVAL.as_secs_f64();
+        let rate = |now: u64, before: u64| now.saturating_sub(before) as f64 / seconds;
+        sample.rates = (
+            rate(counts.0, sample.counts.0),
+            counts
+                .1
+                .iter()
+                .enumerate()
+                .map(|(i, now)| rate(*now, sample.counts.1.get(i).copied().unwrap_or(0)))
+                .collect(),
+        );
+        sample.counts = counts;
+    }
+}
+
+/// Samples the operation counters every few seconds.
+pub async fn run(rates: web::Data<Rates>, store: web::Data<KvStore>) {
+    rates.sample.lock().unwrap().counts = store.operation_counts();
+    loop {
+        sleep(SAMPLE_INTERVAL).await;
+        rates.update(store.operation_counts());
+    }
+}
+
+/// Escapes a Prometheus label value.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// `stats` in the Prometheus text exposition format.
+pub fn render(stats: &StoreStats) -> String {
+    let mut text = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(text, "{}{} {}", name, labels, value);
+        }
+    };
+    metric(
+        "kstore_keys",
+        "gauge",
+        "Keys in the store.",
+        vec![(String::new(), stats.total_keys.to_string())],
+    );
+    metric(
+        "kstore_size_bytes",
+        "gauge",
+        "Size of every value in the store.",
+        vec![(String::new(), stats.total_size_bytes.to_string())],
+    );
+    metric(
+        "kstore_operations_total",
+        "counter",
+        "Reads and writes of keys.",
+        vec![(String::new(), stats.operations_count.to_string())],
+    );
+    metric(
+        "kstore_uptime_seconds",
+        "gauge",
+        "Seconds since the server started.",
+        vec![(String::new(), stats.uptime_seconds.to_string())],
+    );
+    metric(
+        "kstore_disk_full",
+        "gauge",
+        "1 while writes are refused because the disk is full.",
+        vec![(
+            String::new(),
+            u8::from(stats.disk_full.is_some()).to_string(),
+        )],
+    );
+    if stats.prefixes.is_empty() {
+        return text;
+    }
+    let by_prefix = |value: fn(&PrefixStats) -> String| {
+        stats
+            .prefixes
+            .iter()
+            .map(|prefix| {
+                (
+                    format!("{{prefix=\"{}\"}}", label(&prefix.prefix)),
+                    value(prefix),
+                )
+            })
+            .collect()
+    };
+    metric(
+        "kstore_prefix_keys",
+        "gauge",
+        "Keys under the prefix.",
+        by_prefix(|prefix| prefix.keys.to_string()),
+    );
+    metric(
+        "kstore_prefix_size_bytes",
+        "gauge",
+        "Size of the values under the prefix.",
+        by_prefix(|prefix| prefix.size_bytes.to_string()),
+    );
+    metric(
+        "kstore_prefix_operations_total",
+        "counter",
+        "Operations on keys under the prefix since the server started.",
+        by_prefix(|prefix| prefix.operations_count.to_string()),
+    );
+    text
+}
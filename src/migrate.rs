@@ -0,0 +1,197 @@
+//! Copies string keys out of a running Redis instance. The migration runs on
+//! its own thread and publishes progress that can be polled over HTTP.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use kstore::{KvStore, current_timestamp};
+
+use crate::reserved::Reserved;
+use crate::types;
+
+const DEFAULT_BATCH_SIZE: usize = 500;
+const MAX_BATCH_SIZE: usize = 10_000;
+
+#[derive(Deserialize)]
+pub struct MigrationRequest {
+    /// Connection URL, e.g. `redis://:password@host:6379/0`.
+    pub url: String,
+    pub pattern: Option<String>,
+    pub batch_size: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MigrationState {
+    Running,
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationProgress {
+    pub state: MigrationState,
+    pub pattern: String,
+    pub scanned: u64,
+    pub imported: u64,
+    /// Keys that are not strings in Redis, or were deleted before being read.
+    pub skipped: u64,
+    pub failed: u64,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Tracks the most recent migration. Only one may run at a time.
+#[derive(Default)]
+pub struct Migrations {
+    current: Mutex<Option<Arc<Mutex<MigrationProgress>>>>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl Migrations {
+    pub fn progress(&self) -> Option<MigrationProgress> {
+        let current = self.current.lock().unwrap();
+        current.as_ref().map(|p| p.lock().unwrap().clone())
+    }
+
+    /// Requests cancellation of the running migration. Returns false if none
+    /// is running.
+    pub fn cancel(&self) -> bool {
+        let running = matches!(self.progress(), Some(p) if p.state == MigrationState::Running);
+        if running {
+            self.cancel.store(true, Ordering::SeqCst);
+        }
+        running
+    }
+
+    pub fn start(
+        &self,
+        store: Arc<KvStore>,
+        reserved: Arc<Reserved>,
+        request: MigrationRequest,
+    ) -> Result<MigrationProgress, String> {
+        let batch_size = request.batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+        if batch_size == 0 || batch_size > MAX_BATCH_SIZE {
+            return Err(format!(
+                "batch_size must be between 1 and {}",
+                MAX_BATCH_SIZE
+            ));
+        }
+        let client = redis::Client::open(request.url.as_str())
+            .map_err(|e| format!("Invalid Redis URL: {}", e))?;
+
+        let mut current = self.current.lock().unwrap();
+        if let Some(progress) = current.as_ref()
+            && progress.lock().unwrap().state == MigrationState::Running
+        {
+            return Err("A migration is already running".to_string());
+        }
+
+        let pattern = request.pattern.unwrap_or_else(|| "*".to_string());
+        let progress = Arc::new(Mutex::new(MigrationProgress {
+            state: MigrationState::Running,
+            pattern: pattern.clone(),
+            scanned: 0,
+            imported: 0,
+            skipped: 0,
+            failed: 0,
+            started_at: current_timestamp(),
+            finished_at: None,
+            error: None,
+        }));
+        *current = Some(progress.clone());
+        self.cancel.store(false, Ordering::SeqCst);
+
+        let snapshot = progress.lock().unwrap().clone();
+        let cancel = self.cancel.clone();
+        std::thread::spawn(move || {
+            let result = run(
+                &client, &store, &reserved, &pattern, batch_size, &progress, &cancel,
+            );
+            let mut progress = progress.lock().unwrap();
+            progress.finished_at = Some(current_timestamp());
+            progress.state = match result {
+                Ok(()) if cancel.load(Ordering::SeqCst) => MigrationState::Cancelled,
+                Ok(()) => MigrationState::Completed,
+                Err(e) => {
+                    progress.error = Some(e);
+                    MigrationState::Failed
+                }
+            };
+        });
+
+        Ok(snapshot)
+    }
+}
+
+/// Walks the keyspace with SCAN and copies each batch with MGET, which
+/// returns nil for keys of other types so they can be counted as skipped.
+#[allow(clippy::too_many_arguments)]
+fn run(
+    client: &redis::Client,
+    store: &KvStore,
+    reserved: &Reserved,
+    pattern: &str,
+    batch_size: usize,
+    progress: &Mutex<MigrationProgress>,
+    cancel: &AtomicBool,
+) -> Result<(), String> {
+    let mut conn = client
+        .get_connection()
+        .map_err(|e| format!("Failed to connect to Redis: {}", e))?;
+    let mut cursor: u64 = 0;
+
+    loop {
+        if cancel.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let (next, keys): (u64, Vec<Vec<u8>>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(batch_size)
+            .query(&mut conn)
+            .map_err(|e| format!("SCAN failed: {}", e))?;
+
+        if !keys.is_empty() {
+            let values: Vec<Option<Vec<u8>>> = redis::cmd("MGET")
+                .arg(&keys)
+                .query(&mut conn)
+                .map_err(|e| format!("MGET failed: {}", e))?;
+
+            let (mut imported, mut skipped, mut failed) = (0, 0, 0);
+            for (key, value) in keys.into_iter().zip(values) {
+                let Some(value) = value else {
+                    skipped += 1;
+                    continue;
+                };
+                let result = match (String::from_utf8(key), String::from_utf8(value)) {
+                    (Ok(key), Ok(value)) => reserved
+                        .check(&key)
+                        .and_then(|()| types::write_untyped(store, key, value)),
+                    _ => Err("not valid UTF-8".to_string()),
+                };
+                match result {
+                    Ok(()) => imported += 1,
+                    Err(_) => failed += 1,
+                }
+            }
+
+            let mut progress = progress.lock().unwrap();
+            progress.scanned += imported + skipped + failed;
+            progress.imported += imported;
+            progress.skipped += skipped;
+            progress.failed += failed;
+        }
+
+        if next == 0 {
+            return Ok(());
+        }
+        cursor = next;
+    }
+}
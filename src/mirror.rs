@@ -0,0 +1,343 @@
+//! Write-through mirroring. Every key a client changes is sent on to a
+//! secondary endpoint in the background: another kstore, or any HTTP
+//! service that accepts the changes as JSON.
+//!
+//! Changes are read from the store's change log and queued by key. A key
+//! is sent with its value at the time of sending, or as a delete if it no
+//! longer exists, so a backlog collapses to one request per key and the
+//! secondary converges on the primary's current state. The queue is kept
+//! in a journal file until the secondary has accepted each key, so keys
+//! still waiting survive a restart. Keys under the reserved prefix are
+//! internal and not mirrored.
+
+use std::collections::{HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::rt::time::sleep;
+use actix_web::web;
+use kstore::{KvStore, current_timestamp};
+use serde::Serialize;
+
+use crate::config::MirrorFormat;
+use crate::reserved::Reserved;
+
+/// Queue journal, in the working directory like the data file.
+pub const JOURNAL_FILE: &str = "kvstore.mirror";
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+const CHANGES_PER_READ: usize = 1000;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// The journal is rewritten from the queue once it has this many lines
+/// and is mostly keys that were already sent.
+const JOURNAL_REWRITE_LINES: usize = 10_000;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MirrorStatus {
+    pub target: String,
+    /// Keys waiting to be sent.
+    pub pending: usize,
+    pub sent: u64,
+    /// Keys the secondary refused with a client error, which retrying
+    /// would not fix.
+    pub dropped: u64,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<u64>,
+}
+
+enum Failure {
+    Retry(String),
+    Drop(String),
+}
+
+fn is_retryable(status: u16) -> bool {
+    status == 408 || status == 429 || status >= 500
+}
+
+#[derive(Serialize)]
+struct JsonChange<'a> {
+    key: &'a str,
+    /// `None` once the key has been deleted.
+    value: Option<&'a str>,
+    timestamp: u64,
+}
+
+pub struct Mirror {
+    url: String,
+    format: MirrorFormat,
+    journal: Option<PathBuf>,
+    client: kstore_client::Client,
+    http: reqwest::Client,
+    status: Mutex<MirrorStatus>,
+}
+
+impl Mirror {
+    /// Without a journal the queue only lives in memory.
+    pub fn new(
+        url: String,
+        format: MirrorFormat,
+        journal: Option<PathBuf>,
+    ) -> Result<Self, String> {
+        let client = kstore_client::Client::builder(&url)
+            .retries(0)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| format!("Invalid mirror URL: {}", e))?;
+        Ok(Self {
+            status: Mutex::new(MirrorStatus {
+                target: url.clone(),
+                ..Default::default()
+            }),
+            url,
+            format,
+            journal,
+            client,
+            http: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("HTTP client"),
+        })
+    }
+
+    pub fn status(&self) -> MirrorStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn failed(&self, error: String) {
+        let mut status = self.status.lock().unwrap();
+        status.last_error = Some(error);
+        status.last_error_at = Some(current_timestamp());
+    }
+
+    async fn send(&self, key: &str, value: Option<&str>) -> Result<(), Failure> {
+        match self.format {
+            MirrorFormat::Kstore => {
+                let result = match value {
+                    Some(value) => self.client.set(key, value).await,
+                    None => self.client.delete(key).await.map(|_| ()),
+                };
+                result.map_err(|e| match e {
+                    kstore_client::Error::Status { status, .. } if !is_retryable(status) => {
+                        Failure::Drop(format!("{}: {}", key, e))
+                    }
+                    e => Failure::Retry(e.to_string()),
+                })
+            }
+            MirrorFormat::Json => {
+                let change = JsonChange {
+                    key,
+                    value,
+                    timestamp: current_timestamp(),
+                };
+                let response = self
+                    .http
+                    .post(&self.url)
+                    .json(&change)
+                    .send()
+                    .await
+                    .map_err(|e| Failure::Retry(e.to_string()))?;
+                let status = response.status();
+                if status.is_success() {
+                    Ok(())
+                } else if is_retryable(status.as_u16()) {
+                    Err(Failure::Retry(format!("{} answered {}", self.url, status)))
+                } else {
+                    Err(Failure::Drop(format!(
+                        "{}: {} answered {}",
+                        key, self.url, status
+                    )))
+                }
+            }
+        }
+    }
+}
+
+/// Keys waiting to be sent, oldest first, each at most once, mirrored to
+/// a journal with one JSON string per line.
+struct Queue {
+    keys: VecDeque<String>,
+    queued: HashSet<String>,
+    journal: Option<(PathBuf, File)>,
+    journal_lines: usize,
+}
+
+impl Queue {
+    /// Opens the journal at `path`, queueing the keys left in it.
+    fn open(path: Option<PathBuf>) -> std::io::Result<Self> {
+        let mut queue = Queue {
+            keys: VecDeque::new(),
+            queued: HashSet::new(),
+            journal: None,
+            journal_lines: 0,
+        };
+        let Some(path) = path else {
+            return Ok(queue);
+        };
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    // A line cut short by a crash is the key being written
+                    // then, whose change was never acknowledged anyway.
+                    if let Ok(key) = serde_json::from_str::<String>(&line?)
+                        && queue.queued.insert(key.clone())
+                    {
+                        queue.keys.push_back(key);
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        // Start from a clean copy, so nothing is appended to a cut-short
+        // line.
+        let file = queue.rewrite(&path)?;
+        queue.journal = Some((path, file));
+        Ok(queue)
+    }
+
+    /// Replaces the journal at `path` with the keys still queued, returning
+    /// it opened for appending.
+    fn rewrite(&mut self, path: &Path) -> std::io::Result<File> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let mut temp = File::create(&temp_path)?;
+        for key in &self.keys {
+            writeln!(temp, "{}", serde_json::to_string(key).unwrap())?;
+        }
+        temp.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        self.journal_lines = self.keys.len();
+        OpenOptions::new().append(true).open(path)
+    }
+
+    fn push(&mut self, keys: impl IntoIterator<Item = String>) -> std::io::Result<()> {
+        let mut lines = String::new();
+        for key in keys {
+            if self.queued.insert(key.clone()) {
+                lines.push_str(&serde_json::to_string(&key).unwrap());
+                lines.push('\n');
+                self.keys.push_back(key);
+                self.journal_lines += 1;
+            }
+        }
+        if let Some((_, file)) = &mut self.journal
+            && !lines.is_empty()
+        {
+            file.write_all(lines.as_bytes())?;
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn front(&self) -> Option<&String> {
+        self.keys.front()
+    }
+
+    /// Removes the front key once it has been sent.
+    fn pop(&mut self) -> std::io::Result<()> {
+        if let Some(key) = self.keys.pop_front() {
+            self.queued.remove(&key);
+        }
+        let Some((path, file)) = &mut self.journal else {
+            return Ok(());
+        };
+        if self.keys.is_empty() {
+            file.set_len(0)?;
+            self.journal_lines = 0;
+        } else if self.journal_lines > JOURNAL_REWRITE_LINES
+            && self.journal_lines > 4 * self.keys.len()
+        {
+            let path = path.clone();
+            let file = self.rewrite(&path)?;
+            self.journal = Some((path, file));
+        }
+        Ok(())
+    }
+}
+
+/// Sends changes to the mirror until the server shuts down.
+pub async fn run(
+    mirror: web::Data<Option<Mirror>>,
+    store: web::Data<KvStore>,
+    reserved: web::Data<Reserved>,
+) {
+    let Some(mirror) = mirror.as_ref() else {
+        return;
+    };
+    let mut queue = match Queue::open(mirror.journal.clone()) {
+        Ok(queue) => queue,
+        Err(e) => {
+            eprintln!(
+                "Failed to open the mirror queue, mirroring is disabled: {}",
+                e
+            );
+            return;
+        }
+    };
+    let (log, mut seq) = store.change_position();
+    let mut backoff = POLL_INTERVAL;
+
+    loop {
+        let mut changed = Vec::new();
+        loop {
+            match store.changes_since(log, seq, CHANGES_PER_READ) {
+                Some(batch) => {
+                    let Some(last) = batch.changes.last() else {
+                        break;
+                    };
+                    seq = last.seq;
+                    changed.extend(batch.changes.into_iter().map(|change| change.key));
+                }
+                None => {
+                    // Changes were evicted from the log before they were
+                    // read, so every key is sent again. Deletes in the gap
+                    // are lost.
+                    mirror.failed("Fell behind the change log, resending every key".to_string());
+                    seq = store.change_position().1;
+                    changed.extend(store.keys_where(|_| true));
+                    break;
+                }
+            }
+        }
+        changed.retain(|key| !reserved.contains(key));
+        if let Err(e) = queue.push(changed.iter().map(|key| key.to_string())) {
+            mirror.failed(format!("Failed to write the mirror queue: {}", e));
+        }
+
+        let mut retry = None;
+        while let Some(key) = queue.front() {
+            let value = store
+                .values(std::slice::from_ref(key))
+                .into_values()
+                .next()
+                .flatten();
+            match mirror.send(key, value.as_deref()).await {
+                Ok(()) => mirror.status.lock().unwrap().sent += 1,
+                Err(Failure::Drop(e)) => {
+                    mirror.status.lock().unwrap().dropped += 1;
+                    mirror.failed(e);
+                }
+                Err(Failure::Retry(e)) => {
+                    retry = Some(e);
+                    break;
+                }
+            }
+            if let Err(e) = queue.pop() {
+                mirror.failed(format!("Failed to write the mirror queue: {}", e));
+            }
+        }
+        mirror.status.lock().unwrap().pending = queue.keys.len();
+
+        match retry {
+            Some(e) => {
+                mirror.failed(e);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            None => backoff = POLL_INTERVAL,
+        }
+        sleep(backoff).await;
+    }
+}
@@ -0,0 +1,191 @@
+//! Read-through cache mode. A `GET /kv/{key}` miss fetches the value from
+//! an origin server, stores it and returns it, so kstore can sit in front
+//! of a slower service as a persistent cache.
+//!
+//! Fetched values are fresh for the TTL. For the stale window after that
+//! they are still served, while a background fetch refreshes them; past it
+//! a read waits for the origin again. When each key was fetched is kept
+//! under the reserved prefix, along with a checksum of the value, so a key
+//! a client has since written is recognised and never overwritten. The
+//! record is deleted along with the key.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::web;
+use kstore::{Attributes, KvStore, Value, current_timestamp};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use sha2::{Digest, Sha256};
+
+use crate::reserved;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Namespace of the fetch times under the reserved prefix.
+pub const RECORD_NAMESPACE: &str = "origin/";
+/// Everything but RFC 3986 unreserved characters, so any key is one path
+/// segment.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// How a read was served, reported in the `X-Cache` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Stale,
+    Miss,
+}
+
+impl CacheStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Stale => "STALE",
+            CacheStatus::Miss => "MISS",
+        }
+    }
+}
+
+pub struct Origin {
+    /// URL with `{key}` where the key goes.
+    template: String,
+    ttl: u64,
+    stale: u64,
+    /// Prefix of the keys holding fetch times, under the reserved prefix.
+    fetched_prefix: String,
+    client: reqwest::Client,
+    refreshing: Mutex<HashSet<String>>,
+}
+
+fn checksum(value: &str) -> String {
+    Sha256::digest(value.as_bytes())[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl Origin {
+    pub fn new(
+        template: String,
+        ttl: u64,
+        stale: u64,
+        reserved_prefix: &str,
+    ) -> Result<Self, String> {
+        if !template.contains("{key}") {
+            return Err("The origin URL must contain {key}".to_string());
+        }
+        if reserved_prefix.is_empty() {
+            return Err("--origin needs a --reserved-prefix to record fetch times in".to_string());
+        }
+        Ok(Self {
+            template,
+            ttl,
+            stale,
+            fetched_prefix: format!("{}{}", reserved_prefix, RECORD_NAMESPACE),
+            client: reqwest::Client::builder()
+                .timeout(FETCH_TIMEOUT)
+                .build()
+                .expect("HTTP client"),
+            refreshing: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Fetches `key` from the origin. A 404 means the origin has no such
+    /// key either.
+    async fn fetch(&self, key: &str) -> Result<Option<String>, String> {
+        let url = self
+            .template
+            .replace("{key}", &utf8_percent_encode(key, PATH_SEGMENT).to_string());
+        let response = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .map_err(|e| format!("Origin is unreachable: {}", e))?;
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            reqwest::StatusCode::NOT_FOUND => return Ok(None),
+            status => return Err(format!("Origin answered {}", status)),
+        }
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| format!("Origin is unreachable: {}", e))?;
+        String::from_utf8(body.to_vec())
+            .map(Some)
+            .map_err(|_| "Origin returned a value that is not valid UTF-8".to_string())
+    }
+
+    /// When `value` was fetched, or `None` if a client wrote it.
+    fn fetched_at(&self, store: &KvStore, key: &str, value: &str) -> Option<u64> {
+        let record = reserved::record_key(&self.fetched_prefix, key);
+        let record = store.values(&[record]).into_values().next()??;
+        let (fetched_at, sum) = record.split_once(' ')?;
+        if sum != checksum(value) {
+            return None;
+        }
+        fetched_at.parse().ok()
+    }
+
+    /// Stores a fetched value and when it was fetched. A value the store
+    /// refuses is still returned to the reader, just not cached.
+    fn remember(&self, store: &KvStore, key: &str, value: &str) {
+        if store.set(key.to_string(), value.to_string()).is_ok() {
+            let _ = store.set(
+                reserved::record_key(&self.fetched_prefix, key),
+                format!("{} {}", current_timestamp(), checksum(value)),
+            );
+        }
+    }
+
+    /// Fetches `key` again, dropping the cached copy if the origin no
+    /// longer has it.
+    async fn refresh(&self, store: &KvStore, key: &str) -> Result<Option<String>, String> {
+        let value = self.fetch(key).await?;
+        match &value {
+            Some(value) => self.remember(store, key, value),
+            None => {
+                let _ = store.delete(key);
+            }
+        }
+        Ok(value)
+    }
+}
+
+/// Reads `key` through the cache, with its attributes. Returns `None` when
+/// the origin does not have it, and no cache status for keys clients
+/// wrote.
+pub async fn get(
+    origin: web::Data<Option<Origin>>,
+    store: web::Data<KvStore>,
+    key: String,
+) -> Result<Option<(Value, Attributes, Option<CacheStatus>)>, String> {
+    let cache = origin.as_ref().as_ref().expect("origin is configured");
+    let fetched = |value: String| (value.into(), Attributes::NONE, Some(CacheStatus::Miss));
+    let Some((value, attributes)) = store.get_with_attributes(&key) else {
+        return Ok(cache.refresh(&store, &key).await?.map(fetched));
+    };
+    let Some(fetched_at) = cache.fetched_at(&store, &key, &value) else {
+        return Ok(Some((value, attributes, None)));
+    };
+    let age = current_timestamp().saturating_sub(fetched_at);
+    if age < cache.ttl {
+        return Ok(Some((value, attributes, Some(CacheStatus::Hit))));
+    }
+    if age >= cache.ttl + cache.stale {
+        // Too old to serve even while refreshing.
+        return Ok(cache.refresh(&store, &key).await?.map(fetched));
+    }
+    if cache.refreshing.lock().unwrap().insert(key.clone()) {
+        let origin = origin.clone();
+        actix_web::rt::spawn(async move {
+            let cache = origin.as_ref().as_ref().expect("origin is configured");
+            let _ = cache.refresh(&store, &key).await;
+            cache.refreshing.lock().unwrap().remove(&key);
+        });
+    }
+    Ok(Some((value, attributes, Some(CacheStatus::Stale))))
+}
@@ -0,0 +1,190 @@
+//! On-demand profiling of a running server, behind the admin token.
+//! `GET /debug/pprof/profile` samples every thread's stack for a while
+//! and returns the profile for `go tool pprof`, or as a flame graph.
+//! `GET /debug/pprof/heap` reports how much memory is allocated, counted
+//! by the global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use actix_web::HttpResponse;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_SECONDS: u64 = 30;
+const MAX_SECONDS: u64 = 300;
+/// Samples per second. Not a round number, so sampling does not run in
+/// lockstep with periodic work.
+#[cfg(unix)]
+const FREQUENCY: i32 = 99;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static DEALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// The system allocator, counting what is allocated through it.
+pub struct CountingAllocator;
+
+fn allocated(size: usize) {
+    let total = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_ALLOCATED.fetch_max(total, Ordering::Relaxed);
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn deallocated(size: usize) {
+    ALLOCATED.fetch_sub(size, Ordering::Relaxed);
+    DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        deallocated(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            deallocated(layout.size());
+            allocated(new_size);
+        }
+        new_ptr
+    }
+}
+
+#[derive(Serialize)]
+pub struct HeapStats {
+    pub allocated_bytes: usize,
+    pub peak_allocated_bytes: usize,
+    /// Allocations and deallocations since the server started.
+    pub allocations: u64,
+    pub deallocations: u64,
+}
+
+pub fn heap_stats() -> HeapStats {
+    HeapStats {
+        allocated_bytes: ALLOCATED.load(Ordering::Relaxed),
+        peak_allocated_bytes: PEAK_ALLOCATED.load(Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ProfileQuery {
+    seconds: Option<u64>,
+    /// `pprof` (the default) or `flamegraph`.
+    format: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ProfileFormat {
+    Pprof,
+    Flamegraph,
+}
+
+/// Only one profile can be taken at a time.
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+/// Clears [`PROFILING`] however the profile ends, including the client
+/// going away mid-profile.
+struct ProfilingFlag;
+
+impl Drop for ProfilingFlag {
+    fn drop(&mut self) {
+        PROFILING.store(false, Ordering::Release);
+    }
+}
+
+/// Profiles the CPU for the requested number of seconds.
+pub async fn cpu_profile(query: &ProfileQuery) -> HttpResponse {
+    let seconds = query.seconds.unwrap_or(DEFAULT_SECONDS);
+    if seconds == 0 || seconds > MAX_SECONDS {
+        return HttpResponse::BadRequest()
+            .body(format!("seconds must be between 1 and {}", MAX_SECONDS));
+    }
+    let format = match query.format.as_deref().unwrap_or("pprof") {
+        "pprof" => ProfileFormat::Pprof,
+        "flamegraph" => ProfileFormat::Flamegraph,
+        other => {
+            return HttpResponse::BadRequest().body(format!(
+                "Unknown profile format '{}', use pprof or flamegraph",
+                other
+            ));
+        }
+    };
+    if PROFILING.swap(true, Ordering::Acquire) {
+        return HttpResponse::Conflict().body("A CPU profile is already being taken");
+    }
+    let _flag = ProfilingFlag;
+    match sample(Duration::from_secs(seconds), format).await {
+        Ok((content_type, body)) => HttpResponse::Ok().content_type(content_type).body(body),
+        Err(e) => HttpResponse::InternalServerError().body(e),
+    }
+}
+
+#[cfg(unix)]
+async fn sample(
+    duration: Duration,
+    format: ProfileFormat,
+) -> Result<(&'static str, Vec<u8>), String> {
+    use pprof::protos::Message;
+
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| format!("Failed to start profiling: {}", e))?;
+    actix_web::rt::time::sleep(duration).await;
+    let report = guard
+        .report()
+        .build()
+        .map_err(|e| format!("Failed to build the profile: {}", e))?;
+    let mut body = Vec::new();
+    match format {
+        ProfileFormat::Pprof => {
+            report
+                .pprof()
+                .map_err(|e| format!("Failed to build the profile: {}", e))?
+                .encode(&mut body)
+                .map_err(|e| format!("Failed to encode the profile: {}", e))?;
+            Ok(("application/octet-stream", body))
+        }
+        // An empty flame graph would be an empty document.
+        ProfileFormat::Flamegraph if report.data.is_empty() => Ok((
+            "text/plain; charset=utf-8",
+            b"No samples were taken, the server was idle".to_vec(),
+        )),
+        ProfileFormat::Flamegraph => {
+            report
+                .flamegraph(&mut body)
+                .map_err(|e| format!("Failed to draw the flame graph: {}", e))?;
+            Ok(("image/svg+xml", body))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn sample(
+    _duration: Duration,
+    _format: ProfileFormat,
+) -> Result<(&'static str, Vec<u8>), String> {
+    Err("CPU profiling is only supported on Unix".to_string())
+}
@@ -0,0 +1,206 @@
+//! Relays a request to another node and its response back to the client,
+//! for nodes that serve some requests on another node's behalf, and signs
+//! the requests nodes make to each other.
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use hmac::{Hmac, Mac};
+use kstore::current_timestamp;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Set on proxied requests so the receiving node always serves them itself
+/// instead of forwarding them on again. It holds the sending node, the time
+/// and a signature made with the secret the nodes share, as
+/// `<via> <timestamp> <signature>`.
+pub const FORWARDED_HEADER: &str = "X-Kstore-Forwarded";
+
+/// How far the timestamp of a forwarded or signature header may be from
+/// this node's clock, in seconds.
+const MAX_FORWARDED_AGE: u64 = 60;
+
+/// Hop-by-hop headers, which apply to a single connection and must not be
+/// copied onto the proxied request or response.
+const HOP_BY_HOP_HEADERS: [&str; 5] = [
+    "connection",
+    "content-length",
+    "host",
+    "keep-alive",
+    "transfer-encoding",
+];
+
+/// Set on requests one node makes to another's internal endpoints, such
+/// as cluster votes and heartbeats, in the same format as
+/// [`FORWARDED_HEADER`]. Those endpoints refuse requests without it.
+pub const SIGNATURE_HEADER: &str = "X-Kstore-Signature";
+
+/// The secret shared by the nodes of a cluster or shard set, so that only
+/// they can mark a request as forwarded or call each other's internal
+/// endpoints. Without one, requests are still proxied but the forwarded
+/// header is never trusted, and internal endpoints refuse every request.
+#[derive(Clone, Default)]
+pub struct NodeSecret(Option<String>);
+
+impl NodeSecret {
+    pub fn new(secret: Option<String>) -> Self {
+        Self(secret)
+    }
+
+    fn signature(secret: &str, via: &str, timestamp: u64, method: &str, path: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+        mac.update(format!("{}\n{}\n{}\n{}", via, timestamp, method, path).as_bytes());
+        mac
+    }
+
+    /// The signed header for a `method` request to `path` sent by `via`.
+    fn header(&self, via: &str, method: &str, path: &str) -> String {
+        let timestamp = current_timestamp();
+        let signature = match &self.0 {
+            Some(secret) => Self::signature(secret, via, timestamp, method, path)
+                .finalize()
+                .into_bytes()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            None => "-".to_string(),
+        };
+        format!("{} {} {}", via, timestamp, signature)
+    }
+
+    /// Whether the `name` header of `req` is recent and signed with this
+    /// secret for the request's method and path.
+    fn verify(&self, req: &HttpRequest, name: &str) -> bool {
+        let Some(secret) = &self.0 else {
+            return false;
+        };
+        let Some(header) = req
+            .headers()
+            .get(name)
+            .and_then(|header| header.to_str().ok())
+        else {
+            return false;
+        };
+        let mut fields = header.split(' ');
+        let (Some(via), Some(timestamp), Some(signature), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return false;
+        };
+        let (Ok(timestamp), Some(signature)) = (timestamp.parse::<u64>(), decode_hex(signature))
+        else {
+            return false;
+        };
+        let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        current_timestamp().abs_diff(timestamp) <= MAX_FORWARDED_AGE
+            && Self::signature(secret, via, timestamp, req.method().as_str(), path)
+                .verify_slice(&signature)
+                .is_ok()
+    }
+
+    /// Whether `req` was proxied by another node: its forwarded header is
+    /// recent and signed with this secret. A header from anyone else is
+    /// ignored, so clients cannot use it to skip forwarding.
+    pub fn is_forwarded(&self, req: &ServiceRequest) -> bool {
+        self.verify(req.request(), FORWARDED_HEADER)
+    }
+
+    /// A `method` request from the node `via` to another node's internal
+    /// endpoint at `url`, signed with this secret.
+    pub fn request(
+        &self,
+        client: &reqwest::Client,
+        method: reqwest::Method,
+        url: &str,
+        via: &str,
+    ) -> reqwest::RequestBuilder {
+        let path = match reqwest::Url::parse(url) {
+            Ok(url) => match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_string(),
+            },
+            Err(_) => "/".to_string(),
+        };
+        let header = self.header(via, method.as_str(), &path);
+        client.request(method, url).header(SIGNATURE_HEADER, header)
+    }
+
+    /// Checks that `req` comes from another node holding this secret,
+    /// returning the response to send if it does not.
+    pub fn authorize(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        if self.0.is_none() {
+            return Err(HttpResponse::Forbidden()
+                .body("Internal endpoints are disabled, start the server with --node-secret"));
+        }
+        if !self.verify(req, SIGNATURE_HEADER) {
+            return Err(HttpResponse::Unauthorized()
+                .body("Missing or invalid node signature, check --node-secret"));
+        }
+        Ok(())
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Sends the request to the node at `target` and relays its response.
+/// `via` identifies this node in the forwarded header, which is signed
+/// with `secret`.
+pub async fn forward(
+    client: &reqwest::Client,
+    secret: &NodeSecret,
+    target: &str,
+    via: &str,
+    req: &ServiceRequest,
+    body: Bytes,
+) -> HttpResponse {
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let Ok(method) = reqwest::Method::from_bytes(req.method().as_str().as_bytes()) else {
+        return HttpResponse::MethodNotAllowed().finish();
+    };
+    let mut request = client
+        .request(method, format!("{}{}", target, path))
+        .header(
+            FORWARDED_HEADER,
+            secret.header(via, req.method().as_str(), path),
+        )
+        .body(body);
+    for (name, value) in req.headers() {
+        if !HOP_BY_HOP_HEADERS.contains(&name.as_str()) && name != FORWARDED_HEADER {
+            request = request.header(name.as_str(), value.as_bytes());
+        }
+    }
+
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            return HttpResponse::BadGateway().body(format!("{} is unreachable: {}", target, e));
+        }
+    };
+    let status =
+        StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut builder = HttpResponse::build(status);
+    for (name, value) in response.headers() {
+        if !HOP_BY_HOP_HEADERS.contains(&name.as_str()) {
+            builder.append_header((name.as_str(), value.as_bytes()));
+        }
+    }
+    match response.bytes().await {
+        Ok(body) => builder.body(body),
+        Err(e) => HttpResponse::BadGateway().body(format!("{} is unreachable: {}", target, e)),
+    }
+}
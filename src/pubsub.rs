@@ -0,0 +1,98 @@
+//! Publish/subscribe channels that have nothing to do with keys. A message
+//! published to a channel goes to everyone subscribed to it at that moment
+//! over `GET /pubsub/{channel}` and is then gone: it is never stored,
+//! replicated or sent to other nodes.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use actix_web::web::Bytes;
+use futures_util::Stream;
+use kstore::current_timestamp;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Messages a subscriber may fall behind by before it misses some.
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Message {
+    pub channel: String,
+    pub message: String,
+    pub timestamp: u64,
+}
+
+#[derive(Default)]
+pub struct PubSub {
+    /// Channels with subscribers, or that had some since the last message.
+    channels: Mutex<HashMap<String, broadcast::Sender<Message>>>,
+}
+
+impl PubSub {
+    /// Sends `message` to the subscribers of `channel`, returning how many
+    /// there were.
+    pub fn publish(&self, channel: &str, message: String) -> usize {
+        let mut channels = self.channels.lock().unwrap();
+        let Some(sender) = channels.get(channel) else {
+            return 0;
+        };
+        let message = Message {
+            channel: channel.to_string(),
+            message,
+            timestamp: current_timestamp(),
+        };
+        match sender.send(message) {
+            Ok(receivers) => receivers,
+            // Everyone has unsubscribed since the last message.
+            Err(_) => {
+                channels.remove(channel);
+                0
+            }
+        }
+    }
+
+    pub fn subscribe(&self, channel: &str) -> broadcast::Receiver<Message> {
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(channel.to_string())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Channels that have subscribers, and how many each has.
+    pub fn channels(&self) -> Vec<(String, usize)> {
+        let mut channels: Vec<(String, usize)> = self
+            .channels
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(channel, sender)| (channel.clone(), sender.receiver_count()))
+            .filter(|(_, subscribers)| *subscribers > 0)
+            .collect();
+        channels.sort();
+        channels
+    }
+}
+
+/// Turns a channel subscription into a Server-Sent Events body, with a
+/// `lagged` event in place of messages a slow subscriber missed, as for
+/// keyspace events.
+pub fn sse_stream(
+    receiver: broadcast::Receiver<Message>,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let frame = match receiver.recv().await {
+            Ok(message) => format!(
+                "event: message\ndata: {}\n\n",
+                serde_json::to_string(&message).unwrap_or_default()
+            ),
+            Err(RecvError::Lagged(missed)) => {
+                format!("event: lagged\ndata: {{\"missed\":{}}}\n\n", missed)
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok(Bytes::from(frame)), receiver))
+    })
+}
@@ -0,0 +1,622 @@
+//! Reader for Redis RDB dump files. Only plain string keys are returned;
+//! every other data type is parsed far enough to be skipped.
+
+use std::io::Read;
+
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+const OPCODE_FUNCTION: u8 = 0xF5;
+const OPCODE_FUNCTION2: u8 = 0xF6;
+const OPCODE_FREQ: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_MODULE_AUX: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+const ENC_INT8: u64 = 0;
+const ENC_INT16: u64 = 1;
+const ENC_INT32: u64 = 2;
+const ENC_LZF: u64 = 3;
+
+#[derive(Debug)]
+pub struct RdbEntry {
+    pub db: u64,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    /// Absolute expiry in Unix milliseconds, if the key had one.
+    pub expires_at_ms: Option<u64>,
+}
+
+enum Length {
+    Plain(u64),
+    Encoded(u64),
+}
+
+/// Wraps the input to maintain the CRC64 checksum RDB files end with.
+struct ChecksumReader<R> {
+    inner: R,
+    crc: u64,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc = crc64(self.crc, &buf[..n]);
+        Ok(n)
+    }
+}
+
+pub struct RdbReader<R> {
+    reader: ChecksumReader<R>,
+    version: u32,
+    db: u64,
+    finished: bool,
+}
+
+impl<R: Read> RdbReader<R> {
+    pub fn new(reader: R) -> Result<Self, String> {
+        let mut reader = ChecksumReader {
+            inner: reader,
+            crc: 0,
+        };
+        let mut header = [0u8; 9];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| "File is too short to be an RDB dump".to_string())?;
+        if &header[..5] != b"REDIS" {
+            return Err("Not an RDB file: missing REDIS header".to_string());
+        }
+        let version = std::str::from_utf8(&header[5..])
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .ok_or("Invalid RDB version")?;
+
+        Ok(Self {
+            reader,
+            version,
+            db: 0,
+            finished: false,
+        })
+    }
+
+    /// Returns the next entry, `Ok(None)` at the end of the file. Entries of
+    /// other types are reported through `skipped` with their database and
+    /// type name.
+    pub fn next_entry(
+        &mut self,
+        skipped: &mut dyn FnMut(u64, &'static str),
+    ) -> Result<Option<RdbEntry>, String> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let mut expires_at_ms = None;
+        loop {
+            let opcode = self.read_u8()?;
+            match opcode {
+                OPCODE_EOF => {
+                    self.finished = true;
+                    self.verify_checksum()?;
+                    return Ok(None);
+                }
+                OPCODE_SELECTDB => self.db = self.read_length()?,
+                OPCODE_RESIZEDB => {
+                    self.read_length()?;
+                    self.read_length()?;
+                }
+                OPCODE_AUX => {
+                    self.read_string()?;
+                    self.read_string()?;
+                }
+                OPCODE_EXPIRETIME_MS => expires_at_ms = Some(self.read_u64_le()?),
+                OPCODE_EXPIRETIME => expires_at_ms = Some(self.read_u32_le()? as u64 * 1000),
+                OPCODE_IDLE => {
+                    self.read_length()?;
+                }
+                OPCODE_FREQ => {
+                    self.read_u8()?;
+                }
+                OPCODE_SLOT_INFO => {
+                    self.read_length()?;
+                    self.read_length()?;
+                    self.read_length()?;
+                }
+                OPCODE_FUNCTION2 => {
+                    self.read_string()?;
+                }
+                OPCODE_FUNCTION | OPCODE_MODULE_AUX => {
+                    return Err(
+                        "RDB files with modules or legacy functions are not supported".to_string(),
+                    );
+                }
+                value_type => {
+                    let key = self.read_string()?;
+                    if value_type == TYPE_STRING {
+                        let value = self.read_string()?;
+                        return Ok(Some(RdbEntry {
+                            db: self.db,
+                            key,
+                            value,
+                            expires_at_ms,
+                        }));
+                    }
+                    let kind = self.skip_value(value_type)?;
+                    skipped(self.db, kind);
+                    expires_at_ms = None;
+                }
+            }
+        }
+    }
+
+    fn skip_value(&mut self, value_type: u8) -> Result<&'static str, String> {
+        match value_type {
+            TYPE_LIST | TYPE_SET | TYPE_LIST_QUICKLIST => {
+                self.skip_strings(1)?;
+            }
+            TYPE_ZSET => {
+                let count = self.read_length()?;
+                for _ in 0..count {
+                    self.read_string()?;
+                    let len = self.read_u8()?;
+                    // 253..=255 encode NaN and the infinities without a payload.
+                    if len < 253 {
+                        self.skip_bytes(len as u64)?;
+                    }
+                }
+            }
+            TYPE_HASH => self.skip_strings(2)?,
+            TYPE_ZSET_2 => {
+                let count = self.read_length()?;
+                for _ in 0..count {
+                    self.read_string()?;
+                    self.skip_bytes(8)?;
+                }
+            }
+            TYPE_HASH_ZIPMAP | TYPE_LIST_ZIPLIST | TYPE_SET_INTSET | TYPE_ZSET_ZIPLIST
+            | TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK | TYPE_ZSET_LISTPACK | TYPE_SET_LISTPACK => {
+                self.read_string()?;
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let count = self.read_length()?;
+                for _ in 0..count {
+                    self.read_length()?;
+                    self.read_string()?;
+                }
+            }
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                self.skip_stream(value_type)?;
+            }
+            other => {
+                return Err(format!(
+                    "Unsupported RDB value type {} (RDB version {})",
+                    other, self.version
+                ));
+            }
+        }
+        Ok(type_name(value_type))
+    }
+
+    fn skip_stream(&mut self, value_type: u8) -> Result<(), String> {
+        let listpacks = self.read_length()?;
+        for _ in 0..listpacks {
+            self.read_string()?;
+            self.read_string()?;
+        }
+        // Length, last id (ms, seq).
+        for _ in 0..3 {
+            self.read_length()?;
+        }
+        if value_type >= TYPE_STREAM_LISTPACKS_2 {
+            // First id, max deleted id, entries added.
+            for _ in 0..5 {
+                self.read_length()?;
+            }
+        }
+
+        let groups = self.read_length()?;
+        for _ in 0..groups {
+            self.read_string()?;
+            self.read_length()?;
+            self.read_length()?;
+            if value_type >= TYPE_STREAM_LISTPACKS_2 {
+                self.read_length()?;
+            }
+            let pending = self.read_length()?;
+            for _ in 0..pending {
+                // Raw entry id, delivery time, delivery count.
+                self.skip_bytes(16 + 8)?;
+                self.read_length()?;
+            }
+            let consumers = self.read_length()?;
+            for _ in 0..consumers {
+                self.read_string()?;
+                self.skip_bytes(8)?;
+                if value_type >= TYPE_STREAM_LISTPACKS_3 {
+                    self.skip_bytes(8)?;
+                }
+                let consumer_pending = self.read_length()?;
+                self.skip_bytes(consumer_pending.saturating_mul(16))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn skip_strings(&mut self, per_element: u64) -> Result<(), String> {
+        let count = self.read_length()?;
+        for _ in 0..count.saturating_mul(per_element) {
+            self.read_string()?;
+        }
+        Ok(())
+    }
+
+    fn verify_checksum(&mut self) -> Result<(), String> {
+        if self.version < 5 {
+            return Ok(());
+        }
+        let expected = self.reader.crc;
+        let mut checksum = [0u8; 8];
+        self.reader
+            .inner
+            .read_exact(&mut checksum)
+            .map_err(|_| "RDB file is truncated: missing checksum".to_string())?;
+        let checksum = u64::from_le_bytes(checksum);
+        // A zero checksum means the server had rdbchecksum disabled.
+        if checksum != 0 && checksum != expected {
+            return Err("RDB checksum mismatch, the file is corrupted".to_string());
+        }
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        self.reader
+            .read_exact(buf)
+            .map_err(|_| "RDB file is truncated".to_string())
+    }
+
+    fn read_u8(&mut self) -> Result<u8, String> {
+        let mut buf = [0u8; 1];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_u32_le(&mut self) -> Result<u32, String> {
+        let mut buf = [0u8; 4];
+        self.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_u64_le(&mut self) -> Result<u64, String> {
+        let mut buf = [0u8; 8];
+        self.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn skip_bytes(&mut self, count: u64) -> Result<(), String> {
+        let copied = std::io::copy(&mut (&mut self.reader).take(count), &mut std::io::sink())
+            .map_err(|e| e.to_string())?;
+        if copied != count {
+            return Err("RDB file is truncated".to_string());
+        }
+        Ok(())
+    }
+
+    fn read_length_with_encoding(&mut self) -> Result<Length, String> {
+        let first = self.read_u8()?;
+        let length = match first >> 6 {
+            0 => Length::Plain((first & 0x3F) as u64),
+            1 => Length::Plain((((first & 0x3F) as u64) << 8) | self.read_u8()? as u64),
+            2 => match first {
+                0x80 => {
+                    let mut buf = [0u8; 4];
+                    self.read_exact(&mut buf)?;
+                    Length::Plain(u32::from_be_bytes(buf) as u64)
+                }
+                0x81 => {
+                    let mut buf = [0u8; 8];
+                    self.read_exact(&mut buf)?;
+                    Length::Plain(u64::from_be_bytes(buf))
+                }
+                _ => return Err(format!("Invalid RDB length prefix 0x{:02x}", first)),
+            },
+            _ => Length::Encoded((first & 0x3F) as u64),
+        };
+        Ok(length)
+    }
+
+    fn read_length(&mut self) -> Result<u64, String> {
+        match self.read_length_with_encoding()? {
+            Length::Plain(length) => Ok(length),
+            Length::Encoded(_) => Err("Unexpected encoded length in RDB file".to_string()),
+        }
+    }
+
+    /// Reads `length` bytes, growing the buffer as they arrive so a corrupt
+    /// length cannot allocate more than the file holds.
+    fn read_bytes(&mut self, length: u64) -> Result<Vec<u8>, String> {
+        let mut buf = Vec::new();
+        (&mut self.reader)
+            .take(length)
+            .read_to_end(&mut buf)
+            .map_err(|e| e.to_string())?;
+        if buf.len() as u64 != length {
+            return Err("RDB file is truncated".to_string());
+        }
+        Ok(buf)
+    }
+
+    fn read_string(&mut self) -> Result<Vec<u8>, String> {
+        match self.read_length_with_encoding()? {
+            Length::Plain(length) => self.read_bytes(length),
+            Length::Encoded(ENC_INT8) => Ok((self.read_u8()? as i8).to_string().into_bytes()),
+            Length::Encoded(ENC_INT16) => {
+                let mut buf = [0u8; 2];
+                self.read_exact(&mut buf)?;
+                Ok(i16::from_le_bytes(buf).to_string().into_bytes())
+            }
+            Length::Encoded(ENC_INT32) => {
+                let mut buf = [0u8; 4];
+                self.read_exact(&mut buf)?;
+                Ok(i32::from_le_bytes(buf).to_string().into_bytes())
+            }
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.read_length()?;
+                let len = self.read_length()?;
+                let compressed = self.read_bytes(compressed_len)?;
+                lzf_decompress(&compressed, len as usize)
+            }
+            Length::Encoded(other) => Err(format!("Unknown RDB string encoding {}", other)),
+        }
+    }
+}
+
+fn type_name(value_type: u8) -> &'static str {
+    match value_type {
+        TYPE_LIST | TYPE_LIST_ZIPLIST | TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => "list",
+        TYPE_SET | TYPE_SET_INTSET | TYPE_SET_LISTPACK => "set",
+        TYPE_ZSET | TYPE_ZSET_2 | TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => "zset",
+        TYPE_HASH | TYPE_HASH_ZIPMAP | TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => "hash",
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => "stream",
+        _ => "unknown",
+    }
+}
+
+fn lzf_decompress(input: &[u8], expected_len: usize) -> Result<Vec<u8>, String> {
+    let corrupt = || "Corrupt LZF data in RDB file".to_string();
+    // A back reference expands at most 3 input bytes to 264 output bytes.
+    let mut output = Vec::with_capacity(expected_len.min(input.len().saturating_mul(88)));
+    let mut i = 0;
+
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // Literal run of ctrl + 1 bytes.
+            let end = i + ctrl + 1;
+            output.extend_from_slice(input.get(i..end).ok_or_else(corrupt)?);
+            i = end;
+        } else {
+            // Back reference.
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(i).ok_or_else(corrupt)? as usize;
+                i += 1;
+            }
+            let offset = ((ctrl & 0x1F) << 8) + *input.get(i).ok_or_else(corrupt)? as usize + 1;
+            i += 1;
+            let start = output.len().checked_sub(offset).ok_or_else(corrupt)?;
+            for j in 0..len + 2 {
+                output.push(output[start + j]);
+            }
+        }
+    }
+
+    if output.len() != expected_len {
+        return Err(corrupt());
+    }
+    Ok(output)
+}
+
+/// CRC-64/Jones as used by Redis, reflected, without final xor.
+fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    const POLY: u64 = 0x95AC_9329_AC4B_C9B5;
+    for &byte in data {
+        crc ^= byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A version 11 dump of `body` followed by EOF and its checksum.
+    fn dump(body: &[u8]) -> Vec<u8> {
+        let mut file = b"REDIS0011".to_vec();
+        file.extend_from_slice(body);
+        file.push(OPCODE_EOF);
+        let crc = crc64(0, &file);
+        file.extend_from_slice(&crc.to_le_bytes());
+        file
+    }
+
+    fn string(value: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![value.len() as u8];
+        encoded.extend_from_slice(value);
+        encoded
+    }
+
+    /// The database and type of each skipped value.
+    type Skipped = Vec<(u64, &'static str)>;
+
+    fn read_all(file: &[u8]) -> Result<(Vec<RdbEntry>, Skipped), String> {
+        let mut reader = RdbReader::new(file)?;
+        let (mut entries, mut skipped) = (Vec::new(), Vec::new());
+        while let Some(entry) = reader.next_entry(&mut |db, kind| skipped.push((db, kind)))? {
+            entries.push(entry);
+        }
+        Ok((entries, skipped))
+    }
+
+    /// A dump with a string in database 0, an expiring integer encoded
+    /// string and a skipped list in database 2, and an LZF string.
+    fn sample() -> Vec<u8> {
+        let mut body = vec![OPCODE_AUX];
+        body.extend(string(b"redis-ver"));
+        body.extend(string(b"7.2.0"));
+        body.push(TYPE_STRING);
+        body.extend(string(b"plain"));
+        body.extend(string(b"value"));
+        body.extend([OPCODE_SELECTDB, 2, OPCODE_RESIZEDB, 2, 0]);
+        body.push(OPCODE_EXPIRETIME_MS);
+        body.extend(1_700_000_000_000u64.to_le_bytes());
+        body.push(TYPE_STRING);
+        body.extend(string(b"number"));
+        body.extend([0xC1, 0x39, 0x30]);
+        body.push(TYPE_LIST);
+        body.extend(string(b"list"));
+        body.push(2);
+        body.extend(string(b"a"));
+        body.extend(string(b"b"));
+        body.push(TYPE_STRING);
+        body.extend(string(b"compressed"));
+        // "abc" followed by a six byte back reference to it.
+        body.extend([0xC3, 6, 9, 2, b'a', b'b', b'c', 0x80, 2]);
+        dump(&body)
+    }
+
+    #[test]
+    fn crc64_matches_redis() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+    }
+
+    #[test]
+    fn reads_strings_and_skips_other_types() {
+        let (entries, skipped) = read_all(&sample()).unwrap();
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.db,
+                    entry.key.as_slice(),
+                    entry.value.as_slice(),
+                    entry.expires_at_ms,
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (0, &b"plain"[..], &b"value"[..], None),
+                (2, &b"number"[..], &b"12345"[..], Some(1_700_000_000_000)),
+                (2, &b"compressed"[..], &b"abcabcabc"[..], None),
+            ]
+        );
+        assert_eq!(skipped, vec![(2, "list")]);
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let file = sample();
+        for end in 0..file.len() {
+            assert!(
+                read_all(&file[..end]).is_err(),
+                "a dump cut at byte {} of {} was accepted",
+                end,
+                file.len()
+            );
+        }
+    }
+
+    #[test]
+    fn corrupted_checksum_is_rejected() {
+        let mut file = sample();
+        let last = file.len() - 1;
+        file[last] ^= 1;
+        assert!(read_all(&file).unwrap_err().contains("checksum"));
+
+        // Zero means the server did not compute one.
+        let checksum = file.len() - 8;
+        file[checksum..].fill(0);
+        assert!(read_all(&file).is_ok());
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        assert!(RdbReader::new(&b"REDIS"[..]).is_err());
+        assert!(RdbReader::new(&b"NOTREDIS0011"[..]).is_err());
+        assert!(RdbReader::new(&b"REDIS00x1"[..]).is_err());
+    }
+
+    #[test]
+    fn malformed_values_are_rejected() {
+        let unknown_type = dump(&[99, 1, b'k']);
+        assert!(read_all(&unknown_type).unwrap_err().contains("Unsupported"));
+
+        let module = dump(&[OPCODE_MODULE_AUX]);
+        assert!(read_all(&module).is_err());
+
+        let bad_length = dump(&[TYPE_STRING, 0x82]);
+        assert!(read_all(&bad_length).unwrap_err().contains("length"));
+
+        let bad_encoding = dump(&[TYPE_STRING, 1, b'k', 0xC5]);
+        assert!(read_all(&bad_encoding).unwrap_err().contains("encoding"));
+
+        // A back reference to before the start of the output.
+        let bad_lzf = dump(&[TYPE_STRING, 1, b'k', 0xC3, 2, 4, 0x20, 5]);
+        assert!(read_all(&bad_lzf).unwrap_err().contains("LZF"));
+    }
+
+    #[test]
+    fn huge_lengths_do_not_allocate() {
+        let mut huge = [0x81; 9];
+        huge[1..].copy_from_slice(&u64::MAX.to_be_bytes());
+
+        let mut string = vec![TYPE_STRING, 1, b'k'];
+        string.extend(huge);
+        assert!(read_all(&dump(&string)).is_err());
+
+        let mut lzf = vec![TYPE_STRING, 1, b'k', 0xC3];
+        lzf.extend(huge);
+        lzf.extend(huge);
+        assert!(read_all(&dump(&lzf)).is_err());
+
+        let mut lzf_output = vec![TYPE_STRING, 1, b'k', 0xC3, 1];
+        lzf_output.extend(huge);
+        lzf_output.push(0);
+        assert!(read_all(&dump(&lzf_output)).is_err());
+
+        let mut hash = vec![TYPE_HASH, 1, b'k'];
+        hash.extend(huge);
+        assert!(read_all(&dump(&hash)).is_err());
+    }
+}
@@ -0,0 +1,558 @@
+//! Asynchronous primary → replica replication. A replica downloads a
+//! snapshot of the primary along with the change sequence it was taken at,
+//! then tails the primary's change stream from that sequence onwards.
+//! Changes carry the key's current value rather than the value at the time
+//! of the change, so replaying a change more than once is harmless.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::web;
+use actix_web::web::Bytes;
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use kstore::changelog::ChangeRecord;
+use kstore::merkle::{KeyHash, MerkleTree};
+use kstore::{KvStore, current_timestamp};
+
+use crate::proxy::NodeSecret;
+
+pub const SEQ_HEADER: &str = "X-Replication-Seq";
+pub const LOG_HEADER: &str = "X-Replication-Log";
+pub const MAX_CHANGES_PER_POLL: usize = 1000;
+/// Keeps `POST /replication/values` bodies within the default JSON limit.
+pub const MAX_VALUES_PER_REQUEST: usize = 100;
+const STREAM_BATCH_SIZE: usize = 256;
+/// An idle change stream sends an empty line this often, so readers can
+/// tell a quiet primary from a dead connection.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a replica may go without hearing from the primary and still
+/// serve `bounded` reads itself. Longer than the stream heartbeat, so an
+/// idle primary does not make a replica look stale.
+pub const DEFAULT_MAX_STALENESS: u64 = 30;
+/// How often a streaming replica compares its data with the primary's.
+const ANTI_ENTROPY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Primary,
+    Replica,
+}
+
+/// How fresh a read must be, chosen per request with `?consistency=`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Consistency {
+    /// Served by the primary.
+    Strong,
+    /// Served locally if the replica heard from the primary recently
+    /// enough, by the primary otherwise.
+    Bounded,
+    /// Served locally, however stale.
+    Eventual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncState {
+    Bootstrapping,
+    Streaming,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplicationStatus {
+    #[serde(skip)]
+    pub role: Role,
+    pub primary: Option<String>,
+    pub state: Option<SyncState>,
+    /// Last primary sequence applied locally.
+    pub applied_seq: u64,
+    /// Newest sequence the primary reported.
+    pub primary_seq: u64,
+    /// The primary's change log `applied_seq` belongs to, once the replica
+    /// has bootstrapped from it.
+    #[serde(skip)]
+    pub log: Option<u64>,
+    pub last_sync_at: Option<u64>,
+    pub last_error: Option<String>,
+    /// When the replica's data was last compared with the primary's.
+    pub last_check_at: Option<u64>,
+    /// Keys found to differ from the primary and repaired since
+    /// replication started.
+    pub repaired_keys: u64,
+}
+
+pub struct Replication {
+    status: Mutex<ReplicationStatus>,
+    /// Bumped whenever replication is started or stopped, so a running
+    /// follow loop can tell it has been superseded.
+    generation: Mutex<u64>,
+    client: reqwest::Client,
+    /// This node's URL, naming it in signed requests to the primary.
+    id: String,
+    /// Signs requests to the primary, which refuses them unless signed.
+    secret: NodeSecret,
+}
+
+impl Replication {
+    pub fn new(id: String, secret: NodeSecret) -> Self {
+        Self {
+            status: Mutex::new(ReplicationStatus {
+                role: Role::Primary,
+                primary: None,
+                state: None,
+                applied_seq: 0,
+                primary_seq: 0,
+                log: None,
+                last_sync_at: None,
+                last_error: None,
+                last_check_at: None,
+                repaired_keys: 0,
+            }),
+            generation: Mutex::new(0),
+            client: reqwest::Client::new(),
+            id,
+            secret,
+        }
+    }
+
+    /// A signed request to the primary's replication endpoint at `url`.
+    fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        self.secret.request(&self.client, method, url, &self.id)
+    }
+
+    pub fn status(&self) -> ReplicationStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// The primary this node replicates from, if it is a replica.
+    pub fn primary(&self) -> Option<String> {
+        let status = self.status.lock().unwrap();
+        match status.role {
+            Role::Replica => status.primary.clone(),
+            Role::Primary => None,
+        }
+    }
+
+    /// Whether this node has data to serve: always for a primary, and for
+    /// a replica once it has completed its first sync with the primary.
+    pub fn has_synced(&self) -> bool {
+        let status = self.status.lock().unwrap();
+        status.role == Role::Primary || status.last_sync_at.is_some()
+    }
+
+    /// Client for requests to the primary.
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Whether this replica is streaming from the primary and has heard
+    /// from it within the last `max_staleness` seconds.
+    pub fn is_fresh(&self, max_staleness: u64) -> bool {
+        let status = self.status.lock().unwrap();
+        status.state == Some(SyncState::Streaming)
+            && status
+                .last_sync_at
+                .is_some_and(|at| current_timestamp().saturating_sub(at) <= max_staleness)
+    }
+
+    /// Starts following `primary` in the background, replacing the local
+    /// dataset with the primary's.
+    pub fn start(replication: web::Data<Self>, store: web::Data<KvStore>, primary: String) {
+        let primary = primary.trim_end_matches('/').to_string();
+        let generation = {
+            let mut generation = replication.generation.lock().unwrap();
+            *generation += 1;
+            *generation
+        };
+        *replication.status.lock().unwrap() = ReplicationStatus {
+            role: Role::Replica,
+            primary: Some(primary.clone()),
+            state: Some(SyncState::Bootstrapping),
+            applied_seq: 0,
+            primary_seq: 0,
+            log: None,
+            last_sync_at: None,
+            last_error: None,
+            last_check_at: None,
+            repaired_keys: 0,
+        };
+        actix_web::rt::spawn(anti_entropy(
+            replication.clone(),
+            store.clone(),
+            primary.clone(),
+            generation,
+        ));
+        actix_web::rt::spawn(follow(replication, store, primary, generation));
+    }
+
+    /// Stops following the primary and makes this node writable again.
+    /// Returns false if it was not a replica.
+    pub fn promote(&self) -> bool {
+        let mut status = self.status.lock().unwrap();
+        if status.role != Role::Replica {
+            return false;
+        }
+        *self.generation.lock().unwrap() += 1;
+        status.role = Role::Primary;
+        status.primary = None;
+        status.state = None;
+        true
+    }
+
+    fn is_current(&self, generation: u64) -> bool {
+        *self.generation.lock().unwrap() == generation
+    }
+
+    fn update(&self, generation: u64, f: impl FnOnce(&mut ReplicationStatus)) {
+        if self.is_current(generation) {
+            f(&mut self.status.lock().unwrap());
+        }
+    }
+}
+
+async fn follow(
+    replication: web::Data<Replication>,
+    store: web::Data<KvStore>,
+    primary: String,
+    generation: u64,
+) {
+    // Change log id and last applied sequence, once bootstrapped.
+    let mut applied: Option<(u64, u64)> = None;
+
+    while replication.is_current(generation) {
+        let result = match applied {
+            None => bootstrap(&replication, &primary, &store)
+                .await
+                .map(|(log, seq)| {
+                    applied = Some((log, seq));
+                    replication.update(generation, |status| {
+                        status.applied_seq = seq;
+                        status.primary_seq = seq;
+                        status.log = Some(log);
+                    });
+                    false
+                }),
+            Some((log, mut seq)) => {
+                let start = seq;
+                let result = tail(&primary, &store, &replication, generation, log, &mut seq).await;
+                applied = Some((log, seq));
+                match result {
+                    Ok(true) => Ok(seq == start),
+                    Ok(false) => {
+                        applied = None;
+                        Ok(false)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        };
+
+        match result {
+            Ok(caught_up) => {
+                replication.update(generation, |status| {
+                    status.last_sync_at = Some(current_timestamp());
+                    status.last_error = None;
+                    status.state = Some(if applied.is_some() {
+                        SyncState::Streaming
+                    } else {
+                        SyncState::Bootstrapping
+                    });
+                });
+                if caught_up {
+                    actix_web::rt::time::sleep(RECONNECT_INTERVAL).await;
+                }
+            }
+            Err(e) => {
+                replication.update(generation, |status| {
+                    status.state = Some(SyncState::Error);
+                    status.last_error = Some(e);
+                });
+                actix_web::rt::time::sleep(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Replaces the local dataset with the primary's snapshot and returns the
+/// change log and sequence it was taken at.
+async fn bootstrap(
+    replication: &Replication,
+    primary: &str,
+    store: &KvStore,
+) -> Result<(u64, u64), String> {
+    let response = replication
+        .request(
+            reqwest::Method::GET,
+            &format!("{}/replication/snapshot", primary),
+        )
+        .send()
+        .await
+        .map_err(|e| format!("Snapshot request failed: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "Snapshot request failed with {}",
+            response.status()
+        ));
+    }
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .ok_or("Primary did not report a snapshot sequence")
+    };
+    let log = header(LOG_HEADER)?;
+    let seq = header(SEQ_HEADER)?;
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| format!("Snapshot download failed: {}", e))?;
+    store.restore(&body).map_err(|e| e.to_string())?;
+    Ok((log, seq))
+}
+
+/// Applies changes from the primary's change stream until it ends,
+/// advancing `seq` as they are applied. Returns false if the primary no
+/// longer has the changes (or has restarted with a new log) and the replica
+/// must bootstrap again.
+async fn tail(
+    primary: &str,
+    store: &KvStore,
+    replication: &Replication,
+    generation: u64,
+    log: u64,
+    seq: &mut u64,
+) -> Result<bool, String> {
+    let url = format!("{}/replication/stream?log={}&since={}", primary, log, seq);
+    let mut response = replication
+        .request(reqwest::Method::GET, &url)
+        .send()
+        .await
+        .map_err(|e| format!("Change stream request failed: {}", e))?;
+    if response.status() == reqwest::StatusCode::GONE {
+        return Ok(false);
+    }
+    if !response.status().is_success() {
+        return Err(format!(
+            "Change stream request failed with {}",
+            response.status()
+        ));
+    }
+
+    let mut buffer: Vec<u8> = Vec::new();
+    loop {
+        let chunk = actix_web::rt::time::timeout(STREAM_IDLE_TIMEOUT, response.chunk())
+            .await
+            .map_err(|_| "Change stream timed out".to_string())?
+            .map_err(|e| format!("Change stream failed: {}", e))?;
+        let Some(chunk) = chunk else {
+            return Ok(true);
+        };
+        // A promotion while the stream was open must not see more writes.
+        if !replication.is_current(generation) {
+            return Ok(true);
+        }
+        buffer.extend_from_slice(&chunk);
+
+        let mut start = 0;
+        while let Some(offset) = buffer[start..].iter().position(|&b| b == b'\n') {
+            let line = &buffer[start..start + offset];
+            start += offset + 1;
+            if line.is_empty() {
+                continue;
+            }
+            let change: ChangeRecord = serde_json::from_slice(line)
+                .map_err(|e| format!("Invalid change record: {}", e))?;
+            match change.value {
+                Some(value) => store
+                    .set_with(change.key.to_string(), value, change.attributes)
+                    .map_err(|e| e.to_string())?,
+                None => {
+                    store.delete(&change.key).map_err(|e| e.to_string())?;
+                }
+            }
+            *seq = change.seq;
+        }
+        buffer.drain(..start);
+
+        replication.update(generation, |status| {
+            status.applied_seq = *seq;
+            status.primary_seq = status.primary_seq.max(*seq);
+            status.last_sync_at = Some(current_timestamp());
+        });
+    }
+}
+
+/// Periodically compares the replica's Merkle tree with the primary's and
+/// repairs keys the change stream missed, for as long as this generation
+/// of replication lasts.
+async fn anti_entropy(
+    replication: web::Data<Replication>,
+    store: web::Data<KvStore>,
+    primary: String,
+    generation: u64,
+) {
+    loop {
+        actix_web::rt::time::sleep(ANTI_ENTROPY_INTERVAL).await;
+        if !replication.is_current(generation) {
+            return;
+        }
+        // Comparing while bootstrapping would only find the keys the
+        // snapshot is about to bring in.
+        if replication.status().state != Some(SyncState::Streaming) {
+            continue;
+        }
+        match repair(&primary, &store, &replication, generation).await {
+            Ok(repaired) => replication.update(generation, |status| {
+                status.last_check_at = Some(current_timestamp());
+                status.repaired_keys += repaired;
+            }),
+            Err(e) => replication.update(generation, |status| {
+                status.last_error = Some(e);
+            }),
+        }
+    }
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(
+    request: reqwest::RequestBuilder,
+) -> Result<T, String> {
+    let response = request
+        .send()
+        .await
+        .map_err(|e| format!("Anti-entropy request failed: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "Anti-entropy request failed with {}",
+            response.status()
+        ));
+    }
+    response
+        .json()
+        .await
+        .map_err(|e| format!("Invalid anti-entropy response: {}", e))
+}
+
+/// One anti-entropy round. Fetches the primary's root first, and the rest
+/// of the tree and the differing key ranges only if it does not match.
+/// Returns the number of keys repaired.
+async fn repair(
+    primary: &str,
+    store: &KvStore,
+    replication: &Replication,
+    generation: u64,
+) -> Result<u64, String> {
+    let url = format!("{}/replication/merkle", primary);
+    let get = |url: &str| replication.request(reqwest::Method::GET, url);
+    let remote_root: MerkleTree = get_json(get(&format!("{}?depth=0", url))).await?;
+    let local = store.merkle_tree();
+    if remote_root.root() == local.root() {
+        return Ok(0);
+    }
+    let remote: MerkleTree = get_json(get(&url)).await?;
+
+    let mut repaired = 0;
+    for leaf in local.diff(&remote) {
+        let remote_keys: Vec<KeyHash> = get_json(get(&format!("{}/{}", url, leaf))).await?;
+        let local_keys = store.merkle_leaf(leaf);
+        let local_hashes: HashMap<&str, &str> = local_keys
+            .iter()
+            .map(|entry| (entry.key.as_str(), entry.hash.as_str()))
+            .collect();
+        let remote_hashes: HashMap<&str, &str> = remote_keys
+            .iter()
+            .map(|entry| (entry.key.as_str(), entry.hash.as_str()))
+            .collect();
+
+        let mut differing: Vec<&str> = remote_keys
+            .iter()
+            .filter(|entry| local_hashes.get(entry.key.as_str()) != Some(&entry.hash.as_str()))
+            .map(|entry| entry.key.as_str())
+            .collect();
+        differing.extend(
+            local_keys
+                .iter()
+                .filter(|entry| !remote_hashes.contains_key(entry.key.as_str()))
+                .map(|entry| entry.key.as_str()),
+        );
+
+        for keys in differing.chunks(MAX_VALUES_PER_REQUEST) {
+            let values: HashMap<String, Option<String>> = get_json(
+                replication
+                    .request(
+                        reqwest::Method::POST,
+                        &format!("{}/replication/values", primary),
+                    )
+                    .json(keys),
+            )
+            .await?;
+            if !replication.is_current(generation) {
+                return Ok(repaired);
+            }
+            for key in keys {
+                let Some(value) = values.get(*key) else {
+                    continue;
+                };
+                // Skip keys the change stream has touched since they were
+                // compared; the stream has the newer value.
+                let expected = local_hashes.get(key).copied();
+                if store
+                    .repair(key, expected, value.clone())
+                    .map_err(|e| e.to_string())?
+                {
+                    repaired += 1;
+                }
+            }
+        }
+    }
+    Ok(repaired)
+}
+
+/// Streams change records after `since` as newline-delimited JSON, waiting
+/// for new changes once caught up. Records are only read from the log when
+/// the client is ready for more, so a slow reader holds back the stream
+/// rather than buffering it. The stream ends if the reader falls so far
+/// behind that the log no longer has the changes it needs.
+pub fn change_stream(
+    store: web::Data<KvStore>,
+    log: u64,
+    since: u64,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    // Subscribe before the first read so no wakeup is missed.
+    let receiver = store.subscribe();
+    futures_util::stream::unfold(
+        (store, receiver, since),
+        move |(store, mut receiver, seq)| async move {
+            loop {
+                let batch = store.changes_since(log, seq, STREAM_BATCH_SIZE)?;
+                if let Some(last) = batch.changes.last() {
+                    let next = last.seq;
+                    let mut chunk = String::new();
+                    for change in &batch.changes {
+                        chunk.push_str(&serde_json::to_string(change).unwrap_or_default());
+                        chunk.push('\n');
+                    }
+                    return Some((Ok(Bytes::from(chunk)), (store, receiver, next)));
+                }
+                match actix_web::rt::time::timeout(HEARTBEAT_INTERVAL, receiver.recv()).await {
+                    Err(_) => {
+                        return Some((Ok(Bytes::from_static(b"\n")), (store, receiver, seq)));
+                    }
+                    Ok(Err(RecvError::Closed)) => return None,
+                    // A new change, or so many that some were missed: read the log again.
+                    Ok(_) => {}
+                }
+            }
+        },
+    )
+}
@@ -0,0 +1,45 @@
+//! A key prefix kept for the server's own state, such as persisted stats or
+//! format markers. Clients can read keys under it but not write or delete
+//! them; the server writes them through the store directly, and replicas
+//! copy them from their primary like any other key.
+
+use sha2::{Digest, Sha256};
+
+pub struct Reserved {
+    prefix: Option<String>,
+}
+
+impl Reserved {
+    /// An empty prefix reserves nothing.
+    pub fn new(prefix: String) -> Self {
+        Self {
+            prefix: Some(prefix).filter(|prefix| !prefix.is_empty()),
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.prefix
+            .as_deref()
+            .is_some_and(|prefix| key.starts_with(prefix))
+    }
+
+    /// Fails with the message for clients if `key` is reserved.
+    pub fn check(&self, key: &str) -> Result<(), String> {
+        match &self.prefix {
+            Some(prefix) if key.starts_with(prefix.as_str()) => Err(format!(
+                "Keys starting with {} are reserved for internal use",
+                prefix
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Name of the reserved key holding state about `key`, under a namespace
+/// such as `__kstore/origin/`. The key is hashed, so the name fits the key
+/// size limit however long `key` is.
+pub fn record_key(namespace: &str, key: &str) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+    let hash: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}{}", namespace, hash)
+}
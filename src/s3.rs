@@ -0,0 +1,250 @@
+//! Minimal S3 client for shipping backups to S3-compatible object storage
+//! (AWS S3, MinIO, Ceph RGW). Requests use path-style URLs and are signed
+//! with AWS Signature Version 4.
+
+use actix_web::web::Bytes;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Prepended to every object key, e.g. `kstore/prod/`.
+    pub prefix: String,
+}
+
+impl S3Config {
+    /// Reads `KSTORE_S3_*` environment variables. Returns `None` unless a
+    /// bucket and credentials are configured.
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let bucket = var("KSTORE_S3_BUCKET")?;
+        let access_key = var("KSTORE_S3_ACCESS_KEY")?;
+        let secret_key = var("KSTORE_S3_SECRET_KEY")?;
+        let region = var("KSTORE_S3_REGION").unwrap_or_else(|| "us-east-1".to_string());
+        let endpoint = var("KSTORE_S3_ENDPOINT")
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+
+        Some(Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            bucket,
+            region,
+            access_key,
+            secret_key,
+            prefix: var("KSTORE_S3_PREFIX").unwrap_or_default(),
+        })
+    }
+}
+
+pub struct S3Client {
+    config: S3Config,
+    http: reqwest::Client,
+}
+
+impl S3Client {
+    pub fn new(config: S3Config) -> Self {
+        Self {
+            config,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn object_key(&self, name: &str) -> String {
+        format!("{}{}", self.config.prefix, name)
+    }
+
+    pub async fn put_object(&self, name: &str, body: Vec<u8>) -> Result<(), String> {
+        let payload_hash = hex(&Sha256::digest(&body));
+        let request = self.signed_request(reqwest::Method::PUT, name, &payload_hash)?;
+        let response = request
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| format!("S3 upload failed: {}", e))?;
+        if !response.status().is_success() {
+            return Err(error_message("upload", response).await);
+        }
+        Ok(())
+    }
+
+    /// Returns `Ok(None)` if the object does not exist.
+    pub async fn get_object(&self, name: &str) -> Result<Option<Bytes>, String> {
+        let payload_hash = hex(&Sha256::digest(b""));
+        let request = self.signed_request(reqwest::Method::GET, name, &payload_hash)?;
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("S3 download failed: {}", e))?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(error_message("download", response).await);
+        }
+        response
+            .bytes()
+            .await
+            .map(Some)
+            .map_err(|e| format!("S3 download failed: {}", e))
+    }
+
+    fn signed_request(
+        &self,
+        method: reqwest::Method,
+        name: &str,
+        payload_hash: &str,
+    ) -> Result<reqwest::RequestBuilder, String> {
+        let path = format!(
+            "/{}/{}",
+            uri_encode(&self.config.bucket, true),
+            uri_encode(&self.object_key(name), false)
+        );
+        let url = reqwest::Url::parse(&format!("{}{}", self.config.endpoint, path))
+            .map_err(|e| format!("Invalid S3 endpoint: {}", e))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            _ => return Err("Invalid S3 endpoint: missing host".to_string()),
+        };
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let amz_date = format_amz_date(now);
+        let headers = [
+            ("host", host.as_str()),
+            ("x-amz-content-sha256", payload_hash),
+            ("x-amz-date", amz_date.as_str()),
+        ];
+        let authorization = sign(
+            &self.config,
+            method.as_str(),
+            &path,
+            &headers,
+            payload_hash,
+            &amz_date,
+        );
+
+        Ok(self
+            .http
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", &amz_date)
+            .header("authorization", authorization))
+    }
+}
+
+async fn error_message(action: &str, response: reqwest::Response) -> String {
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    format!("S3 {} failed with {}: {}", action, status, body.trim())
+}
+
+/// Builds the SigV4 `Authorization` header. `headers` must be lowercase
+/// and sorted by name.
+fn sign(
+    config: &S3Config,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    payload_hash: &str,
+    amz_date: &str,
+) -> String {
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/s3/aws4_request", date, config.region);
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+
+    let canonical_request = format!(
+        "{}\n{}\n\n{}\n{}\n{}",
+        method, path, canonical_headers, signed_headers, payload_hash
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = hmac(
+        format!("AWS4{}", config.secret_key).as_bytes(),
+        date.as_bytes(),
+    );
+    let key = hmac(&key, config.region.as_bytes());
+    let key = hmac(&key, b"s3");
+    let key = hmac(&key, b"aws4_request");
+    let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        config.access_key, scope, signed_headers, signature
+    )
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Percent-encodes everything except RFC 3986 unreserved characters, and
+/// `/` unless `encode_slash` is set, as SigV4 requires.
+fn uri_encode(input: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for b in input.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Formats a Unix timestamp as `YYYYMMDDTHHMMSSZ`.
+fn format_amz_date(timestamp: u64) -> String {
+    let days = (timestamp / 86_400) as i64;
+    let seconds = timestamp % 86_400;
+
+    // Civil-from-days conversion for the proleptic Gregorian calendar.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        (seconds % 3600) / 60,
+        seconds % 60
+    )
+}
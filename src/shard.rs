@@ -0,0 +1,142 @@
+//! Sharding: keys are spread over a fixed set of nodes with a consistent
+//! hash ring, and a node proxies requests for keys it does not own to the
+//! node that does. Each node is placed on the ring many times so keys are
+//! spread evenly, and adding or removing a node only moves the keys next
+//! to its points.
+
+use std::sync::Mutex;
+
+use actix_web::HttpResponse;
+use actix_web::dev::ServiceRequest;
+use actix_web::web::Bytes;
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::proxy::{self, NodeSecret};
+
+const VIRTUAL_NODES: usize = 128;
+
+/// Routes that operate on a single key, taken from the second path segment.
+const KEY_ROUTES: [&str; 6] = [
+    "/kv/{key}",
+    "/kv/{key}/info",
+    "/kv/{key}/exists",
+    "/geo/{key}",
+    "/geo/{key}/radius",
+    "/geo/{key}/box",
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ShardStatus {
+    pub id: String,
+    pub nodes: Vec<String>,
+    pub virtual_nodes: usize,
+}
+
+struct Ring {
+    nodes: Vec<String>,
+    /// Points on the ring, sorted by hash, each pointing into `nodes`.
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    fn new(mut nodes: Vec<String>) -> Self {
+        // Every node must build the same ring from the same list, in
+        // whatever order it was given.
+        nodes.sort();
+        nodes.dedup();
+        let mut points: Vec<(u64, usize)> = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(index, node)| {
+                (0..VIRTUAL_NODES).map(move |i| (hash(format!("{}#{}", node, i).as_bytes()), index))
+            })
+            .collect();
+        points.sort_unstable();
+        Self { nodes, points }
+    }
+}
+
+pub struct Sharding {
+    id: String,
+    ring: Mutex<Ring>,
+    client: reqwest::Client,
+    secret: NodeSecret,
+}
+
+fn hash(data: &[u8]) -> u64 {
+    let digest = Sha256::digest(data);
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+impl Sharding {
+    /// `nodes` lists every node, including this one as `id`, and `secret`
+    /// is the one they share to sign forwarded requests.
+    pub fn new(id: String, nodes: Vec<String>, secret: NodeSecret) -> Self {
+        let id = id.trim_end_matches('/').to_string();
+        let mut nodes: Vec<String> = nodes
+            .into_iter()
+            .map(|node| node.trim_end_matches('/').to_string())
+            .collect();
+        nodes.push(id.clone());
+        Self {
+            id,
+            ring: Mutex::new(Ring::new(nodes)),
+            client: reqwest::Client::new(),
+            secret,
+        }
+    }
+
+    pub fn status(&self) -> ShardStatus {
+        ShardStatus {
+            id: self.id.clone(),
+            nodes: self.ring.lock().unwrap().nodes.clone(),
+            virtual_nodes: VIRTUAL_NODES,
+        }
+    }
+
+    /// Rebuilds the ring from `nodes`, which lists every node including
+    /// this one.
+    pub fn set_nodes(&self, nodes: &[String]) {
+        let mut ring = self.ring.lock().unwrap();
+        let mut sorted = nodes.to_vec();
+        sorted.sort();
+        if ring.nodes != sorted {
+            *ring = Ring::new(sorted);
+        }
+    }
+
+    /// The node owning `key`: the first point on the ring at or after the
+    /// key's hash, wrapping around.
+    pub fn owner(&self, key: &str) -> String {
+        let ring = self.ring.lock().unwrap();
+        let point = hash(key.as_bytes());
+        let index = ring.points.partition_point(|&(hash, _)| hash < point);
+        let (_, node) = ring.points[index % ring.points.len()];
+        ring.nodes[node].clone()
+    }
+
+    /// The node a request should be proxied to, if it addresses a single
+    /// key that another node owns.
+    pub fn remote_owner(&self, req: &ServiceRequest) -> Option<String> {
+        // Serve proxied requests even if this node's ring disagrees, so
+        // nodes whose views differ do not pass a request back and forth.
+        if self.secret.is_forwarded(req) {
+            return None;
+        }
+        let pattern = req.match_pattern()?;
+        if !KEY_ROUTES.contains(&pattern.as_str()) {
+            return None;
+        }
+        let segment = req.path().split('/').nth(2)?;
+        let key = percent_decode_str(segment).decode_utf8().ok()?;
+        let owner = self.owner(&key);
+        (owner != self.id).then_some(owner)
+    }
+
+    /// Sends the request to `owner` and relays its response.
+    pub async fn forward(&self, owner: &str, req: &ServiceRequest, body: Bytes) -> HttpResponse {
+        proxy::forward(&self.client, &self.secret, owner, &self.id, req, body).await
+    }
+}
@@ -0,0 +1,229 @@
+//! `kstore shell`: an interactive prompt for a running server, with line
+//! editing, history kept across sessions, and tab completion over command
+//! names and keys.
+
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use actix_web::rt::Runtime;
+use kstore_client::Client;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::FileHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+use crate::config::ShellArgs;
+
+const HISTORY_FILE: &str = ".kstore_history";
+/// Most keys offered for one completion.
+const COMPLETION_LIMIT: usize = 100;
+const COMMANDS: [&str; 8] = [
+    "get", "set", "del", "exists", "keys", "help", "quit", "exit",
+];
+const HELP: &str = "\
+get <key>            print the value of a key
+set <key> <value>    create or overwrite a key
+del <key>            delete a key
+exists <key>         check whether a key exists
+keys [prefix]        list keys, optionally under a prefix
+help                 show this help
+quit, exit           leave the shell
+
+Quote keys and values containing spaces with \"double\" or 'single' quotes.";
+
+struct ShellHelper {
+    client: Client,
+    runtime: Rc<Runtime>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &line[start..];
+        let earlier: Vec<&str> = line[..start].split_whitespace().collect();
+        let candidates = match earlier.as_slice() {
+            [] => COMMANDS
+                .iter()
+                .filter(|command| command.starts_with(word))
+                .map(|command| format!("{} ", command))
+                .collect(),
+            [command] if ["get", "set", "del", "exists", "keys"].contains(command) => self
+                .runtime
+                .block_on(self.client.keys(Some(word), Some(COMPLETION_LIMIT)))
+                .unwrap_or_default()
+                .into_iter()
+                .map(|key| quote(&key))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+/// Quotes `word` if it would not survive `split_args` as a single word.
+fn quote(word: &str) -> String {
+    if !word.is_empty() && !word.contains(|c: char| c.is_whitespace() || "\"'\\".contains(c)) {
+        return word.to_string();
+    }
+    let escaped: String = word
+        .chars()
+        .flat_map(|c| match c {
+            '"' | '\\' => vec!['\\', c],
+            c => vec![c],
+        })
+        .collect();
+    format!("\"{}\"", escaped)
+}
+
+/// Splits a command line into words. Double-quoted words may contain
+/// backslash escapes; single-quoted words are taken literally.
+fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Ok(words);
+        };
+        let mut word = String::new();
+        match first {
+            '"' => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => word.extend(chars.next()),
+                        Some(c) => word.push(c),
+                        None => return Err("Unterminated double quote".to_string()),
+                    }
+                }
+            }
+            '\'' => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("Unterminated single quote".to_string()),
+                    }
+                }
+            }
+            _ => {
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    word.push(c);
+                }
+            }
+        }
+        words.push(word);
+    }
+}
+
+/// Runs one command, returning what to print.
+async fn execute(client: &Client, words: &[String]) -> Result<String, String> {
+    let args: Vec<&str> = words.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["get", key] => client
+            .get(key)
+            .await
+            .map(|value| value.unwrap_or_else(|| "(nil)".to_string())),
+        ["set", key, value] => client.set(key, *value).await.map(|()| "OK".to_string()),
+        ["del", key] => client
+            .delete(key)
+            .await
+            .map(|deleted| if deleted { "OK" } else { "(not found)" }.to_string()),
+        ["exists", key] => client
+            .exists(key)
+            .await
+            .map(|exists| if exists { "yes" } else { "no" }.to_string()),
+        ["keys"] | ["keys", _] => client.keys(args.get(1).copied(), None).await.map(|keys| {
+            if keys.is_empty() {
+                return "(empty)".to_string();
+            }
+            keys.iter()
+                .enumerate()
+                .map(|(i, key)| format!("{}) {}", i + 1, quote(key)))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }),
+        ["help"] => Ok(HELP.to_string()),
+        [command, ..] if COMMANDS.contains(command) => {
+            return Err(format!("Wrong arguments for '{}', see 'help'", command));
+        }
+        [command, ..] => return Err(format!("Unknown command '{}', see 'help'", command)),
+        [] => Ok(String::new()),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+pub fn run(args: ShellArgs) -> std::io::Result<()> {
+    let runtime = Rc::new(Runtime::new()?);
+    let client = Client::new(&args.url).map_err(std::io::Error::other)?;
+    let mut editor: Editor<ShellHelper, FileHistory> =
+        Editor::new().map_err(std::io::Error::other)?;
+    editor.set_helper(Some(ShellHelper {
+        client: client.clone(),
+        runtime: runtime.clone(),
+    }));
+    let history: Option<PathBuf> =
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    if let Some(history) = &history {
+        // A missing history file just means a first session.
+        let _ = editor.load_history(history);
+    }
+
+    println!("Connected to {}. Type 'help' for commands.", args.url);
+    loop {
+        let line = match editor.readline("kstore> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(std::io::Error::other(e)),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+        let words = match split_args(&line) {
+            Ok(words) => words,
+            Err(e) => {
+                println!("(error) {}", e);
+                continue;
+            }
+        };
+        if matches!(words[0].as_str(), "quit" | "exit") {
+            break;
+        }
+        match runtime.block_on(execute(&client, &words)) {
+            Ok(output) => println!("{}", output),
+            Err(e) => println!("(error) {}", e),
+        }
+    }
+
+    if let Some(history) = &history
+        && let Err(e) = editor.save_history(history)
+    {
+        eprintln!("Failed to save history: {}", e);
+    }
+    Ok(())
+}
@@ -0,0 +1,97 @@
+//! Orderly shutdown. On SIGTERM, SIGINT or `POST /admin/shutdown` the
+//! server stops accepting
+//! connections, ends its streaming responses, waits for in-flight
+//! requests and only then makes the data durable, so a stop never loses a write that
+//! was already acknowledged.
+
+use std::future::Future;
+
+use actix_web::dev::ServerHandle;
+use actix_web::rt::signal;
+use actix_web::rt::task::JoinHandle;
+use futures_util::FutureExt;
+use kstore::KvStore;
+use tokio::sync::watch;
+
+pub struct Shutdown {
+    started: watch::Sender<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            started: watch::channel(false).0,
+        }
+    }
+}
+
+impl Shutdown {
+    /// Starts shutting down. Calling it again has no effect.
+    pub fn start(&self) {
+        self.started.send_replace(true);
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.started.borrow()
+    }
+
+    /// Resolves once shutdown has started. Long-lived responses end on it,
+    /// since the server would otherwise wait for them until its timeout.
+    pub fn started(&self) -> impl Future<Output = ()> + 'static {
+        let mut started = self.started.subscribe();
+        async move {
+            let _ = started.wait_for(|&started| started).await;
+        }
+    }
+}
+
+async fn terminated() {
+    #[cfg(unix)]
+    {
+        use signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                futures_util::future::select(terminate.recv().boxed(), signal::ctrl_c().boxed())
+                    .await;
+            }
+            Err(_) => {
+                let _ = signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = signal::ctrl_c().await;
+    }
+}
+
+/// Waits for SIGTERM, SIGINT or [`Shutdown::start`], then stops `tasks`
+/// and shuts the server down gracefully. The server must be run with its
+/// own signal handling disabled.
+pub async fn run(
+    shutdown: actix_web::web::Data<Shutdown>,
+    server: ServerHandle,
+    tasks: Vec<JoinHandle<()>>,
+) {
+    futures_util::future::select(terminated().boxed(), shutdown.started().boxed()).await;
+    println!("Shutting down, draining in-flight requests");
+    crate::systemd::notify_stopping();
+    shutdown.start();
+    // Background loops would keep this node acting as a cluster member
+    // while it no longer serves requests.
+    for task in tasks {
+        task.abort();
+    }
+    server.stop(true).await;
+}
+
+/// Runs after the server has stopped: compacts the data file if asked to
+/// and flushes it to disk.
+pub fn finish(store: &KvStore, compact: bool) -> std::io::Result<()> {
+    if compact {
+        store.compact().map_err(std::io::Error::other)?;
+    }
+    store.sync().map_err(std::io::Error::other)?;
+    println!("Data flushed, exiting");
+    Ok(())
+}
@@ -0,0 +1,47 @@
+//! Keeps the `/stats` counters and per-key access counts across restarts.
+//! They are saved to a small file next to the data every minute and on
+//! shutdown, and loaded again at startup, which counts as a restart.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use actix_web::rt::time::sleep;
+use actix_web::web;
+use kstore::{KvStore, SavedStats};
+
+/// In the working directory like the data file.
+pub const STATS_FILE: &str = "kvstore.stats";
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Restores the counters saved at `path`. A missing file means this is
+/// the first run.
+pub fn load(store: &KvStore, path: &Path) -> Result<(), String> {
+    let saved = match std::fs::read(path) {
+        Ok(saved) => saved,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.to_string()),
+    };
+    let saved: SavedStats = serde_json::from_slice(&saved).map_err(|e| e.to_string())?;
+    store.restore_stats(saved);
+    Ok(())
+}
+
+/// Saves the counters to `path`, replacing it only once the new copy is
+/// complete.
+pub fn save(store: &KvStore, path: &Path) -> std::io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    std::fs::write(&temp_path, serde_json::to_vec(&store.saved_stats())?)?;
+    std::fs::rename(&temp_path, path)
+}
+
+/// Saves the counters now, recording the restart straight away, and then
+/// every minute until the server shuts down.
+pub async fn run(store: web::Data<KvStore>, path: PathBuf) {
+    loop {
+        if let Err(e) = save(&store, &path) {
+            eprintln!("Failed to save statistics: {}", e);
+        }
+        sleep(SAVE_INTERVAL).await;
+    }
+}
@@ -0,0 +1,1059 @@
+//! Where a [`KvStore`](crate::KvStore) persists its data. The store keeps
+//! every key in memory and only calls its backend to record changes and to
+//! load them again on startup, so a backend is a durable log of sets and
+//! deletes that can be rewritten down to the live keys.
+//!
+//! A record can also carry the key's [`Attributes`], such as when it
+//! expires, ahead of its value, so the two are always written together.
+//!
+//! The data file can also hold deltas: when a large value is replaced by
+//! one that differs only in a part of it, just that part is appended, with
+//! the whole value written again every few deltas so loading never replays
+//! a long chain.
+//!
+//! The file backend keeps the data file as a checkpoint of every live key
+//! and records changes in a write-ahead log next to it, `FILE.wal`, in the
+//! same format. Loading replays the log over the checkpoint, and a
+//! compaction writes a new checkpoint and empties the log, so deletes are
+//! just appended and recovery only ever replays the changes since the last
+//! checkpoint.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{Attributes, Error};
+
+pub trait StorageBackend: Send {
+    /// Returns every live key with its value and attributes, in no
+    /// particular order.
+    fn load(&mut self) -> Result<Vec<(String, String, Attributes)>, Error>;
+
+    /// Records `key` being set to `value` with `attributes`, or deleted if
+    /// `value` is empty.
+    fn append(&mut self, key: &str, value: &str, attributes: &Attributes) -> Result<(), Error>;
+
+    /// Records `keys` being deleted, all or none of them.
+    fn delete(&mut self, keys: &[&str]) -> Result<(), Error> {
+        for key in keys {
+            self.append(key, "", &Attributes::NONE)?;
+        }
+        Ok(())
+    }
+
+    /// Records each of `entries` being set, all or none of them.
+    fn append_batch(&mut self, entries: &[Entry<'_>]) -> Result<(), Error> {
+        for (key, value, attributes) in entries {
+            self.append(key, value, attributes)?;
+        }
+        Ok(())
+    }
+
+    /// Records `key` changing from `previous` to `value`, which backends
+    /// may store as the difference between them.
+    fn append_update(
+        &mut self,
+        key: &str,
+        previous: &str,
+        value: &str,
+        attributes: &Attributes,
+    ) -> Result<(), Error> {
+        let _ = previous;
+        self.append(key, value, attributes)
+    }
+
+    /// Replaces everything stored with `entries`, dropping overwritten
+    /// values and deleted keys.
+    fn compact(&mut self, entries: &mut dyn Iterator<Item = Entry<'_>>) -> Result<(), Error>;
+
+    /// Makes every change recorded so far durable.
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Bytes the data takes up on disk, or `None` if it is not on disk.
+    fn size(&mut self) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+
+    /// Bytes of changes recorded since the last compaction, for backends
+    /// that log them apart from a checkpoint.
+    fn log_size(&mut self) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+
+    /// Writes a copy of the live keys to `path` in the data file format.
+    fn snapshot(&mut self, path: &Path) -> Result<(), Error> {
+        let mut file = BufWriter::new(File::create(path)?);
+        for (key, value, attributes) in self.load()? {
+            write_record(&mut file, &key, &value, &attributes)?;
+        }
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(())
+    }
+}
+
+/// A live key as backends are given it to store: the key, its value and
+/// its attributes.
+pub type Entry<'a> = (&'a str, &'a str, &'a Attributes);
+
+/// Appends one record in the data file format:
+/// `[key_size: u64 LE][value_size: u64 LE][key][value]`, with the
+/// attributes ahead of the value unless there are none. A deletion never
+/// has any.
+pub(crate) fn write_record<W: Write>(
+    writer: &mut W,
+    key: &str,
+    value: &str,
+    attributes: &Attributes,
+) -> std::io::Result<()> {
+    let attributes = if value.is_empty() {
+        String::new()
+    } else {
+        encode_attributes(attributes)
+    };
+    write_header(writer, key, 0, &attributes, value.len())?;
+    writer.write_all(value.as_bytes())
+}
+
+/// Size of the record [`write_record`] appends.
+pub(crate) fn record_size(key: &str, value: &str, attributes: &Attributes) -> usize {
+    let attributes = if value.is_empty() {
+        0
+    } else {
+        encode_attributes(attributes).len()
+    };
+    RECORD_HEADER_SIZE + key.len() + attributes + value.len()
+}
+
+/// Writes a record's header, key and encoded attributes, leaving the
+/// `value_size` bytes after them to the caller.
+fn write_header<W: Write>(
+    writer: &mut W,
+    key: &str,
+    flags: u64,
+    attributes: &str,
+    value_size: usize,
+) -> std::io::Result<()> {
+    let flags = if attributes.is_empty() {
+        flags
+    } else {
+        flags | ATTRIBUTES_FLAG
+    };
+    writer.write_all(&(key.len() as u64 | flags).to_le_bytes())?;
+    writer.write_all(&((attributes.len() + value_size) as u64).to_le_bytes())?;
+    writer.write_all(key.as_bytes())?;
+    writer.write_all(attributes.as_bytes())
+}
+
+/// Set in the key size of a record whose value is a delta against the
+/// key's previous value rather than the value itself.
+const DELTA_FLAG: u64 = 1 << 63;
+/// Set in the key size of a record whose value starts with the key's
+/// attributes, as `{length}:{attributes}`: the length of the attributes
+/// in bytes, in decimal, then the attributes as JSON. Every record states
+/// all of the key's attributes, so one without them leaves it with none.
+const ATTRIBUTES_FLAG: u64 = 1 << 62;
+/// Values smaller than this are always written whole.
+const DELTA_MIN_VALUE_SIZE: usize = 4096;
+/// Deltas in a row for one key before its whole value is written again.
+const MAX_DELTA_CHAIN: usize = 16;
+
+/// Appends a record replacing the middle of the key's previous value:
+/// the value is `{prefix}:{suffix}:{middle}`, the lengths in bytes of the
+/// unchanged start and end in decimal, so the record stays valid UTF-8.
+fn write_delta_record<W: Write>(
+    writer: &mut W,
+    key: &str,
+    prefix: usize,
+    suffix: usize,
+    middle: &str,
+    attributes: &Attributes,
+) -> std::io::Result<()> {
+    let delta = format!("{}:{}:{}", prefix, suffix, middle);
+    write_header(
+        writer,
+        key,
+        DELTA_FLAG,
+        &encode_attributes(attributes),
+        delta.len(),
+    )?;
+    writer.write_all(delta.as_bytes())
+}
+
+/// `attributes` as they go ahead of a value, or nothing if there are none.
+fn encode_attributes(attributes: &Attributes) -> String {
+    if attributes.is_empty() {
+        return String::new();
+    }
+    let json = serde_json::to_string(attributes).unwrap();
+    format!("{}:{}", json.len(), json)
+}
+
+/// The attributes at the start of a record's value and the value or delta
+/// after them.
+pub fn parse_attributes(value: &[u8]) -> Option<(Attributes, &[u8])> {
+    let colon = value.iter().position(|&b| b == b':')?;
+    let length: usize = std::str::from_utf8(&value[..colon]).ok()?.parse().ok()?;
+    let rest = &value[colon + 1..];
+    if length > rest.len() {
+        return None;
+    }
+    let (json, value) = rest.split_at(length);
+    Some((serde_json::from_slice(json).ok()?, value))
+}
+
+/// The unchanged start and end of `value` compared with `previous`, in
+/// bytes, ending on character boundaries of both.
+fn common_ends(previous: &str, value: &str) -> (usize, usize) {
+    let (old, new) = (previous.as_bytes(), value.as_bytes());
+    let mut prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    while !(previous.is_char_boundary(prefix) && value.is_char_boundary(prefix)) {
+        prefix -= 1;
+    }
+    let longest = old.len().min(new.len()) - prefix;
+    let mut suffix = old
+        .iter()
+        .rev()
+        .zip(new.iter().rev())
+        .take(longest)
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !(previous.is_char_boundary(old.len() - suffix)
+        && value.is_char_boundary(new.len() - suffix))
+    {
+        suffix -= 1;
+    }
+    (prefix, suffix)
+}
+
+/// The unchanged prefix and suffix lengths and the new middle of a delta
+/// record's value.
+pub fn parse_delta(delta: &[u8]) -> Option<(usize, usize, &[u8])> {
+    let mut parts = delta.splitn(3, |&b| b == b':');
+    let mut length = || std::str::from_utf8(parts.next()?).ok()?.parse().ok();
+    let (prefix, suffix) = (length()?, length()?);
+    Some((prefix, suffix, parts.next()?))
+}
+
+/// Rebuilds a value from the previous one and a delta record's value.
+fn apply_delta(previous: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let (prefix, suffix, middle) = parse_delta(delta)?;
+    if prefix.checked_add(suffix)? > previous.len() {
+        return None;
+    }
+    let mut value = Vec::with_capacity(prefix + middle.len() + suffix);
+    value.extend_from_slice(&previous[..prefix]);
+    value.extend_from_slice(middle);
+    value.extend_from_slice(&previous[previous.len() - suffix..]);
+    Some(value)
+}
+
+/// One record of a data file, exactly as stored.
+pub struct Record<'a> {
+    /// Byte offset of the record's header in the file.
+    pub offset: usize,
+    pub key: &'a [u8],
+    /// Empty for a deletion.
+    pub value: &'a [u8],
+    /// Whether `value` is a delta against the key's previous value, as
+    /// read by [`parse_delta`].
+    pub delta: bool,
+    /// Whether `value` starts with the key's attributes, as read by
+    /// [`parse_attributes`].
+    pub attributes: bool,
+}
+
+impl<'a> Record<'a> {
+    /// Size of the record on disk, header included.
+    pub fn size(&self) -> usize {
+        RECORD_HEADER_SIZE + self.key.len() + self.value.len()
+    }
+
+    pub fn is_delete(&self) -> bool {
+        !self.delta && self.value.is_empty()
+    }
+
+    /// The key's attributes and the value or delta, or `None` if the
+    /// attributes cannot be read.
+    pub fn split_attributes(&self) -> Option<(Attributes, &'a [u8])> {
+        if self.attributes {
+            parse_attributes(self.value)
+        } else {
+            Some((Attributes::NONE, self.value))
+        }
+    }
+}
+
+const RECORD_HEADER_SIZE: usize = 16;
+
+/// Iterates over the complete records at the start of a data file.
+pub struct Records<'a> {
+    buffer: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Records<'a> {
+    pub fn new(buffer: &'a [u8]) -> Self {
+        Self { buffer, pos: 0 }
+    }
+
+    /// How many bytes the records returned so far take up. Once iteration
+    /// has finished, a value short of the buffer's length means the data
+    /// ends mid-record.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Record<'a>;
+
+    fn next(&mut self) -> Option<Record<'a>> {
+        let rest = &self.buffer[self.pos..];
+        if rest.len() < RECORD_HEADER_SIZE {
+            return None;
+        }
+        let key_size = u64::from_le_bytes(rest[0..8].try_into().unwrap());
+        let delta = key_size & DELTA_FLAG != 0;
+        let attributes = key_size & ATTRIBUTES_FLAG != 0;
+        let key_size = key_size & !(DELTA_FLAG | ATTRIBUTES_FLAG);
+        let value_size = u64::from_le_bytes(rest[8..16].try_into().unwrap());
+        let body = &rest[RECORD_HEADER_SIZE..];
+        if key_size.saturating_add(value_size) > body.len() as u64 {
+            return None;
+        }
+        let (key, value) = body.split_at(key_size as usize);
+        let record = Record {
+            offset: self.pos,
+            key,
+            value: &value[..value_size as usize],
+            delta,
+            attributes,
+        };
+        self.pos += record.size();
+        Some(record)
+    }
+}
+
+/// The live keys of a data file, each with its value and attributes.
+pub(crate) type Loaded = HashMap<String, (String, Attributes)>;
+
+/// Replays a data file, returning the live keys and how many bytes formed
+/// complete records. A shorter count means the input ends mid-record.
+pub(crate) fn load_records(buffer: &[u8]) -> (Loaded, usize) {
+    let mut data = HashMap::new();
+    let consumed = replay_records(&mut data, buffer);
+    (data, consumed)
+}
+
+/// Replays the records in `buffer` over `data`, returning how many bytes
+/// formed complete records.
+fn replay_records(data: &mut Loaded, buffer: &[u8]) -> usize {
+    let mut records = Records::new(buffer);
+    for record in records.by_ref() {
+        let key = String::from_utf8_lossy(record.key).to_string();
+        if record.is_delete() {
+            data.remove(&key);
+            continue;
+        }
+        // Attributes that cannot be read make the record unreadable too.
+        let Some((attributes, value)) = record.split_attributes() else {
+            continue;
+        };
+        if record.delta {
+            // A delta whose base is missing has nothing to apply to.
+            let value = data
+                .get(&key)
+                .and_then(|(previous, _)| apply_delta(previous.as_bytes(), value));
+            if let Some(value) = value {
+                let value = String::from_utf8(value)
+                    .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned());
+                data.insert(key, (value, attributes));
+            }
+        } else {
+            data.insert(
+                key,
+                (String::from_utf8_lossy(value).to_string(), attributes),
+            );
+        }
+    }
+    records.position()
+}
+
+/// The default backend: a data file holding the last checkpoint and the
+/// write-ahead log of changes since.
+pub struct FileBackend {
+    /// The write-ahead log.
+    wal: File,
+    /// The checkpoint.
+    path: PathBuf,
+    wal_path: PathBuf,
+    /// Deltas written since the whole value, for keys whose whole value is
+    /// in the log. Only these keys get deltas, so every delta in the log
+    /// applies to a value earlier in it, and replaying the log over a
+    /// checkpoint that already has its changes, after a crash during a
+    /// compaction, still ends up with the right values.
+    delta_chains: HashMap<String, usize>,
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+fn open_read_write(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+impl FileBackend {
+    /// Opens the data file at `path` and its log, creating them if needed.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let wal_path = with_suffix(&path, ".wal");
+        open_read_write(&path)?;
+        Ok(Self {
+            wal: open_read_write(&wal_path)?,
+            path,
+            wal_path,
+            delta_chains: HashMap::new(),
+        })
+    }
+
+    /// Writes `entries` to a new checkpoint, puts it in place of the data
+    /// file and empties the log. The data file is left as it was if that
+    /// fails.
+    fn rewrite(&mut self, entries: &mut dyn Iterator<Item = Entry<'_>>) -> Result<(), Error> {
+        let temp_path = with_suffix(&self.path, ".compact");
+        let result = (|| {
+            let mut writer = BufWriter::new(File::create(&temp_path)?);
+            for (key, value, attributes) in entries {
+                write_record(&mut writer, key, value, attributes)?;
+            }
+            // The log is emptied next, so the checkpoint must be on disk
+            // first.
+            writer
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all()?;
+            std::fs::rename(&temp_path, &self.path)?;
+            if let Some(dir) = self.path.parent()
+                && let Ok(dir) = File::open(if dir.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    dir
+                })
+            {
+                let _ = dir.sync_all();
+            }
+            Ok(())
+        })();
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(Error::Io(e));
+        }
+        self.wal.set_len(0)?;
+        self.wal.seek(SeekFrom::Start(0))?;
+        self.delta_chains.clear();
+        Ok(())
+    }
+
+    /// Appends records to the log with `write`.
+    fn write(&mut self, write: impl FnOnce(&mut File) -> std::io::Result<()>) -> Result<(), Error> {
+        let end = self.wal.stream_position()?;
+        if let Err(e) = write(&mut self.wal).and_then(|()| self.wal.flush()) {
+            // Drop what was written of the records, such as when the disk
+            // filled up, so later records are not appended after half of
+            // one.
+            let _ = self.wal.set_len(end);
+            let _ = self.wal.seek(SeekFrom::Start(end));
+            return Err(Error::Io(e));
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for FileBackend {
+    fn load(&mut self) -> Result<Vec<(String, String, Attributes)>, Error> {
+        let mut data = HashMap::new();
+        replay_records(&mut data, &std::fs::read(&self.path)?);
+
+        let mut buffer = Vec::new();
+        self.wal.seek(SeekFrom::Start(0))?;
+        self.wal.read_to_end(&mut buffer)?;
+        let complete = replay_records(&mut data, &buffer);
+        if complete < buffer.len() {
+            // A record cut short by a crash was never acknowledged.
+            self.wal.set_len(complete as u64)?;
+            self.wal.seek(SeekFrom::Start(complete as u64))?;
+        }
+        self.delta_chains.clear();
+        for record in Records::new(&buffer) {
+            let key = String::from_utf8_lossy(record.key);
+            if record.delta {
+                if let Some(chain) = self.delta_chains.get_mut(key.as_ref()) {
+                    *chain += 1;
+                }
+            } else if record.is_delete() {
+                self.delta_chains.remove(key.as_ref());
+            } else {
+                self.delta_chains.insert(key.into_owned(), 0);
+            }
+        }
+        Ok(data
+            .into_iter()
+            .map(|(key, (value, attributes))| (key, value, attributes))
+            .collect())
+    }
+
+    fn append(&mut self, key: &str, value: &str, attributes: &Attributes) -> Result<(), Error> {
+        self.write(|file| write_record(file, key, value, attributes))?;
+        if value.is_empty() {
+            self.delta_chains.remove(key);
+        } else {
+            self.delta_chains.insert(key.to_string(), 0);
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, keys: &[&str]) -> Result<(), Error> {
+        // One write, so a full disk refuses all of them or none.
+        self.write(|file| {
+            let mut writer = BufWriter::new(file);
+            for key in keys {
+                write_record(&mut writer, key, "", &Attributes::NONE)?;
+            }
+            writer.flush()
+        })?;
+        for key in keys {
+            self.delta_chains.remove(*key);
+        }
+        Ok(())
+    }
+
+    fn append_batch(&mut self, entries: &[Entry<'_>]) -> Result<(), Error> {
+        // One write, as for deletes.
+        self.write(|file| {
+            let mut writer = BufWriter::new(file);
+            for (key, value, attributes) in entries {
+                write_record(&mut writer, key, value, attributes)?;
+            }
+            writer.flush()
+        })?;
+        for (key, value, _) in entries {
+            if value.is_empty() {
+                self.delta_chains.remove(*key);
+            } else {
+                self.delta_chains.insert(key.to_string(), 0);
+            }
+        }
+        Ok(())
+    }
+
+    fn append_update(
+        &mut self,
+        key: &str,
+        previous: &str,
+        value: &str,
+        attributes: &Attributes,
+    ) -> Result<(), Error> {
+        let Some(&chain) = self.delta_chains.get(key) else {
+            return self.append(key, value, attributes);
+        };
+        if value.len() < DELTA_MIN_VALUE_SIZE || chain >= MAX_DELTA_CHAIN {
+            return self.append(key, value, attributes);
+        }
+        let (prefix, suffix) = common_ends(previous, value);
+        let middle = &value[prefix..value.len() - suffix];
+        // Only worth it when the delta is well under the whole value.
+        if middle.len() > value.len() / 2 {
+            return self.append(key, value, attributes);
+        }
+        self.write(|file| write_delta_record(file, key, prefix, suffix, middle, attributes))?;
+        self.delta_chains.insert(key.to_string(), chain + 1);
+        Ok(())
+    }
+
+    fn compact(&mut self, entries: &mut dyn Iterator<Item = Entry<'_>>) -> Result<(), Error> {
+        self.rewrite(entries)
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.wal.sync_all()?;
+        Ok(())
+    }
+
+    fn size(&mut self) -> Result<Option<u64>, Error> {
+        Ok(Some(
+            std::fs::metadata(&self.path)?.len() + self.wal.metadata()?.len(),
+        ))
+    }
+
+    fn log_size(&mut self) -> Result<Option<u64>, Error> {
+        Ok(Some(self.wal.metadata()?.len()))
+    }
+
+    fn snapshot(&mut self, path: &Path) -> Result<(), Error> {
+        // Neither file is ever left mid-record, so the checkpoint followed
+        // by the log is a consistent data file.
+        self.wal.flush()?;
+        let mut copy = File::create(path)?;
+        std::io::copy(&mut File::open(&self.path)?, &mut copy)?;
+        std::io::copy(&mut File::open(&self.wal_path)?, &mut copy)?;
+        copy.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Keeps nothing: the store's in-memory map is the only copy of the data,
+/// and it is gone once the process exits.
+#[derive(Default)]
+pub struct MemoryBackend;
+
+impl StorageBackend for MemoryBackend {
+    fn load(&mut self) -> Result<Vec<(String, String, Attributes)>, Error> {
+        Ok(Vec::new())
+    }
+
+    fn append(&mut self, _key: &str, _value: &str, _attributes: &Attributes) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn compact(&mut self, _entries: &mut dyn Iterator<Item = Entry<'_>>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Stores keys in a [sled](https://docs.rs/sled) database directory.
+/// Writes reach disk when sled flushes its log, every half second by
+/// default.
+#[cfg(feature = "sled")]
+pub struct SledBackend {
+    db: sled::Db,
+}
+
+#[cfg(feature = "sled")]
+impl SledBackend {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(Self {
+            db: sled::open(path).map_err(sled_error)?,
+        })
+    }
+}
+
+#[cfg(feature = "sled")]
+fn sled_error(e: sled::Error) -> Error {
+    match e {
+        sled::Error::Io(e) => Error::Io(e),
+        e => Error::Io(std::io::Error::other(e)),
+    }
+}
+
+/// Starts a sled value that has attributes ahead of it, encoded as in
+/// the data file. Values without attributes are stored as they are, and
+/// UTF-8 never has this byte.
+#[cfg(feature = "sled")]
+const SLED_ATTRIBUTES_MARKER: u8 = 0xff;
+
+#[cfg(feature = "sled")]
+fn sled_value(value: &str, attributes: &Attributes) -> Vec<u8> {
+    let attributes = encode_attributes(attributes);
+    if attributes.is_empty() {
+        return value.as_bytes().to_vec();
+    }
+    let mut stored = Vec::with_capacity(1 + attributes.len() + value.len());
+    stored.push(SLED_ATTRIBUTES_MARKER);
+    stored.extend_from_slice(attributes.as_bytes());
+    stored.extend_from_slice(value.as_bytes());
+    stored
+}
+
+#[cfg(feature = "sled")]
+impl StorageBackend for SledBackend {
+    fn load(&mut self) -> Result<Vec<(String, String, Attributes)>, Error> {
+        let mut data = Vec::new();
+        for entry in self.db.iter() {
+            let (key, stored) = entry.map_err(sled_error)?;
+            let (attributes, value) = match stored.split_first() {
+                Some((&SLED_ATTRIBUTES_MARKER, rest)) => match parse_attributes(rest) {
+                    Some(parsed) => parsed,
+                    None => continue,
+                },
+                _ => (Attributes::NONE, &stored[..]),
+            };
+            data.push((
+                String::from_utf8_lossy(&key).into_owned(),
+                String::from_utf8_lossy(value).into_owned(),
+                attributes,
+            ));
+        }
+        Ok(data)
+    }
+
+    fn append(&mut self, key: &str, value: &str, attributes: &Attributes) -> Result<(), Error> {
+        if value.is_empty() {
+            self.db.remove(key).map_err(sled_error)?;
+        } else {
+            self.db
+                .insert(key, sled_value(value, attributes))
+                .map_err(sled_error)?;
+        }
+        Ok(())
+    }
+
+    fn append_batch(&mut self, entries: &[Entry<'_>]) -> Result<(), Error> {
+        let mut batch = sled::Batch::default();
+        for (key, value, attributes) in entries {
+            if value.is_empty() {
+                batch.remove(*key);
+            } else {
+                batch.insert(*key, sled_value(value, attributes));
+            }
+        }
+        self.db.apply_batch(batch).map_err(sled_error)?;
+        Ok(())
+    }
+
+    fn compact(&mut self, entries: &mut dyn Iterator<Item = Entry<'_>>) -> Result<(), Error> {
+        // One batch, so a crash leaves either the old or the new contents.
+        let mut batch = sled::Batch::default();
+        let mut live = std::collections::HashSet::new();
+        for (key, value, attributes) in entries {
+            batch.insert(key, sled_value(value, attributes));
+            live.insert(key.as_bytes().to_vec());
+        }
+        for key in self.db.iter().keys() {
+            let key = key.map_err(sled_error)?;
+            if !live.contains(key.as_ref()) {
+                batch.remove(key);
+            }
+        }
+        self.db.apply_batch(batch).map_err(sled_error)?;
+        self.db.flush().map_err(sled_error)?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.db.flush().map_err(sled_error)?;
+        Ok(())
+    }
+
+    fn size(&mut self) -> Result<Option<u64>, Error> {
+        self.db.size_on_disk().map(Some).map_err(sled_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for one test's data files.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("kstore-storage-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn load_sorted(backend: &mut FileBackend) -> Vec<(String, String)> {
+        let mut data: Vec<(String, String)> = backend
+            .load()
+            .unwrap()
+            .into_iter()
+            .map(|(key, value, _)| (key, value))
+            .collect();
+        data.sort();
+        data
+    }
+
+    fn large_value(fill: char) -> String {
+        std::iter::repeat_n(fill, DELTA_MIN_VALUE_SIZE * 2).collect()
+    }
+
+    #[test]
+    fn delta_record_round_trips() {
+        let mut buffer = Vec::new();
+        write_record(&mut buffer, "key", "hello world", &Attributes::NONE).unwrap();
+        write_delta_record(&mut buffer, "key", 6, 0, "there", &Attributes::NONE).unwrap();
+
+        let records: Vec<Record> = Records::new(&buffer).collect();
+        assert_eq!(records.len(), 2);
+        assert!(!records[0].delta);
+        assert!(records[1].delta);
+        assert_eq!(records[1].key, b"key");
+        assert_eq!(parse_delta(records[1].value), Some((6, 0, &b"there"[..])));
+
+        let (data, consumed) = load_records(&buffer);
+        assert_eq!(consumed, buffer.len());
+        assert_eq!(data["key"].0, "hello there");
+    }
+
+    #[test]
+    fn attributes_are_stored_with_the_value() {
+        let expiring = Attributes {
+            expires_at: Some(1_700_000_000),
+            ..Attributes::NONE
+        };
+        let mut buffer = Vec::new();
+        write_record(&mut buffer, "key", "hello world", &expiring).unwrap();
+        write_delta_record(&mut buffer, "key", 6, 0, "there", &expiring).unwrap();
+        write_record(&mut buffer, "plain", "x", &expiring).unwrap();
+        write_record(&mut buffer, "plain", "y", &Attributes::NONE).unwrap();
+        assert_eq!(
+            record_size("key", "hello world", &expiring),
+            Records::new(&buffer).next().unwrap().size()
+        );
+
+        let (data, consumed) = load_records(&buffer);
+        assert_eq!(consumed, buffer.len());
+        assert_eq!(data["key"], ("hello there".to_string(), expiring));
+        assert_eq!(data["plain"], ("y".to_string(), Attributes::NONE));
+    }
+
+    #[test]
+    fn delta_without_base_is_skipped() {
+        let mut buffer = Vec::new();
+        write_delta_record(&mut buffer, "key", 1, 1, "x", &Attributes::NONE).unwrap();
+        let (data, consumed) = load_records(&buffer);
+        assert_eq!(consumed, buffer.len());
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn delta_reaching_past_its_base_is_rejected() {
+        assert_eq!(apply_delta(b"abc", b"2:2:x"), None);
+        assert_eq!(apply_delta(b"abc", b"not a delta"), None);
+        assert_eq!(apply_delta(b"abc", b"1:1:x"), Some(b"axc".to_vec()));
+    }
+
+    #[test]
+    fn common_ends_stop_on_character_boundaries() {
+        // The values differ in the last byte of a two byte character.
+        let (prefix, suffix) = common_ends("aé", "aè");
+        assert_eq!((prefix, suffix), (1, 0));
+        let (prefix, suffix) = common_ends("éa", "èa");
+        assert_eq!((prefix, suffix), (0, 1));
+    }
+
+    #[test]
+    fn updates_are_written_as_deltas_and_load_back() {
+        let dir = temp_dir("deltas");
+        let path = dir.join("kvstore.db");
+        let base = large_value('a');
+        let mut updated = base.clone();
+        updated.replace_range(100..110, "0123456789");
+
+        let mut backend = FileBackend::open(&path).unwrap();
+        backend.append("key", &base, &Attributes::NONE).unwrap();
+        let before = backend.log_size().unwrap().unwrap();
+        backend
+            .append_update("key", &base, &updated, &Attributes::NONE)
+            .unwrap();
+        let delta_size = backend.log_size().unwrap().unwrap() - before;
+        assert!(delta_size < 100, "delta took {} bytes", delta_size);
+
+        let mut reopened = FileBackend::open(&path).unwrap();
+        assert_eq!(
+            load_sorted(&mut reopened),
+            vec![("key".to_string(), updated)]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn delta_chains_are_cut_short() {
+        let dir = temp_dir("chains");
+        let mut backend = FileBackend::open(dir.join("kvstore.db")).unwrap();
+        let mut value = large_value('a');
+        backend.append("key", &value, &Attributes::NONE).unwrap();
+        for i in 0..=MAX_DELTA_CHAIN {
+            let previous = value.clone();
+            value.replace_range(i..i + 1, "b");
+            backend
+                .append_update("key", &previous, &value, &Attributes::NONE)
+                .unwrap();
+        }
+        // The update after a full chain is written whole.
+        assert_eq!(backend.delta_chains["key"], 0);
+        assert_eq!(load_sorted(&mut backend), vec![("key".to_string(), value)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn torn_wal_tail_is_dropped() {
+        let dir = temp_dir("torn");
+        let path = dir.join("kvstore.db");
+        let mut backend = FileBackend::open(&path).unwrap();
+        backend.append("a", "1", &Attributes::NONE).unwrap();
+        backend.append("b", "2", &Attributes::NONE).unwrap();
+        let complete = backend.log_size().unwrap().unwrap();
+        drop(backend);
+
+        // A crash part way through appending a record.
+        let mut torn = Vec::new();
+        write_record(&mut torn, "c", "3", &Attributes::NONE).unwrap();
+        let mut wal = OpenOptions::new()
+            .append(true)
+            .open(dir.join("kvstore.db.wal"))
+            .unwrap();
+        wal.write_all(&torn[..torn.len() - 1]).unwrap();
+        drop(wal);
+
+        let mut backend = FileBackend::open(&path).unwrap();
+        assert_eq!(
+            load_sorted(&mut backend),
+            vec![
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "2".to_string())
+            ]
+        );
+        assert_eq!(backend.log_size().unwrap(), Some(complete));
+
+        // Later records follow the last complete one.
+        backend.append("d", "4", &Attributes::NONE).unwrap();
+        let mut reopened = FileBackend::open(&path).unwrap();
+        assert_eq!(load_sorted(&mut reopened).len(), 3);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn batches_are_written_whole() {
+        let dir = temp_dir("batch");
+        let path = dir.join("kvstore.db");
+        let mut backend = FileBackend::open(&path).unwrap();
+        backend.append("a", "1", &Attributes::NONE).unwrap();
+        let expiring = Attributes {
+            expires_at: Some(1_700_000_000),
+            ..Attributes::NONE
+        };
+        backend
+            .append_batch(&[("a", "2", &expiring), ("b", "3", &Attributes::NONE)])
+            .unwrap();
+
+        let mut reopened = FileBackend::open(&path).unwrap();
+        let mut data = reopened.load().unwrap();
+        data.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            data,
+            vec![
+                ("a".to_string(), "2".to_string(), expiring),
+                ("b".to_string(), "3".to_string(), Attributes::NONE)
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compaction_empties_the_wal() {
+        let dir = temp_dir("checkpoint");
+        let path = dir.join("kvstore.db");
+        let mut backend = FileBackend::open(&path).unwrap();
+        backend.append("a", "1", &Attributes::NONE).unwrap();
+        backend.append("b", "2", &Attributes::NONE).unwrap();
+        backend.delete(&["b"]).unwrap();
+        backend
+            .compact(&mut [("a", "1", &Attributes::NONE)].into_iter())
+            .unwrap();
+        assert_eq!(backend.log_size().unwrap(), Some(0));
+        backend.append("c", "3", &Attributes::NONE).unwrap();
+
+        let mut reopened = FileBackend::open(&path).unwrap();
+        assert_eq!(
+            load_sorted(&mut reopened),
+            vec![
+                ("a".to_string(), "1".to_string()),
+                ("c".to_string(), "3".to_string())
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn crash_between_checkpoint_and_wal_truncation_is_recovered() {
+        let dir = temp_dir("crash");
+        let path = dir.join("kvstore.db");
+        let base = large_value('a');
+        let mut updated = base.clone();
+        updated.replace_range(0..4, "head");
+
+        let mut backend = FileBackend::open(&path).unwrap();
+        backend.append("big", &base, &Attributes::NONE).unwrap();
+        backend
+            .append_update("big", &base, &updated, &Attributes::NONE)
+            .unwrap();
+        backend.append("gone", "x", &Attributes::NONE).unwrap();
+        backend.delete(&["gone"]).unwrap();
+        backend.append("kept", "1", &Attributes::NONE).unwrap();
+        let wal = std::fs::read(dir.join("kvstore.db.wal")).unwrap();
+        backend
+            .compact(
+                &mut [
+                    ("big", updated.as_str(), &Attributes::NONE),
+                    ("kept", "1", &Attributes::NONE),
+                ]
+                .into_iter(),
+            )
+            .unwrap();
+        drop(backend);
+
+        // The new checkpoint is in place but the log was never emptied.
+        std::fs::write(dir.join("kvstore.db.wal"), &wal).unwrap();
+
+        let mut backend = FileBackend::open(&path).unwrap();
+        let expected = vec![
+            ("big".to_string(), updated.clone()),
+            ("kept".to_string(), "1".to_string()),
+        ];
+        assert_eq!(load_sorted(&mut backend), expected);
+
+        // Deltas written after recovery still apply to the right value.
+        let mut newer = updated.clone();
+        newer.replace_range(4..8, "tail");
+        backend
+            .append_update("big", &updated, &newer, &Attributes::NONE)
+            .unwrap();
+        let mut reopened = FileBackend::open(&path).unwrap();
+        assert_eq!(
+            load_sorted(&mut reopened),
+            vec![
+                ("big".to_string(), newer),
+                ("kept".to_string(), "1".to_string())
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn crash_before_checkpoint_rename_keeps_the_old_one() {
+        let dir = temp_dir("partial");
+        let path = dir.join("kvstore.db");
+        let mut backend = FileBackend::open(&path).unwrap();
+        backend.append("a", "1", &Attributes::NONE).unwrap();
+        backend
+            .compact(&mut [("a", "1", &Attributes::NONE)].into_iter())
+            .unwrap();
+        backend.append("b", "2", &Attributes::NONE).unwrap();
+        drop(backend);
+
+        // A half written checkpoint that was never renamed into place.
+        std::fs::write(dir.join("kvstore.db.compact"), b"garbage").unwrap();
+
+        let mut backend = FileBackend::open(&path).unwrap();
+        assert_eq!(
+            load_sorted(&mut backend),
+            vec![
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "2".to_string())
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,33 @@
+//! systemd integration. With socket activation the server takes over the
+//! listening sockets systemd passes it instead of binding `--bind`, so
+//! systemd can hold the port across restarts, and with `Type=notify` it
+//! reports readiness once the data has been loaded. Both do nothing when
+//! the server was not started by systemd.
+
+use std::net::TcpListener;
+use std::os::fd::FromRawFd;
+
+use sd_notify::NotifyState;
+
+/// Sockets passed in by systemd socket activation, if any.
+pub fn listeners() -> std::io::Result<Vec<TcpListener>> {
+    Ok(sd_notify::listen_fds()?
+        // Safety: systemd hands these descriptors to this process alone,
+        // and `listen_fds` unsets its variables so they are taken once.
+        .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+        .collect())
+}
+
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        eprintln!("Failed to notify systemd: {}", e);
+    }
+}
+
+pub fn notify_ready(status: &str) {
+    notify(&[NotifyState::Ready, NotifyState::Status(status)]);
+}
+
+pub fn notify_stopping() {
+    notify(&[NotifyState::Stopping]);
+}
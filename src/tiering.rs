@@ -0,0 +1,108 @@
+//! Keeps very large values in S3-compatible object storage instead of the
+//! data file. A value over the threshold is uploaded as an object named by
+//! its SHA-256, and the key holds a short pointer to it, which `GET
+//! /kv/{key}` follows transparently. A few huge values then no longer
+//! dominate the data file, memory use and compaction time.
+//!
+//! The pointer is also recorded under the reserved prefix, and deleted
+//! along with the key, so a client value that merely looks like one is
+//! returned as it is. Objects are never
+//! deleted by kstore, since other keys may share them.
+
+use actix_web::HttpResponse;
+use actix_web::web::Bytes;
+use kstore::{KvStore, Value};
+use sha2::{Digest, Sha256};
+
+use crate::reserved;
+use crate::s3::S3Client;
+
+const POINTER_PREFIX: &str = "tiered:";
+/// Namespace of the pointer records under the reserved prefix.
+pub const RECORD_NAMESPACE: &str = "tier/";
+
+pub struct Tiering {
+    s3: S3Client,
+    threshold: usize,
+    /// Prefix of the keys recording pointers, under the reserved prefix.
+    record_prefix: String,
+}
+
+impl Tiering {
+    pub fn new(
+        s3: Option<S3Client>,
+        threshold: usize,
+        reserved_prefix: &str,
+    ) -> Result<Self, String> {
+        let s3 = s3.ok_or("--tier-threshold needs S3 to be configured with KSTORE_S3_*")?;
+        if reserved_prefix.is_empty() {
+            return Err(
+                "--tier-threshold needs a --reserved-prefix to record pointers in".to_string(),
+            );
+        }
+        Ok(Self {
+            s3,
+            threshold,
+            record_prefix: format!("{}{}", reserved_prefix, RECORD_NAMESPACE),
+        })
+    }
+
+    /// The value to store for `value`: the value itself, or once it has
+    /// been uploaded, a pointer to it. Also returns whether it was
+    /// offloaded. The size limit applies to the whole value either way.
+    pub async fn offload(
+        &self,
+        store: &KvStore,
+        value: String,
+    ) -> Result<(String, bool), HttpResponse> {
+        if value.len() <= self.threshold {
+            return Ok((value, false));
+        }
+        let max_value_size = store.limits().max_value_size;
+        if value.len() > max_value_size {
+            return Err(HttpResponse::BadRequest()
+                .body(kstore::Error::ValueTooLarge(max_value_size).to_string()));
+        }
+        let hash: String = Sha256::digest(value.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let size = value.len();
+        self.s3
+            .put_object(&format!("tier/{}", hash), value.into_bytes())
+            .await
+            .map_err(|e| HttpResponse::BadGateway().body(e))?;
+        Ok((format!("{}{}:{}", POINTER_PREFIX, hash, size), true))
+    }
+
+    /// Records whether the value just written to `key` is a pointer.
+    pub fn record(&self, store: &KvStore, key: &str, value: &str, offloaded: bool) {
+        let record = reserved::record_key(&self.record_prefix, key);
+        if offloaded {
+            let _ = store.set(record, value.to_string());
+        } else if store.exists(&record) {
+            let _ = store.delete(&record);
+        }
+    }
+
+    /// The value of `key` to send to clients, fetching it from S3 if
+    /// `value` is a pointer.
+    pub async fn resolve(&self, store: &KvStore, key: &str, value: Value) -> Result<Bytes, String> {
+        let Some(hash) = value
+            .strip_prefix(POINTER_PREFIX)
+            .and_then(|pointer| pointer.split_once(':'))
+            .map(|(hash, _)| hash)
+        else {
+            return Ok(value.into_bytes());
+        };
+        let record = reserved::record_key(&self.record_prefix, key);
+        let record = store.values(&[record]).into_values().next().flatten();
+        if record.as_deref() != Some(value.as_str()) {
+            return Ok(value.into_bytes());
+        }
+        self.s3
+            .get_object(&format!("tier/{}", hash))
+            .await?
+            .ok_or_else(|| format!("The value of {} is missing from S3", key))
+    }
+}
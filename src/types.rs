@@ -0,0 +1,228 @@
+//! Value types. A write can tag its key with a type in the `X-Value-Type`
+//! header, after which every write of the key through `/kv/{key}` or
+//! `/batch` must be a value of that type, until a write names another.
+//! Reads of a typed key are sent with a matching `Content-Type`, and
+//! `GET /kv/{key}?as=` reads any value as a given type.
+//!
+//! The tag is stored in the attributes of the key, written with its value
+//! in one record, so it is kept across restarts, copied to replicas with
+//! the data and deleted along with its key. Creating a key sets or clears
+//! it.
+
+use actix_web::HttpRequest;
+use kstore::{Attributes, KvStore};
+
+pub const TYPE_HEADER: &str = "X-Value-Type";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    String,
+    Int,
+    Float,
+    Bool,
+    Json,
+}
+
+impl ValueType {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "string" => Ok(ValueType::String),
+            "int" => Ok(ValueType::Int),
+            "float" => Ok(ValueType::Float),
+            "bool" => Ok(ValueType::Bool),
+            "json" => Ok(ValueType::Json),
+            _ => Err(format!(
+                "Unknown value type '{}', use string, int, float, bool or json",
+                name
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ValueType::String => "string",
+            ValueType::Int => "int",
+            ValueType::Float => "float",
+            ValueType::Bool => "bool",
+            ValueType::Json => "json",
+        }
+    }
+
+    /// Whether `value` is a value of this type. Numbers and booleans are
+    /// written the way JSON writes them.
+    pub fn accepts(self, value: &str) -> bool {
+        match self {
+            ValueType::String => true,
+            ValueType::Int => value.parse::<i64>().is_ok(),
+            ValueType::Float => value.parse::<f64>().is_ok_and(f64::is_finite),
+            ValueType::Bool => value == "true" || value == "false",
+            ValueType::Json => serde_json::from_str::<serde_json::Value>(value).is_ok(),
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ValueType::Json => "application/json",
+            _ => "text/plain; charset=utf-8",
+        }
+    }
+}
+
+/// The error for a value that is not of the type its key needs.
+pub fn mismatch(key: &str, value_type: ValueType) -> String {
+    format!(
+        "The value of {} must be a valid {}",
+        key,
+        value_type.as_str()
+    )
+}
+
+/// The type a request names in its `X-Value-Type` header, if any.
+pub fn requested(req: &HttpRequest) -> Result<Option<ValueType>, String> {
+    let Some(header) = req.headers().get(TYPE_HEADER) else {
+        return Ok(None);
+    };
+    let name = header
+        .to_str()
+        .map_err(|_| format!("Invalid {} header", TYPE_HEADER))?;
+    ValueType::parse(name).map(Some)
+}
+
+/// The type `attributes` tag their key with.
+pub fn of(attributes: &Attributes) -> Option<ValueType> {
+    ValueType::parse(attributes.value_type.as_deref()?).ok()
+}
+
+/// The type `key` is tagged with.
+pub fn get(store: &KvStore, key: &str) -> Option<ValueType> {
+    of(&store.attributes(key)?)
+}
+
+/// The tag to store in the attributes of a key of `value_type`.
+pub fn tag(value_type: Option<ValueType>) -> Option<String> {
+    value_type.map(|value_type| value_type.as_str().to_string())
+}
+
+/// The type a write of `value` to `key` must have, failing if the value
+/// is not of it. `requested` is the type the write names, and `created`
+/// whether the write creates the key, which then has no type of its own
+/// yet.
+pub fn check_write(
+    store: &KvStore,
+    key: &str,
+    value: &str,
+    requested: Option<ValueType>,
+    created: bool,
+) -> Result<Option<ValueType>, String> {
+    let value_type = match requested {
+        Some(value_type) => Some(value_type),
+        None if created => None,
+        None => get(store, key),
+    };
+    match value_type {
+        Some(value_type) if !value_type.accepts(value) => Err(mismatch(key, value_type)),
+        _ => Ok(value_type),
+    }
+}
+
+/// Writes a value that names no type, such as an imported record, checked
+/// against the type of `key` if it has one, which the write keeps.
+pub fn write_untyped(store: &KvStore, key: String, value: String) -> Result<(), String> {
+    let value_type = check_write(store, &key, &value, None, !store.exists(&key))?;
+    let attributes = Attributes {
+        value_type: tag(value_type),
+        ..Attributes::NONE
+    };
+    store
+        .set_with(key, value, attributes)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kstore::MemoryBackend;
+
+    fn store() -> KvStore {
+        KvStore::with_backend(Box::new(MemoryBackend)).unwrap()
+    }
+
+    fn typed(value_type: ValueType) -> Attributes {
+        Attributes {
+            value_type: tag(Some(value_type)),
+            ..Attributes::NONE
+        }
+    }
+
+    #[test]
+    fn types_accept_their_values_only() {
+        assert!(ValueType::Int.accepts("-42"));
+        assert!(!ValueType::Int.accepts("4.2"));
+        assert!(!ValueType::Int.accepts("99999999999999999999"));
+        assert!(ValueType::Float.accepts("4.2"));
+        assert!(ValueType::Float.accepts("1e3"));
+        assert!(!ValueType::Float.accepts("inf"));
+        assert!(!ValueType::Float.accepts("NaN"));
+        assert!(ValueType::Bool.accepts("true"));
+        assert!(!ValueType::Bool.accepts("True"));
+        assert!(ValueType::Json.accepts(r#"{"a": [1, null]}"#));
+        assert!(ValueType::Json.accepts("42"));
+        assert!(!ValueType::Json.accepts("{"));
+        assert!(ValueType::String.accepts(""));
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for value_type in [
+            ValueType::String,
+            ValueType::Int,
+            ValueType::Float,
+            ValueType::Bool,
+            ValueType::Json,
+        ] {
+            assert_eq!(ValueType::parse(value_type.as_str()), Ok(value_type));
+        }
+        assert!(ValueType::parse("integer").is_err());
+    }
+
+    #[test]
+    fn writes_keep_the_type_of_the_key_unless_they_name_one() {
+        let store = store();
+        store
+            .set_with("n".to_string(), "1".to_string(), typed(ValueType::Int))
+            .unwrap();
+        assert_eq!(get(&store, "n"), Some(ValueType::Int));
+
+        assert_eq!(
+            check_write(&store, "n", "2", None, false),
+            Ok(Some(ValueType::Int))
+        );
+        assert_eq!(
+            check_write(&store, "n", "two", None, false),
+            Err(mismatch("n", ValueType::Int))
+        );
+        assert_eq!(
+            check_write(&store, "n", "two", Some(ValueType::String), false),
+            Ok(Some(ValueType::String))
+        );
+        // A key being created has no type of its own yet.
+        assert_eq!(check_write(&store, "n", "two", None, true), Ok(None));
+    }
+
+    #[test]
+    fn untyped_writes_are_checked_and_keep_the_type() {
+        let store = store();
+        store
+            .set_with("b".to_string(), "true".to_string(), typed(ValueType::Bool))
+            .unwrap();
+        assert!(write_untyped(&store, "b".to_string(), "yes".to_string()).is_err());
+        assert_eq!(store.get("b").unwrap().as_str(), "true");
+
+        write_untyped(&store, "b".to_string(), "false".to_string()).unwrap();
+        assert_eq!(store.get("b").unwrap().as_str(), "false");
+        assert_eq!(get(&store, "b"), Some(ValueType::Bool));
+
+        write_untyped(&store, "new".to_string(), "anything".to_string()).unwrap();
+        assert_eq!(get(&store, "new"), None);
+    }
+}